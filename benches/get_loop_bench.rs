@@ -0,0 +1,92 @@
+// Measures a tight loop of gets over a single connection, along with the allocations each get costs.
+//
+// The server runs in process so that its allocations are counted alongside the client's.
+// Compare revisions by saving a baseline on one (`cargo bench --bench get_loop_bench -- --save-baseline before`)
+// and comparing against it on the other (`-- --baseline before`), the allocation count is printed on each run.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use smoldb::{run, Client, StorageType};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+use tokio::sync::oneshot;
+
+const ADDR: &str = "127.0.0.1:4012";
+const NUM_OPS: u64 = 1000;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn get_loop_bench(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+
+    // Setup
+    let dir = TempDir::new().unwrap();
+    let addr: SocketAddr = ADDR.parse().unwrap();
+    let (tx, rx) = oneshot::channel();
+    let path = dir.path().to_path_buf();
+    rt.spawn(async move { run(addr, path, StorageType::Bitcask, rx).await.unwrap() });
+    let client = rt.block_on(async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let client = Client::connect(addr, 1);
+        for i in 0..NUM_OPS {
+            client
+                .set(format!("key{}", i), "value".to_string())
+                .await
+                .unwrap();
+        }
+        client
+    });
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    rt.block_on(get_keys(&client));
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!(
+        "get_loop_bench: {:.1} allocations per get",
+        allocations as f64 / NUM_OPS as f64
+    );
+
+    // Benchmark
+    let mut group = c.benchmark_group("get_loop_bench");
+    group.throughput(Throughput::Elements(NUM_OPS));
+    group.bench_function("get_loop_bench", |b| {
+        b.to_async(&rt).iter(|| get_keys(&client))
+    });
+    group.finish();
+
+    // Teardown
+    tx.send(()).unwrap();
+}
+
+async fn get_keys(client: &Client) {
+    for i in 0..NUM_OPS {
+        let val = client.get(format!("key{}", i)).await.unwrap();
+        assert_eq!(val, Some("value".to_string()));
+    }
+}
+
+criterion_group!(benches, get_loop_bench);
+criterion_main!(benches);
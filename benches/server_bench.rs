@@ -178,7 +178,7 @@ fn start_server(
     let (tx, rx) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("smoldb").unwrap();
     let mut child = server
-        .args(&[
+        .args([
             "--storage",
             storage_type,
             "--addr",
@@ -190,6 +190,7 @@ fn start_server(
     let handle = thread::spawn(move || {
         let _ = rx.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
     });
     thread::sleep(Duration::from_secs(1));
     (tx, handle)
@@ -0,0 +1,193 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::rngs::SmallRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use smoldb::{Bitcask, BitcaskOptions, Memory, Sled, Storage};
+use std::thread;
+use tempfile::TempDir;
+
+const NUM_KEYS: u64 = 1000;
+const NUM_THREADS: u64 = 8;
+
+// Compares concurrent gets against a single key_dir skip map and a sharded one.
+fn concurrent_get_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_get_bench");
+
+    for shards in [1, 16] {
+        group.bench_with_input(
+            BenchmarkId::new("concurrent_get_bench", format!("{} shards", shards)),
+            &shards,
+            |b, &shards| {
+                // Setup
+                let dir = TempDir::new().unwrap();
+                let options = BitcaskOptions {
+                    key_dir_shards: shards,
+                    ..BitcaskOptions::default()
+                };
+                let store = Bitcask::open_with_options(dir.path(), options).unwrap();
+                for i in 0..NUM_KEYS {
+                    store.set(format!("key{}", i), "value".to_string()).unwrap();
+                }
+
+                // Benchmark
+                b.iter(|| {
+                    let handles: Vec<_> = (0..NUM_THREADS)
+                        .map(|thread_id| {
+                            let store = store.clone();
+                            thread::spawn(move || {
+                                for i in 0..NUM_KEYS {
+                                    let key_id = (i + thread_id) % NUM_KEYS;
+                                    let val = store.get(format!("key{}", key_id)).unwrap();
+                                    assert_eq!(val, Some("value".to_string()));
+                                }
+                            })
+                        })
+                        .collect();
+                    for handle in handles {
+                        handle.join().unwrap();
+                    }
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+// Compares loading keys into an empty store with `bulk_load` against setting them one at a time.
+fn bulk_load_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bulk_load_bench");
+    group.sample_size(10);
+
+    let entries = || (0..NUM_KEYS * 10).map(|i| (format!("key{}", i), "value".to_string()));
+    group.bench_function("set", |b| {
+        b.iter_with_setup(
+            || TempDir::new().unwrap(),
+            |dir| {
+                let store = Bitcask::open(dir.path()).unwrap();
+                for (key, value) in entries() {
+                    store.set(key, value).unwrap();
+                }
+            },
+        );
+    });
+    group.bench_function("bulk_load", |b| {
+        b.iter_with_setup(
+            || TempDir::new().unwrap(),
+            |dir| {
+                let store = Bitcask::open(dir.path()).unwrap();
+                store.bulk_load(entries()).unwrap();
+            },
+        );
+    });
+    group.finish();
+}
+
+// Compares reading every value in key order after a compaction, with and without reading ahead.
+fn sequential_scan_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sequential_scan_bench");
+
+    let dir = TempDir::new().unwrap();
+    {
+        let store = Bitcask::open(dir.path()).unwrap();
+        for i in 0..NUM_KEYS * 10 {
+            store
+                .set(format!("key{:05}", i), "value".repeat(20))
+                .unwrap();
+        }
+        store.compact().unwrap();
+    }
+
+    for prefetch_size in [0, BitcaskOptions::default().prefetch_size] {
+        group.bench_with_input(
+            BenchmarkId::new("scan", format!("{} byte prefetch", prefetch_size)),
+            &prefetch_size,
+            |b, &prefetch_size| {
+                let options = BitcaskOptions {
+                    prefetch_size,
+                    ..BitcaskOptions::default()
+                };
+                let store = Bitcask::open_with_options(dir.path(), options).unwrap();
+                let keys = store.list_keys();
+                b.iter(|| {
+                    for key in keys.iter() {
+                        assert!(store.get(key.clone()).unwrap().is_some());
+                    }
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+// Compares reading values in random order from a compacted store through buffered reads and through a mapping of the
+// sealed merge file.
+fn sealed_read_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sealed_read_bench");
+
+    let dir = TempDir::new().unwrap();
+    {
+        let store = Bitcask::open(dir.path()).unwrap();
+        for i in 0..NUM_KEYS * 100 {
+            store
+                .set(format!("key{:06}", i), "value".repeat(40))
+                .unwrap();
+        }
+        store.compact().unwrap();
+    }
+
+    for mmap_sealed in [false, true] {
+        let name = if mmap_sealed { "mmap" } else { "buffered" };
+        group.bench_with_input(
+            BenchmarkId::new("random_get", name),
+            &mmap_sealed,
+            |b, &mmap_sealed| {
+                let options = BitcaskOptions {
+                    mmap_sealed,
+                    ..BitcaskOptions::default()
+                };
+                let store = Bitcask::open_with_options(dir.path(), options).unwrap();
+                let mut keys = store.list_keys();
+                keys.shuffle(&mut SmallRng::seed_from_u64(0));
+                b.iter(|| {
+                    for key in keys.iter() {
+                        assert!(store.get(key.clone()).unwrap().is_some());
+                    }
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+// Compares setting and then getting every key across the storage engines.
+fn engine_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("engine_bench");
+
+    let dir = TempDir::new().unwrap();
+    let bitcask = Bitcask::open(dir.path().join("bitcask")).unwrap();
+    group.bench_function("bitcask", |b| b.iter(|| set_get(&bitcask)));
+    let sled = Sled::open(dir.path().join("sled")).unwrap();
+    group.bench_function("sled", |b| b.iter(|| set_get(&sled)));
+    let memory = Memory::new();
+    group.bench_function("memory", |b| b.iter(|| set_get(&memory)));
+    group.finish();
+}
+
+fn set_get<S: Storage>(store: &S) {
+    for i in 0..NUM_KEYS {
+        store.set(format!("key{}", i), "value".to_string()).unwrap();
+    }
+    for i in 0..NUM_KEYS {
+        assert!(store.get(format!("key{}", i)).unwrap().is_some());
+    }
+}
+
+criterion_group!(
+    benches,
+    concurrent_get_bench,
+    bulk_load_bench,
+    sequential_scan_bench,
+    sealed_read_bench,
+    engine_bench
+);
+criterion_main!(benches);
@@ -1,17 +1,37 @@
+use std::io::{self, BufRead, BufWriter, Read, Write};
 use std::net::SocketAddr;
+use std::process::exit;
+use std::time::Duration;
 
 use clap::{Args, Parser, Subcommand};
+use serde::{Deserialize, Serialize};
 use smoldb::{Client, ClientResult};
 
 const DEFAULT_ADDR: &str = "127.0.0.1:4001";
 
+// The number of keys `dump` lists and gets at a time, and the number of pairs `load` sets at a time.
+const BATCH_SIZE: usize = 1000;
+
+// A line of a dump.
+#[derive(Serialize, Deserialize)]
+struct DumpEntry {
+    key: String,
+    value: String,
+}
+
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = "The address can also be set with SMOLDB_ADDR. The --addr flag takes precedence over the environment, which takes precedence over the default."
+)]
 struct Cli {
     #[clap(subcommand)]
     command: Command,
 
-    #[arg(short, long, value_enum, default_value = DEFAULT_ADDR)]
+    #[arg(short, long, value_enum, env = "SMOLDB_ADDR", default_value = DEFAULT_ADDR)]
     addr: SocketAddr,
 
     #[arg(short, long, default_value = "1")]
@@ -22,25 +42,66 @@ struct Cli {
 enum Command {
     #[command(name = "get", about = "Get the value of a given key")]
     Get(GetCommand),
+    #[command(
+        name = "mget",
+        about = "Get the values of several keys, printing a KEY<tab>VALUE line for each"
+    )]
+    MultiGet(MultiGetCommand),
     #[command(name = "set", about = "Set the value of a key")]
     Set(SetCommand),
     #[command(name = "rm", about = "Remove a given key")]
     Remove(RemoveCommand),
     #[command(name = "ls", about = "List all keys")]
-    List,
+    List(ListCommand),
+    #[command(
+        name = "dump",
+        about = "Print every key and value as newline-delimited JSON",
+        after_help = "Each line is a JSON object of the form {\"key\":\"KEY\",\"value\":\"VALUE\"}, in key order."
+    )]
+    Dump,
+    #[command(
+        name = "load",
+        about = "Set every key and value read from stdin as newline-delimited JSON, as printed by dump",
+        after_help = "Each line must be a JSON object of the form {\"key\":\"KEY\",\"value\":\"VALUE\"}. Blank lines are skipped."
+    )]
+    Load,
+    #[command(
+        name = "info",
+        about = "Print the server's version, storage engine, uptime and number of keys"
+    )]
+    Info,
 }
 
 #[derive(Args, Debug)]
 struct GetCommand {
     #[arg(name = "KEY", help = "A string key")]
     key: String,
+    #[arg(
+        short,
+        long,
+        help = "Write the value exactly as stored, without a trailing newline. A missing key exits non-zero"
+    )]
+    raw: bool,
+}
+
+#[derive(Args, Debug)]
+struct MultiGetCommand {
+    #[arg(
+        name = "KEY",
+        required = true,
+        help = "String keys. A missing key is printed with (not found) in place of its value"
+    )]
+    keys: Vec<String>,
 }
 
 #[derive(Args, Debug)]
 struct SetCommand {
     #[arg(name = "KEY", help = "A string key")]
     key: String,
-    #[arg(name = "VALUE", help = "A string value")]
+    #[arg(
+        name = "VALUE",
+        help = "A string value, or - to read the value from stdin until EOF"
+    )]
     value: String,
 }
 
@@ -48,6 +109,14 @@ struct SetCommand {
 struct RemoveCommand {
     #[arg(name = "KEY", help = "A string key")]
     key: String,
+    #[arg(long, help = "Succeed even if the key does not exist")]
+    idempotent: bool,
+}
+
+#[derive(Args, Debug)]
+struct ListCommand {
+    #[arg(long, help = "Print the size of each value in bytes next to its key")]
+    sizes: bool,
 }
 
 #[tokio::main]
@@ -57,26 +126,146 @@ async fn main() -> ClientResult<()> {
     let client = Client::connect(cli.addr, cli.pool_size);
 
     match cli.command {
-        Command::Get(GetCommand { key }) => {
+        Command::Get(GetCommand { key, raw: false }) => {
             if let Some(value) = client.get(key.clone()).await? {
                 println!("{}", value);
             } else {
                 println!("Key not found");
             }
         }
+        Command::Get(GetCommand { key, raw: true }) => {
+            if let Some(value) = client.get(key).await? {
+                let mut stdout = io::stdout();
+                stdout.write_all(value.as_bytes())?;
+                stdout.flush()?;
+            } else {
+                eprintln!("Key not found");
+                exit(1);
+            }
+        }
+        Command::MultiGet(MultiGetCommand { keys }) => {
+            let values = client.get_many(keys.clone()).await?;
+            for (key, value) in keys.iter().zip(values) {
+                match value {
+                    Some(value) => println!("{}\t{}", key, value),
+                    None => println!("{}\t(not found)", key),
+                }
+            }
+        }
         Command::Set(SetCommand { key, value }) => {
+            let value = if value == "-" {
+                let mut value = String::new();
+                io::stdin().read_to_string(&mut value)?;
+                value
+            } else {
+                value
+            };
             client.set(key, value).await?;
         }
-        Command::Remove(RemoveCommand { key }) => {
+        Command::Remove(RemoveCommand {
+            key,
+            idempotent: false,
+        }) => {
             client.remove(key).await?;
         }
-        Command::List => {
+        Command::Remove(RemoveCommand {
+            key,
+            idempotent: true,
+        }) => {
+            client.remove_optional(key).await?;
+        }
+        Command::List(ListCommand { sizes: false }) => {
             let keys = client.list().await?;
             for key in keys {
                 println!("{}", key);
             }
         }
+        Command::List(ListCommand { sizes: true }) => {
+            let keys = client.list_sizes().await?;
+            for (key, size) in keys {
+                println!("{}\t{}", key, size);
+            }
+        }
+        Command::Dump => dump(&client).await?,
+        Command::Load => load(&client).await?,
+        Command::Info => {
+            let info = client.info().await?;
+            println!("version: {}", info.version);
+            println!("engine:  {}", info.engine);
+            println!("uptime:  {}", format_uptime(info.uptime));
+            println!("keys:    {}", info.keys);
+        }
     };
 
     Ok(())
 }
+
+// Writes every key and value to stdout a page at a time, so the whole store is never held at once.
+async fn dump(client: &Client) -> ClientResult<()> {
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    let mut cursor = None;
+    loop {
+        let page = client.list_page(cursor, BATCH_SIZE as u32).await?;
+        let values = client.get_many(page.keys.clone()).await?;
+        // A key removed since its page was listed is left out.
+        for (key, value) in page.keys.into_iter().zip(values) {
+            if let Some(value) = value {
+                let line = serde_json::to_string(&DumpEntry { key, value })
+                    .expect("a string pair always serializes");
+                writeln!(stdout, "{}", line)?;
+            }
+        }
+        match page.next_cursor {
+            Some(next_cursor) => cursor = Some(next_cursor),
+            None => break,
+        }
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+// Sets every key and value read from stdin, a batch at a time.
+//
+// A line that is not a valid entry stops the load, after the lines before it have been set.
+async fn load(client: &Client) -> ClientResult<()> {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    for (number, line) in io::stdin().lock().lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: DumpEntry = match serde_json::from_str(&line) {
+            Ok(entry) => entry,
+            Err(e) => {
+                client.set_many(batch).await?;
+                eprintln!("Invalid entry on line {}: {}", number + 1, e);
+                exit(1);
+            }
+        };
+        batch.push((entry.key, entry.value));
+        if batch.len() == BATCH_SIZE {
+            client
+                .set_many(std::mem::replace(
+                    &mut batch,
+                    Vec::with_capacity(BATCH_SIZE),
+                ))
+                .await?;
+        }
+    }
+    client.set_many(batch).await
+}
+
+// Formats an uptime as days, hours, minutes and seconds, leaving out the leading units that are zero.
+fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let (days, hours, minutes, secs) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    if days > 0 {
+        format!("{}d {}h {}m {}s", days, hours, minutes, secs)
+    } else if hours > 0 {
+        format!("{}h {}m {}s", hours, minutes, secs)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, secs)
+    } else {
+        format!("{}s", secs)
+    }
+}
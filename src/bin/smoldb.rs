@@ -1,42 +1,175 @@
-use std::{env::current_dir, net::SocketAddr};
-use tokio::signal;
+use std::{
+    env::current_dir,
+    net::SocketAddr,
+    num::{NonZeroU32, NonZeroU64, NonZeroUsize},
+    path::PathBuf,
+};
 use tokio::sync::oneshot;
+use tokio::{runtime, signal};
 
-use clap::{Parser, ValueEnum};
-use smoldb::{run, ServerResult, StorageType};
+use clap::{error::ErrorKind, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use smoldb::{
+    run_with_config, Bitcask, BitcaskOptions, ServerConfig, ServerOptions, ServerResult,
+    SledOptions, Storage, StorageType, ThreadPoolType,
+};
 use tracing::{info, Level};
 use tracing_subscriber::FmtSubscriber;
 
 const DEFAULT_ADDR: &str = "127.0.0.1:4001";
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = "Options can also be set with their SMOLDB_* environment variables. Flags take precedence over the environment, which takes precedence over the defaults."
+)]
 struct Cli {
-    #[arg(short, long)]
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[arg(short, long, env = "SMOLDB_STORAGE")]
     storage: Option<CliStorageType>,
 
-    #[arg(short, long, default_value = DEFAULT_ADDR)]
+    #[arg(short, long, env = "SMOLDB_ADDR", default_value = DEFAULT_ADDR)]
     addr: SocketAddr,
+
+    #[arg(
+        short,
+        long,
+        env = "SMOLDB_DATA_DIR",
+        help = "The directory to store data in [default: the working directory]"
+    )]
+    data_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "The number of requests per second each connection may make"
+    )]
+    rate_limit: Option<NonZeroU32>,
+
+    #[arg(long, help = "The longest key in bytes a request may name")]
+    max_key_len: Option<usize>,
+
+    #[arg(long, help = "The largest value in bytes a request may write")]
+    max_value_size: Option<usize>,
+
+    #[arg(
+        long,
+        help = "The thread pool to run storage operations on [default: none, they run on the connection's task]"
+    )]
+    pool: Option<CliThreadPoolType>,
+
+    #[arg(
+        long,
+        help = "The number of threads in the thread pool [default: the available parallelism]"
+    )]
+    pool_threads: Option<NonZeroU32>,
+
+    #[arg(
+        long,
+        help = "The number of requests of each connection that may run on the thread pool at once [default: 1]"
+    )]
+    max_inflight_per_conn: Option<NonZeroUsize>,
+
+    #[arg(
+        long,
+        help = "The number of worker threads serving connections [default: the number of cores]"
+    )]
+    threads: Option<NonZeroUsize>,
+
+    #[arg(
+        long,
+        help = "The size in bytes after which the active log file is sealed (bitcask only) [default: 1 MiB]"
+    )]
+    max_log_size: Option<NonZeroU64>,
+
+    #[arg(
+        long,
+        help = "The number of log files after which a compaction is started in the background (bitcask only) [default: none, compaction is never started automatically]"
+    )]
+    max_log_files: Option<NonZeroUsize>,
+
+    #[arg(
+        long,
+        help = "The size in bytes of the page cache (sled only) [default: sled's default]"
+    )]
+    sled_cache_capacity: Option<u64>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[command(
+        name = "segments",
+        about = "Print the log files of the bitcask store in the data directory and exit"
+    )]
+    Segments,
+    #[command(
+        name = "compact",
+        alias = "merge",
+        about = "Compact the bitcask store in the data directory and exit"
+    )]
+    Compact(CompactCommand),
+}
+
+#[derive(Args, Debug)]
+struct CompactCommand {
+    #[arg(
+        long,
+        help = "Print what the compaction would do without changing anything"
+    )]
+    dry_run: bool,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, ValueEnum)]
 enum CliStorageType {
     Bitcask,
     Sled,
+    Memory,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, ValueEnum)]
+enum CliThreadPoolType {
+    Naive,
+    SharedQueue,
+    Rayon,
 }
 
-#[tokio::main]
-async fn main() -> ServerResult<()> {
+fn main() -> ServerResult<()> {
     init_tracing();
 
     let cli = Cli::parse();
+    check_storage_options(&cli);
+    let mut runtime = runtime::Builder::new_multi_thread();
+    if let Some(threads) = cli.threads {
+        runtime.worker_threads(threads.get());
+    }
+    runtime.enable_all().build()?.block_on(serve(cli))
+}
+
+async fn serve(cli: Cli) -> ServerResult<()> {
     let addr = cli.addr;
     let storage_type = cli.storage.unwrap_or(CliStorageType::Bitcask);
-    let current_dir = current_dir()?;
+    let data_dir = match cli.data_dir {
+        Some(data_dir) => data_dir,
+        None => current_dir()?,
+    };
+
+    match cli.command {
+        Some(Command::Segments) => return print_segments(Bitcask::open(&data_dir)?),
+        Some(Command::Compact(CompactCommand { dry_run: true })) => {
+            return print_compact_plan(Bitcask::open(&data_dir)?)
+        }
+        Some(Command::Compact(CompactCommand { dry_run: false })) => {
+            return Ok(Bitcask::open(&data_dir)?.compact()?)
+        }
+        None => {}
+    }
 
     info!("smoldb {}", env!("CARGO_PKG_VERSION"));
     info!("storage type: {:?}", storage_type);
-    info!("working directory: {:?}", current_dir);
+    info!("data directory: {:?}", data_dir);
 
     let (stop_tx, stop_rx) = oneshot::channel();
 
@@ -46,15 +179,131 @@ async fn main() -> ServerResult<()> {
         stop_tx.send(()).expect("failed to send stop signal");
     });
 
+    let options = ServerOptions {
+        rate_limit: cli.rate_limit,
+        max_key_len: cli.max_key_len,
+        max_value_size: cli.max_value_size,
+        thread_pool: cli.pool.map(|pool| match pool {
+            CliThreadPoolType::Naive => ThreadPoolType::Naive,
+            CliThreadPoolType::SharedQueue => ThreadPoolType::SharedQueue,
+            CliThreadPoolType::Rayon => ThreadPoolType::Rayon,
+        }),
+        thread_pool_size: cli.pool_threads,
+        max_inflight_per_conn: cli.max_inflight_per_conn,
+    };
+    if let Some(rate_limit) = options.rate_limit {
+        info!("rate limit: {} requests per second", rate_limit);
+    }
+    if let Some(max_key_len) = options.max_key_len {
+        info!("max key length: {} bytes", max_key_len);
+    }
+    if let Some(max_value_size) = options.max_value_size {
+        info!("max value size: {} bytes", max_value_size);
+    }
+    if let Some(thread_pool) = options.thread_pool {
+        info!("thread pool: {:?}", thread_pool);
+    }
+    if let Some(max_inflight) = options.max_inflight_per_conn {
+        info!("max in-flight requests per connection: {}", max_inflight);
+    }
+    if let Some(threads) = cli.threads {
+        info!("worker threads: {}", threads);
+    }
+
     info!("listening on {}", addr);
 
-    match storage_type {
-        CliStorageType::Bitcask => run(addr, current_dir, StorageType::Bitcask, stop_rx).await?,
-        CliStorageType::Sled => run(addr, current_dir, StorageType::Sled, stop_rx).await?,
+    let storage_type = match storage_type {
+        CliStorageType::Bitcask => StorageType::Bitcask,
+        CliStorageType::Sled => StorageType::Sled,
+        CliStorageType::Memory => StorageType::Memory,
+    };
+    let mut bitcask_options = BitcaskOptions::default();
+    if let Some(max_log_size) = cli.max_log_size {
+        info!("max log size: {} bytes", max_log_size);
+        bitcask_options.max_log_size = max_log_size.get();
+    }
+    if let Some(max_log_files) = cli.max_log_files {
+        info!("max log files: {}", max_log_files);
+        bitcask_options.max_log_files = Some(max_log_files.get());
+    }
+    if let Some(cache_capacity) = cli.sled_cache_capacity {
+        info!("sled cache capacity: {} bytes", cache_capacity);
+    }
+    let config = ServerConfig {
+        addr,
+        dir: data_dir,
+        storage_type,
+        options,
+        bitcask_options,
+        sled_options: SledOptions {
+            cache_capacity: cli.sled_cache_capacity,
+        },
     };
+    let reason = run_with_config(config, stop_rx).await?;
 
-    info!("server stopped");
+    info!("server stopped: {:?}", reason);
+
+    Ok(())
+}
+
+// Exits with a usage error if an option tuning one storage engine is given along with another.
+fn check_storage_options(cli: &Cli) {
+    let storage = cli.storage.unwrap_or(CliStorageType::Bitcask);
+    let engine_options = [
+        (
+            "--max-log-size",
+            CliStorageType::Bitcask,
+            cli.max_log_size.is_some(),
+        ),
+        (
+            "--max-log-files",
+            CliStorageType::Bitcask,
+            cli.max_log_files.is_some(),
+        ),
+        (
+            "--sled-cache-capacity",
+            CliStorageType::Sled,
+            cli.sled_cache_capacity.is_some(),
+        ),
+    ];
+    for (flag, engine, is_set) in engine_options {
+        if is_set && engine != storage {
+            let message = format!(
+                "{} only applies to the {:?} storage engine, not {:?}",
+                flag, engine, storage
+            );
+            Cli::command()
+                .error(ErrorKind::ArgumentConflict, message)
+                .exit();
+        }
+    }
+}
+
+fn print_segments(store: Bitcask) -> ServerResult<()> {
+    println!("file_id\tsize_bytes\tlive_keys\tactive\thint");
+    for segment in store.segments()? {
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            segment.file_id,
+            segment.size_bytes,
+            segment.live_keys,
+            segment.is_active,
+            segment.has_hint
+        );
+    }
+    Ok(())
+}
 
+fn print_compact_plan(store: Bitcask) -> ServerResult<()> {
+    let plan = store.compact_plan()?;
+    let files_to_remove: Vec<String> = plan
+        .files_to_remove
+        .iter()
+        .map(|file_id| file_id.to_string())
+        .collect();
+    println!("files_to_remove\t{}", files_to_remove.join(","));
+    println!("live_keys\t{}", plan.live_keys);
+    println!("estimated_bytes_after\t{}", plan.estimated_bytes_after);
     Ok(())
 }
 
@@ -1,14 +1,26 @@
 use crate::net::{
-    GetResponse, ListResponse, NetError, NetReadExt, NetWriteExt, RemoveResponse, Request,
-    SetResponse,
+    ChangesResponse, CompactResponse, GetMetaResponse, GetOrSetResponse, GetResponse,
+    GetStreamResponse, InfoResponse, ListPageResponse, ListResponse, ListSizesResponse, NetError,
+    NetReadExt, NetWriteExt, PopResponse, PushResponse, PutResponse, RemoveOptionalResponse,
+    RemovePrefixResponse, RemoveResponse, Request, Response, SetIfAbsentResponse, SetResponse,
+    SnapshotResponse, TouchResponse,
 };
+use bytes::Bytes;
+use futures::{stream, Stream, StreamExt};
 use std::{
+    future::Future,
+    io,
     net::SocketAddr,
     sync::{MutexGuard, PoisonError},
+    time::Duration,
 };
 use thiserror::Error;
+use tokio::{sync::mpsc, task};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, Span};
 
-use super::pool::Pool;
+use super::pool::{Connection, Object, Pool, PoolStats, ReuseOrder};
+use crate::{ListPage, PutOutcome, Storage, StorageError};
 
 /// The `ClientError` type for `Client`.
 #[derive(Error, Debug)]
@@ -29,6 +41,46 @@ pub enum ClientError {
     #[error("Acquire error: {0}")]
     Acquire(#[from] tokio::sync::AcquireError),
 
+    /// The server rejected the request as the connection exceeded its rate limit.
+    #[error("Rate limited")]
+    RateLimited,
+
+    /// The server rejected the request as its key is longer than the server accepts.
+    #[error("Key is longer than the limit of {max_key_len} bytes")]
+    KeyTooLong {
+        /// The longest key in bytes the server accepts.
+        max_key_len: usize,
+    },
+
+    /// The value is larger than the server accepts.
+    ///
+    /// Values over the limit the server advertised in the handshake are rejected without being sent.
+    #[error("Value is larger than the limit of {max_value_size} bytes")]
+    ValueTooLarge {
+        /// The largest value in bytes the server accepts.
+        max_value_size: usize,
+    },
+
+    /// The server could not decode a request, and closed the connection.
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+
+    /// The server's storage found corrupted data while serving the request.
+    #[error("Data corruption: {0}")]
+    Corruption(String),
+
+    /// A local store the client was writing to failed, such as the destination of `Client::snapshot_into`.
+    #[error("Storage error: {0}")]
+    Storage(#[from] StorageError),
+
+    /// A handshake error, such as the server not supporting the client's protocol version.
+    #[error("Handshake error: {0}")]
+    Handshake(String),
+
+    /// A value could not be converted to or from JSON by a `TypedClient`.
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+
     /// Mutex Poisoned error.
     #[error("A mutex was poisoned: {0}")]
     MutexPoisoned(String),
@@ -43,66 +95,1345 @@ impl<T> From<PoisonError<MutexGuard<'_, T>>> for ClientError {
 /// The `ClientResult` type for `Client`.
 pub type ClientResult<T> = std::result::Result<T, ClientError>;
 
+// The number of chunks `get_stream` reads ahead of the caller.
+const STREAM_BUFFER: usize = 4;
+
+/// The common interface of the smoldb clients.
+///
+/// Code that depends on `KvClient` rather than `Client` directly can be tested against a `MockClient`.
+pub trait KvClient {
+    /// Gets the string value of a given string key.
+    fn get(&self, key: String) -> impl Future<Output = ClientResult<Option<String>>> + Send;
+
+    /// Sets the value of a string key to a string.
+    fn set(&self, key: String, value: String) -> impl Future<Output = ClientResult<()>> + Send;
+
+    /// Remove a given key.
+    fn remove(&self, key: String) -> impl Future<Output = ClientResult<()>> + Send;
+
+    /// List all keys.
+    fn list(&self) -> impl Future<Output = ClientResult<Vec<String>>> + Send;
+}
+
+fn unexpected(response: Response) -> ClientError {
+    ClientError::Server(format!("unexpected response: {:?}", response))
+}
+
+// Writes a request to a connection and reads the response to it.
+async fn send(conn: &mut Object, request: Request) -> ClientResult<Response> {
+    conn.writer.write(request).await?;
+    match conn.reader.read().await? {
+        Some(response) => Ok(response),
+        None => Err(ClientError::Io(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed by server",
+        ))),
+    }
+}
+
+// Writes several requests to a connection and then reads the responses to them, in order.
+async fn send_many(conn: &mut Object, requests: &[Request]) -> ClientResult<Vec<Response>> {
+    // Each request is flushed as it is written, but none waits on a response.
+    for request in requests {
+        conn.writer.write(request).await?;
+    }
+    let mut responses = Vec::with_capacity(requests.len());
+    for _ in requests {
+        match conn.reader.read().await? {
+            Some(response) => responses.push(response),
+            None => {
+                return Err(ClientError::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed by server",
+                )))
+            }
+        }
+    }
+    Ok(responses)
+}
+
+// Turns the responses the server sends in place of serving a request into errors.
+fn served(response: Response) -> ClientResult<Response> {
+    match response {
+        Response::RateLimited => Err(ClientError::RateLimited),
+        Response::KeyTooLong { max_key_len } => Err(ClientError::KeyTooLong { max_key_len }),
+        Response::ValueTooLarge { max_value_size } => {
+            Err(ClientError::ValueTooLarge { max_value_size })
+        }
+        Response::ProtocolError(e) => Err(ClientError::Protocol(e)),
+        Response::Corruption(e) => Err(ClientError::Corruption(e)),
+        response => Ok(response),
+    }
+}
+
+// Reads the batches of a snapshot from a connection into `dest` until its end, returning the number of pairs copied.
+async fn copy_snapshot(conn: &mut Object, dest: impl Storage) -> ClientResult<usize> {
+    let mut copied = 0;
+    loop {
+        let response = conn.reader.read().await?.ok_or_else(|| {
+            ClientError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed by server",
+            ))
+        })?;
+        let entries = match served(response)? {
+            Response::Snapshot(SnapshotResponse::Entries(entries)) => entries,
+            Response::Snapshot(SnapshotResponse::End) => return Ok(copied),
+            Response::Snapshot(SnapshotResponse::Err(e)) => return Err(ClientError::Server(e)),
+            response => return Err(unexpected(response)),
+        };
+        copied += entries.len();
+        let dest = dest.clone();
+        task::spawn_blocking(move || {
+            entries
+                .into_iter()
+                .try_for_each(|(key, value)| dest.set(key, value))
+        })
+        .await
+        .map_err(io::Error::other)??;
+    }
+}
+
+// Reads the batches of changes from a connection and applies them to `dest` until their end, returning the number of
+// changes applied.
+async fn apply_changes(conn: &mut Object, dest: impl Storage) -> ClientResult<usize> {
+    let mut applied = 0;
+    loop {
+        let response = conn.reader.read().await?.ok_or_else(|| {
+            ClientError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed by server",
+            ))
+        })?;
+        let changes = match served(response)? {
+            Response::Changes(ChangesResponse::Changes(changes)) => changes,
+            Response::Changes(ChangesResponse::End) => return Ok(applied),
+            Response::Changes(ChangesResponse::Err(e)) => return Err(ClientError::Server(e)),
+            response => return Err(unexpected(response)),
+        };
+        applied += changes.len();
+        let dest = dest.clone();
+        task::spawn_blocking(move || {
+            changes
+                .into_iter()
+                .try_for_each(|(key, value)| match value {
+                    Some(value) => dest.set(key, value),
+                    // The key may never have reached `dest`, if it was both written and removed after `since`.
+                    None => match dest.remove(key) {
+                        Err(StorageError::KeyNotFound) => Ok(()),
+                        removed => removed,
+                    },
+                })
+        })
+        .await
+        .map_err(io::Error::other)??;
+    }
+}
+
+// Rejects a request whose value is larger than the server advertised it accepts, saving the round trip.
+fn check_value_size(conn: &Object, request: &Request) -> ClientResult<()> {
+    match conn.max_value_size {
+        Some(max_value_size) if request.value().is_some_and(|v| v.len() > max_value_size) => {
+            Err(ClientError::ValueTooLarge { max_value_size })
+        }
+        _ => Ok(()),
+    }
+}
+
+// Whether an error means the connection itself failed, rather than the request.
+fn is_connection_error(e: &ClientError) -> bool {
+    matches!(e, ClientError::Io(_) | ClientError::Codec(NetError::Io(_)))
+}
+
+/// A description of a server, as reported by `Client::info`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    /// The version of smoldb the server runs.
+    pub version: String,
+    /// The storage engine the server runs on, such as `Bitcask`.
+    pub engine: String,
+    /// How long the server has been running, to the second.
+    pub uptime: Duration,
+    /// The number of keys the server holds.
+    pub keys: usize,
+}
+
+/// Options for tuning a `Client`.
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// The most connections the client will open to the server at once.
+    pub pool_size: usize,
+
+    /// The order in which idle pooled connections are reused.
+    pub reuse_order: ReuseOrder,
+
+    /// How long to wait before retrying after failing to open a connection.
+    ///
+    /// The wait doubles with each consecutive failure, up to a few seconds, and resets once a connection opens.
+    /// `None` retries immediately.
+    pub connect_backoff: Option<Duration>,
+
+    /// How long opening a connection, including its handshake, may take before it fails with a timed out IO error.
+    ///
+    /// `None` waits for as long as the operating system does.
+    pub connect_timeout: Option<Duration>,
+
+    /// Whether requests made inside a `tracing` span carry the span's id, so the server's span for each request can
+    /// be linked to it.
+    ///
+    /// Span ids are only unique within the client's process, so the server records the id as-is for a tracing
+    /// pipeline to correlate. Servers that predate trace propagation reject the requests.
+    pub propagate_trace: bool,
+
+    /// Whether a request that fails on a reused pooled connection is retried on another one.
+    ///
+    /// The server closes idle connections when it restarts, which is only noticed once a request is sent on one. The
+    /// request may have reached the server before the connection failed, so without retrying the connection error is
+    /// returned instead, for requests that must not be made twice.
+    pub retry: bool,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            pool_size: 1,
+            reuse_order: ReuseOrder::default(),
+            connect_backoff: None,
+            connect_timeout: None,
+            propagate_trace: false,
+            retry: true,
+        }
+    }
+}
+
+/// Builds a `Client` one option at a time, starting from the defaults of `ClientOptions`.
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    addr: SocketAddr,
+    options: ClientOptions,
+    min_connections: usize,
+}
+
+impl ClientBuilder {
+    /// Sets the most connections the client will open to the server at once.
+    pub fn pool_size(mut self, pool_size: usize) -> Self {
+        self.options.pool_size = pool_size;
+        self
+    }
+
+    /// Sets the order in which idle pooled connections are reused.
+    pub fn reuse_order(mut self, reuse_order: ReuseOrder) -> Self {
+        self.options.reuse_order = reuse_order;
+        self
+    }
+
+    /// Sets how long to wait before retrying after failing to open a connection, see `ClientOptions::connect_backoff`.
+    pub fn connect_backoff(mut self, connect_backoff: Duration) -> Self {
+        self.options.connect_backoff = Some(connect_backoff);
+        self
+    }
+
+    /// Sets how long opening a connection may take, see `ClientOptions::connect_timeout`.
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.options.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// Sets whether requests carry the id of the span they are made in, see `ClientOptions::propagate_trace`.
+    pub fn propagate_trace(mut self, propagate_trace: bool) -> Self {
+        self.options.propagate_trace = propagate_trace;
+        self
+    }
+
+    /// Sets whether requests that fail on a stale pooled connection are retried, see `ClientOptions::retry`.
+    pub fn retry(mut self, retry: bool) -> Self {
+        self.options.retry = retry;
+        self
+    }
+
+    /// Sets how many connections `connect` opens up front, at most the pool size.
+    ///
+    /// The connections are kept idle in the pool for the first requests, they are not reopened if later closed.
+    pub fn min_connections(mut self, min_connections: usize) -> Self {
+        self.min_connections = min_connections;
+        self
+    }
+
+    /// Builds the client without opening any connections, they are opened as requests need them.
+    pub fn build(self) -> Client {
+        Client::connect_with_options(self.addr, self.options)
+    }
+
+    /// Builds the client and opens its minimum number of connections, failing if any of them cannot be opened.
+    pub async fn connect(self) -> ClientResult<Client> {
+        let min_connections = self.min_connections.min(self.options.pool_size);
+        let client = self.build();
+        let mut conns = Vec::with_capacity(min_connections);
+        for _ in 0..min_connections {
+            conns.push(client.pool.get().await?);
+        }
+        // Dropping the connections returns them to the pool as idle ones.
+        drop(conns);
+        Ok(client)
+    }
+}
+
 /// The client for the smoldb server.
 #[derive(Clone)]
 pub struct Client {
     pool: Pool,
+    propagate_trace: bool,
+    retry: bool,
 }
 
-// TODO: We should not unwrap reads but instead handle none
-// This requires modifying the pool to allow for manual de-allocation of connections
 impl Client {
+    /// Starts building a client for the smoldb server at the given address.
+    pub fn builder(addr: SocketAddr) -> ClientBuilder {
+        ClientBuilder {
+            addr,
+            options: ClientOptions::default(),
+            min_connections: 0,
+        }
+    }
+
     /// Connects to the smoldb server at the given address.
     pub fn connect(addr: SocketAddr, pool_size: usize) -> Self {
-        let pool = Pool::new(addr, pool_size);
-        Self { pool }
+        Client::connect_with_options(
+            addr,
+            ClientOptions {
+                pool_size,
+                ..ClientOptions::default()
+            },
+        )
+    }
+
+    /// Connects to the smoldb server at the given address with the given options.
+    pub fn connect_with_options(addr: SocketAddr, options: ClientOptions) -> Self {
+        let pool = Pool::new(
+            addr,
+            options.pool_size,
+            options.reuse_order,
+            options.connect_backoff,
+        )
+        .with_connect_timeout(options.connect_timeout);
+        Self {
+            pool,
+            propagate_trace: options.propagate_trace,
+            retry: options.retry,
+        }
+    }
+
+    /// Creates a client that makes every request on the given connection, rather than on a pool of connections it
+    /// opens itself.
+    ///
+    /// Requests made while the connection is in use wait for it. Once the connection is closed every request fails, as
+    /// there is no address to open another one to.
+    pub fn from_connection(conn: Connection) -> Self {
+        Self {
+            pool: Pool::from_connection(conn),
+            propagate_trace: false,
+            retry: true,
+        }
+    }
+
+    /// Reports how many of the client's pooled connections are idle and in use.
+    pub fn pool_stats(&self) -> PoolStats {
+        self.pool.stats()
+    }
+
+    // Wraps a request with the id of the current span when trace propagation is enabled.
+    fn traced(&self, request: Request) -> Request {
+        match Span::current().id() {
+            Some(id) if self.propagate_trace => Request::Traced {
+                parent_span: id.into_u64(),
+                request: Box::new(request),
+            },
+            _ => request,
+        }
+    }
+
+    // Sends a request on a pooled connection and reads the response to it.
+    //
+    // Idle connections are closed when the server restarts, so a connection failing on a reused connection is
+    // discarded and, unless retrying is turned off, the request is retried on the next one, until a newly opened
+    // connection is reached.
+    // A connection that fails is never returned to the pool.
+    async fn request(&self, request: Request) -> ClientResult<Response> {
+        let request = self.traced(request);
+        loop {
+            let mut conn = self.pool.get().await?;
+            check_value_size(&conn, &request)?;
+            let response = match send(&mut conn, request.clone()).await {
+                Ok(response) => response,
+                Err(e) => {
+                    let retry = self.retry && conn.is_reused() && is_connection_error(&e);
+                    conn.discard();
+                    if retry {
+                        debug!("retrying request on a new connection: {}", e);
+                        continue;
+                    }
+                    return Err(e);
+                }
+            };
+            // The server closes the connection after a protocol error.
+            if matches!(response, Response::ProtocolError(_)) {
+                conn.discard();
+            }
+            return served(response);
+        }
+    }
+
+    // Sends a request on an idle pooled connection and reads the response to it, or returns `None` without sending
+    // anything if no connection is idle. There is no retry, as any other connection would have to be waited for.
+    async fn try_request(&self, request: Request) -> ClientResult<Option<Response>> {
+        let request = self.traced(request);
+        let mut conn = match self.pool.try_get()? {
+            Some(conn) => conn,
+            None => return Ok(None),
+        };
+        check_value_size(&conn, &request)?;
+        let response = match send(&mut conn, request).await {
+            Ok(response) => response,
+            Err(e) => {
+                conn.discard();
+                return Err(e);
+            }
+        };
+        if matches!(response, Response::ProtocolError(_)) {
+            conn.discard();
+        }
+        served(response).map(Some)
+    }
+
+    // Sends several requests on one pooled connection before reading any of the responses to them,
+    // so the whole batch costs a single round trip. Stale connections are retried as in `request`.
+    async fn request_many(&self, requests: Vec<Request>) -> ClientResult<Vec<Response>> {
+        let requests: Vec<Request> = requests
+            .into_iter()
+            .map(|request| self.traced(request))
+            .collect();
+        loop {
+            let mut conn = self.pool.get().await?;
+            for request in &requests {
+                check_value_size(&conn, request)?;
+            }
+            let responses = match send_many(&mut conn, &requests).await {
+                Ok(responses) => responses,
+                Err(e) => {
+                    let retry = self.retry && conn.is_reused() && is_connection_error(&e);
+                    conn.discard();
+                    if retry {
+                        debug!("retrying requests on a new connection: {}", e);
+                        continue;
+                    }
+                    return Err(e);
+                }
+            };
+            if responses
+                .iter()
+                .any(|response| matches!(response, Response::ProtocolError(_)))
+            {
+                conn.discard();
+            }
+            return responses.into_iter().map(served).collect();
+        }
     }
 
     /// Gets the string value of a given string key.
     pub async fn get(&self, key: String) -> ClientResult<Option<String>> {
         let request = Request::Get { key };
+        match self.request(request).await? {
+            Response::Get(GetResponse::Ok(value)) => Ok(value),
+            Response::Get(GetResponse::Err(e)) => Err(ClientError::Server(e)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Gets the string value of a given string key if one of the client's pooled connections is idle.
+    ///
+    /// Returns `None` without sending anything if every connection is in use or none has been opened yet, so a
+    /// latency-sensitive caller can take another path rather than wait for one. Unlike other requests, an idle
+    /// connection that turns out to have been closed is not retried, the error is returned.
+    pub async fn get_if_idle(&self, key: String) -> ClientResult<Option<Option<String>>> {
+        let request = Request::Get { key };
+        match self.try_request(request).await? {
+            None => Ok(None),
+            Some(Response::Get(GetResponse::Ok(value))) => Ok(Some(value)),
+            Some(Response::Get(GetResponse::Err(e))) => Err(ClientError::Server(e)),
+            Some(response) => Err(unexpected(response)),
+        }
+    }
+
+    /// Gets the string values of several keys, in the order the keys are given.
+    ///
+    /// The gets are pipelined on a single connection, so they take one round trip rather than one each.
+    pub async fn get_many(&self, keys: Vec<String>) -> ClientResult<Vec<Option<String>>> {
+        let requests: Vec<Request> = keys.into_iter().map(|key| Request::Get { key }).collect();
+        self.request_many(requests)
+            .await?
+            .into_iter()
+            .map(|response| match response {
+                Response::Get(GetResponse::Ok(value)) => Ok(value),
+                Response::Get(GetResponse::Err(e)) => Err(ClientError::Server(e)),
+                response => Err(unexpected(response)),
+            })
+            .collect()
+    }
+
+    /// Gets the string value of a given string key along with the time it was last written,
+    /// in milliseconds since the unix epoch.
+    ///
+    /// Both are `None` if the key does not exist. The timestamp may also be `None` for a value the server has no
+    /// record of writing, such as one written to a sled store before timestamps were recorded.
+    pub async fn get_meta(&self, key: String) -> ClientResult<(Option<String>, Option<u64>)> {
+        let request = Request::GetMeta { key };
+        match self.request(request).await? {
+            Response::GetMeta(GetMetaResponse::Ok { value, timestamp }) => Ok((value, timestamp)),
+            Response::GetMeta(GetMetaResponse::Err(e)) => Err(ClientError::Server(e)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Gets the value of a given string key as a stream of chunks.
+    ///
+    /// The server sends the value in fixed size chunks rather than a single frame, so the client never holds a large
+    /// value in memory all at once. The server still reads the whole value from storage and holds it while sending
+    /// it. Returns `None` if the key does not exist.
+    ///
+    /// The connection is returned to the pool once the stream has been read to the end,
+    /// dropping the stream early closes the connection instead.
+    pub async fn get_stream(
+        &self,
+        key: String,
+    ) -> ClientResult<Option<impl Stream<Item = ClientResult<Bytes>>>> {
+        let request = self.traced(Request::GetStream { key });
         let mut conn = self.pool.get().await?;
         conn.writer.write(request).await?;
-        let response: GetResponse = conn.reader.read().await?.unwrap();
-        match response {
-            GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(e) => Err(ClientError::Server(e)),
-        }
+
+        // The chunks are read on their own task which owns the connection until the end of the value is reached.
+        let (tx, mut rx) = mpsc::channel(STREAM_BUFFER);
+        tokio::spawn(async move {
+            let clean = loop {
+                let response = match conn.reader.read::<Response>().await {
+                    Ok(Some(Response::GetStream(GetStreamResponse::End))) => break true,
+                    Ok(Some(Response::GetStream(response))) => response,
+                    Ok(Some(Response::RateLimited)) => {
+                        let _ = tx.send(Err(ClientError::RateLimited)).await;
+                        break true;
+                    }
+                    Ok(Some(Response::KeyTooLong { max_key_len })) => {
+                        let _ = tx.send(Err(ClientError::KeyTooLong { max_key_len })).await;
+                        break true;
+                    }
+                    Ok(Some(Response::ProtocolError(e))) => {
+                        let _ = tx.send(Err(ClientError::Protocol(e))).await;
+                        break false;
+                    }
+                    Ok(Some(Response::Corruption(e))) => {
+                        let _ = tx.send(Err(ClientError::Corruption(e))).await;
+                        break true;
+                    }
+                    Ok(Some(response)) => {
+                        let _ = tx.send(Err(unexpected(response))).await;
+                        break false;
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(ClientError::Codec(e))).await;
+                        break false;
+                    }
+                    Ok(None) => {
+                        let _ = tx
+                            .send(Err(ClientError::Server("connection closed".to_string())))
+                            .await;
+                        break false;
+                    }
+                };
+                let more = matches!(response, GetStreamResponse::Chunk(_));
+                if tx.send(Ok(response)).await.is_err() {
+                    break !more;
+                }
+                if !more {
+                    break true;
+                }
+            };
+            if !clean {
+                conn.discard();
+            }
+        });
+
+        let first = match rx.recv().await {
+            Some(Ok(GetStreamResponse::NotFound)) => return Ok(None),
+            Some(Ok(GetStreamResponse::Err(e))) => return Err(ClientError::Server(e)),
+            Some(Err(e)) => return Err(e),
+            first => first,
+        };
+        let chunks = stream::iter(first)
+            .chain(ReceiverStream::new(rx))
+            .map(|response| match response? {
+                GetStreamResponse::Chunk(chunk) => Ok(Bytes::from(chunk)),
+                GetStreamResponse::Err(e) => Err(ClientError::Server(e)),
+                response => Err(unexpected(Response::GetStream(response))),
+            });
+        Ok(Some(chunks))
     }
 
     /// Sets the value of a string key to a string.
     pub async fn set(&self, key: String, value: String) -> ClientResult<()> {
         let request = Request::Set { key, value };
+        match self.request(request).await? {
+            Response::Set(SetResponse::Ok(())) => Ok(()),
+            Response::Set(SetResponse::Err(e)) => Err(ClientError::Server(e)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Sets the values of several keys, in the order the pairs are given.
+    ///
+    /// The sets are pipelined on a single connection, so they take one round trip rather than one each.
+    /// Every set is sent even if the server fails an earlier one, and the first failure is returned.
+    pub async fn set_many(&self, pairs: Vec<(String, String)>) -> ClientResult<()> {
+        let requests: Vec<Request> = pairs
+            .into_iter()
+            .map(|(key, value)| Request::Set { key, value })
+            .collect();
+        self.request_many(requests)
+            .await?
+            .into_iter()
+            .try_for_each(|response| match response {
+                Response::Set(SetResponse::Ok(())) => Ok(()),
+                Response::Set(SetResponse::Err(e)) => Err(ClientError::Server(e)),
+                response => Err(unexpected(response)),
+            })
+    }
+
+    /// Sets the value of a string key to a string, reporting whether the key was created or updated.
+    pub async fn put(&self, key: String, value: String) -> ClientResult<PutOutcome> {
+        let request = Request::Put { key, value };
+        match self.request(request).await? {
+            Response::Put(PutResponse::Created) => Ok(PutOutcome::Created),
+            Response::Put(PutResponse::Updated) => Ok(PutOutcome::Updated),
+            Response::Put(PutResponse::Err(e)) => Err(ClientError::Server(e)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Sets the value of a string key to a string only if the key does not already exist.
+    ///
+    /// Returns `true` if the value was written and `false` if the key already existed.
+    pub async fn set_if_absent(&self, key: String, value: String) -> ClientResult<bool> {
+        let request = Request::SetIfAbsent { key, value };
+        match self.request(request).await? {
+            Response::SetIfAbsent(SetIfAbsentResponse::Ok(written)) => Ok(written),
+            Response::SetIfAbsent(SetIfAbsentResponse::Err(e)) => Err(ClientError::Server(e)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Gets the value of a string key, setting it to `default` first if the key does not exist.
+    ///
+    /// Returns the value stored once the call completes.
+    pub async fn get_or_set(&self, key: String, default: String) -> ClientResult<String> {
+        let request = Request::GetOrSet { key, default };
+        match self.request(request).await? {
+            Response::GetOrSet(GetOrSetResponse::Ok(value)) => Ok(value),
+            Response::GetOrSet(GetOrSetResponse::Err(e)) => Err(ClientError::Server(e)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Compacts the server's storage, waiting for the compaction to finish.
+    ///
+    /// Returns `true` if the compaction ran and `false` if another compaction requested over the network was already
+    /// running, in which case nothing is done.
+    pub async fn compact(&self) -> ClientResult<bool> {
+        match self.request(Request::Compact).await? {
+            Response::Compact(CompactResponse::Ok(())) => Ok(true),
+            Response::Compact(CompactResponse::AlreadyRunning) => Ok(false),
+            Response::Compact(CompactResponse::Err(e)) => Err(ClientError::Server(e)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Describes the server the client connects to.
+    pub async fn info(&self) -> ClientResult<ServerInfo> {
+        match self.request(Request::Info).await? {
+            Response::Info(InfoResponse::Ok {
+                version,
+                engine,
+                uptime_secs,
+                keys,
+            }) => Ok(ServerInfo {
+                version,
+                engine,
+                uptime: Duration::from_secs(uptime_secs),
+                keys,
+            }),
+            Response::Info(InfoResponse::Err(e)) => Err(ClientError::Server(e)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Records the current time as the last write of a key without changing its value.
+    ///
+    /// Returns an error if the key does not exist.
+    pub async fn touch(&self, key: String) -> ClientResult<()> {
+        let request = Request::Touch { key };
+        match self.request(request).await? {
+            Response::Touch(TouchResponse::Ok(())) => Ok(()),
+            Response::Touch(TouchResponse::Err(e)) => Err(ClientError::Server(e)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Copies every key on the server along with its value into `dest`, returning the number of keys copied.
+    ///
+    /// This bootstraps a replica: the server sends the pairs in batches, each of which is written to `dest` on tokio's
+    /// blocking thread pool. The copy is not taken at a single point in time, writes made on the server while it is
+    /// read may or may not be included. Keys already in `dest` are overwritten but never removed, so `dest` should
+    /// start out empty. If an error ends the copy part way through, `dest` keeps the pairs copied before it.
+    pub async fn snapshot_into(&self, dest: impl Storage) -> ClientResult<usize> {
+        let request = self.traced(Request::Snapshot);
         let mut conn = self.pool.get().await?;
-        conn.writer.write(request).await?;
-        let response: SetResponse = conn.reader.read().await?.unwrap();
-        match response {
-            SetResponse::Ok(()) => Ok(()),
-            SetResponse::Err(e) => Err(ClientError::Server(e)),
+        let copied = match conn.writer.write(request).await {
+            Ok(()) => copy_snapshot(&mut conn, dest).await,
+            Err(e) => Err(e.into()),
+        };
+        // The rest of the snapshot may still be on its way, so the connection cannot be reused after an error.
+        if copied.is_err() {
+            conn.discard();
+        }
+        copied
+    }
+
+    /// Applies the writes made on the server at or after `since`, in milliseconds since the unix epoch, to `dest` in the
+    /// order they were made, returning the number of changes applied.
+    ///
+    /// This catches up a replica copied with `snapshot_into` without copying every key again: record the time before
+    /// taking the copy and pass it as `since`, then record the time again before each call to pass to the next one.
+    /// Changes made on the server while they are read may or may not be included, and applying a change twice is
+    /// harmless, so the windows may overlap. Fails with the server's error if its storage engine does not keep a log
+    /// of its writes.
+    pub async fn changes_since(&self, since: u64, dest: impl Storage) -> ClientResult<usize> {
+        let request = self.traced(Request::Changes { since });
+        let mut conn = self.pool.get().await?;
+        let applied = match conn.writer.write(request).await {
+            Ok(()) => apply_changes(&mut conn, dest).await,
+            Err(e) => Err(e.into()),
+        };
+        // The rest of the changes may still be on their way, so the connection cannot be reused after an error.
+        if applied.is_err() {
+            conn.discard();
         }
+        applied
     }
 
     /// Remove a given key.
     pub async fn remove(&self, key: String) -> ClientResult<()> {
         let request = Request::Remove { key };
-        let mut conn = self.pool.get().await?;
-        conn.writer.write(request).await?;
-        let response: RemoveResponse = conn.reader.read().await?.unwrap();
-        match response {
-            RemoveResponse::Ok(()) => Ok(()),
-            RemoveResponse::Err(e) => Err(ClientError::Server(e)),
+        match self.request(request).await? {
+            Response::Remove(RemoveResponse::Ok(())) => Ok(()),
+            Response::Remove(RemoveResponse::Err(e)) => Err(ClientError::Server(e)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Remove a given key if it exists.
+    ///
+    /// Returns whether the key existed, rather than an error if it did not.
+    pub async fn remove_optional(&self, key: String) -> ClientResult<bool> {
+        let request = Request::RemoveOptional { key };
+        match self.request(request).await? {
+            Response::RemoveOptional(RemoveOptionalResponse::Ok(removed)) => Ok(removed),
+            Response::RemoveOptional(RemoveOptionalResponse::Err(e)) => Err(ClientError::Server(e)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Remove every key starting with the given prefix.
+    ///
+    /// Returns the number of keys removed.
+    pub async fn remove_prefix(&self, prefix: String) -> ClientResult<usize> {
+        let request = Request::RemovePrefix { prefix };
+        match self.request(request).await? {
+            Response::RemovePrefix(RemovePrefixResponse::Ok(removed)) => Ok(removed),
+            Response::RemovePrefix(RemovePrefixResponse::Err(e)) => Err(ClientError::Server(e)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Pushes a value onto the front of the queue stored at a key, creating the queue if the key does not exist.
+    ///
+    /// Returns the length of the queue after the push.
+    pub async fn lpush(&self, key: String, value: String) -> ClientResult<usize> {
+        let request = Request::LPush { key, value };
+        match self.request(request).await? {
+            Response::Push(PushResponse::Ok(len)) => Ok(len),
+            Response::Push(PushResponse::Err(e)) => Err(ClientError::Server(e)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Pushes a value onto the back of the queue stored at a key, creating the queue if the key does not exist.
+    ///
+    /// Returns the length of the queue after the push.
+    pub async fn rpush(&self, key: String, value: String) -> ClientResult<usize> {
+        let request = Request::RPush { key, value };
+        match self.request(request).await? {
+            Response::Push(PushResponse::Ok(len)) => Ok(len),
+            Response::Push(PushResponse::Err(e)) => Err(ClientError::Server(e)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Pops a value from the front of the queue stored at a key.
+    ///
+    /// Returns `None` if the key does not exist.
+    pub async fn lpop(&self, key: String) -> ClientResult<Option<String>> {
+        let request = Request::LPop { key };
+        match self.request(request).await? {
+            Response::Pop(PopResponse::Ok(value)) => Ok(value),
+            Response::Pop(PopResponse::Err(e)) => Err(ClientError::Server(e)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Pops a value from the back of the queue stored at a key.
+    ///
+    /// Returns `None` if the key does not exist.
+    pub async fn rpop(&self, key: String) -> ClientResult<Option<String>> {
+        let request = Request::RPop { key };
+        match self.request(request).await? {
+            Response::Pop(PopResponse::Ok(value)) => Ok(value),
+            Response::Pop(PopResponse::Err(e)) => Err(ClientError::Server(e)),
+            response => Err(unexpected(response)),
         }
     }
 
     /// List all keys.
     pub async fn list(&self) -> ClientResult<Vec<String>> {
         let request = Request::List;
-        let mut conn = self.pool.get().await?;
-        conn.writer.write(request).await?;
-        let response: ListResponse = conn.reader.read().await?.unwrap();
-        match response {
-            ListResponse::Ok(keys) => Ok(keys),
-            ListResponse::Err(e) => Err(ClientError::Server(e)),
+        match self.request(request).await? {
+            Response::List(ListResponse::Ok(keys)) => Ok(keys),
+            Response::List(ListResponse::Err(e)) => Err(ClientError::Server(e)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// List all keys along with the size of their values in bytes.
+    pub async fn list_sizes(&self) -> ClientResult<Vec<(String, u32)>> {
+        let request = Request::ListSizes;
+        match self.request(request).await? {
+            Response::ListSizes(ListSizesResponse::Ok(keys)) => Ok(keys),
+            Response::ListSizes(ListSizesResponse::Err(e)) => Err(ClientError::Server(e)),
+            response => Err(unexpected(response)),
         }
     }
+
+    /// List up to `limit` keys in order, starting after `cursor` or from the first key if it is `None`.
+    ///
+    /// Pass the page's `next_cursor` to list the next page, until it is `None`.
+    /// The server only reads the keys of the page, so large key sets can be listed without holding them all at once.
+    pub async fn list_page(&self, cursor: Option<String>, limit: u32) -> ClientResult<ListPage> {
+        let request = Request::ListPage { cursor, limit };
+        match self.request(request).await? {
+            Response::ListPage(ListPageResponse::Ok { keys, next_cursor }) => {
+                Ok(ListPage { keys, next_cursor })
+            }
+            Response::ListPage(ListPageResponse::Err(e)) => Err(ClientError::Server(e)),
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+impl KvClient for Client {
+    fn get(&self, key: String) -> impl Future<Output = ClientResult<Option<String>>> + Send {
+        Client::get(self, key)
+    }
+
+    fn set(&self, key: String, value: String) -> impl Future<Output = ClientResult<()>> + Send {
+        Client::set(self, key, value)
+    }
+
+    fn remove(&self, key: String) -> impl Future<Output = ClientResult<()>> + Send {
+        Client::remove(self, key)
+    }
+
+    fn list(&self) -> impl Future<Output = ClientResult<Vec<String>>> + Send {
+        Client::list(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use tempfile::TempDir;
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::net::{
+        frame_reader, frame_writer, HelloResponse, PROTOCOL_VERSION, STREAM_CHUNK_SIZE,
+    };
+    use crate::{run, Memory, StorageType};
+
+    #[tokio::test]
+    async fn test_get_stream() {
+        let addr = "127.0.0.1:4015";
+        let (_dir, _stop) = spawn_test_server(addr).await;
+        let client = Client::connect(addr.parse().unwrap(), 1);
+
+        let value: String = (0..STREAM_CHUNK_SIZE * 3 + 100)
+            .map(|i| char::from(b'a' + (i % 26) as u8))
+            .collect();
+        client.set("key1".to_owned(), value.clone()).await.unwrap();
+
+        let chunks: Vec<Bytes> = client
+            .get_stream("key1".to_owned())
+            .await
+            .unwrap()
+            .expect("key1 should exist")
+            .map(|chunk| chunk.unwrap())
+            .collect()
+            .await;
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks.concat(), value.as_bytes());
+
+        assert!(client
+            .get_stream("key2".to_owned())
+            .await
+            .unwrap()
+            .is_none());
+
+        // The connection should be reusable after the stream has been read
+        assert_eq!(client.get("key1".to_owned()).await.unwrap(), Some(value));
+    }
+
+    #[tokio::test]
+    async fn test_queue() {
+        let addr = "127.0.0.1:4019";
+        let (_dir, _stop) = spawn_test_server(addr).await;
+        let client = Client::connect(addr.parse().unwrap(), 1);
+
+        assert_eq!(
+            client
+                .rpush("jobs".to_owned(), "a".to_owned())
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            client
+                .rpush("jobs".to_owned(), "b".to_owned())
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            client
+                .lpush("jobs".to_owned(), "z".to_owned())
+                .await
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            client.rpop("jobs".to_owned()).await.unwrap(),
+            Some("b".to_owned())
+        );
+        assert_eq!(
+            client.lpop("jobs".to_owned()).await.unwrap(),
+            Some("z".to_owned())
+        );
+        assert_eq!(
+            client.lpop("jobs".to_owned()).await.unwrap(),
+            Some("a".to_owned())
+        );
+        assert_eq!(client.lpop("jobs".to_owned()).await.unwrap(), None);
+
+        client
+            .set("key1".to_owned(), "value1".to_owned())
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.rpush("key1".to_owned(), "a".to_owned()).await,
+            Err(ClientError::Server(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let addr = "127.0.0.1:4016";
+        let (_dir, _stop) = spawn_test_server(addr).await;
+        let addr = addr.parse().unwrap();
+
+        let conn = Connection::connect(addr, PROTOCOL_VERSION).await.unwrap();
+        assert_eq!(conn.protocol_version, PROTOCOL_VERSION);
+
+        let err = Connection::connect(addr, PROTOCOL_VERSION + 1)
+            .await
+            .unwrap_err();
+        match err {
+            ClientError::Handshake(e) => assert!(e.contains("unsupported protocol version")),
+            e => panic!("expected a handshake error, got {}", e),
+        }
+
+        // Other connections are unaffected by the refused one
+        let client = Client::connect(addr, 1);
+        client
+            .set("key1".to_owned(), "value1".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(
+            client.get("key1".to_owned()).await.unwrap(),
+            Some("value1".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_if_idle() {
+        let addr = "127.0.0.1:4043";
+        let (_dir, _stop) = spawn_test_server(addr).await;
+        let client = Client::connect(addr.parse().unwrap(), 1);
+
+        // Nothing is sent until a connection has been opened and returned to the pool.
+        assert_eq!(client.get_if_idle("key".to_owned()).await.unwrap(), None);
+        client
+            .set("key".to_owned(), "value".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(
+            client.get_if_idle("key".to_owned()).await.unwrap(),
+            Some(Some("value".to_owned()))
+        );
+        assert_eq!(
+            client.get_if_idle("missing".to_owned()).await.unwrap(),
+            Some(None)
+        );
+    }
+
+    // A client created from an already established stream should make its requests on it, and fail once it is closed
+    // rather than opening another connection.
+    #[tokio::test]
+    async fn test_from_connection() {
+        let addr = "127.0.0.1:4048";
+        let (_dir, _stop) = spawn_test_server(addr).await;
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let conn = Connection::from_stream(stream).await.unwrap();
+        assert_eq!(conn.protocol_version, PROTOCOL_VERSION);
+        let client = Client::from_connection(conn);
+        client
+            .set("key1".to_owned(), "value1".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(
+            client.get("key1".to_owned()).await.unwrap(),
+            Some("value1".to_owned())
+        );
+        assert_eq!(client.pool_stats().max_size, 1);
+        assert_eq!(client.pool_stats().idle, 1);
+
+        // A server that closes the connection once the handshake is done.
+        let listener = TcpListener::bind("127.0.0.1:4049").await.unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, writer) = stream.into_split();
+            let (mut reader, mut writer) = (frame_reader(reader), frame_writer(writer));
+            reader.read::<Request>().await.unwrap();
+            let hello = HelloResponse::Ok {
+                protocol_version: PROTOCOL_VERSION,
+                max_value_size: None,
+            };
+            writer.write(hello).await.unwrap();
+        });
+        let stream = tokio::net::TcpStream::connect("127.0.0.1:4049")
+            .await
+            .unwrap();
+        let client = Client::from_connection(Connection::from_stream(stream).await.unwrap());
+        match client.get("key1".to_owned()).await {
+            Err(ClientError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::NotConnected),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_into() {
+        let addr = "127.0.0.1:4045";
+        let (_dir, _stop) = spawn_test_server(addr).await;
+        let client = Client::connect(addr.parse().unwrap(), 1);
+
+        // Enough data for several batches, including a value larger than a batch on its own.
+        for i in 0..2000 {
+            client
+                .set(format!("key{:04}", i), format!("value{}", i).repeat(10))
+                .await
+                .unwrap();
+        }
+        client
+            .set("large".to_owned(), "a".repeat(STREAM_CHUNK_SIZE * 2))
+            .await
+            .unwrap();
+        client.remove("key0000".to_owned()).await.unwrap();
+
+        let replica = Memory::new();
+        assert_eq!(client.snapshot_into(replica.clone()).await.unwrap(), 2000);
+        // The connection is returned to the pool once the snapshot has been read to the end.
+        assert_eq!(client.pool_stats().idle, 1);
+
+        let keys = client.list().await.unwrap();
+        assert_eq!(replica.list_keys(), keys);
+        for key in keys {
+            assert_eq!(
+                replica.get(key.clone()).unwrap(),
+                client.get(key).await.unwrap()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_changes_since() {
+        let addr = "127.0.0.1:4046";
+        let (_dir, _stop) = spawn_test_server(addr).await;
+        let client = Client::connect(addr.parse().unwrap(), 1);
+
+        for i in 0..100 {
+            client
+                .set(format!("key{}", i), format!("value{}", i))
+                .await
+                .unwrap();
+        }
+        let since = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let replica = Memory::new();
+        assert_eq!(client.snapshot_into(replica.clone()).await.unwrap(), 100);
+
+        client
+            .set("key1".to_owned(), "updated".to_owned())
+            .await
+            .unwrap();
+        client.remove("key2".to_owned()).await.unwrap();
+        client
+            .set("added".to_owned(), "value".to_owned())
+            .await
+            .unwrap();
+        client
+            .set("removed".to_owned(), "value".to_owned())
+            .await
+            .unwrap();
+        client.remove("removed".to_owned()).await.unwrap();
+
+        // Writes made in the same millisecond as `since` are applied again along with the five changes.
+        assert!(client.changes_since(since, replica.clone()).await.unwrap() >= 5);
+        assert_eq!(client.pool_stats().idle, 1);
+
+        let keys = client.list().await.unwrap();
+        assert_eq!(replica.list_keys(), keys);
+        for key in keys {
+            assert_eq!(
+                replica.get(key.clone()).unwrap(),
+                client.get(key).await.unwrap()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_meta() {
+        let addr = "127.0.0.1:4025";
+        let (_dir, _stop) = spawn_test_server(addr).await;
+        let client = Client::connect(addr.parse().unwrap(), 1);
+
+        let before = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        client
+            .set("key".to_owned(), "value".to_owned())
+            .await
+            .unwrap();
+        let after = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        let (value, timestamp) = client.get_meta("key".to_owned()).await.unwrap();
+        assert_eq!(value, Some("value".to_owned()));
+        let timestamp = timestamp.unwrap();
+        assert!(timestamp >= before && timestamp <= after);
+
+        // Touching the key moves its timestamp on without changing its value.
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        client.touch("key".to_owned()).await.unwrap();
+        let (value, touched) = client.get_meta("key".to_owned()).await.unwrap();
+        assert_eq!(value, Some("value".to_owned()));
+        assert!(touched.unwrap() > timestamp);
+
+        assert_eq!(
+            client.get_meta("missing".to_owned()).await.unwrap(),
+            (None, None)
+        );
+        assert!(matches!(
+            client.touch("missing".to_owned()).await,
+            Err(ClientError::Server(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_list_page() {
+        let addr = "127.0.0.1:4033";
+        let (_dir, _stop) = spawn_test_server(addr).await;
+        let client = Client::connect(addr.parse().unwrap(), 1);
+
+        for key_id in 0..10 {
+            client
+                .set(format!("key{}", key_id), "value".to_owned())
+                .await
+                .unwrap();
+        }
+
+        let mut keys = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = client.list_page(cursor, 3).await.unwrap();
+            assert!(page.keys.len() <= 3);
+            keys.extend(page.keys);
+            cursor = match page.next_cursor {
+                Some(next_cursor) => Some(next_cursor),
+                None => break,
+            };
+        }
+        assert_eq!(keys, client.list().await.unwrap());
+    }
+
+    // A value over the limit the server advertised should be rejected without being sent.
+    #[tokio::test]
+    async fn test_max_value_size() {
+        let addr = "127.0.0.1:4035";
+        let listener = TcpListener::bind(addr).await.unwrap();
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, writer) = stream.into_split();
+            let (mut reader, mut writer) = (frame_reader(reader), frame_writer(writer));
+            reader.read::<Request>().await.unwrap();
+            let hello = HelloResponse::Ok {
+                protocol_version: PROTOCOL_VERSION,
+                max_value_size: Some(16),
+            };
+            writer.write(hello).await.unwrap();
+            while let Ok(Some(_)) = reader.read::<Request>().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                writer
+                    .write(Response::Set(SetResponse::Ok(())))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let client = Client::connect(addr.parse().unwrap(), 1);
+        match client.set("key1".to_owned(), "v".repeat(17)).await {
+            Err(ClientError::ValueTooLarge { max_value_size }) => assert_eq!(max_value_size, 16),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(matches!(
+            client.rpush("queue".to_owned(), "v".repeat(17)).await,
+            Err(ClientError::ValueTooLarge { .. })
+        ));
+        assert_eq!(received.load(Ordering::SeqCst), 0);
+
+        // Values up to the limit are sent as usual, on the same connection
+        client.set("key1".to_owned(), "v".repeat(16)).await.unwrap();
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_corruption() {
+        let addr = "127.0.0.1:4041";
+        let listener = TcpListener::bind(addr).await.unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, writer) = stream.into_split();
+            let (mut reader, mut writer) = (frame_reader(reader), frame_writer(writer));
+            reader.read::<Request>().await.unwrap();
+            let hello = HelloResponse::Ok {
+                protocol_version: PROTOCOL_VERSION,
+                max_value_size: None,
+            };
+            writer.write(hello).await.unwrap();
+            // Every read finds corrupted data, which is reported in place of the request's own response.
+            while let Ok(Some(request)) = reader.read::<Request>().await {
+                let response = match request {
+                    Request::Set { .. } => Response::Set(SetResponse::Ok(())),
+                    _ => Response::Corruption("checksum mismatch".to_string()),
+                };
+                writer.write(response).await.unwrap();
+            }
+        });
+
+        let client = Client::connect(addr.parse().unwrap(), 1);
+        match client.get("key1".to_owned()).await {
+            Err(ClientError::Corruption(e)) => assert_eq!(e, "checksum mismatch"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(matches!(
+            client.get_stream("key1".to_owned()).await,
+            Err(ClientError::Corruption(_))
+        ));
+
+        // The connection is still usable after corruption is reported.
+        client
+            .set("key1".to_owned(), "value1".to_owned())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_builder() {
+        let addr = "127.0.0.1:4038";
+        let (_dir, _stop) = spawn_test_server(addr).await;
+
+        // The minimum connections are opened up front and left idle for the first requests.
+        let client = Client::builder(addr.parse().unwrap())
+            .pool_size(4)
+            .min_connections(2)
+            .connect_timeout(Duration::from_secs(1))
+            .connect_backoff(Duration::from_millis(10))
+            .connect()
+            .await
+            .unwrap();
+        let stats = client.pool_stats();
+        assert_eq!((stats.max_size, stats.idle, stats.in_use), (4, 2, 0));
+        client
+            .set("key1".to_owned(), "value1".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(
+            client.get("key1".to_owned()).await.unwrap(),
+            Some("value1".to_owned())
+        );
+
+        // A server that accepts the connection but never answers the handshake times out.
+        let listener = TcpListener::bind("127.0.0.1:4039").await.unwrap();
+        let client = Client::builder(listener.local_addr().unwrap())
+            .connect_timeout(Duration::from_millis(100))
+            .build();
+        match client.get("key1".to_owned()).await {
+            Err(ClientError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::TimedOut),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    async fn spawn_test_server(addr: &str) -> (TempDir, oneshot::Sender<()>) {
+        let dir = TempDir::new().unwrap();
+        let (tx, rx) = oneshot::channel();
+        let path = dir.path().to_path_buf();
+        let addr = addr.parse().unwrap();
+        tokio::spawn(async move { run(addr, path, StorageType::Bitcask, rx).await.unwrap() });
+        // Brief delay to ensure server is ready
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        (dir, tx)
+    }
 }
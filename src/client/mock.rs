@@ -0,0 +1,83 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use super::{ClientError, ClientResult, KvClient};
+
+/// An in-memory `KvClient` for testing code that uses smoldb without running a server.
+///
+/// Clones share the same underlying map.
+#[derive(Debug, Clone, Default)]
+pub struct MockClient {
+    values: Arc<Mutex<BTreeMap<String, String>>>,
+}
+
+impl MockClient {
+    /// Creates an empty `MockClient`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a `MockClient` holding the given key/value pairs.
+    pub fn with_values(values: impl IntoIterator<Item = (String, String)>) -> Self {
+        MockClient {
+            values: Arc::new(Mutex::new(values.into_iter().collect())),
+        }
+    }
+}
+
+impl KvClient for MockClient {
+    async fn get(&self, key: String) -> ClientResult<Option<String>> {
+        Ok(self.values.lock()?.get(&key).cloned())
+    }
+
+    async fn set(&self, key: String, value: String) -> ClientResult<()> {
+        self.values.lock()?.insert(key, value);
+        Ok(())
+    }
+
+    async fn remove(&self, key: String) -> ClientResult<()> {
+        match self.values.lock()?.remove(&key) {
+            Some(_) => Ok(()),
+            None => Err(ClientError::Server("Key not found".to_string())),
+        }
+    }
+
+    async fn list(&self) -> ClientResult<Vec<String>> {
+        Ok(self.values.lock()?.keys().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Application code written against `KvClient` rather than a concrete client.
+    async fn greeting<C: KvClient>(client: &C, user: &str) -> ClientResult<String> {
+        let name = client.get(format!("name:{}", user)).await?;
+        Ok(format!("Hello, {}!", name.as_deref().unwrap_or("stranger")))
+    }
+
+    #[tokio::test]
+    async fn test_mock_client() {
+        let client = MockClient::with_values([("name:1".to_owned(), "Evan".to_owned())]);
+
+        assert_eq!(greeting(&client, "1").await.unwrap(), "Hello, Evan!");
+        assert_eq!(greeting(&client, "2").await.unwrap(), "Hello, stranger!");
+
+        client
+            .set("name:2".to_owned(), "Sam".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(greeting(&client, "2").await.unwrap(), "Hello, Sam!");
+        assert_eq!(
+            client.list().await.unwrap(),
+            vec!["name:1".to_owned(), "name:2".to_owned()]
+        );
+
+        client.remove("name:1".to_owned()).await.unwrap();
+        assert!(client.remove("name:1".to_owned()).await.is_err());
+        assert_eq!(greeting(&client, "1").await.unwrap(), "Hello, stranger!");
+    }
+}
@@ -1,4 +1,12 @@
+#[allow(clippy::module_inception)]
 mod client;
+mod mock;
 mod pool;
+mod typed;
 
-pub use client::{Client, ClientError, ClientResult};
+pub use client::{
+    Client, ClientBuilder, ClientError, ClientOptions, ClientResult, KvClient, ServerInfo,
+};
+pub use mock::MockClient;
+pub use pool::{Connection, PoolStats, ReuseOrder};
+pub use typed::TypedClient;
@@ -1,25 +1,71 @@
 use std::collections::VecDeque;
+use std::io;
 use std::net::SocketAddr;
 use std::ops::{Deref, DerefMut};
 use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
 use tokio::sync::Semaphore;
+use tokio::time::{self, sleep_until, Instant};
+use tracing::debug;
 
-use super::ClientResult;
+use super::{ClientError, ClientResult};
+use crate::net::{
+    frame_reader, frame_writer, FrameReader, FrameWriter, HelloResponse, NetReadExt, NetWriteExt,
+    Request, PROTOCOL_VERSION,
+};
 
-/// Defines the Inner Pooled Resource
+/// A connection to a smoldb server that has completed the handshake, as pooled by a `Client`.
 #[derive(Debug)]
 pub struct Connection {
-    pub reader: OwnedReadHalf,
-    pub writer: OwnedWriteHalf,
+    pub(crate) reader: FrameReader<OwnedReadHalf>,
+    pub(crate) writer: FrameWriter<OwnedWriteHalf>,
+    /// The protocol version agreed with the server during the handshake.
+    pub protocol_version: u32,
+    /// The largest value in bytes the server accepts, as advertised during the handshake.
+    pub max_value_size: Option<usize>,
 }
 
 impl Connection {
     async fn new(addr: SocketAddr) -> ClientResult<Self> {
+        Connection::connect(addr, PROTOCOL_VERSION).await
+    }
+
+    /// Performs the handshake over an already established stream, such as a socket passed in by systemd or inetd.
+    pub async fn from_stream(stream: TcpStream) -> ClientResult<Self> {
+        Connection::handshake(stream, PROTOCOL_VERSION).await
+    }
+
+    // Connects and performs the handshake, proposing the given protocol version.
+    pub(super) async fn connect(addr: SocketAddr, protocol_version: u32) -> ClientResult<Self> {
         let stream = TcpStream::connect(addr).await?;
+        Connection::handshake(stream, protocol_version).await
+    }
+
+    // Performs the handshake over the stream, proposing the given protocol version.
+    async fn handshake(stream: TcpStream, protocol_version: u32) -> ClientResult<Self> {
         let (reader, writer) = stream.into_split();
-        Ok(Connection { reader, writer })
+        let (mut reader, mut writer) = (frame_reader(reader), frame_writer(writer));
+        writer.write(Request::Hello { protocol_version }).await?;
+        let (protocol_version, max_value_size) = match reader.read::<HelloResponse>().await? {
+            Some(HelloResponse::Ok {
+                protocol_version,
+                max_value_size,
+            }) => (protocol_version, max_value_size),
+            Some(HelloResponse::Err(e)) => return Err(ClientError::Handshake(e)),
+            None => {
+                return Err(ClientError::Handshake(
+                    "connection closed during handshake".to_string(),
+                ))
+            }
+        };
+        Ok(Connection {
+            reader,
+            writer,
+            protocol_version,
+            max_value_size,
+        })
     }
 }
 
@@ -30,6 +76,7 @@ impl Connection {
 pub struct Object {
     inner: Option<Connection>,
     pool: Weak<PoolInner>,
+    reused: bool,
 }
 
 impl Deref for Object {
@@ -45,6 +92,23 @@ impl DerefMut for Object {
     }
 }
 
+impl Object {
+    /// Whether the connection was taken from the idle connections rather than newly opened.
+    /// A reused connection may have been closed by the server while it sat idle.
+    pub fn is_reused(&self) -> bool {
+        self.reused
+    }
+
+    /// Closes the connection rather than returning it to the pool.
+    /// Used when the connection is left part way through a response and can not be reused.
+    pub fn discard(mut self) {
+        self.inner.take();
+        if let Some(pool) = self.pool.upgrade() {
+            pool.semaphore.add_permits(1);
+        }
+    }
+}
+
 impl Drop for Object {
     fn drop(&mut self) {
         if let Some(inner) = self.inner.take() {
@@ -55,23 +119,100 @@ impl Drop for Object {
     }
 }
 
+/// The order in which idle connections are reused.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReuseOrder {
+    /// Reuse the connection that has been idle the longest, cycling through every open connection.
+    #[default]
+    Fifo,
+    /// Reuse the most recently returned connection, so under light load the same connection is reused
+    /// and the others are left idle.
+    Lifo,
+}
+
+/// A snapshot of how a client's connection pool is being used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    /// The most connections the pool will hold.
+    pub max_size: usize,
+    /// The number of open connections waiting in the pool to be reused.
+    pub idle: usize,
+    /// The number of connections currently checked out of the pool, including ones still being opened.
+    pub in_use: usize,
+}
+
 ///The Pool that manages Connections
 #[derive(Debug, Clone)]
 pub struct Pool {
-    addr: SocketAddr,
+    // `None` for a pool created from a connection, which cannot open any more.
+    addr: Option<SocketAddr>,
+    max_size: usize,
+    connect_timeout: Option<Duration>,
     inner: Arc<PoolInner>,
 }
 
 impl Pool {
-    /// Create a new Pool with the given address and max size.
+    /// Create a new Pool with the given address, max size, connection reuse order and connection backoff.
     /// Connections are created lazily and thus calling new is not necessarily
     /// indicative of connections being created successfully or the current number of connections in the pool.
-    pub fn new(addr: SocketAddr, max_size: usize) -> Self {
+    ///
+    /// With a `connect_backoff`, a failed connection attempt delays the next one by that long, doubling with each
+    /// further failure, until a connection succeeds.
+    pub fn new(
+        addr: SocketAddr,
+        max_size: usize,
+        reuse_order: ReuseOrder,
+        connect_backoff: Option<Duration>,
+    ) -> Self {
         let inner = Arc::new(PoolInner {
             slots: Mutex::new(VecDeque::with_capacity(max_size)),
             semaphore: Semaphore::new(max_size),
+            reuse_order,
+            backoff: connect_backoff.map(Backoff::new),
+        });
+        Pool {
+            addr: Some(addr),
+            max_size,
+            connect_timeout: None,
+            inner,
+        }
+    }
+
+    /// Create a new Pool holding only the given connection.
+    /// Once the connection is closed, getting a connection fails rather than opening a new one.
+    pub fn from_connection(conn: Connection) -> Self {
+        let inner = Arc::new(PoolInner {
+            slots: Mutex::new(VecDeque::from([conn])),
+            semaphore: Semaphore::new(1),
+            reuse_order: ReuseOrder::default(),
+            backoff: None,
         });
-        Pool { addr, inner }
+        Pool {
+            addr: None,
+            max_size: 1,
+            connect_timeout: None,
+            inner,
+        }
+    }
+
+    /// Gives up on opening a connection, including its handshake, once it has taken longer than the given timeout.
+    pub fn with_connect_timeout(mut self, connect_timeout: Option<Duration>) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Reports how many connections are idle and in use.
+    pub fn stats(&self) -> PoolStats {
+        // The slots are only read, so a poisoned lock still gives a usable count.
+        let idle = match self.inner.slots.lock() {
+            Ok(slots) => slots.len(),
+            Err(e) => e.into_inner().len(),
+        };
+        PoolStats {
+            max_size: self.max_size,
+            idle,
+            in_use: self.max_size - self.inner.semaphore.available_permits(),
+        }
     }
 
     /// Get a connection from the pool.
@@ -89,17 +230,131 @@ impl Pool {
             conns.pop_front()
         };
 
+        let reused = conn.is_some();
         let conn = match conn {
             Some(conn) => conn,
-            None => Connection::new(self.addr).await?,
+            None => self.connect().await?,
         };
 
         permit.forget();
         Ok(Object {
             inner: Some(conn),
             pool: Arc::downgrade(&self.inner),
+            reused,
         })
     }
+
+    /// Get an idle connection from the pool without waiting.
+    ///
+    /// Returns `None` if the pool is full or has no idle connection, rather than waiting for one to be returned or
+    /// opening a new one.
+    pub fn try_get(&self) -> ClientResult<Option<Object>> {
+        let Ok(permit) = self.inner.semaphore.try_acquire() else {
+            return Ok(None);
+        };
+        let conn = match self.inner.slots.lock()?.pop_front() {
+            Some(conn) => conn,
+            // The permit is released on drop, leaving the pool as it was.
+            None => return Ok(None),
+        };
+
+        permit.forget();
+        Ok(Some(Object {
+            inner: Some(conn),
+            pool: Arc::downgrade(&self.inner),
+            reused: true,
+        }))
+    }
+
+    // Opens a new connection, waiting out any backoff from earlier failures first.
+    async fn connect(&self) -> ClientResult<Connection> {
+        let backoff = match &self.inner.backoff {
+            Some(backoff) => backoff,
+            None => return self.open().await,
+        };
+        sleep_until(backoff.next_attempt()?).await;
+        let result = self.open().await;
+        match result {
+            Ok(_) => backoff.succeeded()?,
+            Err(_) => backoff.failed()?,
+        }
+        result
+    }
+
+    // Opens a new connection within the connect timeout.
+    async fn open(&self) -> ClientResult<Connection> {
+        let addr = self.addr.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the connection the client was created from is closed",
+            )
+        })?;
+        let conn = match self.connect_timeout {
+            None => Connection::new(addr).await?,
+            Some(timeout) => match time::timeout(timeout, Connection::new(addr)).await {
+                Ok(result) => result?,
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("connecting to {} timed out after {:?}", addr, timeout),
+                    )
+                    .into())
+                }
+            },
+        };
+        debug!(
+            "connected to {} with protocol version {}",
+            addr, conn.protocol_version
+        );
+        Ok(conn)
+    }
+}
+
+// The most a connection attempt is delayed by backoff.
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+// Spaces out connection attempts after failures.
+// Shared by every caller of the pool so that concurrent callers do not each retry a dead server.
+#[derive(Debug)]
+struct Backoff {
+    initial: Duration,
+    state: Mutex<BackoffState>,
+}
+
+#[derive(Debug)]
+struct BackoffState {
+    next_attempt: Instant,
+    delay: Duration,
+}
+
+impl Backoff {
+    fn new(initial: Duration) -> Self {
+        Backoff {
+            initial,
+            state: Mutex::new(BackoffState {
+                next_attempt: Instant::now(),
+                delay: Duration::ZERO,
+            }),
+        }
+    }
+
+    fn next_attempt(&self) -> ClientResult<Instant> {
+        Ok(self.state.lock()?.next_attempt)
+    }
+
+    fn failed(&self) -> ClientResult<()> {
+        let mut state = self.state.lock()?;
+        state.delay = (state.delay * 2).clamp(self.initial, MAX_CONNECT_BACKOFF);
+        state.next_attempt = Instant::now() + state.delay;
+        Ok(())
+    }
+
+    fn succeeded(&self) -> ClientResult<()> {
+        let mut state = self.state.lock()?;
+        state.delay = Duration::ZERO;
+        state.next_attempt = Instant::now();
+        Ok(())
+    }
 }
 
 // sync mutex is used to acquire locks within Drop implementation
@@ -107,12 +362,18 @@ impl Pool {
 struct PoolInner {
     slots: Mutex<VecDeque<Connection>>,
     semaphore: Semaphore,
+    reuse_order: ReuseOrder,
+    backoff: Option<Backoff>,
 }
 
 impl PoolInner {
+    // Connections are always taken from the front, so the end they are returned to decides the reuse order.
     fn return_object(&self, obj: Connection) {
         let mut slots = self.slots.lock().unwrap();
-        slots.push_back(obj);
+        match self.reuse_order {
+            ReuseOrder::Fifo => slots.push_back(obj),
+            ReuseOrder::Lifo => slots.push_front(obj),
+        }
         drop(slots);
         self.semaphore.add_permits(1);
     }
@@ -129,7 +390,7 @@ mod tests {
     async fn test_pool() {
         let addr = "127.0.0.1:4012";
         spawn_test_server(addr).await;
-        let pool = Pool::new(addr.parse().unwrap(), 2);
+        let pool = Pool::new(addr.parse().unwrap(), 2, ReuseOrder::Fifo, None);
 
         let conn1 = pool.get().await.unwrap();
         let conn2 = pool.get().await.unwrap();
@@ -152,11 +413,34 @@ mod tests {
         assert_eq!(pool.inner.slots.lock().unwrap().len(), 2);
     }
 
+    #[tokio::test]
+    async fn test_pool_try_get() {
+        let addr = "127.0.0.1:4042";
+        spawn_test_server(addr).await;
+        let pool = Pool::new(addr.parse().unwrap(), 1, ReuseOrder::Fifo, None);
+
+        // No connection has been opened yet, and try_get does not open one.
+        assert!(pool.try_get().unwrap().is_none());
+        assert_eq!(pool.stats().in_use, 0);
+
+        // The only connection is held, so there is nothing to take.
+        let conn = pool.get().await.unwrap();
+        assert!(pool.try_get().unwrap().is_none());
+        drop(conn);
+
+        // Once it is returned it can be taken without waiting.
+        let conn = pool.try_get().unwrap().expect("an idle connection");
+        assert!(conn.is_reused());
+        assert_eq!((pool.stats().idle, pool.stats().in_use), (0, 1));
+        drop(conn);
+        assert_eq!((pool.stats().idle, pool.stats().in_use), (1, 0));
+    }
+
     #[tokio::test]
     async fn test_pool_concurrent() {
         let addr = "127.0.0.1:4013";
         spawn_test_server(addr).await;
-        let pool = Pool::new(addr.parse().unwrap(), 2);
+        let pool = Pool::new(addr.parse().unwrap(), 2, ReuseOrder::Fifo, None);
 
         assert_eq!(pool.inner.slots.lock().unwrap().len(), 0);
 
@@ -191,7 +475,7 @@ mod tests {
 
         spawn_test_server(addr).await;
 
-        let pool = Pool::new(addr.parse().unwrap(), 2);
+        let pool = Pool::new(addr.parse().unwrap(), 2, ReuseOrder::Fifo, None);
 
         let handles = (0..100)
             .map(|_| {
@@ -209,12 +493,132 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_pool_stats() {
+        let addr = "127.0.0.1:4021";
+        spawn_test_server(addr).await;
+        let pool = Pool::new(addr.parse().unwrap(), 3, ReuseOrder::Fifo, None);
+
+        let stats = |idle, in_use| PoolStats {
+            max_size: 3,
+            idle,
+            in_use,
+        };
+        assert_eq!(pool.stats(), stats(0, 0));
+
+        let conn1 = pool.get().await.unwrap();
+        let conn2 = pool.get().await.unwrap();
+        assert_eq!(pool.stats(), stats(0, 2));
+
+        drop(conn1);
+        assert_eq!(pool.stats(), stats(1, 1));
+
+        drop(conn2);
+        assert_eq!(pool.stats(), stats(2, 0));
+
+        // Reusing an idle connection does not open a new one
+        let conn3 = pool.get().await.unwrap();
+        assert_eq!(pool.stats(), stats(1, 1));
+
+        // A discarded connection is closed rather than returned
+        conn3.discard();
+        assert_eq!(pool.stats(), stats(1, 0));
+    }
+
+    #[tokio::test]
+    async fn test_pool_reuse_order() {
+        let addr = "127.0.0.1:4022";
+        spawn_test_server(addr).await;
+
+        // Open three connections, then check them out one at a time and record which one is handed out.
+        async fn reused(pool: &Pool) -> Vec<SocketAddr> {
+            let (conn1, conn2, conn3) = tokio::join!(pool.get(), pool.get(), pool.get());
+            drop((conn1.unwrap(), conn2.unwrap(), conn3.unwrap()));
+            let mut reused = Vec::new();
+            for _ in 0..6 {
+                let conn = pool.get().await.unwrap();
+                reused.push(conn.reader.get_ref().local_addr().unwrap());
+            }
+            reused
+        }
+
+        // FIFO cycles through every connection
+        let pool = Pool::new(addr.parse().unwrap(), 3, ReuseOrder::Fifo, None);
+        let fifo = reused(&pool).await;
+        assert_eq!(fifo[..3], fifo[3..]);
+        assert!(fifo[0] != fifo[1] && fifo[1] != fifo[2] && fifo[0] != fifo[2]);
+
+        // LIFO keeps reusing one connection, leaving the other two idle
+        let pool = Pool::new(addr.parse().unwrap(), 3, ReuseOrder::Lifo, None);
+        let lifo = reused(&pool).await;
+        assert!(lifo.iter().all(|conn| *conn == lifo[0]));
+        assert_eq!(pool.stats().idle, 3);
+    }
+
+    #[tokio::test]
+    async fn test_pool_failed_gets_release_permits() {
+        let addr = "127.0.0.1:4023";
+        let pool = Pool::new(addr.parse().unwrap(), 2, ReuseOrder::Fifo, None);
+
+        // The server is not up yet, so every connection attempt fails
+        for _ in 0..10 {
+            assert!(pool.get().await.is_err());
+        }
+        assert_eq!(pool.stats().in_use, 0);
+
+        // Once the server is up the pool can still reach its max size
+        spawn_test_server(addr).await;
+        let conn1 = pool.get().await.unwrap();
+        let conn2 = pool.get().await.unwrap();
+        assert_eq!(pool.stats().in_use, 2);
+        drop((conn1, conn2));
+        assert_eq!(pool.stats().idle, 2);
+    }
+
+    #[tokio::test]
+    async fn test_pool_connect_backoff() {
+        let addr = "127.0.0.1:4024";
+        let pool = Pool::new(
+            addr.parse().unwrap(),
+            2,
+            ReuseOrder::Fifo,
+            Some(Duration::from_millis(50)),
+        );
+
+        // Attempts against a dead server are delayed by 0, 50, 100 and then 200ms
+        let start = Instant::now();
+        for _ in 0..4 {
+            assert!(pool.get().await.is_err());
+        }
+        assert!(start.elapsed() >= Duration::from_millis(350));
+
+        // A successful connection resets the backoff
+        spawn_test_server(addr).await;
+        let conn1 = pool.get().await.unwrap();
+        let start = Instant::now();
+        let conn2 = pool.get().await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(50));
+        drop((conn1, conn2));
+    }
+
     async fn spawn_test_server(addr: &str) {
         let listener = TcpListener::bind(addr).await.unwrap();
         spawn(async move {
             while let Ok((socket, _)) = listener.accept().await {
-                // Just accept connections and let them drop
-                let _ = socket;
+                // Just complete the handshake and hold the connection until the client drops it
+                spawn(async move {
+                    let (reader, writer) = socket.into_split();
+                    let (mut reader, mut writer) = (frame_reader(reader), frame_writer(writer));
+                    while let Ok(Some(_)) = reader.read::<Request>().await {
+                        let response = HelloResponse::Ok {
+                            protocol_version: PROTOCOL_VERSION,
+                            max_value_size: None,
+                        };
+                        if writer.write(response).await.is_err() {
+                            break;
+                        }
+                    }
+                });
             }
         });
         // Brief delay to ensure server is ready
@@ -0,0 +1,101 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{Client, ClientResult, KvClient};
+
+/// A client that sets and gets values of any serializable type rather than strings.
+///
+/// Values are stored as JSON through the wrapped `KvClient`, a `Client` unless another is given such as a
+/// `MockClient` in tests, so they can still be read and written as strings by other clients.
+#[derive(Clone)]
+pub struct TypedClient<C: KvClient = Client> {
+    client: C,
+}
+
+impl<C: KvClient> TypedClient<C> {
+    /// Wraps a client.
+    pub fn new(client: C) -> Self {
+        TypedClient { client }
+    }
+
+    /// The wrapped client, for the operations on strings.
+    pub fn inner(&self) -> &C {
+        &self.client
+    }
+
+    /// Gets the value of a given key, deserialized from JSON.
+    ///
+    /// Returns `None` if the given key does not exist, and `ClientError::Json` if its value is not the JSON of a `T`.
+    pub async fn get_typed<T: DeserializeOwned>(&self, key: String) -> ClientResult<Option<T>> {
+        match self.client.get(key).await? {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the value of a key to the JSON of a value.
+    pub async fn set_typed<T: Serialize>(&self, key: String, value: &T) -> ClientResult<()> {
+        let value = serde_json::to_string(value)?;
+        self.client.set(key, value).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::client::{ClientError, MockClient};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        address: Address,
+        tags: Vec<String>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Address {
+        city: String,
+        zip: Option<u32>,
+    }
+
+    #[tokio::test]
+    async fn test_typed_client() {
+        let client = TypedClient::new(MockClient::new());
+        let user = User {
+            name: "Evan".to_owned(),
+            address: Address {
+                city: "Toronto".to_owned(),
+                zip: None,
+            },
+            tags: vec!["admin".to_owned()],
+        };
+
+        client.set_typed("user:1".to_owned(), &user).await.unwrap();
+        assert_eq!(
+            client.get_typed::<User>("user:1".to_owned()).await.unwrap(),
+            Some(user)
+        );
+        assert_eq!(
+            client.inner().get("user:1".to_owned()).await.unwrap(),
+            Some(
+                r#"{"name":"Evan","address":{"city":"Toronto","zip":null},"tags":["admin"]}"#
+                    .to_owned()
+            )
+        );
+        assert_eq!(
+            client.get_typed::<User>("user:2".to_owned()).await.unwrap(),
+            None
+        );
+
+        client
+            .inner()
+            .set("user:2".to_owned(), "Sam".to_owned())
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.get_typed::<User>("user:2".to_owned()).await,
+            Err(ClientError::Json(_))
+        ));
+    }
+}
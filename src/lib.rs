@@ -5,5 +5,18 @@ mod client;
 mod net;
 mod server;
 
-pub use client::{Client, ClientError, ClientResult};
-pub use server::{run, ServerError, ServerResult, StorageType};
+pub use client::{
+    Client, ClientBuilder, ClientError, ClientOptions, ClientResult, Connection, KvClient,
+    MockClient, PoolStats, ReuseOrder, ServerInfo, TypedClient,
+};
+pub use server::{
+    run, run_with_config, run_with_options, AsyncBitcask, Bitcask, BitcaskOptions, BitcaskStats,
+    Changes, CompactionPlan, CompactionStats, FileSystem, ListPage, LogRecord, Memory, MemoryStats,
+    NaiveThreadPool, PutOutcome, RayonThreadPool, SegmentInfo, ServerConfig, ServerError,
+    ServerOptions, ServerResult, SharedQueueThreadPool, ShutdownReason, Sled, SledOptions,
+    SledStats, StdFileSystem, Storage, StorageError, StorageResult, StorageType, ThreadPool,
+    ThreadPoolError, ThreadPoolResult, ThreadPoolType, ValueMetadata,
+};
+
+/// The `Result` type for smoldb, with `ServerError` covering every error the server and its storage can return.
+pub type Result<T> = std::result::Result<T, ServerError>;
@@ -1,6 +1,11 @@
+#[allow(clippy::module_inception)]
 mod net;
 
 pub use net::{
-    GetResponse, ListResponse, NetError, NetReadExt, NetWriteExt, RemoveResponse, Request,
-    SetResponse,
+    frame_reader, frame_writer, ChangesResponse, CompactResponse, FrameReader, FrameWriter,
+    GetMetaResponse, GetOrSetResponse, GetResponse, GetStreamResponse, HelloResponse, InfoResponse,
+    ListPageResponse, ListResponse, ListSizesResponse, NetError, NetReadExt, NetWriteExt,
+    PopResponse, PushResponse, PutResponse, RemoveOptionalResponse, RemovePrefixResponse,
+    RemoveResponse, Request, Response, SetIfAbsentResponse, SetResponse, SnapshotResponse,
+    TouchResponse, PROTOCOL_VERSION, STREAM_CHUNK_SIZE,
 };
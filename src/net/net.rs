@@ -1,11 +1,28 @@
+use bytes::{BufMut, BytesMut};
 use futures::sink::SinkExt;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_stream::StreamExt;
 use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 
+/// The version of the request/response protocol.
+///
+/// Exchanged in the `Hello` handshake that starts every connection, and bumped whenever the wire format changes.
+/// Requests and responses are encoded with the index of their variant, so new variants are added at the end of
+/// `Request` and `Response` to leave the encoding of the existing ones unchanged.
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// The maximum number of value bytes sent in a single `GetStreamResponse::Chunk`.
+pub const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The largest item `NetWriteExt::write_with` serializes into its reusable buffer.
+///
+/// Larger items are serialized into a buffer of their own so that one large value does not pin its memory for as
+/// long as the reusable buffer lives.
+pub const SCRATCH_BUFFER_LIMIT: usize = 128 * 1024;
+
 /// The `NetError` type.
 #[derive(Error, Debug)]
 pub enum NetError {
@@ -25,52 +42,403 @@ pub enum NetError {
 /// The `NetResult` type.
 pub type NetResult<T> = std::result::Result<T, NetError>;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Hello {
+        protocol_version: u32,
+    },
+    Get {
+        key: String,
+    },
+    /// Streams the value of a key in chunks, see `GetStreamResponse`.
+    ///
+    /// Only the sending is chunked: the server reads the whole value from storage and holds it in memory until its last
+    /// chunk is sent, so a value is still limited by the server's memory.
+    GetStream {
+        key: String,
+    },
+    GetMeta {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Put {
+        key: String,
+        value: String,
+    },
+    SetIfAbsent {
+        key: String,
+        value: String,
+    },
+    GetOrSet {
+        key: String,
+        default: String,
+    },
+    Remove {
+        key: String,
+    },
+    RemovePrefix {
+        prefix: String,
+    },
+    LPush {
+        key: String,
+        value: String,
+    },
+    RPush {
+        key: String,
+        value: String,
+    },
+    LPop {
+        key: String,
+    },
+    RPop {
+        key: String,
+    },
     List,
+    ListSizes,
+    /// Lists up to `limit` keys after `cursor`, from the first key if it is `None`.
+    ListPage {
+        cursor: Option<String>,
+        limit: u32,
+    },
+    /// Wraps a request with the id of the client span it was made in, so the server's span for the request can be
+    /// linked to it.
+    Traced {
+        parent_span: u64,
+        request: Box<Request>,
+    },
+    Touch {
+        key: String,
+    },
+    /// Streams every key and its value, see `SnapshotResponse`.
+    Snapshot,
+    /// Streams the writes made at or after `since`, in milliseconds since the unix epoch, see `ChangesResponse`.
+    Changes {
+        since: u64,
+    },
+    /// Compacts the storage, see `CompactResponse`.
+    Compact,
+    /// Describes the server, see `InfoResponse`.
+    Info,
+    /// Removes a key if it exists, see `RemoveOptionalResponse`.
+    RemoveOptional {
+        key: String,
+    },
+}
+
+impl Request {
+    /// The name of the operation the request performs, as recorded in the server's span for it.
+    pub fn op(&self) -> &'static str {
+        match self {
+            Request::Hello { .. } => "hello",
+            Request::Get { .. } => "get",
+            Request::GetStream { .. } => "get_stream",
+            Request::GetMeta { .. } => "get_meta",
+            Request::Set { .. } => "set",
+            Request::Put { .. } => "put",
+            Request::SetIfAbsent { .. } => "set_if_absent",
+            Request::GetOrSet { .. } => "get_or_set",
+            Request::Remove { .. } => "remove",
+            Request::RemoveOptional { .. } => "remove_optional",
+            Request::RemovePrefix { .. } => "remove_prefix",
+            Request::LPush { .. } => "lpush",
+            Request::RPush { .. } => "rpush",
+            Request::LPop { .. } => "lpop",
+            Request::RPop { .. } => "rpop",
+            Request::List => "list",
+            Request::ListSizes => "list_sizes",
+            Request::ListPage { .. } => "list_page",
+            Request::Traced { request, .. } => request.op(),
+            Request::Touch { .. } => "touch",
+            Request::Snapshot => "snapshot",
+            Request::Changes { .. } => "changes",
+            Request::Compact => "compact",
+            Request::Info => "info",
+        }
+    }
+
+    /// The key the request reads or writes, if it names one.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Request::Get { key }
+            | Request::GetStream { key }
+            | Request::GetMeta { key }
+            | Request::Set { key, .. }
+            | Request::Put { key, .. }
+            | Request::SetIfAbsent { key, .. }
+            | Request::GetOrSet { key, .. }
+            | Request::Remove { key }
+            | Request::RemoveOptional { key }
+            | Request::LPush { key, .. }
+            | Request::RPush { key, .. }
+            | Request::LPop { key }
+            | Request::RPop { key }
+            | Request::Touch { key } => Some(key),
+            Request::Traced { request, .. } => request.key(),
+            Request::Hello { .. }
+            | Request::RemovePrefix { .. }
+            | Request::List
+            | Request::ListSizes
+            | Request::ListPage { .. }
+            | Request::Snapshot
+            | Request::Changes { .. }
+            | Request::Compact
+            | Request::Info => None,
+        }
+    }
+
+    /// The value the request writes, if it carries one.
+    pub fn value(&self) -> Option<&str> {
+        match self {
+            Request::Set { value, .. }
+            | Request::Put { value, .. }
+            | Request::SetIfAbsent { value, .. }
+            | Request::GetOrSet { default: value, .. }
+            | Request::LPush { value, .. }
+            | Request::RPush { value, .. } => Some(value),
+            Request::Traced { request, .. } => request.value(),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Accepts the handshake with the agreed protocol version and the largest value in bytes the server accepts, which
+/// is `None` if values of any size are accepted.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum HelloResponse {
+    Ok {
+        protocol_version: u32,
+        max_value_size: Option<usize>,
+    },
+    Err(String),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum GetResponse {
     Ok(Option<String>),
     Err(String),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A value is streamed as any number of `Chunk`s followed by `End`.
+/// `NotFound` and `Err` are sent in place of the chunks and are not followed by `End`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum GetStreamResponse {
+    Chunk(Vec<u8>),
+    End,
+    NotFound,
+    Err(String),
+}
+
+/// The timestamp is when the value was last written, in milliseconds since the unix epoch.
+/// It is `None` if the key does not exist or the server has no record of when it was written.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum GetMetaResponse {
+    Ok {
+        value: Option<String>,
+        timestamp: Option<u64>,
+    },
+    Err(String),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum SetResponse {
     Ok(()),
     Err(String),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum PutResponse {
+    Created,
+    Updated,
+    Err(String),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum SetIfAbsentResponse {
+    Ok(bool),
+    Err(String),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum GetOrSetResponse {
+    Ok(String),
+    Err(String),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum RemoveResponse {
     Ok(()),
     Err(String),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum TouchResponse {
+    Ok(()),
+    Err(String),
+}
+
+/// A snapshot is streamed as any number of `Entries`, each a batch of key/value pairs in key order, followed by `End`.
+/// The server reads the pairs a page at a time as it sends them, so `Err` may follow some of the batches in place of
+/// the rest, and is not followed by `End`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum SnapshotResponse {
+    Entries(Vec<(String, String)>),
+    End,
+    Err(String),
+}
+
+/// Changes are streamed as any number of `Changes`, each a batch of keys in the order they were written along with the
+/// value they were set to or `None` if they were removed, followed by `End`. As with a snapshot, `Err` may follow some
+/// of the batches in place of the rest, and is not followed by `End`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum ChangesResponse {
+    Changes(Vec<(String, Option<String>)>),
+    End,
+    Err(String),
+}
+
+/// Only one compaction requested over the network runs at a time, a request made while one is running is answered with
+/// `AlreadyRunning` rather than waiting for it.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum CompactResponse {
+    Ok(()),
+    AlreadyRunning,
+    Err(String),
+}
+
+/// Describes the server with the version of smoldb it runs, its storage engine, how many seconds it has been running
+/// and how many keys it holds.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum InfoResponse {
+    Ok {
+        version: String,
+        engine: String,
+        uptime_secs: u64,
+        keys: usize,
+    },
+    Err(String),
+}
+
+/// Reports whether the key existed, a missing key is not an error.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum RemoveOptionalResponse {
+    Ok(bool),
+    Err(String),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum RemovePrefixResponse {
+    Ok(usize),
+    Err(String),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum PushResponse {
+    Ok(usize),
+    Err(String),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum PopResponse {
+    Ok(Option<String>),
+    Err(String),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum ListResponse {
     Ok(Vec<String>),
     Err(String),
 }
 
-/// Helper trait for reading our defined request/response types from a tcp stream.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum ListSizesResponse {
+    Ok(Vec<(String, u32)>),
+    Err(String),
+}
+
+/// `next_cursor` is `None` once the last page has been listed.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum ListPageResponse {
+    Ok {
+        keys: Vec<String>,
+        next_cursor: Option<String>,
+    },
+    Err(String),
+}
+
+/// Every response the server sends once the handshake is complete.
+///
+/// Each request is answered by the variant of the same name, apart from `Push`/`Pop` which answer both ends of a queue.
+/// `RateLimited`, `KeyTooLong` and `ValueTooLarge` may answer any request that was rejected without being served. `ProtocolError`
+/// answers a frame that could not be decoded as a request, after which the server closes the connection. `Corruption`
+/// answers any request whose storage operation found corrupted data, in place of the request's own error, so that it can
+/// be told apart from other errors.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum Response {
+    Hello(HelloResponse),
+    Get(GetResponse),
+    GetStream(GetStreamResponse),
+    GetMeta(GetMetaResponse),
+    Set(SetResponse),
+    Put(PutResponse),
+    SetIfAbsent(SetIfAbsentResponse),
+    GetOrSet(GetOrSetResponse),
+    Remove(RemoveResponse),
+    RemovePrefix(RemovePrefixResponse),
+    Push(PushResponse),
+    Pop(PopResponse),
+    List(ListResponse),
+    ListSizes(ListSizesResponse),
+    ListPage(ListPageResponse),
+    RateLimited,
+    KeyTooLong { max_key_len: usize },
+    ValueTooLarge { max_value_size: usize },
+    ProtocolError(String),
+    Corruption(String),
+    Touch(TouchResponse),
+    Snapshot(SnapshotResponse),
+    Changes(ChangesResponse),
+    Compact(CompactResponse),
+    Info(InfoResponse),
+    RemoveOptional(RemoveOptionalResponse),
+}
+
+/// Reads length delimited frames from a stream.
+///
+/// Kept for the lifetime of a connection so bytes buffered past the end of one frame are not lost before the next read.
+pub type FrameReader<R> = FramedRead<R, LengthDelimitedCodec>;
+
+/// Writes length delimited frames to a stream.
+pub type FrameWriter<W> = FramedWrite<W, LengthDelimitedCodec>;
+
+/// Wraps the read side of a stream in a `FrameReader`.
+pub fn frame_reader<R: AsyncRead>(reader: R) -> FrameReader<R> {
+    FramedRead::new(reader, LengthDelimitedCodec::new())
+}
+
+/// Wraps the write side of a stream in a `FrameWriter`.
+pub fn frame_writer<W: AsyncWrite>(writer: W) -> FrameWriter<W> {
+    FramedWrite::new(writer, LengthDelimitedCodec::new())
+}
+
+/// Helper trait for reading our defined request/response types from a stream.
 pub trait NetReadExt {
     async fn read<E: DeserializeOwned>(&mut self) -> NetResult<Option<E>>;
 }
 
-/// Helper trait for writing our defined request/response types to a tcp stream.
+/// Helper trait for writing our defined request/response types to a stream.
 pub trait NetWriteExt {
     async fn write<E: Serialize>(&mut self, request: E) -> NetResult<()>;
+
+    /// Like `write`, but serializes into `buf` so its allocation is reused from one item to the next.
+    async fn write_with<E: Serialize>(&mut self, request: E, buf: &mut BytesMut) -> NetResult<()>;
 }
 
-impl NetReadExt for OwnedReadHalf {
+impl<R: AsyncRead + Unpin> NetReadExt for FrameReader<R> {
     async fn read<E: DeserializeOwned>(&mut self) -> NetResult<Option<E>> {
-        let mut reader = FramedRead::new(self, LengthDelimitedCodec::new());
-        if let Some(ser) = reader.next().await {
+        if let Some(ser) = self.next().await {
             Ok(Some(bincode::deserialize(&ser?)?))
         } else {
             Ok(None)
@@ -78,12 +446,232 @@ impl NetReadExt for OwnedReadHalf {
     }
 }
 
-impl NetWriteExt for OwnedWriteHalf {
+impl<W: AsyncWrite + Unpin> NetWriteExt for FrameWriter<W> {
     async fn write<E: Serialize>(&mut self, request: E) -> NetResult<()> {
-        let mut writer = FramedWrite::new(self, LengthDelimitedCodec::new());
         let ser = bincode::serialize(&request)?;
-        writer.send(ser.into()).await?;
-        writer.flush().await?;
+        self.send(ser.into()).await?;
+        Ok(())
+    }
+
+    async fn write_with<E: Serialize>(&mut self, request: E, buf: &mut BytesMut) -> NetResult<()> {
+        let size = bincode::serialized_size(&request)? as usize;
+        if size > SCRATCH_BUFFER_LIMIT {
+            return self.write(request).await;
+        }
+        // The frame is split off the buffer and copied into the writer's own buffer when sent, after which the
+        // buffer can reclaim the allocation. Reserving the exact size up front lets it do so rather than growing.
+        buf.clear();
+        buf.reserve(size);
+        bincode::serialize_into(buf.writer(), &request)?;
+        self.send(buf.split().freeze()).await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{self, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_duplex_round_trip() {
+        let (client, server) = io::duplex(64);
+        let (client_reader, client_writer) = io::split(client);
+        let (mut client_reader, mut client_writer) =
+            (frame_reader(client_reader), frame_writer(client_writer));
+        let (server_reader, server_writer) = io::split(server);
+        let (mut server_reader, mut server_writer) =
+            (frame_reader(server_reader), frame_writer(server_writer));
+
+        let request = Request::Set {
+            key: "key".to_string(),
+            value: "value".repeat(100),
+        };
+        let (written, read) = tokio::join!(
+            client_writer.write(request),
+            server_reader.read::<Request>()
+        );
+        written.unwrap();
+        match read.unwrap() {
+            Some(Request::Set { key, value }) => {
+                assert_eq!(key, "key");
+                assert_eq!(value, "value".repeat(100));
+            }
+            r => panic!("unexpected request: {:?}", r),
+        }
+
+        server_writer.write(SetResponse::Ok(())).await.unwrap();
+        let response = client_reader.read::<SetResponse>().await.unwrap();
+        assert_eq!(response, Some(SetResponse::Ok(())));
+
+        drop((client_reader, client_writer));
+        assert!(server_reader.read::<Request>().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_split_frames() {
+        let (client, server) = io::duplex(1024);
+        let (_, mut client_writer) = io::split(client);
+        let mut server_reader = frame_reader(server);
+
+        // Encode several requests back to back and deliver them in pieces that straddle the frame boundaries.
+        let requests = (0..3)
+            .map(|i| Request::Get {
+                key: format!("key{}", i),
+            })
+            .collect::<Vec<_>>();
+        let mut bytes = Vec::new();
+        for request in &requests {
+            let ser = bincode::serialize(request).unwrap();
+            bytes.extend_from_slice(&(ser.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&ser);
+        }
+        tokio::spawn(async move {
+            for piece in bytes.chunks(7) {
+                client_writer.write_all(piece).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+
+        for i in 0..3 {
+            match server_reader.read::<Request>().await.unwrap() {
+                Some(Request::Get { key }) => assert_eq!(key, format!("key{}", i)),
+                r => panic!("unexpected request: {:?}", r),
+            }
+        }
+        assert!(server_reader.read::<Request>().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_write_with_reuses_buffer() {
+        let (client, server) = io::duplex(1024);
+        let mut writer = frame_writer(server);
+        let mut reader = frame_reader(client);
+
+        let mut buf = BytesMut::with_capacity(64);
+        let ptr = buf.as_ptr();
+        for i in 0..10 {
+            let response = Response::Get(GetResponse::Ok(Some(format!("value{}", i))));
+            let (written, read) = tokio::join!(
+                writer.write_with(&response, &mut buf),
+                reader.read::<Response>()
+            );
+            written.unwrap();
+            assert_eq!(read.unwrap(), Some(response));
+        }
+        buf.reserve(64);
+        assert_eq!(buf.as_ptr(), ptr);
+
+        // Items over the limit are still written, without growing the buffer
+        let response = Response::Get(GetResponse::Ok(Some("v".repeat(SCRATCH_BUFFER_LIMIT))));
+        let (client, server) = io::duplex(1024);
+        let mut writer = frame_writer(server);
+        let mut reader = frame_reader(client);
+        let (written, read) = tokio::join!(
+            writer.write_with(&response, &mut buf),
+            reader.read::<Response>()
+        );
+        written.unwrap();
+        assert_eq!(read.unwrap(), Some(response));
+        assert_eq!(buf.capacity(), 64);
+    }
+
+    // Peers of the same protocol version decode each other's messages by variant index, which must not change
+    // without bumping `PROTOCOL_VERSION`.
+    #[test]
+    fn test_variant_indices() {
+        let index = |bytes: Vec<u8>| u32::from_le_bytes(bytes[..4].try_into().unwrap());
+        let request = |request: Request| index(bincode::serialize(&request).unwrap());
+        let response = |response: Response| index(bincode::serialize(&response).unwrap());
+        let key = || "key".to_string();
+
+        assert_eq!(
+            request(Request::Hello {
+                protocol_version: PROTOCOL_VERSION
+            }),
+            0
+        );
+        assert_eq!(request(Request::Remove { key: key() }), 8);
+        assert_eq!(request(Request::RemovePrefix { prefix: key() }), 9);
+        assert_eq!(request(Request::List), 14);
+        assert_eq!(request(Request::Info), 22);
+        assert_eq!(request(Request::RemoveOptional { key: key() }), 23);
+
+        assert_eq!(response(Response::Remove(RemoveResponse::Ok(()))), 8);
+        assert_eq!(
+            response(Response::RemovePrefix(RemovePrefixResponse::Ok(1))),
+            9
+        );
+        assert_eq!(response(Response::List(ListResponse::Ok(vec![]))), 12);
+        assert_eq!(response(Response::Corruption(key())), 19);
+        assert_eq!(response(Response::Info(InfoResponse::Err(key()))), 24);
+        assert_eq!(
+            response(Response::RemoveOptional(RemoveOptionalResponse::Ok(true))),
+            25
+        );
+    }
+
+    #[tokio::test]
+    async fn test_response_round_trip() {
+        let responses = vec![
+            Response::Hello(HelloResponse::Ok {
+                protocol_version: PROTOCOL_VERSION,
+                max_value_size: Some(1024),
+            }),
+            Response::Get(GetResponse::Ok(Some("value".to_string()))),
+            Response::GetStream(GetStreamResponse::Chunk(b"chunk".to_vec())),
+            Response::GetMeta(GetMetaResponse::Ok {
+                value: Some("value".to_string()),
+                timestamp: Some(1),
+            }),
+            Response::Set(SetResponse::Ok(())),
+            Response::Put(PutResponse::Created),
+            Response::SetIfAbsent(SetIfAbsentResponse::Ok(true)),
+            Response::GetOrSet(GetOrSetResponse::Ok("value".to_string())),
+            Response::Remove(RemoveResponse::Err("Key not found".to_string())),
+            Response::RemovePrefix(RemovePrefixResponse::Ok(3)),
+            Response::Push(PushResponse::Ok(2)),
+            Response::Pop(PopResponse::Ok(None)),
+            Response::List(ListResponse::Ok(vec!["key".to_string()])),
+            Response::ListSizes(ListSizesResponse::Ok(vec![("key".to_string(), 5)])),
+            Response::ListPage(ListPageResponse::Ok {
+                keys: vec!["key".to_string()],
+                next_cursor: Some("key".to_string()),
+            }),
+            Response::RateLimited,
+            Response::KeyTooLong { max_key_len: 16 },
+            Response::ValueTooLarge {
+                max_value_size: 1024,
+            },
+            Response::ProtocolError("invalid request".to_string()),
+            Response::Corruption("checksum mismatch".to_string()),
+            Response::Touch(TouchResponse::Ok(())),
+            Response::Snapshot(SnapshotResponse::Entries(vec![(
+                "key".to_string(),
+                "value".to_string(),
+            )])),
+            Response::Changes(ChangesResponse::Changes(vec![
+                ("key".to_string(), Some("value".to_string())),
+                ("key".to_string(), None),
+            ])),
+            Response::Compact(CompactResponse::AlreadyRunning),
+            Response::Info(InfoResponse::Ok {
+                version: "0.1.0".to_string(),
+                engine: "Bitcask".to_string(),
+                uptime_secs: 60,
+                keys: 3,
+            }),
+            Response::RemoveOptional(RemoveOptionalResponse::Ok(false)),
+        ];
+
+        let (client, server) = io::duplex(1024);
+        let mut writer = frame_writer(server);
+        let mut reader = frame_reader(client);
+        for response in responses {
+            let (written, read) = tokio::join!(writer.write(&response), reader.read::<Response>());
+            written.unwrap();
+            assert_eq!(read.unwrap(), Some(response));
+        }
+    }
+}
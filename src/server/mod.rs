@@ -1,4 +1,18 @@
+#[allow(clippy::module_inception)]
 mod server;
 mod storage;
+mod thread_pool;
 
-pub use server::{run, ServerError, ServerResult, StorageType};
+pub use server::{
+    run, run_with_config, run_with_options, ServerConfig, ServerError, ServerOptions, ServerResult,
+    ShutdownReason, StorageType,
+};
+pub use storage::{
+    AsyncBitcask, Bitcask, BitcaskOptions, BitcaskStats, Changes, CompactionPlan, CompactionStats,
+    FileSystem, ListPage, LogRecord, Memory, MemoryStats, PutOutcome, SegmentInfo, Sled,
+    SledOptions, SledStats, StdFileSystem, Storage, StorageError, StorageResult, ValueMetadata,
+};
+pub use thread_pool::{
+    NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool, ThreadPoolError,
+    ThreadPoolResult, ThreadPoolType,
+};
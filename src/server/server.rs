@@ -1,21 +1,45 @@
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
 use std::io;
 use std::net::SocketAddr;
+use std::num::{NonZeroU32, NonZeroUsize};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
 
+use bytes::BytesMut;
 use thiserror::Error;
 use tokio::{
-    net::{TcpListener, TcpStream},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
     select,
-    sync::oneshot,
+    sync::{mpsc, oneshot, Semaphore},
+    time::{self, Instant},
 };
-use tracing::{debug, error};
+use tracing::{debug, error, info, info_span, Instrument, Span};
 
 use crate::net::{
-    GetResponse, ListResponse, NetError, NetReadExt, NetWriteExt, RemoveResponse, Request,
-    SetResponse,
+    frame_reader, frame_writer, ChangesResponse, CompactResponse, FrameReader, FrameWriter,
+    GetMetaResponse, GetOrSetResponse, GetResponse, GetStreamResponse, HelloResponse, InfoResponse,
+    ListPageResponse, ListResponse, ListSizesResponse, NetError, NetReadExt, NetWriteExt,
+    PopResponse, PushResponse, PutResponse, RemoveOptionalResponse, RemovePrefixResponse,
+    RemoveResponse, Request, Response, SetIfAbsentResponse, SetResponse, SnapshotResponse,
+    TouchResponse, PROTOCOL_VERSION, STREAM_CHUNK_SIZE,
 };
 
-use super::storage::{Bitcask, Sled, Storage, StorageError};
+use super::storage::{
+    Bitcask, BitcaskOptions, Memory, PutOutcome, Sled, SledOptions, Storage, StorageError,
+    StorageResult,
+};
+use super::thread_pool::{
+    NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool, ThreadPoolError,
+    ThreadPoolType,
+};
 
 /// The `ServerError` type for `Server`.
 #[derive(Error, Debug)]
@@ -31,6 +55,10 @@ pub enum ServerError {
     /// A codec error.
     #[error("Codec error: {0}")]
     CodecError(#[from] NetError),
+
+    /// A thread pool error.
+    #[error("Thread pool error: {0}")]
+    ThreadPool(#[from] ThreadPoolError),
 }
 
 /// The `ServerResult` type for `Server`.
@@ -43,117 +71,1621 @@ pub enum StorageType {
     Bitcask,
     /// Sled storage.
     Sled,
+    /// Memory storage, which keeps nothing on disk and ignores the data directory.
+    Memory,
+}
+
+/// Why a running server stopped, as returned by `run`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ShutdownReason {
+    /// The stop signal was sent.
+    Signaled,
+    /// The sender of the stop signal was dropped without sending, which also stops the server.
+    SignalDropped,
+}
+
+/// Options for tuning the smoldb server.
+#[derive(Debug, Clone, Default)]
+pub struct ServerOptions {
+    /// The number of requests per second each connection may make.
+    ///
+    /// Requests over the limit are answered with `Response::RateLimited` rather than queued.
+    /// A connection may burst up to the limit after being idle. `None` disables rate limiting.
+    pub rate_limit: Option<NonZeroU32>,
+
+    /// The longest key in bytes a request may name.
+    ///
+    /// Requests with longer keys are answered with `Response::KeyTooLong` before they reach storage.
+    /// `None` accepts keys of any length.
+    pub max_key_len: Option<usize>,
+
+    /// The largest value in bytes a request may write.
+    ///
+    /// The limit is advertised in the handshake so that clients can reject larger values without sending them.
+    /// Requests with larger values are answered with `Response::ValueTooLarge` before they reach storage.
+    /// `None` accepts values of any size.
+    pub max_value_size: Option<usize>,
+
+    /// The thread pool that requests are run against storage on.
+    ///
+    /// `None` runs them on the connection's task, which blocks the tokio worker thread running it for as long as the
+    /// storage operation takes.
+    pub thread_pool: Option<ThreadPoolType>,
+
+    /// The number of threads in the thread pool, ignored without one.
+    ///
+    /// `None` uses the available parallelism of the machine.
+    pub thread_pool_size: Option<NonZeroU32>,
+
+    /// The number of requests of each connection that may be run on the thread pool at once, ignored without one.
+    ///
+    /// Further requests pipelined by the connection are not read until one of them finishes, so that a single
+    /// connection cannot take over the pool. Responses are still written in the order the requests were sent, but the
+    /// requests that run at once may do so in any order, so a request pipelined right after a write may not see it.
+    /// `None` runs a connection's requests one at a time.
+    pub max_inflight_per_conn: Option<NonZeroUsize>,
+}
+
+/// Everything that configures a smoldb server, as run by `run_with_config`.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// The address to listen on.
+    pub addr: SocketAddr,
+
+    /// The directory the storage keeps its data in.
+    pub dir: PathBuf,
+
+    /// The storage engine to keep the data with.
+    pub storage_type: StorageType,
+
+    /// Options for tuning the server.
+    pub options: ServerOptions,
+
+    /// Options for tuning the bitcask storage engine, ignored by the others.
+    pub bitcask_options: BitcaskOptions,
+
+    /// Options for tuning the sled storage engine, ignored by the others.
+    pub sled_options: SledOptions,
+}
+
+impl ServerConfig {
+    /// Creates a config for a server at the given address keeping its data in the given directory, with bitcask
+    /// storage and the default options.
+    pub fn new(addr: SocketAddr, dir: PathBuf) -> Self {
+        ServerConfig {
+            addr,
+            dir,
+            storage_type: StorageType::Bitcask,
+            options: ServerOptions::default(),
+            bitcask_options: BitcaskOptions::default(),
+            sled_options: SledOptions::default(),
+        }
+    }
 }
 
 /// Runs the smoldb server at the given address with the given stop signal.
+///
+/// Returns why the server stopped once it has.
 pub async fn run(
     addr: SocketAddr,
     dir: PathBuf,
     storage_type: StorageType,
     rx: oneshot::Receiver<()>,
-) -> ServerResult<()> {
+) -> ServerResult<ShutdownReason> {
+    run_with_options(addr, dir, storage_type, ServerOptions::default(), rx).await
+}
+
+/// Runs the smoldb server at the given address with the given options and stop signal.
+///
+/// Returns why the server stopped once it has.
+pub async fn run_with_options(
+    addr: SocketAddr,
+    dir: PathBuf,
+    storage_type: StorageType,
+    options: ServerOptions,
+    rx: oneshot::Receiver<()>,
+) -> ServerResult<ShutdownReason> {
+    let config = ServerConfig {
+        storage_type,
+        options,
+        ..ServerConfig::new(addr, dir)
+    };
+    run_with_config(config, rx).await
+}
+
+/// Runs the smoldb server with the given config and stop signal.
+///
+/// Returns why the server stopped once it has.
+pub async fn run_with_config(
+    config: ServerConfig,
+    rx: oneshot::Receiver<()>,
+) -> ServerResult<ShutdownReason> {
+    let ServerConfig {
+        addr,
+        dir,
+        storage_type,
+        options,
+        bitcask_options,
+        sled_options,
+    } = config;
     let listener = TcpListener::bind(addr).await?;
     match storage_type {
-        StorageType::Bitcask => listen(listener, Bitcask::open(&dir)?, rx).await,
-        StorageType::Sled => listen(listener, Sled::open(&dir)?, rx).await,
+        StorageType::Bitcask => {
+            let storage = Bitcask::open_with_options(&dir, bitcask_options)?;
+            listen_on_pool(listener, storage, storage_type, options, rx).await
+        }
+        StorageType::Sled => {
+            let storage = Sled::open_with_options(&dir, sled_options)?;
+            listen_on_pool(listener, storage, storage_type, options, rx).await
+        }
+        StorageType::Memory => {
+            listen_on_pool(listener, Memory::new(), storage_type, options, rx).await
+        }
     }
 }
 
-async fn listen<S: Storage>(
+async fn listen_on_pool<S: Storage>(
     listener: TcpListener,
     storage: S,
+    storage_type: StorageType,
+    options: ServerOptions,
     rx: oneshot::Receiver<()>,
-) -> ServerResult<()> {
-    select! {
-        _ = async {
+) -> ServerResult<ShutdownReason> {
+    let threads = match options.thread_pool_size {
+        Some(threads) => threads.get(),
+        None => thread::available_parallelism().map_or(1, |threads| threads.get() as u32),
+    };
+    match options.thread_pool {
+        // The pool type is irrelevant when there is no pool.
+        None => {
+            listen::<S, NaiveThreadPool>(listener, storage, storage_type, None, options, rx).await
+        }
+        Some(ThreadPoolType::Naive) => {
+            let pool = NaiveThreadPool::new(threads)?;
+            listen(listener, storage, storage_type, Some(pool), options, rx).await
+        }
+        Some(ThreadPoolType::SharedQueue) => {
+            let pool = SharedQueueThreadPool::new(threads)?;
+            listen(listener, storage, storage_type, Some(pool), options, rx).await
+        }
+        Some(ThreadPoolType::Rayon) => {
+            let pool = RayonThreadPool::new(threads)?;
+            listen(listener, storage, storage_type, Some(pool), options, rx).await
+        }
+    }
+}
+
+async fn listen<S: Storage, P: ThreadPool>(
+    listener: TcpListener,
+    storage: S,
+    storage_type: StorageType,
+    pool: Option<P>,
+    options: ServerOptions,
+    rx: oneshot::Receiver<()>,
+) -> ServerResult<ShutdownReason> {
+    let pool = pool.map(Arc::new);
+    let state = Arc::new(ServerState::new(storage_type));
+    // The accept loop only ever ends with the stop signal, accept errors are logged and the loop carries on
+    // once it has backed off.
+    let accepted = storage.clone();
+    let reason = select! {
+        _ = async move {
+            let storage = accepted;
+            let mut backoff = AcceptBackoff::new();
             loop {
-                let (stream, _) = match listener.accept().await {
-                    Ok(s) => s,
-                    Err(e) => {
-                        error!("error accepting connection: {}", e);
-                        continue;
-                    }
-                };
+                let (stream, _) = accept(|| listener.accept(), &mut backoff).await;
                 let storage = storage.clone();
+                let pool = pool.clone();
+                let state = state.clone();
+                let options = options.clone();
                 tokio::spawn(async move {
                     let addr = stream.peer_addr().unwrap();
-                    match serve(storage, stream).await {
+                    match serve(storage, pool, state, stream, options).await {
                         Ok(_) => debug!("{}: connection closed", addr),
                         Err(e) => error!("{}: error serving connection: {}", addr, e),
                     }
 
                 });
             }
-        } => {},
-        _ = rx => {},
+        } => unreachable!("the accept loop never ends"),
+        signal = rx => match signal {
+            Ok(()) => ShutdownReason::Signaled,
+            Err(_) => ShutdownReason::SignalDropped,
+        },
     };
-    Ok(())
+
+    // Connections still being served hold their own clones of the storage, so it is flushed explicitly rather than
+    // relying on the last clone being dropped before the process exits.
+    storage.flush()?;
+    info!("storage flushed");
+    Ok(reason)
 }
 
-async fn serve<S: Storage>(storage: S, stream: TcpStream) -> ServerResult<()> {
+async fn serve<S: Storage, P: ThreadPool>(
+    storage: S,
+    pool: Option<Arc<P>>,
+    state: Arc<ServerState>,
+    stream: TcpStream,
+    options: ServerOptions,
+) -> ServerResult<()> {
     let peer_addr = stream.peer_addr()?;
-    let (mut reader, mut writer) = stream.into_split();
+    let (reader, writer) = stream.into_split();
+    let (mut reader, mut writer) = (frame_reader(reader), frame_writer(writer));
     debug!("{}: connection established", peer_addr);
-    loop {
-        let request = if let Some(r) = reader.read::<Request>().await? {
-            r
-        } else {
+
+    // Every connection starts with a handshake agreeing on the protocol version.
+    let protocol_version = match reader.read::<Request>().await {
+        Ok(Some(Request::Hello { protocol_version })) => protocol_version,
+        Ok(Some(_)) => {
+            let response = HelloResponse::Err("expected a handshake".to_string());
+            writer.write(response).await?;
             return Ok(());
+        }
+        Ok(None) => return Ok(()),
+        Err(NetError::Bincode(e)) => {
+            let response = HelloResponse::Err(format!("invalid handshake: {}", e));
+            writer.write(response).await?;
+            return Err(NetError::Bincode(e).into());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let response = handshake(protocol_version, &options);
+    let accepted = matches!(response, HelloResponse::Ok { .. });
+    writer.write(response).await?;
+    if !accepted {
+        debug!(
+            "{}: refused unsupported protocol version {}",
+            peer_addr, protocol_version
+        );
+        return Ok(());
+    }
+    debug!("{}: protocol version {}", peer_addr, protocol_version);
+
+    // Requests are read and run here while their replies are written, in the order the requests came in, by
+    // `write_replies`, so that pipelined requests can be run at the same time. Without a thread pool they are run on
+    // this task and so one at a time regardless.
+    let max_inflight = match pool {
+        Some(_) => options.max_inflight_per_conn.map_or(1, NonZeroUsize::get),
+        None => 1,
+    };
+    let (replies, pending) = mpsc::channel(max_inflight);
+    let connection = Connection {
+        storage,
+        idle: Arc::new(Mutex::new(Vec::new())),
+        inflight: Arc::new(Semaphore::new(max_inflight)),
+        pool: pool.clone(),
+        state,
+        peer_addr,
+    };
+    let (read, written) = tokio::join!(
+        read_requests(connection, &mut reader, replies, &options),
+        write_replies(&mut writer, pending, pool.as_ref()),
+    );
+    read.and(written)
+}
+
+// The state shared by the requests of a connection.
+struct Connection<S, P> {
+    // The connection's storage, which requests are run on directly without a thread pool.
+    storage: S,
+    // Clones of the storage not in use by a request on the thread pool, each moved to the pool for a request and
+    // handed back after it. They keep the engine's per-clone state such as open file handles from one request to the
+    // next without the engine having to be `Sync`, and there are never more of them than requests run at once.
+    idle: Arc<Mutex<Vec<S>>>,
+    // Limits how many of the connection's requests are run on the thread pool at once.
+    inflight: Arc<Semaphore>,
+    pool: Option<Arc<P>>,
+    state: Arc<ServerState>,
+    peer_addr: SocketAddr,
+}
+
+// The state shared by every connection of a server.
+struct ServerState {
+    storage_type: StorageType,
+    started: Instant,
+    // Whether a compaction requested by any connection is running.
+    compacting: AtomicBool,
+}
+
+impl ServerState {
+    fn new(storage_type: StorageType) -> Self {
+        ServerState {
+            storage_type,
+            started: Instant::now(),
+            compacting: AtomicBool::new(false),
+        }
+    }
+}
+
+// Marks a compaction as running until it is dropped.
+struct CompactionGuard(Arc<ServerState>);
+
+impl CompactionGuard {
+    // Marks a compaction as running, or returns `None` if one already is.
+    fn try_acquire(state: &Arc<ServerState>) -> Option<Self> {
+        state
+            .compacting
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .ok()
+            .map(|_| CompactionGuard(Arc::clone(state)))
+    }
+}
+
+impl Drop for CompactionGuard {
+    fn drop(&mut self) {
+        self.0.compacting.store(false, Ordering::Release);
+    }
+}
+
+// A reply that is being worked out, along with the span of its request.
+type PendingReply = (oneshot::Receiver<Reply>, Span);
+
+// Reads the requests of a connection and runs them, sending their pending replies to be written in order.
+async fn read_requests<S: Storage, P: ThreadPool>(
+    mut connection: Connection<S, P>,
+    reader: &mut FrameReader<OwnedReadHalf>,
+    replies: mpsc::Sender<PendingReply>,
+    options: &ServerOptions,
+) -> ServerResult<()> {
+    let peer_addr = connection.peer_addr;
+    let mut rate_limiter = options.rate_limit.map(RateLimiter::new);
+
+    loop {
+        let request = select! {
+            request = reader.read::<Request>() => request,
+            // Writing a reply failed, so there is no point reading any more requests.
+            _ = replies.closed() => return Ok(()),
         };
-        match request {
-            Request::Get { key } => {
-                debug!("{}: get {}", peer_addr, &key);
-                let response = match storage.get(key) {
-                    Ok(value) => GetResponse::Ok(value),
-                    Err(e) => GetResponse::Err(format!("test: {}", e.to_string())),
-                };
-                writer.write(response).await?;
+        let mut request = match request {
+            Ok(Some(r)) => r,
+            Ok(None) => return Ok(()),
+            // A frame that is not a request is answered before the connection is closed, so the client is told why
+            // rather than just seeing the connection drop.
+            Err(NetError::Bincode(e)) => {
+                let response = Response::ProtocolError(format!("invalid request: {}", e));
+                let _ = replies
+                    .send((ready(Reply::Response(response)), Span::current()))
+                    .await;
+                return Err(NetError::Bincode(e).into());
             }
-            Request::Set { key, value } => {
-                debug!("{}: set {} {}", peer_addr, &key, &value);
-                let response = match storage.set(key, value) {
-                    Ok(()) => SetResponse::Ok(()),
-                    Err(e) => SetResponse::Err(e.to_string()),
-                };
-                writer.write(response).await?;
+            Err(e) => return Err(e.into()),
+        };
+        let mut parent_span = None;
+        while let Request::Traced {
+            parent_span: parent,
+            request: traced,
+        } = request
+        {
+            parent_span = Some(parent);
+            request = *traced;
+        }
+        let span = info_span!(
+            "request",
+            op = request.op(),
+            key = request.key(),
+            peer = %peer_addr,
+            parent_span,
+        );
+        let reply = respond(&mut connection, request, options, rate_limiter.as_mut())
+            .instrument(span.clone())
+            .await;
+        if replies.send((reply, span)).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+// Starts working out the reply to a single request.
+//
+// On the thread pool this waits until the connection has fewer than its limit of requests running, so a connection
+// that pipelines many requests cannot take over the pool.
+async fn respond<S: Storage, P: ThreadPool>(
+    connection: &mut Connection<S, P>,
+    request: Request,
+    options: &ServerOptions,
+    rate_limiter: Option<&mut RateLimiter>,
+) -> oneshot::Receiver<Reply> {
+    let peer_addr = connection.peer_addr;
+    if let Some(rate_limiter) = rate_limiter {
+        if !rate_limiter.try_acquire() {
+            debug!("{}: rate limited", peer_addr);
+            return ready(Reply::Response(Response::RateLimited));
+        }
+    }
+    if let Some(max_key_len) = options.max_key_len {
+        if request.key().is_some_and(|key| key.len() > max_key_len) {
+            debug!("{}: key longer than {} bytes", peer_addr, max_key_len);
+            return ready(Reply::Response(Response::KeyTooLong { max_key_len }));
+        }
+    }
+    if let Some(max_value_size) = options.max_value_size {
+        if request
+            .value()
+            .is_some_and(|value| value.len() > max_value_size)
+        {
+            debug!("{}: value larger than {} bytes", peer_addr, max_value_size);
+            return ready(Reply::Response(Response::ValueTooLarge { max_value_size }));
+        }
+    }
+
+    // The guard is held until the compaction has run, on the thread pool if there is one.
+    let compaction = match request {
+        Request::Compact => match CompactionGuard::try_acquire(&connection.state) {
+            Some(guard) => Some(guard),
+            None => {
+                debug!("{}: compaction already running", peer_addr);
+                let response = Response::Compact(CompactResponse::AlreadyRunning);
+                return ready(Reply::Response(response));
             }
-            Request::Remove { key } => {
-                debug!("{}: remove {}", peer_addr, &key);
-                let response = match storage.remove(key) {
-                    Ok(()) => RemoveResponse::Ok(()),
-                    Err(e) => RemoveResponse::Err(e.to_string()),
-                };
-                writer.write(response).await?;
+        },
+        _ => None,
+    };
+
+    let Some(pool) = &connection.pool else {
+        let reply = handle(&connection.storage, &connection.state, request, peer_addr);
+        drop(compaction);
+        return ready(reply);
+    };
+    let permit = Arc::clone(&connection.inflight)
+        .acquire_owned()
+        .await
+        .expect("the semaphore is never closed");
+    let idle = Arc::clone(&connection.idle);
+    let storage = idle
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .pop()
+        .unwrap_or_else(|| connection.storage.clone());
+    let state = Arc::clone(&connection.state);
+    let (tx, rx) = oneshot::channel();
+    let span = Span::current();
+    pool.spawn(move || {
+        let reply = span.in_scope(|| handle(&storage, &state, request, peer_addr));
+        drop(compaction);
+        idle.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(storage);
+        drop(permit);
+        let _ = tx.send(reply);
+    });
+    rx
+}
+
+// A reply that is already worked out.
+fn ready(reply: Reply) -> oneshot::Receiver<Reply> {
+    let (tx, rx) = oneshot::channel();
+    let _ = tx.send(reply);
+    rx
+}
+
+// Writes the replies of a connection in the order its requests came in, each once it is worked out.
+//
+// The pages of a snapshot or list of changes are read as they are written, on the thread pool if there is one.
+async fn write_replies<P: ThreadPool>(
+    writer: &mut FrameWriter<OwnedWriteHalf>,
+    mut replies: mpsc::Receiver<PendingReply>,
+    pool: Option<&Arc<P>>,
+) -> ServerResult<()> {
+    // Requests are decoded from the frame reader's buffer, which is already reused from one frame to the next.
+    // Responses are encoded into this buffer so they do not allocate one each either.
+    let mut buf = BytesMut::new();
+    while let Some((reply, span)) = replies.recv().await {
+        // A reply that never comes means its job was dropped by the thread pool without being run.
+        let reply = reply.await.map_err(|_| ThreadPoolError::JobAborted)?;
+        write_reply(writer, reply, pool, &mut buf)
+            .instrument(span)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn write_reply<P: ThreadPool>(
+    writer: &mut FrameWriter<OwnedWriteHalf>,
+    reply: Reply,
+    pool: Option<&Arc<P>>,
+    buf: &mut BytesMut,
+) -> ServerResult<()> {
+    match reply {
+        Reply::Response(response) => writer.write_with(response, buf).await?,
+        Reply::Stream(Ok(Some(value))) => {
+            for chunk in value.as_bytes().chunks(STREAM_CHUNK_SIZE) {
+                let chunk = GetStreamResponse::Chunk(chunk.to_vec());
+                writer.write_with(Response::GetStream(chunk), buf).await?;
             }
-            Request::List => {
-                debug!("{}: list", peer_addr);
-                let keys = storage.list_keys();
-                let response = ListResponse::Ok(keys);
-                writer.write(response).await?;
+            writer
+                .write_with(Response::GetStream(GetStreamResponse::End), buf)
+                .await?;
+        }
+        Reply::Stream(Ok(None)) => {
+            writer
+                .write_with(Response::GetStream(GetStreamResponse::NotFound), buf)
+                .await?
+        }
+        Reply::Stream(Err(e)) => {
+            let response = match error_message(e) {
+                Ok(e) => Response::GetStream(GetStreamResponse::Err(e)),
+                Err(Corrupted(e)) => Response::Corruption(e),
+            };
+            writer.write_with(response, buf).await?
+        }
+        Reply::Snapshot(mut pages) => loop {
+            let page;
+            (pages, page) = next_page(pages, pool).await?;
+            let response = match page {
+                Ok(Some(entries)) => {
+                    for batch in batches(entries, |(key, value)| key.len() + value.len()) {
+                        let entries = SnapshotResponse::Entries(batch);
+                        writer.write_with(Response::Snapshot(entries), buf).await?;
+                    }
+                    continue;
+                }
+                Ok(None) => Response::Snapshot(SnapshotResponse::End),
+                Err(e) => match error_message(e) {
+                    Ok(e) => Response::Snapshot(SnapshotResponse::Err(e)),
+                    Err(Corrupted(e)) => Response::Corruption(e),
+                },
+            };
+            writer.write_with(response, buf).await?;
+            break;
+        },
+        Reply::Changes(mut pages) => loop {
+            let page;
+            (pages, page) = next_page(pages, pool).await?;
+            let response = match page {
+                Ok(Some(changes)) => {
+                    let len = |(key, value): &(String, Option<String>)| {
+                        key.len() + value.as_ref().map_or(0, String::len)
+                    };
+                    for batch in batches(changes, len) {
+                        let changes = ChangesResponse::Changes(batch);
+                        writer.write_with(Response::Changes(changes), buf).await?;
+                    }
+                    continue;
+                }
+                Ok(None) => Response::Changes(ChangesResponse::End),
+                Err(e) => match error_message(e) {
+                    Ok(e) => Response::Changes(ChangesResponse::Err(e)),
+                    Err(Corrupted(e)) => Response::Corruption(e),
+                },
+            };
+            writer.write_with(response, buf).await?;
+            break;
+        },
+    }
+    Ok(())
+}
+
+// Reads the next page of a streamed reply, on the thread pool if there is one, handing the pages back along with it.
+async fn next_page<T: Send + 'static, P: ThreadPool>(
+    mut pages: Pages<T>,
+    pool: Option<&Arc<P>>,
+) -> ServerResult<(Pages<T>, StorageResult<Option<Vec<T>>>)> {
+    let Some(pool) = pool else {
+        let page = pages.next();
+        return Ok((pages, page));
+    };
+    let (tx, rx) = oneshot::channel();
+    let span = Span::current();
+    pool.spawn(move || {
+        let page = span.in_scope(|| pages.next());
+        let _ = tx.send((pages, page));
+    });
+    // A page that never comes means its job was dropped by the thread pool without being run.
+    Ok(rx.await.map_err(|_| ThreadPoolError::JobAborted)?)
+}
+
+// Splits items into batches that each hold at least `STREAM_CHUNK_SIZE` bytes, apart from the last, so that small
+// items are not sent one by one.
+fn batches<T>(items: Vec<T>, len: impl Fn(&T) -> usize) -> Vec<Vec<T>> {
+    let mut batches = Vec::new();
+    let mut batch = Vec::new();
+    let mut batch_len = 0;
+    for item in items {
+        batch_len += len(&item);
+        batch.push(item);
+        if batch_len >= STREAM_CHUNK_SIZE {
+            batches.push(std::mem::take(&mut batch));
+            batch_len = 0;
+        }
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+// What a request is answered with, a streamed value, snapshot or list of changes is written as several responses.
+enum Reply {
+    Response(Response),
+    Stream(StorageResult<Option<String>>),
+    Snapshot(Pages<(String, String)>),
+    Changes(Pages<(String, Option<String>)>),
+}
+
+// The pages of a snapshot or list of changes, read one at a time as the previous page is written so that only a page
+// is held at once.
+struct Pages<T>(Box<dyn FnMut() -> StorageResult<Option<Vec<T>>> + Send>);
+
+impl<T> Pages<T> {
+    // Reads the next page, `None` once every page has been read.
+    fn next(&mut self) -> StorageResult<Option<Vec<T>>> {
+        (self.0)()
+    }
+}
+
+// Runs a request against the storage.
+//
+// This is the blocking part of serving a request, which may run on the thread pool.
+fn handle<S: Storage>(
+    storage: &S,
+    state: &ServerState,
+    request: Request,
+    peer_addr: SocketAddr,
+) -> Reply {
+    match try_handle(storage, state, request, peer_addr) {
+        Ok(reply) => reply,
+        Err(Corrupted(e)) => {
+            error!("{}: data corruption: {}", peer_addr, e);
+            Reply::Response(Response::Corruption(e))
+        }
+    }
+}
+
+// The number of keys, or changes, read at a time while streaming a snapshot, or a list of changes.
+const PAGE_SIZE: usize = 1000;
+
+// Reads every key and its value a page of keys at a time, skipping keys that are removed before their value is read.
+fn snapshot<S: Storage>(storage: S) -> Pages<(String, String)> {
+    let mut cursor = None;
+    let mut done = false;
+    Pages(Box::new(move || {
+        if done {
+            return Ok(None);
+        }
+        let page = storage.list_page(cursor.take(), PAGE_SIZE)?;
+        let mut entries = Vec::with_capacity(page.keys.len());
+        for key in page.keys {
+            if let Some(value) = storage.get(key.clone())? {
+                entries.push((key, value));
             }
         }
+        cursor = page.next_cursor;
+        done = cursor.is_none();
+        Ok(Some(entries))
+    }))
+}
+
+// Reads the changes made at or after `since` a page at a time, only replaying the log once the first page is read.
+fn changes<S: Storage>(storage: S, since: u64) -> Pages<(String, Option<String>)> {
+    let mut changes = None;
+    Pages(Box::new(move || {
+        let changes = match &mut changes {
+            Some(changes) => changes,
+            None => changes.insert(storage.changes_since(since)?),
+        };
+        let page = changes.take(PAGE_SIZE).collect::<StorageResult<Vec<_>>>()?;
+        Ok((!page.is_empty()).then_some(page))
+    }))
+}
+
+// Corrupted data found by the storage, which is answered with `Response::Corruption` whatever the request.
+struct Corrupted(String);
+
+// The message a storage error is answered with in the request's own error response, or `Corrupted` if the error
+// is corruption.
+fn error_message(e: StorageError) -> Result<String, Corrupted> {
+    match e {
+        StorageError::DataCorruption(..) | StorageError::TruncatedValue { .. } => {
+            Err(Corrupted(e.to_string()))
+        }
+        e => Ok(e.to_string()),
     }
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use tempfile::TempDir;
-//     use tokio::sync::oneshot;
-//     use tokio::time;
+fn try_handle<S: Storage>(
+    storage: &S,
+    state: &ServerState,
+    request: Request,
+    peer_addr: SocketAddr,
+) -> Result<Reply, Corrupted> {
+    let response = match request {
+        Request::Hello { .. } => Response::Hello(HelloResponse::Err(
+            "handshake already completed".to_string(),
+        )),
+        Request::Get { key } => {
+            debug!("{}: get {}", peer_addr, &key);
+            Response::Get(match storage.get(key) {
+                Ok(value) => GetResponse::Ok(value),
+                Err(e) => GetResponse::Err(format!("test: {}", error_message(e)?)),
+            })
+        }
+        Request::GetStream { key } => {
+            debug!("{}: get stream {}", peer_addr, &key);
+            return Ok(Reply::Stream(storage.get(key)));
+        }
+        Request::GetMeta { key } => {
+            debug!("{}: get meta {}", peer_addr, &key);
+            Response::GetMeta(match storage.get_with_metadata(key) {
+                Ok(Some((value, timestamp))) => GetMetaResponse::Ok {
+                    value: Some(value),
+                    timestamp,
+                },
+                Ok(None) => GetMetaResponse::Ok {
+                    value: None,
+                    timestamp: None,
+                },
+                Err(e) => GetMetaResponse::Err(error_message(e)?),
+            })
+        }
+        Request::Set { key, value } => {
+            debug!("{}: set {} {}", peer_addr, &key, &value);
+            Response::Set(match storage.set(key, value) {
+                Ok(()) => SetResponse::Ok(()),
+                Err(e) => SetResponse::Err(error_message(e)?),
+            })
+        }
+        Request::Put { key, value } => {
+            debug!("{}: put {} {}", peer_addr, &key, &value);
+            Response::Put(match storage.put(key, value) {
+                Ok(PutOutcome::Created) => PutResponse::Created,
+                Ok(PutOutcome::Updated) => PutResponse::Updated,
+                Err(e) => PutResponse::Err(error_message(e)?),
+            })
+        }
+        Request::SetIfAbsent { key, value } => {
+            debug!("{}: set if absent {} {}", peer_addr, &key, &value);
+            Response::SetIfAbsent(match storage.set_if_absent(key, value) {
+                Ok(written) => SetIfAbsentResponse::Ok(written),
+                Err(e) => SetIfAbsentResponse::Err(error_message(e)?),
+            })
+        }
+        Request::GetOrSet { key, default } => {
+            debug!("{}: get or set {} {}", peer_addr, &key, &default);
+            Response::GetOrSet(match storage.get_or_set(key, default) {
+                Ok(value) => GetOrSetResponse::Ok(value),
+                Err(e) => GetOrSetResponse::Err(error_message(e)?),
+            })
+        }
+        Request::Snapshot => {
+            debug!("{}: snapshot", peer_addr);
+            return Ok(Reply::Snapshot(snapshot(storage.clone())));
+        }
+        Request::Compact => {
+            debug!("{}: compact", peer_addr);
+            Response::Compact(match storage.compact() {
+                Ok(()) => CompactResponse::Ok(()),
+                Err(e) => CompactResponse::Err(error_message(e)?),
+            })
+        }
+        Request::Info => {
+            debug!("{}: info", peer_addr);
+            Response::Info(match storage.len() {
+                Ok(keys) => InfoResponse::Ok {
+                    version: env!("CARGO_PKG_VERSION").to_owned(),
+                    engine: format!("{:?}", state.storage_type),
+                    uptime_secs: state.started.elapsed().as_secs(),
+                    keys,
+                },
+                Err(e) => InfoResponse::Err(error_message(e)?),
+            })
+        }
+        Request::Changes { since } => {
+            debug!("{}: changes since {}", peer_addr, since);
+            return Ok(Reply::Changes(changes(storage.clone(), since)));
+        }
+        Request::Touch { key } => {
+            debug!("{}: touch {}", peer_addr, &key);
+            Response::Touch(match storage.touch(key) {
+                Ok(()) => TouchResponse::Ok(()),
+                Err(e) => TouchResponse::Err(error_message(e)?),
+            })
+        }
+        Request::Remove { key } => {
+            debug!("{}: remove {}", peer_addr, &key);
+            Response::Remove(match storage.remove(key) {
+                Ok(()) => RemoveResponse::Ok(()),
+                Err(e) => RemoveResponse::Err(error_message(e)?),
+            })
+        }
+        Request::RemoveOptional { key } => {
+            debug!("{}: remove optional {}", peer_addr, &key);
+            Response::RemoveOptional(match storage.remove_optional(key) {
+                Ok(removed) => RemoveOptionalResponse::Ok(removed),
+                Err(e) => RemoveOptionalResponse::Err(error_message(e)?),
+            })
+        }
+        Request::RemovePrefix { prefix } => {
+            debug!("{}: remove prefix {}", peer_addr, &prefix);
+            Response::RemovePrefix(match storage.remove_prefix(prefix) {
+                Ok(removed) => RemovePrefixResponse::Ok(removed),
+                Err(e) => RemovePrefixResponse::Err(error_message(e)?),
+            })
+        }
+        Request::LPush { key, value } => {
+            debug!("{}: lpush {} {}", peer_addr, &key, &value);
+            Response::Push(match storage.lpush(key, value) {
+                Ok(len) => PushResponse::Ok(len),
+                Err(e) => PushResponse::Err(error_message(e)?),
+            })
+        }
+        Request::RPush { key, value } => {
+            debug!("{}: rpush {} {}", peer_addr, &key, &value);
+            Response::Push(match storage.rpush(key, value) {
+                Ok(len) => PushResponse::Ok(len),
+                Err(e) => PushResponse::Err(error_message(e)?),
+            })
+        }
+        Request::LPop { key } => {
+            debug!("{}: lpop {}", peer_addr, &key);
+            Response::Pop(match storage.lpop(key) {
+                Ok(value) => PopResponse::Ok(value),
+                Err(e) => PopResponse::Err(error_message(e)?),
+            })
+        }
+        Request::RPop { key } => {
+            debug!("{}: rpop {}", peer_addr, &key);
+            Response::Pop(match storage.rpop(key) {
+                Ok(value) => PopResponse::Ok(value),
+                Err(e) => PopResponse::Err(error_message(e)?),
+            })
+        }
+        Request::List => {
+            debug!("{}: list", peer_addr);
+            Response::List(ListResponse::Ok(storage.list_keys()))
+        }
+        Request::ListSizes => {
+            debug!("{}: list sizes", peer_addr);
+            Response::ListSizes(match storage.list_with_sizes() {
+                Ok(keys) => ListSizesResponse::Ok(keys),
+                Err(e) => ListSizesResponse::Err(error_message(e)?),
+            })
+        }
+        Request::ListPage { cursor, limit } => {
+            debug!("{}: list page {:?} {}", peer_addr, &cursor, limit);
+            Response::ListPage(match storage.list_page(cursor, limit as usize) {
+                Ok(page) => ListPageResponse::Ok {
+                    keys: page.keys,
+                    next_cursor: page.next_cursor,
+                },
+                Err(e) => ListPageResponse::Err(error_message(e)?),
+            })
+        }
+        Request::Traced { .. } => {
+            unreachable!("traced requests are unwrapped before they are served")
+        }
+    };
+    Ok(Reply::Response(response))
+}
+
+// A token bucket refilled at `rate` tokens per second, holding at most `rate` tokens.
+struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: NonZeroU32) -> Self {
+        let rate = f64::from(rate.get());
+        RateLimiter {
+            rate,
+            tokens: rate,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    // Takes a token if one is available.
+    fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// The first and the most that accepting a connection is delayed by after an error.
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+// Accepts the next connection, logging and retrying accept errors.
+//
+// Retries are backed off so that an error that persists, such as running out of file descriptors, does not spin the
+// loop and flood the log.
+async fn accept<F, Fut, T>(mut accept: F, backoff: &mut AcceptBackoff) -> T
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    loop {
+        match accept().await {
+            Ok(accepted) => {
+                backoff.succeeded();
+                return accepted;
+            }
+            Err(e) => {
+                let delay = backoff.failed();
+                error!("error accepting connection, retrying in {:?}: {}", delay, e);
+                time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+// Spaces out accepts after errors, doubling the delay with each error in a row up to `MAX_ACCEPT_BACKOFF`.
+// Each delay is jittered down by up to half so that servers hitting the same limit do not retry in lockstep.
+struct AcceptBackoff {
+    delay: Duration,
+}
+
+impl AcceptBackoff {
+    fn new() -> Self {
+        AcceptBackoff {
+            delay: Duration::ZERO,
+        }
+    }
+
+    // Returns how long to wait before accepting again.
+    fn failed(&mut self) -> Duration {
+        self.delay = (self.delay * 2).clamp(MIN_ACCEPT_BACKOFF, MAX_ACCEPT_BACKOFF);
+        // A fresh `RandomState` is randomly seeded, which is plenty for jitter.
+        let random = RandomState::new().build_hasher().finish();
+        let jitter = self.delay.mul_f64((random as f64 / u64::MAX as f64) / 2.0);
+        self.delay - jitter
+    }
+
+    fn succeeded(&mut self) {
+        self.delay = Duration::ZERO;
+    }
+}
+
+fn handshake(protocol_version: u32, options: &ServerOptions) -> HelloResponse {
+    if protocol_version == PROTOCOL_VERSION {
+        HelloResponse::Ok {
+            protocol_version: PROTOCOL_VERSION,
+            max_value_size: options.max_value_size,
+        }
+    } else {
+        HelloResponse::Err(format!(
+            "unsupported protocol version {}, the server supports version {}",
+            protocol_version, PROTOCOL_VERSION
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use futures::{SinkExt, StreamExt};
+    use tempfile::TempDir;
+    use tokio::io::AsyncWriteExt;
+    use tokio::time;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id};
+    use tracing::Subscriber;
+    use tracing_subscriber::layer::{self, Layer, SubscriberExt};
+    use tracing_subscriber::Registry;
+
+    use super::*;
+    use crate::{Client, ClientError, ClientOptions};
+
+    #[tokio::test]
+    async fn test_run() {
+        let addr = "127.0.0.1:4017".parse().unwrap();
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().to_path_buf();
+        let (tx, rx) = oneshot::channel();
+        let handle = tokio::spawn(async move { run(addr, path, StorageType::Bitcask, rx).await });
+        time::sleep(Duration::from_millis(100)).await;
+        tx.send(()).unwrap();
+        assert_eq!(handle.await.unwrap().unwrap(), ShutdownReason::Signaled);
+    }
+
+    // Writes acknowledged before the stop signal should be on disk once the server has stopped.
+    #[tokio::test]
+    async fn test_run_flushes_on_shutdown() {
+        let addr = "127.0.0.1:4028".parse().unwrap();
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().to_path_buf();
+        let (tx, rx) = oneshot::channel();
+        let handle = tokio::spawn(async move { run(addr, path, StorageType::Bitcask, rx).await });
+        time::sleep(Duration::from_millis(100)).await;
+
+        let client = Client::connect(addr, 1);
+        client
+            .set("key1".to_owned(), "value1".to_owned())
+            .await
+            .unwrap();
+        tx.send(()).unwrap();
+        assert_eq!(handle.await.unwrap().unwrap(), ShutdownReason::Signaled);
+
+        let store = Bitcask::open(dir.path()).unwrap();
+        assert_eq!(
+            store.get("key1".to_owned()).unwrap(),
+            Some("value1".to_owned())
+        );
+    }
+
+    // Records the fields of every request span created.
+    #[derive(Clone, Default)]
+    struct SpanRecorder(Arc<Mutex<Vec<HashMap<String, String>>>>);
+
+    impl<S: Subscriber> Layer<S> for SpanRecorder {
+        fn on_new_span(&self, attrs: &Attributes<'_>, _: &Id, _: layer::Context<'_, S>) {
+            struct Visitor<'a>(&'a mut HashMap<String, String>);
+            impl Visit for Visitor<'_> {
+                fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                    self.0
+                        .insert(field.name().to_owned(), format!("{:?}", value));
+                }
+                fn record_str(&mut self, field: &Field, value: &str) {
+                    self.0.insert(field.name().to_owned(), value.to_owned());
+                }
+            }
+            if attrs.metadata().name() != "request" {
+                return;
+            }
+            let mut fields = HashMap::new();
+            attrs.record(&mut Visitor(&mut fields));
+            self.0.lock().unwrap().push(fields);
+        }
+    }
+
+    // Every request should be served in its own span, linked to the client's span when the client propagates it.
+    #[tokio::test]
+    async fn test_request_spans() {
+        let recorder = SpanRecorder::default();
+        let _guard = tracing::subscriber::set_default(Registry::default().with(recorder.clone()));
+
+        let addr = "127.0.0.1:4029".parse().unwrap();
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().to_path_buf();
+        let (_tx, rx) = oneshot::channel();
+        tokio::spawn(async move { run(addr, path, StorageType::Bitcask, rx).await });
+        time::sleep(Duration::from_millis(100)).await;
+
+        let options = ClientOptions {
+            propagate_trace: true,
+            ..ClientOptions::default()
+        };
+        let client = Client::connect_with_options(addr, options);
+        let span = tracing::info_span!("client");
+        let parent_span = span.id().unwrap().into_u64().to_string();
+        async {
+            client
+                .set("key1".to_owned(), "value1".to_owned())
+                .await
+                .unwrap();
+            client.list().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+        client.get("key1".to_owned()).await.unwrap();
+
+        let spans = recorder.0.lock().unwrap().clone();
+        assert_eq!(spans.len(), 3);
+        let peer = client_peer(&spans[0]);
+        assert_eq!(spans[0]["op"], "set");
+        assert_eq!(spans[0]["key"], "key1");
+        assert_eq!(spans[0]["parent_span"], parent_span);
+        assert_eq!(spans[1]["op"], "list");
+        assert!(!spans[1].contains_key("key"));
+        assert_eq!(spans[1]["parent_span"], parent_span);
+        assert_eq!(spans[2]["op"], "get");
+        assert_eq!(spans[2]["key"], "key1");
+        assert!(!spans[2].contains_key("parent_span"));
+        assert!(spans.iter().all(|span| client_peer(span) == peer));
+    }
+
+    fn client_peer(fields: &HashMap<String, String>) -> SocketAddr {
+        fields["peer"].parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_run_signal_dropped() {
+        let addr = "127.0.0.1:4026".parse().unwrap();
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().to_path_buf();
+        let (tx, rx) = oneshot::channel::<()>();
+        let handle = tokio::spawn(async move { run(addr, path, StorageType::Bitcask, rx).await });
+        time::sleep(Duration::from_millis(100)).await;
+        drop(tx);
+        assert_eq!(
+            handle.await.unwrap().unwrap(),
+            ShutdownReason::SignalDropped
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let addr = "127.0.0.1:4020".parse().unwrap();
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().to_path_buf();
+        let (_tx, rx) = oneshot::channel();
+        let options = ServerOptions {
+            rate_limit: NonZeroU32::new(5),
+            ..ServerOptions::default()
+        };
+        tokio::spawn(async move {
+            run_with_options(addr, path, StorageType::Bitcask, options, rx).await
+        });
+        time::sleep(Duration::from_millis(100)).await;
+
+        let client = Client::connect(addr, 1);
+        let mut served = 0;
+        let mut limited = 0;
+        for _ in 0..20 {
+            match client.get("key".to_string()).await {
+                Ok(_) => served += 1,
+                Err(ClientError::RateLimited) => limited += 1,
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+        }
+        assert!(served >= 5);
+        assert!(limited > 0);
+        assert_eq!(served + limited, 20);
+
+        // The bucket refills over time
+        time::sleep(Duration::from_secs(1)).await;
+        assert!(client.get("key".to_string()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_max_key_len() {
+        let addr = "127.0.0.1:4027".parse().unwrap();
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().to_path_buf();
+        let (_tx, rx) = oneshot::channel();
+        let options = ServerOptions {
+            max_key_len: Some(16),
+            ..ServerOptions::default()
+        };
+        tokio::spawn(async move {
+            run_with_options(addr, path, StorageType::Bitcask, options, rx).await
+        });
+        time::sleep(Duration::from_millis(100)).await;
+
+        let client = Client::connect(addr, 1);
+        let long_key = "k".repeat(1024 * 1024);
+        match client.set(long_key.clone(), "value".to_string()).await {
+            Err(ClientError::KeyTooLong { max_key_len }) => assert_eq!(max_key_len, 16),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(matches!(
+            client.get(long_key).await,
+            Err(ClientError::KeyTooLong { .. })
+        ));
+        assert!(client.list().await.unwrap().is_empty());
+
+        // Keys up to the limit are served as usual, on the same connection
+        let key = "k".repeat(16);
+        client.set(key.clone(), "value".to_string()).await.unwrap();
+        assert_eq!(client.list().await.unwrap(), vec![key]);
+    }
+
+    #[tokio::test]
+    async fn test_max_value_size() {
+        let addr = "127.0.0.1:4036".parse().unwrap();
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().to_path_buf();
+        let (_tx, rx) = oneshot::channel();
+        let options = ServerOptions {
+            max_value_size: Some(16),
+            ..ServerOptions::default()
+        };
+        tokio::spawn(async move {
+            run_with_options(addr, path, StorageType::Bitcask, options, rx).await
+        });
+        time::sleep(Duration::from_millis(100)).await;
+
+        // The limit is advertised in the handshake, and enforced for clients that send larger values anyway
+        let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let (mut reader, mut writer) = (frame_reader(reader), frame_writer(writer));
+        writer
+            .write(Request::Hello {
+                protocol_version: PROTOCOL_VERSION,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            reader.read::<HelloResponse>().await.unwrap(),
+            Some(HelloResponse::Ok {
+                protocol_version: PROTOCOL_VERSION,
+                max_value_size: Some(16),
+            })
+        );
+        for request in [
+            Request::Set {
+                key: "key".to_string(),
+                value: "v".repeat(17),
+            },
+            Request::RPush {
+                key: "queue".to_string(),
+                value: "v".repeat(17),
+            },
+        ] {
+            writer.write(request).await.unwrap();
+            assert_eq!(
+                reader.read::<Response>().await.unwrap(),
+                Some(Response::ValueTooLarge { max_value_size: 16 })
+            );
+        }
 
-//     use super::*;
+        let client = Client::connect(addr, 1);
+        assert!(matches!(
+            client.set("key".to_string(), "v".repeat(17)).await,
+            Err(ClientError::ValueTooLarge { max_value_size: 16 })
+        ));
+        assert!(client.list().await.unwrap().is_empty());
+        client.set("key".to_string(), "v".repeat(16)).await.unwrap();
+        assert_eq!(client.list().await.unwrap(), vec!["key".to_string()]);
+    }
+
+    // Requests should be served the same on every thread pool, including by several connections at once.
+    #[tokio::test]
+    async fn test_thread_pools() {
+        let pools = [
+            (ThreadPoolType::Naive, "127.0.0.1:4030"),
+            (ThreadPoolType::SharedQueue, "127.0.0.1:4031"),
+            (ThreadPoolType::Rayon, "127.0.0.1:4032"),
+        ];
+        for (thread_pool, addr) in pools {
+            let addr = addr.parse().unwrap();
+            let dir = TempDir::new().unwrap();
+            let path = dir.path().to_path_buf();
+            let (_tx, rx) = oneshot::channel();
+            let options = ServerOptions {
+                thread_pool: Some(thread_pool),
+                thread_pool_size: NonZeroU32::new(2),
+                ..ServerOptions::default()
+            };
+            tokio::spawn(async move {
+                run_with_options(addr, path, StorageType::Bitcask, options, rx).await
+            });
+            time::sleep(Duration::from_millis(100)).await;
+
+            let client = Client::connect(addr, 4);
+            let sets = (0..16).map(|i| client.set(format!("key{}", i), format!("value{}", i)));
+            for result in futures::future::join_all(sets).await {
+                result.unwrap();
+            }
+            for i in 0..16 {
+                assert_eq!(
+                    client.get(format!("key{}", i)).await.unwrap(),
+                    Some(format!("value{}", i)),
+                    "{:?}",
+                    thread_pool
+                );
+            }
+            let stream = client.get_stream("key0".to_owned()).await.unwrap().unwrap();
+            let chunks: Vec<Bytes> = stream.map(Result::unwrap).collect().await;
+            assert_eq!(chunks.concat(), b"value0");
+            assert_eq!(client.list().await.unwrap().len(), 16);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_split_across_writes() {
+        let addr = "127.0.0.1:4018".parse().unwrap();
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().to_path_buf();
+        let (_tx, rx) = oneshot::channel();
+        tokio::spawn(async move { run(addr, path, StorageType::Bitcask, rx).await });
+        time::sleep(Duration::from_millis(100)).await;
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = frame_reader(reader);
+
+        // Send the handshake and a set in one write, then a get split in two with a pause in between
+        let mut bytes = Vec::new();
+        for request in [
+            Request::Hello {
+                protocol_version: PROTOCOL_VERSION,
+            },
+            Request::Set {
+                key: "key".to_string(),
+                value: "value".to_string(),
+            },
+            Request::Get {
+                key: "key".to_string(),
+            },
+        ] {
+            let ser = bincode::serialize(&request).unwrap();
+            bytes.extend_from_slice(&(ser.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&ser);
+        }
+        let (first, second) = bytes.split_at(bytes.len() - 5);
+        writer.write_all(first).await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        writer.write_all(second).await.unwrap();
+
+        let hello = reader.read::<HelloResponse>().await.unwrap();
+        assert_eq!(
+            hello,
+            Some(HelloResponse::Ok {
+                protocol_version: PROTOCOL_VERSION,
+                max_value_size: None,
+            })
+        );
+        let set = reader.read::<Response>().await.unwrap();
+        assert_eq!(set, Some(Response::Set(SetResponse::Ok(()))));
+        let get = reader.read::<Response>().await.unwrap();
+        assert_eq!(
+            get,
+            Some(Response::Get(GetResponse::Ok(Some("value".to_string()))))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_garbage_frame() {
+        let addr = "127.0.0.1:4034".parse().unwrap();
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().to_path_buf();
+        let (_tx, rx) = oneshot::channel();
+        tokio::spawn(async move { run(addr, path, StorageType::Bitcask, rx).await });
+        time::sleep(Duration::from_millis(100)).await;
+
+        // A frame that is not a request is answered with a protocol error, and the connection is then closed.
+        let garbage = Bytes::from_static(&[0xff; 8]);
+        let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let (mut reader, mut writer) = (frame_reader(reader), frame_writer(writer));
+        writer
+            .write(Request::Hello {
+                protocol_version: PROTOCOL_VERSION,
+            })
+            .await
+            .unwrap();
+        let hello = reader.read::<HelloResponse>().await.unwrap();
+        assert_eq!(
+            hello,
+            Some(HelloResponse::Ok {
+                protocol_version: PROTOCOL_VERSION,
+                max_value_size: None,
+            })
+        );
+        writer.send(garbage.clone()).await.unwrap();
+        match reader.read::<Response>().await.unwrap() {
+            Some(Response::ProtocolError(e)) => assert!(e.starts_with("invalid request")),
+            r => panic!("unexpected response: {:?}", r),
+        }
+        assert!(reader.read::<Response>().await.unwrap().is_none());
+
+        // The same goes for a handshake that cannot be decoded.
+        let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let (mut reader, mut writer) = (frame_reader(reader), frame_writer(writer));
+        writer.send(garbage).await.unwrap();
+        match reader.read::<HelloResponse>().await.unwrap() {
+            Some(HelloResponse::Err(e)) => assert!(e.starts_with("invalid handshake")),
+            r => panic!("unexpected response: {:?}", r),
+        }
+        assert!(reader.read::<Response>().await.unwrap().is_none());
+    }
 
-//     #[tokio::test]
-//     async fn test_run() {
-//         let addr = "127.0.0.1:4014".parse().unwrap();
-//         let dir = TempDir::new().unwrap();
-//         let storage_type = StorageType::Bitcask;
-//         let (tx, rx) = oneshot::channel();
-//         let handle = tokio::spawn(async move {
-//             run(addr, dir.into_path(), storage_type, rx).await;
-//         });
-//         time::sleep(time::Duration::from_secs(1)).await;
-//         tx.send(()).unwrap();
-//         handle.await.unwrap();
-//     }
-// }
+    #[tokio::test]
+    async fn test_max_inflight_per_conn() {
+        let addr = "127.0.0.1:4037".parse().unwrap();
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().to_path_buf();
+        let (_tx, rx) = oneshot::channel();
+        let options = ServerOptions {
+            thread_pool: Some(ThreadPoolType::SharedQueue),
+            thread_pool_size: NonZeroU32::new(4),
+            max_inflight_per_conn: NonZeroUsize::new(2),
+            ..ServerOptions::default()
+        };
+        tokio::spawn(async move {
+            run_with_options(addr, path, StorageType::Bitcask, options, rx).await
+        });
+        time::sleep(Duration::from_millis(100)).await;
+
+        let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let (mut reader, mut writer) = (frame_reader(reader), frame_writer(writer));
+        writer
+            .write(Request::Hello {
+                protocol_version: PROTOCOL_VERSION,
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            reader.read::<HelloResponse>().await.unwrap(),
+            Some(HelloResponse::Ok { .. })
+        ));
+
+        // Many more requests than the cap are sent before any response is read, and every one is answered in the
+        // order it was sent.
+        let requests = 64;
+        for i in 0..requests {
+            let request = Request::Set {
+                key: format!("key{}", i),
+                value: format!("value{}", i),
+            };
+            writer.write(request).await.unwrap();
+        }
+        for _ in 0..requests {
+            assert_eq!(
+                reader.read::<Response>().await.unwrap(),
+                Some(Response::Set(SetResponse::Ok(())))
+            );
+        }
+        for i in 0..requests {
+            let request = Request::Get {
+                key: format!("key{}", i),
+            };
+            writer.write(request).await.unwrap();
+        }
+        for i in 0..requests {
+            assert_eq!(
+                reader.read::<Response>().await.unwrap(),
+                Some(Response::Get(GetResponse::Ok(Some(format!("value{}", i)))))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accept_backoff() {
+        // An accept that keeps failing is retried with growing delays rather than in a busy loop.
+        let attempts = AtomicUsize::new(0);
+        let mut backoff = AcceptBackoff::new();
+        let failing = || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err::<(), _>(io::Error::other("too many open files"))
+        };
+        let result = time::timeout(Duration::from_millis(500), accept(failing, &mut backoff)).await;
+        assert!(result.is_err());
+        let failed = attempts.load(Ordering::SeqCst);
+        assert!((2..=12).contains(&failed), "{} attempts", failed);
+
+        // Once the delay has grown to its maximum every delay stays within the bounds, and a successful accept starts
+        // the delays over.
+        for _ in 0..8 {
+            backoff.failed();
+        }
+        for _ in 0..100 {
+            let delay = backoff.failed();
+            assert!((MAX_ACCEPT_BACKOFF / 2..=MAX_ACCEPT_BACKOFF).contains(&delay));
+        }
+        let mut failures = 0;
+        let flaky = || {
+            failures += 1;
+            let result = if failures < 2 {
+                Err(io::Error::other("too many open files"))
+            } else {
+                Ok(failures)
+            };
+            async move { result }
+        };
+        assert_eq!(accept(flaky, &mut backoff).await, 2);
+        let delay = backoff.failed();
+        assert!((MIN_ACCEPT_BACKOFF / 2..=MIN_ACCEPT_BACKOFF).contains(&delay));
+    }
+
+    #[tokio::test]
+    async fn test_run_with_config() {
+        let dir = TempDir::new().unwrap();
+        let addr = "127.0.0.1:4040".parse().unwrap();
+        let mut config = ServerConfig::new(addr, dir.path().to_path_buf());
+        config.storage_type = StorageType::Sled;
+        config.options.thread_pool = Some(ThreadPoolType::Rayon);
+        let (tx, rx) = oneshot::channel();
+        let handle = tokio::spawn(run_with_config(config, rx));
+        time::sleep(Duration::from_millis(100)).await;
+
+        let client = Client::connect(addr, 1);
+        client
+            .set("key1".to_owned(), "value1".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(
+            client.get("key1".to_owned()).await.unwrap(),
+            Some("value1".to_owned())
+        );
+
+        tx.send(()).unwrap();
+        assert_eq!(handle.await.unwrap().unwrap(), ShutdownReason::Signaled);
+    }
+
+    // Of two compactions requested at once, one should run while the other is told one is already running.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_compact() {
+        let dir = TempDir::new().unwrap();
+        // Enough data that the compaction is still running when the second request arrives.
+        let store = Bitcask::open(dir.path()).unwrap();
+        for round in 0..2 {
+            for key_id in 0..50_000 {
+                store
+                    .set(
+                        format!("key{}", key_id),
+                        format!("value{}", round).repeat(10),
+                    )
+                    .unwrap();
+            }
+        }
+        drop(store);
+
+        let addr = "127.0.0.1:4047".parse().unwrap();
+        let path = dir.path().to_path_buf();
+        let (_tx, rx) = oneshot::channel();
+        tokio::spawn(async move { run(addr, path, StorageType::Bitcask, rx).await });
+        time::sleep(Duration::from_millis(100)).await;
+
+        // Each client has its own connection, handshaken before the compactions are requested.
+        let (first, second) = (Client::connect(addr, 1), Client::connect(addr, 1));
+        first.get("key0".to_owned()).await.unwrap();
+        second.get("key0".to_owned()).await.unwrap();
+        let (first, second) = tokio::join!(first.compact(), second.compact());
+        let mut ran = vec![first.unwrap(), second.unwrap()];
+        ran.sort();
+        assert_eq!(ran, vec![false, true]);
+
+        // Once the compaction is done another one may run.
+        let client = Client::connect(addr, 1);
+        assert!(client.compact().await.unwrap());
+        assert_eq!(
+            client.get("key0".to_owned()).await.unwrap(),
+            Some("value1".repeat(10))
+        );
+    }
+
+    // The memory engine should serve requests without writing anything to the data directory.
+    #[tokio::test]
+    async fn test_run_memory() {
+        let dir = TempDir::new().unwrap();
+        let addr = "127.0.0.1:4044".parse().unwrap();
+        let mut config = ServerConfig::new(addr, dir.path().to_path_buf());
+        config.storage_type = StorageType::Memory;
+        let (tx, rx) = oneshot::channel();
+        let handle = tokio::spawn(run_with_config(config, rx));
+        time::sleep(Duration::from_millis(100)).await;
+
+        let client = Client::connect(addr, 1);
+        client
+            .set("key1".to_owned(), "value1".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(
+            client.get("key1".to_owned()).await.unwrap(),
+            Some("value1".to_owned())
+        );
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        tx.send(()).unwrap();
+        assert_eq!(handle.await.unwrap().unwrap(), ShutdownReason::Signaled);
+    }
+
+    // A snapshot and a list of changes should be read a page at a time rather than all at once.
+    #[test]
+    fn test_pages() -> StorageResult<()> {
+        let dir = TempDir::new().unwrap();
+        let storage = Bitcask::open(dir.path())?;
+        for i in 0..PAGE_SIZE * 2 + 1 {
+            storage.set(format!("key{:04}", i), "value".to_owned())?;
+        }
+
+        let mut pages = snapshot(storage.clone());
+        let mut lens = Vec::new();
+        while let Some(page) = pages.next()? {
+            lens.push(page.len());
+        }
+        assert_eq!(lens, vec![PAGE_SIZE, PAGE_SIZE, 1]);
+
+        let mut pages = changes(storage, 0);
+        let mut lens = Vec::new();
+        while let Some(page) = pages.next()? {
+            lens.push(page.len());
+        }
+        assert_eq!(lens, vec![PAGE_SIZE, PAGE_SIZE, 1]);
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use tokio::task;
+
+use super::{Bitcask, Storage, StorageError, StorageResult};
+
+/// An async adapter for embedding a `Bitcask` store in a tokio application without running the server.
+///
+/// Each operation runs on tokio's blocking thread pool so it never blocks the runtime's worker threads.
+/// Clones share the underlying store.
+#[derive(Clone)]
+pub struct AsyncBitcask {
+    // Clones of the store that are not in use by an operation, so that the files a clone has opened for reading are
+    // reused by later operations rather than reopened each time. There is always at least one.
+    idle: Arc<Mutex<Vec<Bitcask>>>,
+}
+
+impl AsyncBitcask {
+    /// Wraps an open `Bitcask` store.
+    pub fn new(store: Bitcask) -> Self {
+        AsyncBitcask {
+            idle: Arc::new(Mutex::new(vec![store])),
+        }
+    }
+
+    /// Opens a `Bitcask` store at the given path, see `Bitcask::open`.
+    pub async fn open(path: impl Into<PathBuf>) -> StorageResult<Self> {
+        let path = path.into();
+        let store = task::spawn_blocking(move || Bitcask::open(path))
+            .await
+            .map_err(|e| StorageError::Unexpected(e.to_string()))??;
+        Ok(AsyncBitcask::new(store))
+    }
+
+    /// Gets the string value of a given string key, see `Storage::get`.
+    pub async fn get(&self, key: String) -> StorageResult<Option<String>> {
+        self.run(move |store| store.get(key)).await
+    }
+
+    /// Sets the value of a string key to a string, see `Storage::set`.
+    pub async fn set(&self, key: String, value: String) -> StorageResult<()> {
+        self.run(move |store| store.set(key, value)).await
+    }
+
+    /// Removes a given key, see `Storage::remove`.
+    pub async fn remove(&self, key: String) -> StorageResult<()> {
+        self.run(move |store| store.remove(key)).await
+    }
+
+    /// Lists all keys, see `Storage::list_keys`.
+    pub async fn list(&self) -> StorageResult<Vec<String>> {
+        self.run(|store| Ok(store.list_keys())).await
+    }
+
+    // Runs an operation on an idle clone of the store on the blocking thread pool.
+    async fn run<T, F>(&self, op: F) -> StorageResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Bitcask) -> StorageResult<T> + Send + 'static,
+    {
+        let store = {
+            let mut idle = self.idle.lock()?;
+            match idle.len() {
+                1 => idle[0].clone(),
+                _ => idle.pop().expect("there is always an idle store"),
+            }
+        };
+        let idle = self.idle.clone();
+        task::spawn_blocking(move || {
+            let result = op(&store);
+            if let Ok(mut idle) = idle.lock() {
+                idle.push(store);
+            }
+            result
+        })
+        .await
+        .map_err(|e| StorageError::Unexpected(e.to_string()))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // Should serve concurrent gets and sets from many tasks.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_get_set() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = AsyncBitcask::open(temp_dir.path()).await?;
+
+        let mut tasks = Vec::new();
+        for i in 0..32 {
+            let store = store.clone();
+            tasks.push(tokio::spawn(async move {
+                let key = format!("key{}", i);
+                store.set(key.clone(), format!("value{}", i)).await?;
+                store.get(key).await
+            }));
+        }
+        for (i, task) in tasks.into_iter().enumerate() {
+            assert_eq!(
+                task.await.expect("task panicked")?,
+                Some(format!("value{}", i))
+            );
+        }
+
+        assert_eq!(store.list().await?.len(), 32);
+        store.remove("key0".to_owned()).await?;
+        assert_eq!(store.get("key0".to_owned()).await?, None);
+        assert!(matches!(
+            store.remove("missing".to_owned()).await,
+            Err(StorageError::KeyNotFound)
+        ));
+
+        Ok(())
+    }
+}
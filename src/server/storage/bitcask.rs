@@ -1,40 +1,345 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use crossbeam_skiplist::SkipMap;
+use crossbeam_skiplist::{map, SkipMap};
+use crossbeam_utils::atomic::AtomicCell;
+use memmap2::Mmap;
 use std::{
-    cell::RefCell,
-    collections::HashMap,
-    fs::{self, File},
-    io::{BufReader, BufWriter, Read, Seek, Write},
+    cell::{Cell, RefCell},
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    io::{self, BufReader, BufWriter, Cursor, Read, Seek, Write},
+    ops::Bound,
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
-    time::{SystemTime, UNIX_EPOCH},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
+use tracing::{debug, error, field, info, info_span, warn};
 
-use super::{Storage, StorageError, StorageResult};
+use super::{
+    access_error,
+    bloom::BloomFilter,
+    file_id::{FileIdAllocator, LOWEST_LOG_FILE_ID},
+    meta, queue, validate_key, Changes, FileSystem, ListPage, PutOutcome, StdFileSystem, Storage,
+    StorageError, StorageResult,
+};
 
 const X25: crc::Crc<u16> = crc::Crc::<u16>::new(&crc::CRC_16_IBM_SDLC);
 
-const TOMBSTONE: &str = "";
+// The value length recorded for a tombstone, so that it can be told apart from an empty value.
+const TOMBSTONE_VALUE_LEN: u32 = u32::MAX;
+
+// The longest key or value in bytes that a record can hold, as their lengths are recorded as `u32`s and the largest
+// of those marks a tombstone.
+const MAX_RECORD_LEN: usize = TOMBSTONE_VALUE_LEN as usize - 1;
 
 const LOG_FILE_EXT: &str = "log";
 
 const HINT_FILE_EXT: &str = "hint";
 
-const LOWEST_LOG_FILE_ID: u64 = 0;
+// A hint file is written under this suffix and renamed into place once it is complete.
+const HINT_TMP_FILE_SUFFIX: &str = ".hint.tmp";
+
+// The name this engine records in the store meta file.
+const ENGINE: &str = "bitcask";
+
+// The format file predates the store meta file, it is still written so that older builds keep recognising the
+// format of stores opened by this one.
+const FORMAT_FILE: &str = "format";
+
+// The version of the data record format written by this build, prefixed to every record.
+//
+// Version 0 records have no version byte, stores written before the format was versioned are recognised by the
+// absence of the format file and are migrated to the current version when they are opened.
+// Version 2 records are timestamped in milliseconds rather than the seconds of earlier versions.
+// Version 3 records mark tombstones with `TOMBSTONE_VALUE_LEN`, earlier versions wrote an empty value for them.
+// Version 4 records carry the time their value expires. Records without an expiry are still written as version 3
+// records so they do not grow, while the hint records of a version 4 store always carry one.
+const FORMAT_VERSION: u8 = 4;
+
+// The first format version whose timestamps are in milliseconds.
+const MILLIS_FORMAT_VERSION: u8 = 2;
+
+// The first format version that tells tombstones apart from empty values.
+const TOMBSTONE_FORMAT_VERSION: u8 = 3;
+
+// The first format version whose records can carry an expiry.
+const EXPIRY_FORMAT_VERSION: u8 = 4;
 
 const LOG_SIZE_THRESHOLD: u64 = 1024 * 1024;
 
+// The approximate size in bytes of a key directory entry beyond the bytes of its key: the key's `String`, the entry
+// and the skip map node's reference count, height and an average of two tower pointers.
+const KEY_DIR_ENTRY_OVERHEAD: usize = std::mem::size_of::<String>()
+    + std::mem::size_of::<AtomicCell<Entry>>()
+    + 4 * std::mem::size_of::<usize>();
+
+// The size in bytes of the records `bulk_load` buffers before writing them to the active file.
+const BULK_LOAD_BUFFER_SIZE: u64 = 4 * 1024 * 1024;
+
+// The size of the fixed-width header of a data record without an expiry, see `write_value`.
+const RECORD_HEADER_LEN: u64 = 1 + 2 + 8 + 4 + 4;
+
+// The size of the expiry that follows the header of a data record with one, see `write_value`.
+const RECORD_EXPIRY_LEN: u64 = 8;
+
+// The size of the fixed-width header of a hint record, see `write_hint`.
+const HINT_HEADER_LEN: u64 = 8 + 4 + 4 + 8 + 8;
+
+// The number of keys compaction goes through between progress events.
+const COMPACTION_PROGRESS_INTERVAL: usize = 100_000;
+
+// The default size in bytes of the window a log file is read ahead in, see `BitcaskOptions::prefetch_size`.
+const PREFETCH_SIZE: usize = 256 * 1024;
+
+// The number of reads of a file in a row that must each start shortly after the previous one ended before the file is
+// read ahead.
+const SEQUENTIAL_READS_BEFORE_PREFETCH: u32 = 2;
+
+// How far past the end of one read the next may start and still count as sequential, which leaves room for the header
+// and key of the record in between.
+const SEQUENTIAL_READ_GAP: u64 = 4 * 1024;
+
+/// Options for tuning a `Bitcask` store.
+#[derive(Debug, Clone)]
+pub struct BitcaskOptions {
+    /// The size in bytes after which the active log file is sealed and a new one is started.
+    pub max_log_size: u64,
+
+    /// The number of log files after which a compaction is started in the background.
+    ///
+    /// `None` disables automatic compaction.
+    pub max_log_files: Option<usize>,
+
+    /// The number of bytes of dead records, see `BitcaskStats::dead_bytes`, after which a compaction is started in the
+    /// background.
+    ///
+    /// Like `max_log_files` it is only checked when the active log file is sealed. `None` ignores the dead bytes.
+    pub max_dead_bytes: Option<u64>,
+
+    /// The number of skip maps the in-memory key directory is split across.
+    ///
+    /// Keys are assigned to a shard by hash, which reduces contention on any one skip map under heavy concurrent
+    /// access. The tradeoff is that anything iterating keys in order (listing, compaction) has to merge across every
+    /// shard, so it is only worth raising for read-heavy workloads with many concurrent clients.
+    /// Values below 1 are treated as 1.
+    pub key_dir_shards: usize,
+
+    /// The size in bits of a bloom filter kept alongside the in-memory key directory.
+    ///
+    /// Lookups of keys that were never written are answered by the filter without searching the key directory, which
+    /// helps read-heavy workloads that mostly miss. The filter is not resized, so it should be several bits per key
+    /// to be effective. `None` disables the filter.
+    pub bloom_filter_bits: Option<usize>,
+
+    /// A function normalizing keys for prefix matching and ordering, such as `str::to_lowercase` for case-insensitive
+    /// prefixes.
+    ///
+    /// Keys are still stored and looked up by their original bytes, only `scan_prefix` and `remove_prefix` compare
+    /// normalized keys. A normalized prefix cannot be found with a range over the key directory, so with a normalizer
+    /// those scan every key. `None` matches prefixes on the original bytes.
+    pub key_normalizer: Option<fn(&str) -> String>,
+
+    /// The size in bytes of the window a log file is read ahead in once its values are being read in order.
+    ///
+    /// Reading values in the order they were written, such as in key order after a compaction, otherwise costs a seek
+    /// and a read per value. Once a few reads of a file in a row have each started just after the previous one, the
+    /// next read fills a window of this size and the values after it are served from memory. Reads in any other order
+    /// read just the value as before. Each clone of the store keeps a window per file it reads, so this is also the
+    /// most memory a clone uses per file. 0 disables reading ahead.
+    pub prefetch_size: usize,
+
+    /// The number of versions of each key that compaction keeps, the current one included.
+    ///
+    /// Compaction normally keeps only the current version. With more than 1, the latest versions of each key up to
+    /// that many are copied into the merge file oldest first, where `replay` can read them, though `get` still only
+    /// reads the current one. The versions of a removed key are discarded along with it. Finding the older versions
+    /// means reading every merged file rather than just the key directory, which makes compaction slower.
+    /// Values below 1 are treated as 1.
+    pub retain_versions: usize,
+
+    /// How long compaction keeps the tombstone of a removed key before dropping it.
+    ///
+    /// Tombstones written within the grace period are copied into the merge file, so that `changes_since` still lists
+    /// the removal for a replica that catches up within it. Older tombstones are dropped along with the removed value.
+    /// Zero drops every tombstone.
+    pub tombstone_grace: Duration,
+
+    /// Whether sealed log files are mapped into memory and their values copied straight out of the mapping.
+    ///
+    /// This saves the seek and read per value of a read-heavy workload over large sealed files, at the cost of address
+    /// space for every file read from. The active file is still read through a buffered reader as it grows, and is
+    /// mapped on its next read once sealed. Read ahead, see `prefetch_size`, only applies to files that are not mapped.
+    /// A sealed file truncated by another process while it is mapped makes reads of the missing part crash the process
+    /// rather than fail, so the data directory must only be written by the store.
+    pub mmap_sealed: bool,
+}
+
+impl Default for BitcaskOptions {
+    fn default() -> Self {
+        BitcaskOptions {
+            max_log_size: LOG_SIZE_THRESHOLD,
+            max_log_files: None,
+            max_dead_bytes: None,
+            key_dir_shards: 1,
+            bloom_filter_bits: None,
+            key_normalizer: None,
+            prefetch_size: PREFETCH_SIZE,
+            retain_versions: 1,
+            tombstone_grace: Duration::ZERO,
+            mmap_sealed: false,
+        }
+    }
+}
+
+/// Describes one log file of a `Bitcask` store, as reported by `Bitcask::segments`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentInfo {
+    /// The id of the log file.
+    pub file_id: u64,
+
+    /// The size of the log file on disk in bytes.
+    pub size_bytes: u64,
+
+    /// The number of keys whose current value is stored in the file.
+    pub live_keys: usize,
+
+    /// Whether the file is the active file that new writes are appended to.
+    pub is_active: bool,
+
+    /// Whether the file has a hint file, which is the case for the output of a compaction.
+    pub has_hint: bool,
+}
+
+/// Where and when the value of a key was written, as returned by `Bitcask::get_with_segment`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueMetadata {
+    /// The time the value was written, in milliseconds since the unix epoch.
+    pub timestamp: u64,
+
+    /// The id of the log file the value was read from.
+    pub file_id: u64,
+
+    /// Whether the value was read from the active file, rather than a sealed one.
+    ///
+    /// Values in the active file were written recently, so reading them is more likely to hit the page cache.
+    pub is_active_segment: bool,
+}
+
+/// A record of the log of a `Bitcask` store, as yielded by `Bitcask::replay`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRecord {
+    /// The key the record was written for.
+    pub key: String,
+
+    /// The value that was set, empty for a tombstone.
+    pub value: String,
+
+    /// When the record was written, in milliseconds since the unix epoch.
+    pub timestamp: u64,
+
+    /// Whether the record removed the key rather than setting it.
+    pub is_tombstone: bool,
+}
+
+/// What a compaction of a `Bitcask` store would do, as reported by `Bitcask::compact_plan`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactionPlan {
+    /// The ids of the log and hint files the compaction would remove.
+    pub files_to_remove: Vec<u64>,
+
+    /// The number of live keys the compaction would copy into the merged file.
+    pub live_keys: usize,
+
+    /// The size in bytes of the merged log and hint files the compaction would write.
+    pub estimated_bytes_after: u64,
+}
+
+/// What a compaction of a `Bitcask` store into another directory wrote, as reported by `Bitcask::compact_into`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactionStats {
+    /// The number of live keys copied into the merged file.
+    pub live_keys: usize,
+
+    /// The size in bytes of the merged log and hint files written.
+    pub bytes_written: u64,
+}
+
+/// Statistics about a `Bitcask` store, as reported by `Bitcask::stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BitcaskStats {
+    /// The number of keys in the store.
+    pub keys: usize,
+
+    /// The approximate size in bytes of the in-memory key directory, see `Bitcask::index_memory_estimate`.
+    pub index_memory_estimate: usize,
+
+    /// The size in bytes of the store's log and hint files.
+    pub total_bytes: u64,
+
+    /// The size in bytes of the log records holding the current value of each key.
+    ///
+    /// Values that have expired are counted until a compaction drops them.
+    pub live_bytes: u64,
+
+    /// The size in bytes of the log records a compaction would drop, such as overwritten values and tombstones.
+    ///
+    /// Older versions kept by `BitcaskOptions::retain_versions` are counted as dead even though compaction keeps them.
+    pub dead_bytes: u64,
+
+    /// The share of `total_bytes` that is dead, from 0 right after a compaction towards 1 as values are overwritten
+    /// and removed.
+    ///
+    /// Together with `avg_segment_size` it tells whether compactions run often enough, as a high ratio means reads are
+    /// spread across files mostly holding records that are no longer read. 0 for a store without any bytes.
+    pub fragmentation_ratio: f64,
+
+    /// The average size in bytes of the store's log files, 0 for a store without any.
+    pub avg_segment_size: u64,
+}
+
 /// `Bitcask` stores string key/value pairs durably on disk using the Bitcask append-only log format.
 ///
 /// The implementation follows the [Bitcask Paper](https://riak.com/assets/bitcask-intro.pdf).
 ///
 /// `Bitcask` is a thread-safe implementation of the `Storage` trait and can be cloned and shared between threads.
+///
+/// ```
+/// use smoldb::{Bitcask, Storage, StorageError, StorageResult};
+///
+/// fn main() -> StorageResult<()> {
+///     let dir = tempfile::TempDir::new()?;
+///     let store = Bitcask::open(dir.path())?;
+///     store.set("key".to_owned(), "value".to_owned())?;
+///     assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+///
+///     assert!(matches!(
+///         store.remove("missing".to_owned()),
+///         Err(StorageError::KeyNotFound)
+///     ));
+///     Ok(())
+/// }
+/// ```
 #[derive(Clone)]
-pub struct Bitcask {
-    key_dir: Arc<SkipMap<String, Entry>>,
+pub struct Bitcask<F: FileSystem = StdFileSystem> {
+    fs: F,
+    key_dir: Arc<KeyDir>,
     path: Arc<PathBuf>,
-    writer: Arc<Mutex<Writer>>,
-    reader: Reader,
+    writer: Arc<Mutex<Writer<F>>>,
+    reader: Reader<F>,
+    // Dropped after the reader so that the last clone of the store has closed its handles before the files pending
+    // removal are retried.
+    obsolete: Arc<ObsoleteFiles<F>>,
+    compaction: Arc<Mutex<()>>,
+    compacting: Arc<AtomicBool>,
+    usage: Arc<DiskUsage>,
+    options: BitcaskOptions,
+    // The format version of the data files, which is only below `FORMAT_VERSION` for a read-only store.
+    format_version: u8,
+    // Whether no log or hint files were found when the store was opened.
+    is_new: bool,
 }
 
 impl Bitcask {
@@ -42,14 +347,92 @@ impl Bitcask {
     ///
     /// If the path does not exist, it will be created.
     pub fn open(path: impl Into<PathBuf>) -> StorageResult<Bitcask> {
-        let path: PathBuf = path.into();
-        fs::create_dir_all(&path)?;
+        Bitcask::open_with_options(path, BitcaskOptions::default())
+    }
+
+    /// Opens `Storage` at a given path with the given options.
+    ///
+    /// If the path does not exist, it will be created.
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: BitcaskOptions,
+    ) -> StorageResult<Bitcask> {
+        Bitcask::load(path.into(), options, None, StdFileSystem)
+    }
+
+    /// Opens the store at a given path read-only, as it was when `max_file_id` was the active log file.
+    ///
+    /// Log files with a higher id are ignored as if they had never been written, which is useful for looking into
+    /// data issues. Writes and compactions fail with `StorageError::ReadOnly`. Compaction removes the files it
+    /// merges, so a `max_file_id` below the store's merge file fails as that history no longer exists.
+    pub fn open_up_to(path: impl Into<PathBuf>, max_file_id: u64) -> StorageResult<Bitcask> {
+        Bitcask::load(
+            path.into(),
+            BitcaskOptions::default(),
+            Some(max_file_id),
+            StdFileSystem,
+        )
+    }
+}
+
+impl<F: FileSystem> Bitcask<F> {
+    /// Opens `Storage` at a given path on the given file system with the given options.
+    ///
+    /// This lets tests simulate failures that are hard to cause on a real disk, such as the disk filling up.
+    /// If the path does not exist, it will be created.
+    pub fn open_with_file_system(
+        path: impl Into<PathBuf>,
+        options: BitcaskOptions,
+        fs: F,
+    ) -> StorageResult<Bitcask<F>> {
+        Bitcask::load(path.into(), options, None, fs)
+    }
+
+    // Loads the store, only the files up to `max_file_id` and read-only if it is given.
+    fn load(
+        path: PathBuf,
+        options: BitcaskOptions,
+        max_file_id: Option<u64>,
+        fs: F,
+    ) -> StorageResult<Bitcask<F>> {
+        let read_only = max_file_id.is_some();
+        if !read_only {
+            fs.create_dir_all(&path)
+                .map_err(access_error("create the data directory", &path))?;
+        }
+
+        // A migration to the current format that was cut short leaves its record in the meta file. Once the hint file
+        // of its merge file is in place the store is in the current format, as the files it was merged from are no
+        // longer read. Until then the merge file is the only file in the current format, and it is dropped so that the
+        // migration starts over from the files it was being merged from.
+        let migration = meta::read(&fs, &path)?.and_then(|meta| meta.migration);
+        let migrated = match migration {
+            Some(file_id) => match fs.len(&hint_path(&path, &file_id)) {
+                Ok(_) => true,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+                Err(e) => return Err(e.into()),
+            },
+            None => false,
+        };
+        let unfinished_migration = migration.filter(|_| !migrated);
 
         // Find the highest hint file and then find all the log files that are higher than that hint file.
         let mut hint_file = Option::<u64>::None;
         let mut log_files = Vec::<u64>::new();
-        for entry in fs::read_dir(&path)? {
-            let file_path = entry?.path();
+        let mut data_files = Vec::<(u64, PathBuf)>::new();
+        for file_path in fs
+            .read_dir(&path)
+            .map_err(access_error("read the data directory", &path))?
+        {
+            // A compaction that stopped before renaming its hint file into place leaves the unfinished hint file behind,
+            // which nothing reads.
+            let is_hint_tmp = file_path
+                .to_str()
+                .is_some_and(|file_path| file_path.ends_with(HINT_TMP_FILE_SUFFIX));
+            if is_hint_tmp && !read_only {
+                fs.remove_file(&file_path)
+                    .map_err(access_error("remove the unfinished hint file", &file_path))?;
+            }
             let ext = file_path.extension().and_then(|ext| ext.to_str());
             if (ext != Some(LOG_FILE_EXT)) && (ext != Some(HINT_FILE_EXT)) {
                 continue;
@@ -62,14 +445,22 @@ impl Bitcask {
                     "Could not parse file {}",
                     file_path.display()
                 )))?;
+            if ext == Some(LOG_FILE_EXT) && unfinished_migration == Some(stem) {
+                if !read_only {
+                    fs.remove_file(&file_path).map_err(access_error(
+                        "remove the unfinished migration file",
+                        &file_path,
+                    ))?;
+                }
+                continue;
+            }
+            data_files.push((stem, file_path.clone()));
             match ext {
                 Some(LOG_FILE_EXT) => {
                     log_files.push(stem);
                 }
-                Some(HINT_FILE_EXT) => {
-                    if hint_file.map_or(true, |hint_file| stem > hint_file) {
-                        hint_file = Some(stem);
-                    }
+                Some(HINT_FILE_EXT) if hint_file.is_none_or(|hint_file| stem > hint_file) => {
+                    hint_file = Some(stem);
                 }
                 _ => {}
             }
@@ -80,621 +471,4731 @@ impl Bitcask {
             // This is what we want but it's a bit subtle...
             // The merge file shares the same file extension as the log files.
             // But it will be exluded here because it shares the same id as it's hint file and we are evaluating on > hint_file.
-            .filter(|file_id| hint_file.map_or(true, |hint_file| file_id > &hint_file))
+            .filter(|file_id| hint_file.is_none_or(|hint_file| file_id > &hint_file))
+            .filter(|file_id| max_file_id.is_none_or(|max_file_id| file_id <= &max_file_id))
             .collect();
         log_files.sort_unstable();
 
-        let key_dir = SkipMap::new();
-        let mut readers = HashMap::<u64, BufReader<File>>::new();
+        if let (Some(max_file_id), Some(hint_file)) = (max_file_id, hint_file) {
+            if hint_file > max_file_id {
+                return Err(StorageError::Unexpected(format!(
+                    "The log files up to {} have been compacted into file {}",
+                    max_file_id, hint_file
+                )));
+            }
+        }
+
+        // A store without a meta file was written by a build that only recorded the format version in the format
+        // file, and a store with data files but neither predates versioned records.
+        let format_version = match meta::check(&fs, &path, ENGINE, FORMAT_VERSION)? {
+            Some(_) if migrated => FORMAT_VERSION,
+            Some(format_version) => format_version,
+            None => match read_format(&fs, &path)? {
+                Some(format_version) => format_version,
+                None if !log_files.is_empty() || hint_file.is_some() => 0,
+                None => FORMAT_VERSION,
+            },
+        };
+        if format_version > FORMAT_VERSION {
+            return Err(StorageError::IncompatibleFormat {
+                engine: ENGINE.to_owned(),
+                format_version,
+            });
+        }
+
+        let key_dir = KeyDir::new(
+            options.key_dir_shards,
+            options.bloom_filter_bits,
+            options.key_normalizer,
+        );
+        let mut readers = HashMap::<u64, FileReader<F>>::new();
+        let mut last_timestamp = 0;
 
         // Open a reader for the hint file if it exists
         // Read through hint file and load the key_dir with it's entries
         // Add a reader for the associated merge file to the readers map
         if let Some(hint_file) = hint_file {
-            let mut hint_reader = BufReader::new(
-                fs::OpenOptions::new()
-                    .read(true)
-                    .open(hint_path(&path, &hint_file))?,
-            );
+            let mut hint_reader = BufReader::new(fs.open(&hint_path(&path, &hint_file))?);
 
-            while let Some((key, entry)) = read_next_hint(&mut hint_reader, hint_file.clone())? {
-                key_dir.insert(key, entry);
+            let mut merge_reader = BufReader::new(fs.open(&log_path(&path, &hint_file))?);
+            let merge_len = fs.file_len(merge_reader.get_ref())?;
+
+            let mut entries = Vec::new();
+            while let Some((key, entry)) =
+                read_next_hint(&mut hint_reader, hint_file, format_version)?
+            {
+                entries.push((key, entry));
             }
 
-            let merge_reader = BufReader::new(
-                fs::OpenOptions::new()
-                    .read(true)
-                    .open(log_path(&path, &hint_file))?,
-            );
-            readers.insert(hint_file, merge_reader);
+            // Hint records carry no checksum, so one pointing past the end of the merge file would only be noticed
+            // when its value is read. The merge file is itself a log, so the key directory is rebuilt from it instead.
+            if entries.iter().any(|(_, entry)| {
+                entry.value_pos.saturating_add(entry.value_len as u64) > merge_len
+            }) {
+                warn!(
+                    "hint file {} points past the end of its merge file, reading the merge file instead",
+                    hint_path(&path, &hint_file).display()
+                );
+                entries.clear();
+                while let Some((key, entry)) =
+                    read_next_entry(&mut merge_reader, hint_file, format_version)?
+                {
+                    entries.push((key, entry));
+                }
+            }
+            for (key, entry) in entries {
+                last_timestamp = last_timestamp.max(entry.timestamp);
+                key_dir.upsert(key, entry);
+            }
+
+            readers.insert(hint_file, FileReader::new(merge_reader));
         }
 
         // Open a reader for each log file and load the key_dir with it's entries
         // Add a reader for the log file to the readers map
+        // Records are applied in file id order and then in the order they were written, so the last record written for
+        // a key wins even if the clock went backwards between the writes. Timestamps are never compared.
         for file_id in log_files.iter() {
-            let mut reader = BufReader::new(
-                fs::OpenOptions::new()
-                    .read(true)
-                    .open(log_path(&path, &file_id))?,
-            );
+            let mut reader = BufReader::new(fs.open(&log_path(&path, file_id))?);
 
-            while let Some((key, entry)) = read_next_entry(&mut reader, *file_id)? {
-                key_dir.insert(key, entry);
+            while let Some((key, entry)) = read_next_entry(&mut reader, *file_id, format_version)? {
+                last_timestamp = last_timestamp.max(entry.timestamp);
+                key_dir.upsert(key, entry);
             }
 
-            readers.insert(*file_id, reader);
+            readers.insert(*file_id, FileReader::new(reader));
         }
 
-        // Get the last file id or 1 if there are no files
         // The last file is the current file that we write too
-        let active_file_id = match (log_files.last(), hint_file) {
-            (Some(&last_file_id), _) => last_file_id,
-            (None, Some(hint_file_id)) => hint_file_id + 1,
-            (None, None) => LOWEST_LOG_FILE_ID,
+        let ids = FileIdAllocator::resume(log_files.last().copied(), hint_file);
+        let writer = if read_only {
+            None
+        } else {
+            Some(BufWriter::new(open_active_log(&fs, &path, ids.active())?))
         };
-        let writer = BufWriter::new(
-            fs::OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(log_path(&path, &active_file_id))?,
-        );
 
         let path = Arc::new(path);
+        let obsolete = Arc::new(ObsoleteFiles::new(fs.clone(), hint_file.unwrap_or(0)));
+        let sealed_below = Arc::new(AtomicU64::new(ids.active()));
+        let usage = Arc::new(DiskUsage::default());
 
-        Ok(Bitcask {
+        let bitcask = Bitcask {
+            fs: fs.clone(),
             key_dir: Arc::new(key_dir),
             path: path.clone(),
             writer: Arc::new(Mutex::new(Writer {
+                fs: fs.clone(),
                 path: path.clone(),
                 writer,
-                active_file_id,
+                ids,
+                sealed_below: sealed_below.clone(),
+                usage: usage.clone(),
+                last_timestamp,
             })),
             reader: Reader {
+                fs,
                 path,
                 readers: RefCell::new(readers),
+                prefetch_size: options.prefetch_size,
+                mmap_sealed: options.mmap_sealed,
+                sealed_below,
+                obsolete: obsolete.clone(),
+                closed_below: Cell::new(obsolete.below()),
             },
-        })
-    }
-}
+            obsolete,
+            compaction: Arc::new(Mutex::new(())),
+            compacting: Arc::new(AtomicBool::new(false)),
+            usage,
+            options,
+            format_version: if read_only {
+                format_version
+            } else {
+                FORMAT_VERSION
+            },
+            is_new: data_files.is_empty(),
+        };
 
-impl Storage for Bitcask {
-    /// Compacts the storage.
-    fn compact(&self) -> StorageResult<()> {
-        // Notes:
-        // - Merging (compaction) can be done asynchronously instead of on open but it requires that the merge/hint files are not overwritten but
-        //   are instead incremented with a new file id. The readers do not necessarily have to be shared between threads in this scenario as the Key_dir
-        //   wil be updated as a part of the merge process with new entries that are not in the reader map. And when those are encountered a new reader can be opened.
-        // - Merging just becomes dumping the current key_dir into hint/merge files with the next incremented file_id since we acquire a lock on the writer as a part
-        //   of this process we can be sure that no new entries are being written to the active file during this time.
-        // - Since we are incrementing files, reads can still be served during the merge process as the old files are still available.
-        // - Once the key_dir is updated we can clean up the older log files and hint/merge files leaving only the latest merge/hint file with a new log file left.
-        //
-        // Merge process:
-        // 0. Acquire lock on writer
-        // 1. Create new merge/hint files
-        // 2. Iterate over key_dir and read the value for each entry
-        //  - Write the key and value to the merge file
-        //  - Write the key and value info to the hint file
-        //  - Update the key_dir with the new entry
-        // 3. Flush the merge/hint files
-        // 4. Set wrtier to new active log file
-        // 5. Release lock on writer
-        // 6. Remove all old log files
-        // 7. Remove old merge and hint files
+        if read_only {
+            bitcask.recount_disk_usage()?;
+            return Ok(bitcask);
+        }
 
-        let mut writer = self.writer.lock()?;
+        // Files merged by a compaction that could not be removed before the store was last closed.
+        if let Some(hint_file) = hint_file {
+            bitcask.obsolete.remove(
+                data_files
+                    .into_iter()
+                    .filter(|(file_id, _)| *file_id < hint_file)
+                    .map(|(_, file_path)| file_path),
+            )?;
+        }
 
-        let compaction_file_id = writer.active_file_id + 1;
-        let mut merge_writer = BufWriter::new(
-            fs::OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(log_path(&self.path, &compaction_file_id))?,
-        );
-        let mut hint_writer = BufWriter::new(
-            fs::OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(hint_path(&self.path, &compaction_file_id))?,
-        );
+        // Compaction rewrites every live value with the current format and starts a fresh active file,
+        // after which no file of the old format remains to be parsed on the next open. The merge file it will write is
+        // recorded first, so that a crash part way through is recognised on the next open rather than the new file
+        // being read in the old format or the old files in the new one.
+        if format_version < FORMAT_VERSION {
+            let merge_file_id = bitcask.writer.lock()?.ids.next_merge();
+            meta::write_migration(
+                &bitcask.fs,
+                &bitcask.path,
+                ENGINE,
+                format_version,
+                merge_file_id,
+            )?;
+            bitcask.compact()?;
+        }
+        write_format(&bitcask.fs, &bitcask.path)?;
+        meta::write(&bitcask.fs, &bitcask.path, ENGINE, FORMAT_VERSION)?;
+        bitcask.recount_disk_usage()?;
+
+        Ok(bitcask)
+    }
+
+    /// Gets the value of a key along with where and when it was written, for diagnosing read latency.
+    ///
+    /// Returns `None` if the key does not exist. A value reported as in the active file may be in a sealed one by the
+    /// time this returns, if the active file was rotated in the meantime.
+    pub fn get_with_segment(&self, key: String) -> StorageResult<Option<(String, ValueMetadata)>> {
+        validate_key(&key)?;
+        let (value, entry) = match self.read_live(&key)? {
+            Some(live) => live,
+            None => return Ok(None),
+        };
+        let active_file_id = self.writer.lock()?.active_file_id();
+        let metadata = ValueMetadata {
+            timestamp: entry.timestamp,
+            file_id: entry.file_id,
+            is_active_segment: entry.file_id == active_file_id,
+        };
+        Ok(Some((value, metadata)))
+    }
+
+    /// Lists the log files of the store in file id order.
+    ///
+    /// This is read-only introspection intended for debugging. Writes that are still buffered are not included in the
+    /// size of the active file, and files may be rotated or compacted away as soon as the list is returned.
+    pub fn segments(&self) -> StorageResult<Vec<SegmentInfo>> {
+        let active_file_id = self.writer.lock()?.active_file_id();
 
-        // Dump the current key_dir into the merge/hint files
-        // Update the key_dir with the new hint entry that points to the new merge file
+        let mut live_keys = HashMap::<u64, usize>::new();
+        let now = now();
         for item in self.key_dir.iter() {
-            let key = item.key();
-            let entry = item.value();
-            if entry.value_len == 0 {
+            let entry = item.value().load();
+            if entry.is_live(now) {
+                *live_keys.entry(entry.file_id).or_default() += 1;
+            }
+        }
+
+        let file_paths = self.fs.read_dir(&self.path)?;
+        let mut segments = Vec::new();
+        for file_path in file_paths.iter() {
+            if file_path.extension().and_then(|ext| ext.to_str()) != Some(LOG_FILE_EXT) {
                 continue;
             }
+            let file_id = match file_path
+                .file_stem()
+                .and_then(|file_id| file_id.to_str())
+                .and_then(|file_id| file_id.parse::<u64>().ok())
+            {
+                // Files past the active file are ignored by a store opened with `open_up_to`.
+                Some(file_id) if file_id <= active_file_id => file_id,
+                _ => continue,
+            };
+            segments.push(SegmentInfo {
+                file_id,
+                size_bytes: self.fs.len(file_path)?,
+                live_keys: live_keys.get(&file_id).copied().unwrap_or(0),
+                is_active: file_id == active_file_id,
+                has_hint: file_paths.contains(&hint_path(&self.path, &file_id)),
+            });
+        }
+        segments.sort_unstable_by_key(|segment| segment.file_id);
+        Ok(segments)
+    }
 
-            let value = self.reader.read_value(entry)?;
+    /// Reads every record of the log in the order it was written, including the ones that have since been
+    /// overwritten or removed.
+    ///
+    /// The log files are read in file id order and each file from start to end. Compaction replaces the files it
+    /// merges with a single file holding only the live values, so the history before the last compaction is not
+    /// available beyond the versions kept by `BitcaskOptions::retain_versions`. The records are read lazily, and a
+    /// compaction that runs during the replay may remove files before they are reached, failing the replay. Buffered
+    /// writes are flushed first so that every completed write is included.
+    pub fn replay(&self) -> StorageResult<impl Iterator<Item = StorageResult<LogRecord>>> {
+        let mut file_ids: Vec<u64> = {
+            let mut writer = self.writer.lock()?;
+            writer.flush()?;
+            let active_file_id = writer.active_file_id();
+            let merge_file_id = self.obsolete.below();
+            log_file_ids(&self.fs, &self.path)?
+                .into_iter()
+                .filter(|file_id| (merge_file_id..=active_file_id).contains(file_id))
+                .collect()
+        };
+        file_ids.sort_unstable();
+        Ok(Replay {
+            fs: self.fs.clone(),
+            path: self.path.clone(),
+            file_ids: file_ids.into_iter(),
+            current: None,
+            format_version: self.format_version,
+        })
+    }
 
-            let merge_entry = write_value(&mut merge_writer, compaction_file_id, &key, &value)?;
+    /// Lists the keys whose current value was written at or after the given time, in milliseconds since the unix
+    /// epoch.
+    ///
+    /// The cutoff is inclusive, so a key written in the same millisecond as `since` is included rather than risk
+    /// missing it. Values written by versions that recorded seconds have their timestamps rounded down to the second.
+    /// Removed keys are not listed.
+    pub fn keys_modified_since(&self, since: u64) -> StorageResult<Vec<String>> {
+        let now = now();
+        Ok(self
+            .key_dir
+            .iter()
+            .filter(|item| {
+                let entry = item.value().load();
+                entry.is_live(now) && entry.timestamp >= since
+            })
+            .map(|item| item.key().clone())
+            .collect())
+    }
 
-            write_hint(&mut hint_writer, &key, &merge_entry)?;
+    /// Reports statistics about the store.
+    ///
+    /// The byte counts are kept up to date by every write and compaction rather than read from the files, so they can
+    /// be checked often. Files a compaction merged are not counted even if they could not be removed yet.
+    pub fn stats(&self) -> BitcaskStats {
+        let log_bytes = self.usage.log_bytes.load(Ordering::Relaxed);
+        let total_bytes = log_bytes + self.usage.hint_bytes.load(Ordering::Relaxed);
+        let dead_bytes = self.usage.dead_bytes();
+        BitcaskStats {
+            keys: self.live_keys().count(),
+            index_memory_estimate: self.index_memory_estimate(),
+            total_bytes,
+            live_bytes: self.usage.live_bytes.load(Ordering::Relaxed),
+            dead_bytes,
+            fragmentation_ratio: match total_bytes {
+                0 => 0.0,
+                total_bytes => dead_bytes as f64 / total_bytes as f64,
+            },
+            avg_segment_size: log_bytes
+                .checked_div(self.usage.log_files.load(Ordering::Relaxed))
+                .unwrap_or(0),
+        }
+    }
 
-            self.key_dir.insert(key.clone(), merge_entry);
+    /// Calls `f` with every key that has not been removed or expired, in order.
+    ///
+    /// Unlike `list_keys`, the keys are borrowed from the key directory rather than cloned into a vector. Writes made
+    /// while iterating may or may not be seen.
+    pub fn for_each_key(&self, mut f: impl FnMut(&str)) {
+        for item in self.live_keys() {
+            f(item.key());
         }
+    }
 
-        merge_writer.flush()?;
-        hint_writer.flush()?;
+    // Counts the bytes in the store's files from scratch, see `BitcaskStats`.
+    fn recount_disk_usage(&self) -> StorageResult<()> {
+        let file_ids = self.obsolete.below()..=self.writer.lock()?.active_file_id();
+        let (mut log_files, mut log_bytes, mut hint_bytes) = (0, 0, 0);
+        for file_path in self.fs.read_dir(&self.path)? {
+            let file_id = file_path
+                .file_stem()
+                .and_then(|file_id| file_id.to_str())
+                .and_then(|file_id| file_id.parse::<u64>().ok());
+            if !file_id.is_some_and(|file_id| file_ids.contains(&file_id)) {
+                continue;
+            }
+            match file_path.extension().and_then(|ext| ext.to_str()) {
+                Some(LOG_FILE_EXT) => {
+                    log_files += 1;
+                    log_bytes += self.fs.len(&file_path)?;
+                }
+                Some(HINT_FILE_EXT) => hint_bytes += self.fs.len(&file_path)?,
+                _ => {}
+            }
+        }
+        let live_bytes = self
+            .key_dir
+            .iter()
+            .map(|item| {
+                let entry = item.value().load();
+                live_len(item.key(), &entry)
+            })
+            .sum();
+        self.usage.log_files.store(log_files, Ordering::Relaxed);
+        self.usage.log_bytes.store(log_bytes, Ordering::Relaxed);
+        self.usage.hint_bytes.store(hint_bytes, Ordering::Relaxed);
+        self.usage.live_bytes.store(live_bytes, Ordering::Relaxed);
+        Ok(())
+    }
 
-        writer.set_writer(compaction_file_id + 1)?;
+    // Points the key_dir at a record written to the active file, moving the live bytes from the record it replaces.
+    //
+    // Callers must hold the writer lock, and count the bytes of the record as written themselves.
+    fn index(&self, key: String, entry: Entry) {
+        let live = live_len(&key, &entry);
+        let replaced = self
+            .key_dir
+            .upsert(key.clone(), entry)
+            .map_or(0, |previous| live_len(&key, &previous));
+        self.usage.live_bytes.fetch_add(live, Ordering::Relaxed);
+        self.usage.live_bytes.fetch_sub(replaced, Ordering::Relaxed);
+    }
 
-        // Release the lock on the writer as the key_dir is now updated
-        drop(writer);
+    // Whether an entry is a tombstone written within `BitcaskOptions::tombstone_grace` of the given time, which
+    // compaction keeps.
+    fn within_tombstone_grace(&self, entry: &Entry, now: u64) -> bool {
+        let grace = self.options.tombstone_grace.as_millis() as u64;
+        entry.tombstone && now.saturating_sub(entry.timestamp) < grace
+    }
 
-        // Anything with file id lower than compaction_file_id can now be safely removed as nothing in the key_dir should point to these files
-        //
-        let mut readers = self.reader.readers.borrow_mut();
-        fs::read_dir(self.path.as_ref())?
-            .filter_map(|entry| {
-                let entry = entry.ok()?;
-                let file_path = entry.path();
-                let stem = file_path
-                    .file_stem()
-                    .and_then(|file_id| file_id.to_str())
-                    .and_then(|file_id| file_id.parse::<u64>().ok());
-                match stem {
-                    Some(file_id) if file_id < compaction_file_id => {
-                        readers.remove(&file_id);
-                        Some(fs::remove_file(file_path))
+    // Iterates over the key_dir entries that have not been removed or expired, in order.
+    fn live_keys(&self) -> impl Iterator<Item = KeyDirEntry<'_>> {
+        let now = now();
+        self.key_dir
+            .iter()
+            .filter(move |item| item.value().load().is_live(now))
+    }
+
+    /// Estimates the size in bytes of the in-memory key directory, for capacity planning.
+    ///
+    /// The estimate is the length of every key plus a fixed overhead per entry, along with the bloom filter if there
+    /// is one. Removed keys are included as the key directory keeps them until the next compaction.
+    /// It ignores allocator overhead, so the true usage will be somewhat higher.
+    pub fn index_memory_estimate(&self) -> usize {
+        self.key_dir.memory_estimate()
+    }
+
+    /// Sets every key/value pair of `entries` in order, returning the number of pairs written.
+    ///
+    /// This is a fast path for the initial load of a store. Records are buffered and written in large chunks rather
+    /// than one at a time, and the key directory is updated once per chunk, so concurrent readers may not see a key
+    /// until several after it have been written. Durability is relaxed to match: the records are only synced to disk
+    /// once every pair has been written, and a crash or an error part way through may keep any number of the pairs
+    /// that came before it. Other writes wait until the load has finished.
+    pub fn bulk_load(
+        &self,
+        entries: impl IntoIterator<Item = (String, String)>,
+    ) -> StorageResult<usize> {
+        self.write(|writer| {
+            let mut count = 0;
+            let mut records = Cursor::new(Vec::new());
+            let mut batch = Vec::new();
+            let mut file_len = writer.active_len()?;
+            for (key, value) in entries {
+                validate_key(&key)?;
+                let timestamp = writer.next_timestamp()?;
+                let mut entry = write_value(
+                    &mut records,
+                    writer.active_file_id(),
+                    &key,
+                    Some(&value),
+                    timestamp,
+                    None,
+                )?;
+                entry.value_pos += file_len;
+                batch.push((key, entry));
+                count += 1;
+
+                // Rotate at the same size `append` would, otherwise write the records once enough have built up.
+                let rotate = file_len + records.position() > self.options.max_log_size;
+                if rotate || records.position() >= BULK_LOAD_BUFFER_SIZE {
+                    writer.append_records(records.get_ref())?;
+                    self.usage.add_log_bytes(records.position());
+                    file_len += records.position();
+                    records = Cursor::new(Vec::new());
+                    for (key, entry) in batch.drain(..) {
+                        self.index(key, entry);
                     }
-                    _ => None,
                 }
-            })
-            .collect::<Result<Vec<_>, _>>()?;
-
-        Ok(())
+                if rotate {
+                    writer.rotate()?;
+                    file_len = 0;
+                }
+            }
+            writer.append_records(records.get_ref())?;
+            self.usage.add_log_bytes(records.position());
+            for (key, entry) in batch {
+                self.index(key, entry);
+            }
+            writer.flush()?;
+            Ok(count)
+        })
     }
 
-    /// Gets the string value of a given string key.
+    /// Reports what `compact` would do if it were run now, without changing anything.
     ///
-    /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> StorageResult<Option<String>> {
-        if let Some(entry) = self.key_dir.get(&key) {
-            let entry = entry.value();
-            if entry.value_len == 0 {
-                return Ok(None);
+    /// Writes made between planning and compacting change what a compaction actually does, so the plan is only
+    /// exact for a store that is not being written to. The estimate only counts the current version of each key, even
+    /// with `BitcaskOptions::retain_versions`.
+    pub fn compact_plan(&self) -> StorageResult<CompactionPlan> {
+        // Compaction merges every file up to and including the active one, see `compact`.
+        let active_file_id = self.writer.lock()?.active_file_id();
+
+        let mut files_to_remove = Vec::new();
+        for file_path in self.fs.read_dir(&self.path)? {
+            let file_id = file_path
+                .file_stem()
+                .and_then(|file_id| file_id.to_str())
+                .and_then(|file_id| file_id.parse::<u64>().ok());
+            match file_id {
+                Some(file_id) if file_id <= active_file_id => files_to_remove.push(file_id),
+                _ => {}
             }
+        }
+        files_to_remove.sort_unstable();
+        files_to_remove.dedup();
 
-            return Ok(Some(self.reader.read_value(entry)?));
+        let now = now();
+        let mut live_keys = 0;
+        let mut estimated_bytes_after = 0;
+        for item in self.key_dir.iter() {
+            let entry = item.value().load();
+            if entry.is_live(now) {
+                live_keys += 1;
+            } else if !self.within_tombstone_grace(&entry, now) {
+                continue;
+            }
+            estimated_bytes_after += record_len(item.key(), &entry);
+            estimated_bytes_after += hint_len(item.key());
         }
-        Ok(None)
+
+        Ok(CompactionPlan {
+            files_to_remove,
+            live_keys,
+            estimated_bytes_after,
+        })
     }
 
-    /// Sets the value of a string key to a string.
+    /// Writes a compacted copy of the store into `dest`, leaving the store itself unchanged.
     ///
-    /// If the key already exists, the previous value will be overwritten.
-    fn set(&self, key: String, value: String) -> StorageResult<()> {
-        let mut writer = self.writer.lock().unwrap();
-        let entry = writer.write_value(&key, &value)?;
+    /// The copy holds the current version of every live key, with the time it was written and when it expires, in a
+    /// single log and hint file pair, as an in-place compaction would leave it. `dest` is created if it does not exist
+    /// and must not already hold a store. Reads and writes continue to be served while the copy is written, and writes
+    /// made in the meantime may or may not be included. In-place compactions wait for the copy to finish.
+    pub fn compact_into(&self, dest: impl AsRef<Path>) -> StorageResult<CompactionStats> {
+        let dest = dest.as_ref();
+        self.fs.create_dir_all(dest)?;
+        if !log_file_ids(&self.fs, dest)?.is_empty() || meta::read(&self.fs, dest)?.is_some() {
+            return Err(StorageError::Io(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already holds a store", dest.display()),
+            )));
+        }
+
+        // The files the key_dir points into are only removed by a compaction.
+        let _compaction = self.compaction.lock()?;
+
+        let merge_file_id = LOWEST_LOG_FILE_ID;
+        let mut merge_writer = BufWriter::new(self.fs.create(&log_path(dest, &merge_file_id))?);
+        let mut hint_writer = BufWriter::new(self.fs.create(&hint_tmp_path(dest, &merge_file_id))?);
+        let mut live_keys = 0;
+        for item in self.live_keys() {
+            let entry = item.value().load();
+            let value = self.reader.read_value(item.key(), &entry)?;
+            let merge_entry = write_value(
+                &mut merge_writer,
+                merge_file_id,
+                item.key(),
+                Some(&value),
+                entry.timestamp,
+                entry.expires_at,
+            )?;
+            write_hint(&mut hint_writer, item.key(), &merge_entry)?;
+            live_keys += 1;
+        }
+
+        let merge_file = merge_writer.into_inner().map_err(|e| e.into_error())?;
+        let hint_file = hint_writer.into_inner().map_err(|e| e.into_error())?;
+        self.fs.sync_all(&merge_file)?;
+        self.fs.sync_all(&hint_file)?;
+        let bytes_written = self.fs.file_len(&merge_file)? + self.fs.file_len(&hint_file)?;
+        self.fs.rename(
+            &hint_tmp_path(dest, &merge_file_id),
+            &hint_path(dest, &merge_file_id),
+        )?;
+        self.fs.sync_dir(dest)?;
+        write_format(&self.fs, dest)?;
+        meta::write(&self.fs, dest, ENGINE, FORMAT_VERSION)?;
+
+        Ok(CompactionStats {
+            live_keys,
+            bytes_written,
+        })
+    }
+
+    // Runs `f` while holding the writer lock.
+    //
+    // If `f` rotated the active file, a background compaction is considered once the lock has been released.
+    fn write<T>(&self, f: impl FnOnce(&mut Writer<F>) -> StorageResult<T>) -> StorageResult<T> {
+        let mut writer = self.writer.lock()?;
+        let active_file_id = writer.active_file_id();
+        let result = f(&mut writer);
+        let rotated = writer.active_file_id() != active_file_id;
+        drop(writer);
+
+        if rotated {
+            self.compact_in_background_if_needed();
+        }
+
+        result
+    }
+
+    // Appends a key/value pair to the active file and points the key_dir at it, a `None` value being a tombstone.
+    fn append(
+        &self,
+        writer: &mut Writer<F>,
+        key: String,
+        value: Option<&String>,
+    ) -> StorageResult<()> {
+        self.append_expiring(writer, key, value, None)
+    }
+
+    // Like `append`, but the value expires at the given time in milliseconds since the unix epoch.
+    fn append_expiring(
+        &self,
+        writer: &mut Writer<F>,
+        key: String,
+        value: Option<&String>,
+        expires_at: Option<u64>,
+    ) -> StorageResult<()> {
+        let entry = writer.write_value(&key, value, expires_at)?;
         // If the size of the active file is greater than the threshold we will create a new active file
         //
         // Adding the pos of the last value written to the end of the file with it's length will
         // give us the total size in bytes of the active file.
-        if entry.value_pos + (entry.value_len as u64) > LOG_SIZE_THRESHOLD {
-            let active_file_id = writer.active_file_id + 1;
-            writer.set_writer(active_file_id)?;
+        if entry.value_pos + (entry.value_len as u64) > self.options.max_log_size {
+            writer.rotate()?;
         }
 
-        self.key_dir.insert(key, entry);
-
+        // The record is written to the file unbuffered before the key_dir points at it, so any clone that finds the
+        // entry can read the value, opening the file if it has not read from it before.
+        self.usage.add_log_bytes(record_len(&key, &entry));
+        self.index(key, entry);
         Ok(())
     }
 
-    /// Remove a given key.
-    ///
-    /// Returns `StorageError::KeyNotFound` if the key does not exist.
-    fn remove(&self, key: String) -> StorageResult<()> {
-        if self.key_dir.get(&key).is_none() {
-            return Err(StorageError::KeyNotFound);
-        }
-        let entry = self
-            .writer
-            .lock()
-            .unwrap()
-            .write_value(&key, &TOMBSTONE.to_string())?;
-        self.key_dir.insert(key, entry);
-        Ok(())
+    // Applies `f` to the queue stored at a key while holding the writer lock and writes the updated queue back.
+    //
+    // An emptied queue removes the key rather than leaving an empty queue behind.
+    fn update_queue<T>(
+        &self,
+        key: String,
+        f: impl FnOnce(&mut VecDeque<String>) -> T,
+    ) -> StorageResult<T> {
+        validate_key(&key)?;
+        self.write(|writer| {
+            let mut queue = queue::decode(self.get(key.clone())?.as_deref())?;
+            let existed = !queue.is_empty();
+            let result = f(&mut queue);
+            if !queue.is_empty() {
+                self.append(writer, key, Some(&queue::encode(&queue)))?;
+            } else if existed {
+                self.append(writer, key, None)?;
+            }
+            Ok(result)
+        })
     }
 
-    /// List all keys.
-    fn list_keys(&self) -> Vec<String> {
-        // Keys that have been removed will still have an entry in the key_dir
-        // but the value_len will be 0.
+    // Whether the key currently has a value, removed keys keep a tombstone entry in the key_dir and expired keys keep
+    // theirs until the next compaction.
+    fn contains_key(&self, key: &str) -> bool {
         self.key_dir
-            .iter()
-            .filter(|entry| entry.value().value_len != 0)
-            .map(|entry| entry.key().clone())
-            .collect()
+            .get(key)
+            .is_some_and(|entry| entry.value().load().is_live(now()))
     }
-}
 
-#[derive(Debug, Clone)]
+    // Reads the live value of a key along with its entry.
+    //
+    // A compaction may merge and remove the file an entry points to between the entry being loaded and its value
+    // being read, in which case the key is looked up again to find where its value was merged to.
+    fn read_live(&self, key: &str) -> StorageResult<Option<(String, Entry)>> {
+        loop {
+            let entry = match self.key_dir.get(key) {
+                Some(entry) => entry.value().load(),
+                None => return Ok(None),
+            };
+            if !entry.is_live(now()) {
+                return Ok(None);
+            }
+            match self.reader.read_value(key, &entry) {
+                Err(StorageError::Io(e))
+                    if e.kind() == io::ErrorKind::NotFound
+                        && entry.file_id < self.obsolete.below() =>
+                {
+                    continue
+                }
+                result => return Ok(Some((result?, entry))),
+            }
+        }
+    }
+
+    // Writes the live values of the files sealed below `compaction_file_id` into the merge and hint files of a
+    // compaction, returning the entries to install, see `compact`.
+    //
+    // The hint file is only renamed into place once both files have been written in full and synced to disk.
+    fn merge_sealed(&self, compaction_file_id: u64) -> StorageResult<Merge> {
+        let mut merge_writer =
+            BufWriter::new(self.fs.append(&log_path(&self.path, &compaction_file_id))?);
+        let mut hint_writer = BufWriter::new(
+            self.fs
+                .create(&hint_tmp_path(&self.path, &compaction_file_id))?,
+        );
+
+        // The key_dir only knows where the current version of each key is, so older versions to retain are found by
+        // reading the sealed files.
+        let retained = self.options.retain_versions.max(1) - 1;
+        let mut history = match retained {
+            0 => HashMap::new(),
+            _ => self.sealed_history(compaction_file_id, retained + 1)?,
+        };
+
+        // Dump the sealed part of the key_dir into the merge/hint files.
+        // Expired values and tombstones past their grace period are not copied, they are dropped from the key_dir
+        // during install instead.
+        let now = now();
+        let mut merged = Vec::<(String, Entry, Option<Entry>)>::new();
+        // The bytes of the records and hints written so far, as the merge and hint files are only flushed at the end.
+        let mut bytes_written = 0;
+        for (keys, item) in self.key_dir.iter().enumerate() {
+            if keys > 0 && keys % COMPACTION_PROGRESS_INTERVAL == 0 {
+                info!(keys, bytes_written, "compaction progress");
+            }
+            let key = item.key();
+            let entry = item.value().load();
+            let sealed = entry.file_id < compaction_file_id;
+            if !entry.is_live(now) {
+                if !sealed {
+                    continue;
+                }
+                if self.within_tombstone_grace(&entry, now) {
+                    let merge_entry = write_value(
+                        &mut merge_writer,
+                        compaction_file_id,
+                        key,
+                        None,
+                        entry.timestamp,
+                        None,
+                    )?;
+                    write_hint(&mut hint_writer, key, &merge_entry)?;
+                    bytes_written += record_len(key, &merge_entry) + hint_len(key);
+                    merged.push((key.clone(), entry, Some(merge_entry)));
+                } else {
+                    merged.push((key.clone(), entry, None));
+                }
+                continue;
+            }
+
+            // Older versions are written before the current one, so that it is still the last one read back for the
+            // key if the hint file is ever missing. Only the current version is hinted.
+            if let Some(mut versions) = history.remove(key) {
+                if sealed {
+                    versions.pop_back();
+                }
+                while versions.len() > retained {
+                    versions.pop_front();
+                }
+                for version in versions {
+                    let value = self.reader.read_value(key, &version)?;
+                    let version_entry = write_value(
+                        &mut merge_writer,
+                        compaction_file_id,
+                        key,
+                        Some(&value),
+                        version.timestamp,
+                        version.expires_at,
+                    )?;
+                    bytes_written += record_len(key, &version_entry);
+                }
+            }
+            if !sealed {
+                continue;
+            }
+
+            let value = self.reader.read_value(key, &entry)?;
+
+            // The value keeps the time it was written rather than the time it was merged, and the time it expires.
+            let merge_entry = write_value(
+                &mut merge_writer,
+                compaction_file_id,
+                key,
+                Some(&value),
+                entry.timestamp,
+                entry.expires_at,
+            )?;
+
+            write_hint(&mut hint_writer, key, &merge_entry)?;
+            bytes_written += record_len(key, &merge_entry) + hint_len(key);
+
+            merged.push((key.clone(), entry, Some(merge_entry)));
+        }
+
+        // The merge and hint files are synced before the hint file is renamed into place, and the rename before the
+        // merged files can be removed, so that the merged values are on disk before the only other copy is gone.
+        merge_writer.flush()?;
+        hint_writer.flush()?;
+        self.fs.sync_data(merge_writer.get_ref())?;
+        self.fs.sync_data(hint_writer.get_ref())?;
+        let merge_bytes = self.fs.file_len(merge_writer.get_ref())?;
+        let hint_bytes = self.fs.file_len(hint_writer.get_ref())?;
+        self.fs.rename(
+            &hint_tmp_path(&self.path, &compaction_file_id),
+            &hint_path(&self.path, &compaction_file_id),
+        )?;
+        self.fs.sync_dir(&self.path)?;
+        Ok(Merge {
+            merged,
+            merge_bytes,
+            hint_bytes,
+        })
+    }
+
+    // Reads the files that a compaction into `compaction_file_id` merges, returning the latest versions of each key up
+    // to `versions` of them, oldest first. A key's versions from before it was last removed are not included.
+    fn sealed_history(
+        &self,
+        compaction_file_id: u64,
+        versions: usize,
+    ) -> StorageResult<HashMap<String, VecDeque<Entry>>> {
+        // Files below the previous merge file are already merged into it, even if they could not be removed yet.
+        let merge_file_id = self.obsolete.below();
+        let mut file_ids: Vec<u64> = log_file_ids(&self.fs, &self.path)?
+            .into_iter()
+            .filter(|file_id| (merge_file_id..compaction_file_id).contains(file_id))
+            .collect();
+        file_ids.sort_unstable();
+
+        let mut history = HashMap::<String, VecDeque<Entry>>::new();
+        for file_id in file_ids {
+            let mut reader = BufReader::new(self.fs.open(&log_path(&self.path, &file_id))?);
+            while let Some((key, entry)) =
+                read_next_entry(&mut reader, file_id, self.format_version)?
+            {
+                let key_versions = history.entry(key).or_default();
+                if entry.tombstone {
+                    key_versions.clear();
+                    continue;
+                }
+                if key_versions.len() == versions {
+                    key_versions.pop_front();
+                }
+                key_versions.push_back(entry);
+            }
+        }
+        Ok(history)
+    }
+
+    // Starts a compaction on a background thread if there are more log files than `max_log_files` allows or more dead
+    // bytes than `max_dead_bytes` allows.
+    //
+    // Only one background compaction runs at a time. Once it finishes the log files are counted again, as rotations
+    // that happened while it was running would have skipped starting another one.
+    fn compact_in_background_if_needed(&self) {
+        let too_many_dead_bytes = self
+            .options
+            .max_dead_bytes
+            .is_some_and(|max_dead_bytes| self.usage.dead_bytes() > max_dead_bytes);
+        let too_many_log_files = match self.options.max_log_files {
+            Some(max_log_files) => match log_file_count(&self.fs, &self.path) {
+                Ok(count) => count > max_log_files,
+                Err(e) => {
+                    error!("unable to count log files: {}", e);
+                    false
+                }
+            },
+            None => false,
+        };
+        if !too_many_dead_bytes && !too_many_log_files {
+            return;
+        }
+        if self
+            .compacting
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return;
+        }
+        let store = self.clone();
+        thread::spawn(move || {
+            let result = store.compact();
+            store.compacting.store(false, Ordering::SeqCst);
+            match result {
+                Ok(()) => store.compact_in_background_if_needed(),
+                Err(e) => error!("background compaction failed: {}", e),
+            }
+        });
+    }
+}
+
+impl<F: FileSystem> Storage for Bitcask<F> {
+    /// Flushes any buffered writes to the active log file and syncs it to disk.
+    ///
+    /// The active file is also flushed on a best-effort basis when the last clone of the store is dropped.
+    fn flush(&self) -> StorageResult<()> {
+        self.writer.lock()?.flush()
+    }
+
+    fn data_dir(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+
+    /// Whether no log or hint files were found when the store was opened.
+    ///
+    /// Opening a store creates its active log file, so a store opened once is not new the next time even if nothing
+    /// was written to it.
+    fn is_new(&self) -> bool {
+        self.is_new
+    }
+
+    /// Compacts the storage.
+    ///
+    /// Reads and writes continue to be served while the compaction is running.
+    /// The merged files are removed once no clone of the store has them open. Other clones close their handles to
+    /// them on their next read, so on Windows, which cannot remove an open file, removal may be left to a later
+    /// compaction or to the store being closed.
+    ///
+    /// The compaction runs in a `compaction` span and logs info events as it starts, every 100,000 keys it goes
+    /// through and once it has finished, so that a long merge can be followed.
+    fn compact(&self) -> StorageResult<()> {
+        // Compaction is split into three phases so that the writer lock is only held briefly:
+        //
+        // 1. Seal: Acquire the writer lock just long enough to reserve `compaction_file_id` for the merge file and move
+        //    the writer onto a fresh active file at `compaction_file_id + 1`. Every file below `compaction_file_id` is now
+        //    immutable, so it can be read without coordinating with writers.
+        // 2. Merge: Without holding the writer lock, copy every live value that still points into a sealed file into the
+        //    merge file and record its new location in the hint file. Reads are served from the sealed files throughout.
+        // 3. Install: Re-acquire the writer lock and swap the merged entries into the key_dir, skipping any key that was
+        //    overwritten or removed while we were merging. Writers update the key_dir while holding the writer lock, so
+        //    the comparison cannot race with them.
+        //
+        // Once installed, nothing in the key_dir points below `compaction_file_id` and the sealed files can be removed.
+        //
+        // The hint file is written to a temporary path and only renamed into place once it is complete, so a crash
+        // mid-merge leaves the sealed log files as the source of truth on the next open.
+
+        // Only one compaction may run at a time as each one removes the files below its own merge file.
+        let _compaction = self.compaction.lock()?;
+        let span = info_span!("compaction", merge_file_id = field::Empty);
+        let _span = span.enter();
+        let started = Instant::now();
+
+        let compaction_file_id = self.writer.lock()?.seal_for_merge()?;
+        span.record("merge_file_id", compaction_file_id);
+        info!(live_keys = self.live_keys().count(), "compaction started");
+
+        let Merge {
+            merged,
+            merge_bytes,
+            hint_bytes,
+        } = match self.merge_sealed(compaction_file_id) {
+            Ok(merge) => merge,
+            Err(e) => {
+                // A partly written merge file would be read as a log file on the next open, so it is removed to leave
+                // the sealed files as the only copy of their values.
+                for file_path in [
+                    log_path(&self.path, &compaction_file_id),
+                    hint_tmp_path(&self.path, &compaction_file_id),
+                ] {
+                    match self.fs.remove_file(&file_path) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => warn!(
+                            "unable to remove {} after a failed compaction: {}",
+                            file_path.display(),
+                            e
+                        ),
+                        _ => {}
+                    }
+                }
+                return Err(e);
+            }
+        };
+        self.usage.log_files.fetch_add(1, Ordering::Relaxed);
+        self.usage.add_log_bytes(merge_bytes);
+        self.usage
+            .hint_bytes
+            .fetch_add(hint_bytes, Ordering::Relaxed);
+
+        // Install the merged entries, leaving alone anything that changed since it was read.
+        let writer = self.writer.lock()?;
+        let (mut installed, mut dropped_keys) = (0, 0);
+        for (key, sealed_entry, merge_entry) in merged {
+            let current = match self.key_dir.get(&key) {
+                Some(current) if current.value().load() == sealed_entry => current,
+                _ => continue,
+            };
+            match merge_entry {
+                Some(merge_entry) => {
+                    installed += 1;
+                    current.value().store(merge_entry)
+                }
+                None => {
+                    dropped_keys += 1;
+                    // Expired values are live until they are dropped here, tombstones never were.
+                    let dropped = live_len(&key, &sealed_entry);
+                    self.usage.live_bytes.fetch_sub(dropped, Ordering::Relaxed);
+                    current.remove();
+                }
+            }
+        }
+        drop(writer);
+
+        // Anything with file id lower than compaction_file_id can now be safely removed as nothing in the key_dir should
+        // point to these files. Other clones of the store may still hold them open until their next read, so a file
+        // that cannot be removed yet is left for a later compaction to retry.
+        let merged_from = self.obsolete.below();
+        self.obsolete.mark_below(compaction_file_id);
+        self.reader.close_obsolete();
+        let mut files = Vec::new();
+        for file_path in self.fs.read_dir(&self.path)? {
+            let stem = file_path
+                .file_stem()
+                .and_then(|file_id| file_id.to_str())
+                .and_then(|file_id| file_id.parse::<u64>().ok());
+            if stem.is_some_and(|file_id| file_id < compaction_file_id) {
+                // The merged files stop counting towards the store now, whether or not they can be removed yet. Files
+                // left over from earlier compactions already have.
+                let len = if stem.is_some_and(|file_id| file_id >= merged_from) {
+                    self.fs.len(&file_path)?
+                } else {
+                    0
+                };
+                match file_path.extension().and_then(|ext| ext.to_str()) {
+                    Some(LOG_FILE_EXT) => {
+                        if stem.is_some_and(|file_id| file_id >= merged_from) {
+                            self.usage.log_files.fetch_sub(1, Ordering::Relaxed);
+                        }
+                        self.usage.log_bytes.fetch_sub(len, Ordering::Relaxed);
+                    }
+                    Some(HINT_FILE_EXT) => {
+                        self.usage.hint_bytes.fetch_sub(len, Ordering::Relaxed);
+                    }
+                    _ => {}
+                }
+                files.push(file_path);
+            }
+        }
+        let merged_files = files.len();
+        self.obsolete.remove(files)?;
+        info!(
+            merged_keys = installed,
+            dropped_keys,
+            merged_files,
+            bytes_written = merge_bytes + hint_bytes,
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "compaction finished"
+        );
+        Ok(())
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get(&self, key: String) -> StorageResult<Option<String>> {
+        validate_key(&key)?;
+        Ok(self.read_live(&key)?.map(|(value, _)| value))
+    }
+
+    /// Gets the string value of a given string key along with the time it was last written.
+    ///
+    /// The timestamp is the one recorded in the value's log entry, which compaction preserves.
+    fn get_with_metadata(&self, key: String) -> StorageResult<Option<(String, Option<u64>)>> {
+        validate_key(&key)?;
+        Ok(self
+            .read_live(&key)?
+            .map(|(value, entry)| (value, Some(entry.timestamp))))
+    }
+
+    /// Checks the key_dir for a live entry, so no value is read.
+    fn exists(&self, key: String) -> StorageResult<bool> {
+        validate_key(&key)?;
+        Ok(self.contains_key(&key))
+    }
+
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set(&self, key: String, value: String) -> StorageResult<()> {
+        validate_key(&key)?;
+        self.write(|writer| self.append(writer, key, Some(&value)))
+    }
+
+    /// Records the expiry in the value's log entry. Expired values are skipped by reads and dropped by compaction.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> StorageResult<()> {
+        validate_key(&key)?;
+        let expires_at = now().saturating_add(ttl.as_millis() as u64);
+        self.write(|writer| self.append_expiring(writer, key, Some(&value), Some(expires_at)))
+    }
+
+    /// Sets the value of a string key to a string, reporting whether the key was created or updated.
+    fn put(&self, key: String, value: String) -> StorageResult<PutOutcome> {
+        validate_key(&key)?;
+        self.write(|writer| {
+            let outcome = if self.contains_key(&key) {
+                PutOutcome::Updated
+            } else {
+                PutOutcome::Created
+            };
+            self.append(writer, key, Some(&value))?;
+            Ok(outcome)
+        })
+    }
+
+    /// Sets the value of a string key to a string only if the key does not already exist.
+    ///
+    /// Returns `true` if the value was written.
+    fn set_if_absent(&self, key: String, value: String) -> StorageResult<bool> {
+        validate_key(&key)?;
+        self.write(|writer| {
+            if self.contains_key(&key) {
+                return Ok(false);
+            }
+            self.append(writer, key, Some(&value))?;
+            Ok(true)
+        })
+    }
+
+    /// Gets the value of a string key, setting it to `default` first if the key does not exist.
+    fn get_or_set(&self, key: String, default: String) -> StorageResult<String> {
+        validate_key(&key)?;
+        self.write(|writer| {
+            if let Some(value) = self.get(key.clone())? {
+                return Ok(value);
+            }
+            self.append(writer, key, Some(&default))?;
+            Ok(default)
+        })
+    }
+
+    /// Appends the live value again with the current time and the same expiry, as records are never updated in place.
+    ///
+    /// Returns `StorageError::KeyNotFound` if the key does not exist.
+    fn touch(&self, key: String) -> StorageResult<()> {
+        validate_key(&key)?;
+        self.write(|writer| {
+            let (value, entry) = self.read_live(&key)?.ok_or(StorageError::KeyNotFound)?;
+            self.append_expiring(writer, key, Some(&value), entry.expires_at)
+        })
+    }
+
+    /// Remove a given key.
+    ///
+    /// Returns `StorageError::KeyNotFound` if the key does not exist.
+    fn remove(&self, key: String) -> StorageResult<()> {
+        if !self.remove_optional(key)? {
+            return Err(StorageError::KeyNotFound);
+        }
+        Ok(())
+    }
+
+    /// Remove a given key if it exists, appending a tombstone only if it does.
+    ///
+    /// Returns whether the key existed.
+    fn remove_optional(&self, key: String) -> StorageResult<bool> {
+        validate_key(&key)?;
+        self.write(|writer| {
+            if !self.contains_key(&key) {
+                return Ok(false);
+            }
+            self.append(writer, key, None)?;
+            Ok(true)
+        })
+    }
+
+    /// Remove every key starting with the given prefix.
+    ///
+    /// Returns the number of keys removed.
+    fn remove_prefix(&self, prefix: String) -> StorageResult<usize> {
+        self.write(|writer| {
+            let now = now();
+            let keys: Vec<String> = self
+                .key_dir
+                .scan_prefix(&prefix)
+                .filter(|entry| entry.value().load().is_live(now))
+                .map(|entry| entry.key().clone())
+                .collect();
+            for key in keys.iter() {
+                self.append(writer, key.clone(), None)?;
+            }
+            Ok(keys.len())
+        })
+    }
+
+    /// Remove every key whose value equals the given value.
+    ///
+    /// Every live value is read from disk under the writer lock, so writes are blocked for the whole scan.
+    fn remove_by_value(&self, value: String) -> StorageResult<usize> {
+        self.write(|writer| {
+            let mut keys = Vec::new();
+            for entry in self.live_keys() {
+                let current = entry.value().load();
+                // Only values of the same length can match, which spares reading the rest.
+                if current.value_len as usize != value.len() {
+                    continue;
+                }
+                if self.reader.read_value(entry.key(), &current)? == value {
+                    keys.push(entry.key().clone());
+                }
+            }
+            for key in keys.iter() {
+                self.append(writer, key.clone(), None)?;
+            }
+            Ok(keys.len())
+        })
+    }
+
+    /// Pushes a value onto the front of the queue stored at a key.
+    fn lpush(&self, key: String, value: String) -> StorageResult<usize> {
+        self.update_queue(key, |queue| {
+            queue.push_front(value);
+            queue.len()
+        })
+    }
+
+    /// Pushes a value onto the back of the queue stored at a key.
+    fn rpush(&self, key: String, value: String) -> StorageResult<usize> {
+        self.update_queue(key, |queue| {
+            queue.push_back(value);
+            queue.len()
+        })
+    }
+
+    /// Pops a value from the front of the queue stored at a key.
+    fn lpop(&self, key: String) -> StorageResult<Option<String>> {
+        self.update_queue(key, VecDeque::pop_front)
+    }
+
+    /// Pops a value from the back of the queue stored at a key.
+    fn rpop(&self, key: String) -> StorageResult<Option<String>> {
+        self.update_queue(key, VecDeque::pop_back)
+    }
+
+    /// List all keys.
+    fn list_keys(&self) -> Vec<String> {
+        // Keys that have been removed or have expired will still have an entry in the key_dir.
+        self.live_keys().map(|entry| entry.key().clone()).collect()
+    }
+
+    /// List all keys along with the size of their values in bytes.
+    ///
+    /// The sizes come from the key_dir, so no values are read.
+    fn list_with_sizes(&self) -> StorageResult<Vec<(String, u32)>> {
+        Ok(self
+            .live_keys()
+            .map(|entry| (entry.key().clone(), entry.value().load().value_len))
+            .collect())
+    }
+
+    /// Lists a page of keys by iterating the key_dir from the cursor, so only the keys of the page are visited.
+    fn list_page(&self, cursor: Option<String>, limit: usize) -> StorageResult<ListPage> {
+        let start = cursor.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+        let now = now();
+        let keys = self
+            .key_dir
+            .range((start, Bound::Unbounded))
+            .filter(|entry| entry.value().load().is_live(now))
+            .map(|entry| Ok(entry.key().clone()));
+        ListPage::collect(keys, limit)
+    }
+
+    /// Replays the log from the first record written at or after `since`, see `Bitcask::replay`.
+    ///
+    /// Compaction drops overwritten values and tombstones older than `BitcaskOptions::tombstone_grace`, so removals
+    /// made before then are not listed and a copy taken before them may keep keys that have since been removed. Values
+    /// are listed without their expiry.
+    fn changes_since(&self, since: u64) -> StorageResult<Changes> {
+        let changes = self.replay()?.filter_map(move |record| match record {
+            Ok(record) if record.timestamp >= since => {
+                let value = (!record.is_tombstone).then_some(record.value);
+                Some(Ok((record.key, value)))
+            }
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        });
+        Ok(Box::new(changes))
+    }
+
+    /// Lists the keys starting with the given prefix.
+    ///
+    /// Keys are compared and ordered by `BitcaskOptions::key_normalizer` if there is one, and returned as they were
+    /// written.
+    fn scan_prefix(&self, prefix: String) -> StorageResult<Vec<String>> {
+        let now = now();
+        Ok(self
+            .key_dir
+            .scan_prefix(&prefix)
+            .filter(|entry| entry.value().load().is_live(now))
+            .map(|entry| entry.key().clone())
+            .collect())
+    }
+
+    /// Lists the keys in the range from the key_dir.
+    ///
+    /// Keys are always compared by their bytes, `BitcaskOptions::key_normalizer` only applies to prefixes.
+    fn range(&self, start: String, end: String) -> StorageResult<Vec<String>> {
+        if start >= end {
+            return Ok(Vec::new());
+        }
+        let now = now();
+        Ok(self
+            .key_dir
+            .range((Bound::Included(&start), Bound::Excluded(&end)))
+            .filter(|entry| entry.value().load().is_live(now))
+            .map(|entry| entry.key().clone())
+            .collect())
+    }
+
+    /// Counts the live keys in the key_dir.
+    fn len(&self) -> StorageResult<usize> {
+        Ok(self.live_keys().count())
+    }
+
+    fn is_empty(&self) -> StorageResult<bool> {
+        Ok(self.live_keys().next().is_none())
+    }
+}
+
+// The in-memory index pointing each key at the location of its latest value on disk.
+//
+// The index is split across one or more skip maps by a hash of the key.
+// An optional bloom filter of every key ever inserted lets lookups of unknown keys skip the skip maps.
+struct KeyDir {
+    shards: Vec<SkipMap<String, AtomicCell<Entry>>>,
+    bloom: Option<BloomFilter>,
+    normalizer: Option<fn(&str) -> String>,
+}
+
+type KeyDirEntry<'a> = map::Entry<'a, String, AtomicCell<Entry>>;
+
+impl KeyDir {
+    fn new(
+        shards: usize,
+        bloom_filter_bits: Option<usize>,
+        normalizer: Option<fn(&str) -> String>,
+    ) -> Self {
+        KeyDir {
+            shards: (0..shards.max(1)).map(|_| SkipMap::new()).collect(),
+            bloom: bloom_filter_bits.map(BloomFilter::new),
+            normalizer,
+        }
+    }
+
+    fn shard(&self, key: &str) -> &SkipMap<String, AtomicCell<Entry>> {
+        if self.shards.len() == 1 {
+            return &self.shards[0];
+        }
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[(hasher.finish() % self.shards.len() as u64) as usize]
+    }
+
+    fn get(&self, key: &str) -> Option<KeyDirEntry<'_>> {
+        if self
+            .bloom
+            .as_ref()
+            .is_some_and(|bloom| !bloom.contains(key))
+        {
+            return None;
+        }
+        self.shard(key).get(key)
+    }
+
+    // Point a key at a new entry.
+    //
+    // Existing entries are updated in place rather than re-inserted as `SkipMap::insert` unlinks the old node before
+    // linking the new one, which would let a concurrent `get` briefly observe the key as missing.
+    // Callers other than `open` must hold the writer lock so that two inserts of the same new key cannot race.
+    // Returns the entry the key pointed at before, if any.
+    fn upsert(&self, key: String, entry: Entry) -> Option<Entry> {
+        let shard = self.shard(&key);
+        match shard.get(&key) {
+            Some(current) => Some(current.value().swap(entry)),
+            None => {
+                // The filter learns the key before the key becomes visible so `get` never misses it.
+                if let Some(bloom) = &self.bloom {
+                    bloom.insert(&key);
+                }
+                shard.insert(key, AtomicCell::new(entry));
+                None
+            }
+        }
+    }
+
+    // The approximate size in bytes of the key directory, see `Bitcask::index_memory_estimate`.
+    fn memory_estimate(&self) -> usize {
+        let entries: usize = self
+            .iter()
+            .map(|item| item.key().len() + KEY_DIR_ENTRY_OVERHEAD)
+            .sum();
+        entries + self.bloom.as_ref().map_or(0, BloomFilter::size_bytes)
+    }
+
+    // Iterate over every key in order, merging across shards.
+    fn iter(&self) -> impl Iterator<Item = KeyDirEntry<'_>> {
+        merge_shards(self.shards.iter().map(|shard| shard.iter()).collect())
+    }
+
+    // Iterate over every key within the given bounds in order, merging across shards.
+    fn range<'a>(
+        &'a self,
+        range: (Bound<&'a str>, Bound<&'a str>),
+    ) -> impl Iterator<Item = KeyDirEntry<'a>> {
+        merge_shards(
+            self.shards
+                .iter()
+                .map(|shard| shard.range::<str, _>(range))
+                .collect(),
+        )
+    }
+
+    // Iterate over every key starting with the given prefix in order, merging across shards.
+    //
+    // With a normalizer the keys are matched and ordered by their normalized form, which means scanning every key.
+    fn scan_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> Box<dyn Iterator<Item = KeyDirEntry<'a>> + 'a> {
+        if let Some(normalize) = self.normalizer {
+            let prefix = normalize(prefix);
+            let mut entries: Vec<(String, KeyDirEntry<'a>)> = self
+                .iter()
+                .map(|entry| (normalize(entry.key()), entry))
+                .filter(|(key, _)| key.starts_with(&prefix))
+                .collect();
+            // The sort is stable so keys that normalize the same stay in the order of their original bytes.
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            return Box::new(entries.into_iter().map(|(_, entry)| entry));
+        }
+        Box::new(
+            self.range((Bound::Included(prefix), Bound::Unbounded))
+                .take_while(move |entry| entry.key().starts_with(prefix)),
+        )
+    }
+}
+
+// Merge the ordered iterators of each shard into a single ordered iterator.
+fn merge_shards<'a, I: Iterator<Item = KeyDirEntry<'a>>>(
+    mut iters: Vec<I>,
+) -> impl Iterator<Item = KeyDirEntry<'a>> {
+    let mut heads: Vec<_> = iters.iter_mut().map(|iter| iter.next()).collect();
+    std::iter::from_fn(move || {
+        let next = heads
+            .iter()
+            .enumerate()
+            .filter_map(|(i, head)| head.as_ref().map(|head| (i, head.key())))
+            .min_by(|(_, a), (_, b)| a.cmp(b))
+            .map(|(i, _)| i)?;
+        std::mem::replace(&mut heads[next], iters[next].next())
+    })
+}
+
+// What the merge phase of a compaction wrote, see `Bitcask::merge_sealed`.
+struct Merge {
+    // Each key merged, with the entry it had when it was read and its entry in the merge file, `None` if it is to be
+    // dropped instead.
+    merged: Vec<(String, Entry, Option<Entry>)>,
+    // The lengths in bytes of the merge and hint files.
+    merge_bytes: u64,
+    hint_bytes: u64,
+}
+
+// Running totals of the bytes in the store's files, shared by every clone, see `BitcaskStats`.
+#[derive(Debug, Default)]
+struct DiskUsage {
+    log_files: AtomicU64,
+    log_bytes: AtomicU64,
+    hint_bytes: AtomicU64,
+    live_bytes: AtomicU64,
+}
+
+impl DiskUsage {
+    fn add_log_bytes(&self, bytes: u64) {
+        self.log_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn dead_bytes(&self) -> u64 {
+        let log_bytes = self.log_bytes.load(Ordering::Relaxed);
+        log_bytes.saturating_sub(self.live_bytes.load(Ordering::Relaxed))
+    }
+}
+
+// The length in bytes of the record of an entry, as written by `write_value`.
+fn record_len(key: &str, entry: &Entry) -> u64 {
+    let expiry_len = match entry.expires_at {
+        Some(_) => RECORD_EXPIRY_LEN,
+        None => 0,
+    };
+    RECORD_HEADER_LEN + expiry_len + key.len() as u64 + entry.value_len as u64
+}
+
+// The length in bytes of the record of an entry if it holds a value, 0 for a tombstone.
+fn live_len(key: &str, entry: &Entry) -> u64 {
+    if entry.tombstone {
+        0
+    } else {
+        record_len(key, entry)
+    }
+}
+
+// The length in bytes of the hint record of a key.
+fn hint_len(key: &str) -> u64 {
+    HINT_HEADER_LEN + key.len() as u64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 struct Entry {
     file_id: u64,
     value_len: u32,
     value_pos: u64,
-    _timestamp: u64,
+    timestamp: u64,
+    // Whether the entry records the removal of its key, the value of a tombstone is empty.
+    tombstone: bool,
+    // When the value expires in milliseconds since the unix epoch, `None` if it does not.
+    expires_at: Option<u64>,
+}
+
+impl Entry {
+    // Whether the key has a value at the given time, rather than having been removed or having expired.
+    fn is_live(&self, now: u64) -> bool {
+        !self.tombstone && self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+#[derive(Debug)]
+struct Writer<F: FileSystem> {
+    fs: F,
+    path: Arc<PathBuf>,
+    // `None` if the store was opened read-only.
+    writer: Option<BufWriter<F::File>>,
+    ids: FileIdAllocator,
+    // The id of the active file, shared with the readers, below which every file is sealed.
+    sealed_below: Arc<AtomicU64>,
+    // Shared with the store, to count each new active file.
+    usage: Arc<DiskUsage>,
+    // The latest timestamp written, so that timestamps never go backwards even if the clock does.
+    last_timestamp: u64,
 }
 
-#[derive(Debug)]
-struct Writer {
-    path: Arc<PathBuf>,
-    writer: BufWriter<File>,
-    active_file_id: u64,
-}
+impl<F: FileSystem> Writer<F> {
+    fn write_value(
+        &mut self,
+        key: &String,
+        value: Option<&String>,
+        expires_at: Option<u64>,
+    ) -> StorageResult<Entry> {
+        let timestamp = self.next_timestamp()?;
+        let writer = self.writer.as_mut().ok_or(StorageError::ReadOnly)?;
+        write_value(
+            writer.get_mut(),
+            self.ids.active(),
+            key,
+            value,
+            timestamp,
+            expires_at,
+        )
+    }
+
+    // The time to record for the next write, which is never before the previous one.
+    fn next_timestamp(&mut self) -> StorageResult<u64> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        self.last_timestamp = now.max(self.last_timestamp);
+        Ok(self.last_timestamp)
+    }
+
+    // Appends already encoded records to the active file, without syncing them to disk.
+    fn append_records(&mut self, records: &[u8]) -> StorageResult<()> {
+        let writer = self.writer.as_mut().ok_or(StorageError::ReadOnly)?;
+        writer.write_all(records)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    // The length in bytes of the active file.
+    fn active_len(&mut self) -> StorageResult<u64> {
+        let writer = self.writer.as_mut().ok_or(StorageError::ReadOnly)?;
+        writer.flush()?;
+        Ok(self.fs.file_len(writer.get_ref())?)
+    }
+
+    fn active_file_id(&self) -> u64 {
+        self.ids.active()
+    }
+
+    // Seals the active file and moves the writer onto a new one.
+    fn rotate(&mut self) -> StorageResult<()> {
+        self.ids.rotate();
+        self.open_active()
+    }
+
+    // Seals the active file and moves the writer past the id it reserves for a merge file, which it returns.
+    fn seal_for_merge(&mut self) -> StorageResult<u64> {
+        if self.writer.is_none() {
+            return Err(StorageError::ReadOnly);
+        }
+        let merge_file_id = self.ids.reserve_merge();
+        self.open_active()?;
+        Ok(merge_file_id)
+    }
+
+    fn open_active(&mut self) -> StorageResult<()> {
+        self.writer = Some(BufWriter::new(open_active_log(
+            &self.fs,
+            &self.path,
+            self.ids.active(),
+        )?));
+        self.sealed_below
+            .store(self.ids.active(), Ordering::Release);
+        self.usage.log_files.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StorageResult<()> {
+        if let Some(writer) = &mut self.writer {
+            writer.flush()?;
+            self.fs.sync_data(writer.get_ref())?;
+        }
+        Ok(())
+    }
+}
+
+impl<F: FileSystem> Drop for Writer<F> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("unable to flush the active log file: {}", e);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Reader<F: FileSystem> {
+    fs: F,
+    path: Arc<PathBuf>,
+    readers: RefCell<HashMap<u64, FileReader<F>>>,
+    prefetch_size: usize,
+    mmap_sealed: bool,
+    sealed_below: Arc<AtomicU64>,
+    obsolete: Arc<ObsoleteFiles<F>>,
+    // The files below this id have had their handles closed.
+    closed_below: Cell<u64>,
+}
+
+impl<F: FileSystem> Reader<F> {
+    // Reads the value of a key, a value that extends past the end of its file being reported as
+    // `StorageError::TruncatedValue`.
+    fn read_value(&self, key: &str, entry: &Entry) -> StorageResult<String> {
+        self.read_entry(entry).map_err(|e| match e {
+            StorageError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                StorageError::TruncatedValue {
+                    key: key.to_owned(),
+                    file_id: entry.file_id,
+                    value_pos: entry.value_pos,
+                    value_len: entry.value_len,
+                }
+            }
+            e => e,
+        })
+    }
+
+    fn read_entry(&self, entry: &Entry) -> StorageResult<String> {
+        self.close_obsolete();
+        let mut readers = self.readers.borrow_mut();
+        if let Some(reader) = readers.get_mut(&entry.file_id) {
+            self.map_if_sealed(reader, entry.file_id)?;
+            return reader.read_value(entry, self.prefetch_size);
+        }
+        let mut reader = FileReader::new(BufReader::new(
+            self.fs.open(&log_path(&self.path, &entry.file_id))?,
+        ));
+        self.map_if_sealed(&mut reader, entry.file_id)?;
+        let value = reader.read_value(entry, self.prefetch_size)?;
+        readers.insert(entry.file_id, reader);
+        Ok(value)
+    }
+
+    // Maps a file into memory with `BitcaskOptions::mmap_sealed` once it is sealed, including a file that was still
+    // the active one when its reader was opened.
+    fn map_if_sealed(&self, reader: &mut FileReader<F>, file_id: u64) -> StorageResult<()> {
+        if self.mmap_sealed
+            && reader.map.is_none()
+            && file_id < self.sealed_below.load(Ordering::Acquire)
+        {
+            reader.map = self.fs.map(reader.reader.get_ref())?;
+        }
+        Ok(())
+    }
+}
+
+impl<F: FileSystem> Reader<F> {
+    // Closes the handles to files that a compaction has merged, so that they can be removed.
+    fn close_obsolete(&self) {
+        let below = self.obsolete.below();
+        if below > self.closed_below.get() {
+            self.readers
+                .borrow_mut()
+                .retain(|file_id, _| *file_id >= below);
+            self.closed_below.set(below);
+        }
+    }
+}
+
+impl<F: FileSystem> Clone for Reader<F> {
+    fn clone(&self) -> Self {
+        Reader {
+            fs: self.fs.clone(),
+            path: self.path.clone(),
+            readers: RefCell::new(HashMap::new()),
+            prefetch_size: self.prefetch_size,
+            mmap_sealed: self.mmap_sealed,
+            sealed_below: self.sealed_below.clone(),
+            obsolete: self.obsolete.clone(),
+            closed_below: Cell::new(self.obsolete.below()),
+        }
+    }
+}
+
+// Reads the values of a single log file, from a mapping of it if it has one and otherwise reading ahead once they are
+// being read in order.
+#[derive(Debug)]
+struct FileReader<F: FileSystem> {
+    reader: BufReader<F::File>,
+    // The whole file mapped into memory, only ever for a sealed file as the mapping does not grow with the file.
+    map: Option<Mmap>,
+    // The position in the file the last read ended at.
+    last_end: u64,
+    // The number of reads in a row that each started shortly after the previous one ended.
+    sequential_reads: u32,
+    // The part of the file that was last read ahead, starting at `window_start`.
+    window: Vec<u8>,
+    window_start: u64,
+    // The number of reads made from the file rather than from the window.
+    disk_reads: u64,
+}
+
+impl<F: FileSystem> FileReader<F> {
+    fn new(reader: BufReader<F::File>) -> Self {
+        FileReader {
+            reader,
+            map: None,
+            last_end: 0,
+            sequential_reads: 0,
+            window: Vec::new(),
+            window_start: 0,
+            disk_reads: 0,
+        }
+    }
+
+    fn read_value(&mut self, entry: &Entry, prefetch_size: usize) -> StorageResult<String> {
+        let start = entry.value_pos;
+        let end = start + entry.value_len as u64;
+        if let Some(map) = &self.map {
+            // The file ends before the value does, which reading it from the file would have failed on as well.
+            let value = map
+                .get(start as usize..end as usize)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+            return Ok(String::from_utf8(value.to_vec())?);
+        }
+        if start >= self.last_end && start - self.last_end <= SEQUENTIAL_READ_GAP {
+            self.sequential_reads = self.sequential_reads.saturating_add(1);
+        } else {
+            self.sequential_reads = 0;
+        }
+        self.last_end = end;
+
+        if start < self.window_start || end > self.window_start + self.window.len() as u64 {
+            self.disk_reads += 1;
+            if prefetch_size == 0 || self.sequential_reads < SEQUENTIAL_READS_BEFORE_PREFETCH {
+                return read_value(&mut self.reader, entry);
+            }
+            self.reader.seek(std::io::SeekFrom::Start(start))?;
+            self.window.clear();
+            (&mut self.reader)
+                .take(prefetch_size.max(entry.value_len as usize) as u64)
+                .read_to_end(&mut self.window)?;
+            self.window_start = start;
+            // The file ends before the value does, which reading just the value would have failed on as well.
+            if end > self.window_start + self.window.len() as u64 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+
+        let offset = (start - self.window_start) as usize;
+        let value = &self.window[offset..offset + entry.value_len as usize];
+        Ok(String::from_utf8(value.to_vec())?)
+    }
+}
+
+// The files merged by compactions, shared by every clone of a store.
+//
+// Each clone keeps its own handles to the files it has read from, and only closes the handles to merged files on its
+// next read. Unix removes a file that is still open, its data staying readable through the open handles until they
+// are closed. Windows refuses to remove a file that is open, so a file that cannot be removed is kept here and its
+// removal is retried by every later compaction, by the last clone of the store when it is dropped, and failing all
+// of those by the next open of the store.
+#[derive(Debug)]
+struct ObsoleteFiles<F: FileSystem> {
+    fs: F,
+    // Every file with a lower id has been merged.
+    below: AtomicU64,
+    // The merged files that could not be removed yet.
+    pending: Mutex<Vec<PathBuf>>,
+}
+
+impl<F: FileSystem> ObsoleteFiles<F> {
+    fn new(fs: F, below: u64) -> Self {
+        ObsoleteFiles {
+            fs,
+            below: AtomicU64::new(below),
+            pending: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn below(&self) -> u64 {
+        self.below.load(Ordering::Acquire)
+    }
+
+    fn mark_below(&self, file_id: u64) {
+        self.below.fetch_max(file_id, Ordering::AcqRel);
+    }
+
+    // Removes the given files along with those still pending from earlier calls, keeping any that fail for later.
+    fn remove(&self, files: impl IntoIterator<Item = PathBuf>) -> StorageResult<()> {
+        let mut pending = self.pending.lock()?;
+        pending.extend(files);
+        remove_files(&self.fs, &mut pending);
+        Ok(())
+    }
+}
+
+impl<F: FileSystem> Drop for ObsoleteFiles<F> {
+    fn drop(&mut self) {
+        if let Ok(pending) = self.pending.get_mut() {
+            remove_files(&self.fs, pending);
+        }
+    }
+}
+
+// Removes every file of `files` that can be, leaving the rest in it.
+fn remove_files(fs: &impl FileSystem, files: &mut Vec<PathBuf>) {
+    files.retain(|file_path| match fs.remove_file(file_path) {
+        Ok(()) => false,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+        Err(e) => {
+            debug!(
+                "unable to remove merged file {} yet: {}",
+                file_path.display(),
+                e
+            );
+            true
+        }
+    });
+}
+
+// Reads the records of a list of log files in order, see `Bitcask::replay`.
+struct Replay<F: FileSystem> {
+    fs: F,
+    path: Arc<PathBuf>,
+    file_ids: std::vec::IntoIter<u64>,
+    current: Option<(u64, BufReader<F::File>)>,
+    format_version: u8,
+}
+
+impl<F: FileSystem> Replay<F> {
+    fn next_record(&mut self) -> StorageResult<Option<LogRecord>> {
+        loop {
+            let (file_id, reader) = match &mut self.current {
+                Some(current) => current,
+                None => match self.file_ids.next() {
+                    Some(file_id) => {
+                        let file = self.fs.open(&log_path(&self.path, &file_id))?;
+                        self.current.insert((file_id, BufReader::new(file)))
+                    }
+                    None => return Ok(None),
+                },
+            };
+            match read_next_record(reader, *file_id, self.format_version)? {
+                Some((key, entry, value)) => {
+                    return Ok(Some(LogRecord {
+                        key,
+                        value: String::from_utf8(value)?,
+                        timestamp: entry.timestamp,
+                        is_tombstone: entry.tombstone,
+                    }))
+                }
+                None => self.current = None,
+            }
+        }
+    }
+}
+
+impl<F: FileSystem> Iterator for Replay<F> {
+    type Item = StorageResult<LogRecord>;
+
+    // The replay ends at the first error, as the position in the file it happened in is unknown.
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.next_record().transpose();
+        if let Some(Err(_)) = record {
+            self.current = None;
+            self.file_ids = Vec::new().into_iter();
+        }
+        record
+    }
+}
+
+// Lists the ids of the log files in a directory, in no particular order.
+fn log_file_ids(fs: &impl FileSystem, path: &Path) -> StorageResult<Vec<u64>> {
+    let mut file_ids = Vec::new();
+    for file_path in fs.read_dir(path)? {
+        if file_path.extension().and_then(|ext| ext.to_str()) != Some(LOG_FILE_EXT) {
+            continue;
+        }
+        if let Some(file_id) = file_path
+            .file_stem()
+            .and_then(|file_id| file_id.to_str())
+            .and_then(|file_id| file_id.parse::<u64>().ok())
+        {
+            file_ids.push(file_id);
+        }
+    }
+    Ok(file_ids)
+}
+
+fn log_file_count(fs: &impl FileSystem, path: &Path) -> StorageResult<usize> {
+    let mut count = 0;
+    for file_path in fs.read_dir(path)? {
+        if file_path.extension().and_then(|ext| ext.to_str()) == Some(LOG_FILE_EXT) {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+// Reads the format version of the store, `None` if the store has no format file.
+fn read_format(fs: &impl FileSystem, path: &Path) -> StorageResult<Option<u8>> {
+    match fs.read(&path.join(FORMAT_FILE)) {
+        Ok(bytes) => match bytes[..] {
+            [format_version] => Ok(Some(format_version)),
+            _ => Err(StorageError::Unexpected(format!(
+                "Could not parse format file {}",
+                path.join(FORMAT_FILE).display()
+            ))),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Records that the store is in the current format version.
+fn write_format(fs: &impl FileSystem, path: &Path) -> StorageResult<()> {
+    let format_path = path.join(FORMAT_FILE);
+    fs.write(&format_path, &[FORMAT_VERSION])
+        .map_err(access_error("write the format file", &format_path))?;
+    Ok(())
+}
+
+// Opens the log file that writes are appended to, creating it if it does not exist.
+fn open_active_log<F: FileSystem>(fs: &F, path: &Path, file_id: u64) -> StorageResult<F::File> {
+    let log_path = log_path(path, &file_id);
+    fs.append(&log_path).map_err(access_error(
+        "open the active log file for writing",
+        &log_path,
+    ))
+}
+
+fn log_path(path: &Path, gen: &u64) -> PathBuf {
+    path.join(format!("{}.log", gen))
+}
+
+fn hint_path(path: &Path, gen: &u64) -> PathBuf {
+    path.join(format!("{}.hint", gen))
+}
+
+fn hint_tmp_path(path: &Path, gen: &u64) -> PathBuf {
+    path.join(format!("{}{}", gen, HINT_TMP_FILE_SUFFIX))
+}
+
+// Write a key/value pair to the given writer in the bitcask format, recording the given write time and expiry.
+// A `None` value writes a tombstone, which has no value bytes and a val_len of `TOMBSTONE_VALUE_LEN`.
+// Only a value with an expiry is written as a version 4 record, others are written as version 3 records.
+// An entry indicating the location of the value for the given key is returned.
+// Fixed-width header                        Variable-length body
+//+====+=====+=====+=====+=====+======= - - +============== - - +
+//| u8 | u16 | u64 | u32 | u32 | (u64)      | [u8] | [u8] |
+//+====+=====+=====+=====+=====+======= - - +============== - - +
+// format version (1 byte)
+// checksum (2 bytes)
+// timestamp (8 bytes)
+// key_len (4 bytes)
+// val_len (4 bytes)
+// expires_at (8 bytes, version 4 records only)
+// key (key_len bytes)
+// value (val_len bytes)
+fn write_value<W: Write + Seek>(
+    writer: &mut W,
+    file_id: u64,
+    key: &String,
+    value: Option<&String>,
+    timestamp: u64,
+    expires_at: Option<u64>,
+) -> StorageResult<Entry> {
+    let key_len = key.len();
+    let value_len = value.map_or(0, String::len);
+    // Longer lengths would be truncated when recorded, leaving a record that reads back as something else.
+    if key_len > MAX_RECORD_LEN {
+        return Err(StorageError::InvalidKey(format!(
+            "the key is {} bytes, longer than the {} bytes a record can hold",
+            key_len, MAX_RECORD_LEN
+        )));
+    }
+    if value_len > MAX_RECORD_LEN {
+        return Err(StorageError::ValueTooLarge {
+            len: value_len,
+            max: MAX_RECORD_LEN,
+        });
+    }
+    let mut entry = Vec::<u8>::with_capacity(8 + 4 + 4 + 8 + key_len + value_len);
+
+    entry.write_u64::<BigEndian>(timestamp)?;
+    entry.write_u32::<BigEndian>(key_len as u32)?;
+    entry.write_u32::<BigEndian>(match value {
+        Some(_) => value_len as u32,
+        None => TOMBSTONE_VALUE_LEN,
+    })?;
+    if let Some(expires_at) = expires_at {
+        entry.write_u64::<BigEndian>(expires_at)?;
+    }
+    entry.write_all(key.as_bytes())?;
+    if let Some(value) = value {
+        entry.write_all(value.as_bytes())?;
+    }
+
+    let checksum = X25.checksum(&entry);
+
+    writer.write_u8(match expires_at {
+        Some(_) => EXPIRY_FORMAT_VERSION,
+        None => TOMBSTONE_FORMAT_VERSION,
+    })?;
+    writer.write_u16::<BigEndian>(checksum)?;
+    writer.write_all(&entry)?;
+    writer.flush()?;
+
+    let value_pos = writer.stream_position()? - value_len as u64;
+
+    Ok(Entry {
+        file_id,
+        value_len: value_len as u32,
+        value_pos,
+        timestamp,
+        tombstone: value.is_none(),
+        expires_at,
+    })
+}
+
+// Read the next key/value entry from the given reader in the bitcask data format.
+// Fixed-width header                        Variable-length body
+//+====+=====+=====+=====+=====+======= - - +============== - - +
+//| u8 | u16 | u64 | u32 | u32 | (u64)      | [u8] | [u8] |
+//+====+=====+=====+=====+=====+======= - - +============== - - +
+// format version (1 byte, absent in version 0 stores)
+// checksum (2 bytes)
+// timestamp (8 bytes)
+// key_len (4 bytes)
+// val_len (4 bytes, `TOMBSTONE_VALUE_LEN` for a tombstone or 0 before version 3)
+// expires_at (8 bytes, version 4 records only)
+// key (key_len bytes)
+// value (val_len bytes)
+fn read_next_entry<R: Read + Seek>(
+    reader: &mut R,
+    file_id: u64,
+    format_version: u8,
+) -> StorageResult<Option<(String, Entry)>> {
+    Ok(read_next_record(reader, file_id, format_version)?.map(|(key, entry, _)| (key, entry)))
+}
+
+// Like `read_next_entry`, but also returns the bytes of the value, which are empty for a tombstone.
+fn read_next_record<R: Read + Seek>(
+    reader: &mut R,
+    file_id: u64,
+    format_version: u8,
+) -> StorageResult<Option<(String, Entry, Vec<u8>)>> {
+    // Check if we are at the end of the reader
+    // Move back to the current position after checking
+    let current_pos = reader.stream_position()?;
+    if current_pos == reader.seek(std::io::SeekFrom::End(0))? {
+        return Ok(None);
+    }
+    reader.seek(std::io::SeekFrom::Start(current_pos))?;
+
+    let record_version = match format_version {
+        0 => 0,
+        _ => reader.read_u8()?,
+    };
+    if record_version > FORMAT_VERSION || (format_version > 0 && record_version == 0) {
+        return Err(StorageError::UnsupportedFormat(record_version));
+    }
+
+    let checksum = reader.read_u16::<BigEndian>()?;
+    let timestamp = reader.read_u64::<BigEndian>()?;
+    let key_len = reader.read_u32::<BigEndian>()?;
+    let value_len = reader.read_u32::<BigEndian>()?;
+    let expires_at = match record_version >= EXPIRY_FORMAT_VERSION {
+        true => Some(reader.read_u64::<BigEndian>()?),
+        false => None,
+    };
+
+    let mut key_bytes = vec![0; key_len as usize];
+    reader.read_exact(&mut key_bytes)?;
+
+    let value_pos = reader.stream_position()?;
+
+    let tombstone = is_tombstone(value_len, record_version);
+    let mut value_bytes = vec![0; if tombstone { 0 } else { value_len as usize }];
+    reader.read_exact(&mut value_bytes)?;
+
+    let mut entry_bytes =
+        Vec::<u8>::with_capacity(8 + 4 + 4 + 8 + key_len as usize + value_bytes.len());
+    entry_bytes.write_u64::<BigEndian>(timestamp)?;
+    entry_bytes.write_u32::<BigEndian>(key_len)?;
+    entry_bytes.write_u32::<BigEndian>(value_len)?;
+    if let Some(expires_at) = expires_at {
+        entry_bytes.write_u64::<BigEndian>(expires_at)?;
+    }
+    entry_bytes.write_all(&key_bytes)?;
+    entry_bytes.write_all(&value_bytes)?;
+
+    let read_checksum = X25.checksum(&entry_bytes);
+
+    if checksum != read_checksum {
+        return Err(StorageError::DataCorruption(checksum, read_checksum));
+    }
+
+    let entry = Entry {
+        file_id,
+        value_len: value_bytes.len() as u32,
+        value_pos,
+        timestamp: timestamp_millis(timestamp, record_version),
+        tombstone,
+        expires_at,
+    };
+
+    let key = String::from_utf8(key_bytes)?;
+
+    Ok(Some((key, entry, value_bytes)))
+}
+
+// Read the value for the given entry from the given reader.
+fn read_value<R: Read + Seek>(reader: &mut R, entry: &Entry) -> StorageResult<String> {
+    reader.seek(std::io::SeekFrom::Start(entry.value_pos))?;
+
+    let mut value_bytes = vec![0; entry.value_len as usize];
+    reader.read_exact(&mut value_bytes)?;
+
+    Ok(String::from_utf8(value_bytes)?)
+}
+
+// Write a given key/value entry to the writer in the bitcask hint format.
+// Fixed-width header                  Variable-length body
+//+=====+=====+=====+=====+====== - - +======== - - +
+//| u64 | u32 | u32 | u64 | u64       | [u8] |
+//+=====+=====+=====+=====+====== - - +======== - - +
+// timestamp (8 bytes)
+// key_len (4 bytes)
+// val_len (4 bytes, `TOMBSTONE_VALUE_LEN` for a tombstone)
+// val_pos (8 bytes)
+// expires_at (8 bytes, 0 if the value does not expire)
+// key (key_len bytes)
+fn write_hint<W: Write + Seek>(writer: &mut W, key: &String, entry: &Entry) -> StorageResult<()> {
+    writer.write_u64::<BigEndian>(entry.timestamp)?;
+    writer.write_u32::<BigEndian>(key.len() as u32)?;
+    writer.write_u32::<BigEndian>(match entry.tombstone {
+        true => TOMBSTONE_VALUE_LEN,
+        false => entry.value_len,
+    })?;
+    writer.write_u64::<BigEndian>(entry.value_pos)?;
+    writer.write_u64::<BigEndian>(entry.expires_at.unwrap_or(0))?;
+    writer.write_all(key.as_bytes())?;
+    Ok(())
+}
+
+// Read the next key/value entry from the given reader in the bitcask hint format.
+// Fixed-width header                  Variable-length body
+//+=====+=====+=====+=====+====== - - +======== - - +
+//| u64 | u32 | u32 | u64 | (u64)     | [u8] |
+//+=====+=====+=====+=====+====== - - +======== - - +
+// timestamp (8 bytes)
+// key_len (4 bytes)
+// val_len (4 bytes, `TOMBSTONE_VALUE_LEN` for a tombstone or 0 before version 3)
+// val_pos (8 bytes)
+// expires_at (8 bytes from version 4, 0 if the value does not expire)
+// key (key_len bytes)
+// Hint records carry no version of their own, they are in the format of the store that wrote them.
+fn read_next_hint<R: Read + Seek>(
+    reader: &mut R,
+    file_id: u64,
+    format_version: u8,
+) -> StorageResult<Option<(String, Entry)>> {
+    // Check if we are at the end of the reader
+    // Move back to the current position after checking
+    let current_pos = reader.stream_position()?;
+    if current_pos == reader.seek(std::io::SeekFrom::End(0))? {
+        return Ok(None);
+    }
+    reader.seek(std::io::SeekFrom::Start(current_pos))?;
+
+    let timestamp = reader.read_u64::<BigEndian>()?;
+    let key_len = reader.read_u32::<BigEndian>()?;
+    let value_len = reader.read_u32::<BigEndian>()?;
+    let value_pos = reader.read_u64::<BigEndian>()?;
+    let expires_at = match format_version >= EXPIRY_FORMAT_VERSION {
+        true => Some(reader.read_u64::<BigEndian>()?).filter(|expires_at| *expires_at != 0),
+        false => None,
+    };
+
+    let mut key_bytes = vec![0; key_len as usize];
+    reader.read_exact(&mut key_bytes)?;
+    let key = String::from_utf8(key_bytes)?;
+
+    let tombstone = is_tombstone(value_len, format_version);
+    let entry = Entry {
+        file_id,
+        value_len: if tombstone { 0 } else { value_len },
+        value_pos,
+        timestamp: timestamp_millis(timestamp, format_version),
+        tombstone,
+        expires_at,
+    };
+
+    Ok(Some((key, entry)))
+}
+
+// Whether a record of the given format version with the given value length is a tombstone.
+fn is_tombstone(value_len: u32, format_version: u8) -> bool {
+    if format_version < TOMBSTONE_FORMAT_VERSION {
+        value_len == 0
+    } else {
+        value_len == TOMBSTONE_VALUE_LEN
+    }
+}
+
+// The current time in milliseconds since the unix epoch, for checking expiries. A clock set before the epoch expires
+// nothing.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+// Converts a timestamp read from a record of the given format version to milliseconds.
+fn timestamp_millis(timestamp: u64, format_version: u8) -> u64 {
+    if format_version < MILLIS_FORMAT_VERSION {
+        timestamp.saturating_mul(1000)
+    } else {
+        timestamp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::storage::file_id::LOWEST_LOG_FILE_ID;
+    use std::fs::{self, File};
+    use std::sync::Barrier;
+    use std::time::Duration;
+    use tempfile::TempDir;
+    use tracing::field::{Field, Visit};
+    use tracing_subscriber::layer::{self, Layer, SubscriberExt};
+    use tracing_subscriber::Registry;
+    use walkdir::WalkDir;
+
+    // Should get previously stored value.
+    #[test]
+    fn get_stored_value() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let bitcask = Bitcask::open(temp_dir.path())?;
+
+        bitcask.set("key1".to_owned(), "value1".to_owned())?;
+        bitcask.set("key2".to_owned(), "value2".to_owned())?;
+
+        assert_eq!(bitcask.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(bitcask.get("key2".to_owned())?, Some("value2".to_owned()));
+
+        // Open from disk again and check persistent data.
+        drop(bitcask);
+        let store = Bitcask::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+        Ok(())
+    }
+
+    // Should report when a value was written, surviving a reopen and compaction.
+    #[test]
+    fn get_with_metadata() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let bitcask = Bitcask::open(temp_dir.path())?;
+
+        let before = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        bitcask.set("key1".to_owned(), "value1".to_owned())?;
+        let after = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+
+        let (value, timestamp) = bitcask.get_with_metadata("key1".to_owned())?.unwrap();
+        assert_eq!(value, "value1");
+        let timestamp = timestamp.unwrap();
+        assert!(timestamp >= before && timestamp <= after);
+        assert_eq!(bitcask.get_with_metadata("key2".to_owned())?, None);
+
+        // Compacting later still reports the original write time.
+        drop(bitcask);
+        thread::sleep(Duration::from_millis(10));
+        let store = Bitcask::open(temp_dir.path())?;
+        store.compact()?;
+        assert_eq!(
+            store.get_with_metadata("key1".to_owned())?,
+            Some(("value1".to_owned(), Some(timestamp)))
+        );
+
+        Ok(())
+    }
+
+    // Should list only the keys written at or after the cutoff.
+    #[test]
+    fn keys_modified_since() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Bitcask::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+
+        thread::sleep(Duration::from_millis(10));
+        let cutoff = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        store.set("key2".to_owned(), "value4".to_owned())?;
+        store.set("key4".to_owned(), "value5".to_owned())?;
+        store.remove("key3".to_owned())?;
+
+        assert_eq!(
+            store.keys_modified_since(cutoff)?,
+            vec!["key2".to_owned(), "key4".to_owned()]
+        );
+        assert_eq!(store.keys_modified_since(0)?.len(), 3);
+        assert!(store.keys_modified_since(cutoff + 60_000)?.is_empty());
+
+        Ok(())
+    }
+
+    // Bulk loaded keys should read back as if they were set one at a time, before and after a reopen.
+    #[test]
+    fn bulk_load() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            max_log_size: 4 * 1024,
+            ..BitcaskOptions::default()
+        };
+        let store = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("key0".to_owned(), "old".to_owned())?;
+        store.set("existing".to_owned(), "value".to_owned())?;
+
+        // Overwrites within the load resolve to the last pair, like individual sets would.
+        let entries = (0..1000)
+            .map(|key_id| (format!("key{}", key_id), format!("value{}", key_id)))
+            .chain([("key1".to_owned(), "last".to_owned())]);
+        assert_eq!(store.bulk_load(entries)?, 1001);
+
+        let check = |store: &Bitcask| -> StorageResult<()> {
+            assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+            assert_eq!(store.get("key1".to_owned())?, Some("last".to_owned()));
+            assert_eq!(store.get("key999".to_owned())?, Some("value999".to_owned()));
+            assert_eq!(store.get("existing".to_owned())?, Some("value".to_owned()));
+            assert_eq!(store.len()?, 1001);
+            Ok(())
+        };
+        check(&store)?;
+        assert!(store.segments()?.len() > 1);
+
+        store.set("key2".to_owned(), "after".to_owned())?;
+        drop(store);
+        let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+        check(&store)?;
+        assert_eq!(store.get("key2".to_owned())?, Some("after".to_owned()));
+
+        Ok(())
+    }
+
+    // Should timestamp rapid successive writes in milliseconds without ever going backwards, even across a reopen.
+    #[test]
+    fn monotonic_timestamps() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Bitcask::open(temp_dir.path())?;
+        let timestamp = |store: &Bitcask, key: String| {
+            store
+                .get_with_metadata(key)
+                .map(|value| value.and_then(|(_, timestamp)| timestamp).unwrap())
+        };
+
+        let mut timestamps = Vec::new();
+        for i in 0..100 {
+            store.set(format!("key{}", i), "value".to_owned())?;
+            timestamps.push(timestamp(&store, format!("key{}", i))?);
+        }
+        assert!(timestamps.windows(2).all(|pair| pair[0] <= pair[1]));
+        thread::sleep(Duration::from_millis(10));
+        store.set("key100".to_owned(), "value".to_owned())?;
+        assert!(timestamp(&store, "key100".to_owned())? > timestamps[99]);
+
+        // A timestamp from the future stands in for the clock having gone backwards.
+        let future = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64 + 60_000;
+        store.writer.lock()?.last_timestamp = future;
+        store.set("key101".to_owned(), "value".to_owned())?;
+        assert_eq!(timestamp(&store, "key101".to_owned())?, future);
+        drop(store);
+
+        let store = Bitcask::open(temp_dir.path())?;
+        store.set("key102".to_owned(), "value".to_owned())?;
+        assert_eq!(timestamp(&store, "key102".to_owned())?, future);
+
+        Ok(())
+    }
+
+    // Should notice a hint pointing past the end of its merge file and read the merge file instead.
+    #[test]
+    fn hint_out_of_bounds() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Bitcask::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.compact()?;
+        drop(store);
+
+        // The value position follows the timestamp and the key and value lengths.
+        let path = hint_path(temp_dir.path(), &1);
+        let mut hint = fs::read(&path)?;
+        hint[16..24].copy_from_slice(&u64::MAX.to_be_bytes());
+        fs::write(&path, hint)?;
+
+        let store = Bitcask::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+        Ok(())
+    }
+
+    // Should overwrite existent value.
+    #[test]
+    fn overwrite_value() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let bitcask = Bitcask::open(temp_dir.path())?;
+
+        bitcask.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(bitcask.get("key1".to_owned())?, Some("value1".to_owned()));
+        bitcask.set("key1".to_owned(), "value2".to_owned())?;
+        assert_eq!(bitcask.get("key1".to_owned())?, Some("value2".to_owned()));
+
+        // Open from disk again and check persistent data.
+        drop(bitcask);
+        let store = Bitcask::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+        store.set("key1".to_owned(), "value3".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+        Ok(())
+    }
+
+    // Should reject a value too long for its length to be recorded rather than truncating it.
+    #[test]
+    fn value_too_large() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let bitcask = Bitcask::open(temp_dir.path())?;
+
+        // The zeroed allocation is not backed by memory until it is written to, which it never is.
+        let value = String::from_utf8(vec![0; u32::MAX as usize + 1])?;
+        match bitcask.set("key1".to_owned(), value) {
+            Err(StorageError::ValueTooLarge { len, max }) => {
+                assert_eq!(len, u32::MAX as usize + 1);
+                assert_eq!(max, MAX_RECORD_LEN);
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        // Nothing was written, so the store still opens cleanly.
+        assert_eq!(bitcask.get("key1".to_owned())?, None);
+        drop(bitcask);
+        let store = Bitcask::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+
+        Ok(())
+    }
+
+    // Should get `None` when getting a non-existent key.
+    #[test]
+    fn get_non_existent_value() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let bitcask = Bitcask::open(temp_dir.path())?;
+
+        bitcask.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(bitcask.get("key2".to_owned())?, None);
+
+        // Open from disk again and check persistent data.
+        drop(bitcask);
+        let store = Bitcask::open(temp_dir.path())?;
+        assert_eq!(store.get("key2".to_owned())?, None);
+
+        Ok(())
+    }
+
+    // Should persist writes that were flushed.
+    #[test]
+    fn flush() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let bitcask = Bitcask::open(temp_dir.path())?;
+
+        bitcask.set("key1".to_owned(), "value1".to_owned())?;
+        bitcask.remove("key1".to_owned())?;
+        bitcask.set("key2".to_owned(), "value2".to_owned())?;
+        bitcask.flush()?;
+
+        // Open from disk again and check persistent data.
+        drop(bitcask);
+        let store = Bitcask::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn remove_non_existent_key() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let bitcask = Bitcask::open(temp_dir.path())?;
+        assert!(bitcask.remove("key1".to_owned()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn remove_key() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let bitcask = Bitcask::open(temp_dir.path())?;
+        bitcask.set("key1".to_owned(), "value1".to_owned())?;
+        assert!(bitcask.remove("key1".to_owned()).is_ok());
+        assert_eq!(bitcask.get("key1".to_owned())?, None);
+
+        Ok(())
+    }
+
+    // Insert data and call `merge` to compact log files
+    // Test dir size grows and shrinks before and after merging
+    // Test data correctness after merging
+    #[test]
+    fn compaction() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let bitcask = Bitcask::open(temp_dir.path()).unwrap();
+
+        let dir_size = || {
+            let entries = WalkDir::new(temp_dir.path()).into_iter();
+            let len: walkdir::Result<u64> = entries
+                .map(|res| {
+                    res.and_then(|entry| entry.metadata())
+                        .map(|metadata| metadata.len())
+                })
+                .sum();
+            len.expect("fail to get directory size")
+        };
+
+        let initial_size = dir_size();
+        for iter in 0..=1000 {
+            for key_id in 0..=1000 {
+                let key = format!("key{}", key_id);
+                let value = format!("{}", iter);
+                bitcask.set(key, value).unwrap();
+            }
+        }
+
+        let new_size = dir_size();
+        assert!(
+            new_size > initial_size,
+            "expected dir size to grow before merge"
+        );
+
+        bitcask.compact()?;
+
+        let final_size = dir_size();
+        assert!(
+            final_size < new_size,
+            "expected dir size to shrink after merge"
+        );
+
+        // test that store can read from the merged log
+        drop(bitcask);
+
+        let store = Bitcask::open(temp_dir.path())?;
+        for key_id in 0..=1000 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key)?, Some(format!("{}", 1000)));
+        }
+
+        Ok(())
+    }
+
+    // Writes and reads on other handles should keep making progress while a compaction is merging.
+    #[test]
+    fn concurrent_compaction() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Bitcask::open(temp_dir.path())?;
+        for iter in 0..2 {
+            for key_id in 0..10000 {
+                store.set(
+                    format!("key{}", key_id),
+                    format!("value{}-{}", key_id, iter),
+                )?;
+            }
+        }
+
+        let compacting = Arc::new(AtomicBool::new(true));
+
+        let writer = {
+            let store = store.clone();
+            let compacting = compacting.clone();
+            std::thread::spawn(move || {
+                let mut writes_during_compaction = 0;
+                let mut i = 0;
+                while compacting.load(Ordering::SeqCst) {
+                    store
+                        .set(format!("new{}", i), format!("value{}", i))
+                        .unwrap();
+                    if compacting.load(Ordering::SeqCst) {
+                        writes_during_compaction += 1;
+                    }
+                    i += 1;
+                }
+                (i, writes_during_compaction)
+            })
+        };
+
+        let reader = {
+            let store = store.clone();
+            let compacting = compacting.clone();
+            std::thread::spawn(move || {
+                let mut key_id = 0;
+                while compacting.load(Ordering::SeqCst) {
+                    assert_eq!(
+                        store.get(format!("key{}", key_id)).unwrap(),
+                        Some(format!("value{}-1", key_id))
+                    );
+                    key_id = (key_id + 1) % 10000;
+                }
+            })
+        };
+
+        store.compact()?;
+        compacting.store(false, Ordering::SeqCst);
+
+        let (written, writes_during_compaction) = writer.join().unwrap();
+        reader.join().unwrap();
+        assert!(
+            writes_during_compaction > 10,
+            "expected writes to make progress during compaction, only {} completed",
+            writes_during_compaction
+        );
+
+        let check = |store: &Bitcask| -> StorageResult<()> {
+            for key_id in 0..10000 {
+                assert_eq!(
+                    store.get(format!("key{}", key_id))?,
+                    Some(format!("value{}-1", key_id))
+                );
+            }
+            for i in 0..written {
+                assert_eq!(store.get(format!("new{}", i))?, Some(format!("value{}", i)));
+            }
+            Ok(())
+        };
+        check(&store)?;
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = Bitcask::open(temp_dir.path())?;
+        check(&store)?;
+
+        Ok(())
+    }
+
+    // Values read in the order they were written should mostly be served from the window read ahead, while reads in
+    // any other order should still read one value each.
+    #[test]
+    fn prefetch() -> StorageResult<()> {
+        let disk_reads = |store: &Bitcask| -> u64 {
+            store
+                .reader
+                .readers
+                .borrow()
+                .values()
+                .map(|reader| reader.disk_reads)
+                .sum()
+        };
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let keys: Vec<String> = (0..2000).map(|i| format!("key{:04}", i)).collect();
+        let value = |key: &str| format!("{}-{}", key, "v".repeat(100));
+        {
+            let store = Bitcask::open(temp_dir.path())?;
+            for key in keys.iter().rev() {
+                store.set(key.clone(), value(key))?;
+            }
+            // The merge file holds the values in key order.
+            store.compact()?;
+        }
+
+        let store = Bitcask::open(temp_dir.path())?;
+        for key in keys.iter() {
+            assert_eq!(store.get(key.clone())?, Some(value(key)));
+        }
+        let sequential = disk_reads(&store);
+        assert!(sequential < 10, "{} disk reads", sequential);
+
+        let store = Bitcask::open(temp_dir.path())?;
+        let mut shuffled = keys.clone();
+        shuffled.sort_by_key(|key| (key.as_bytes()[6], key.as_bytes()[5]));
+        for key in shuffled.iter() {
+            assert_eq!(store.get(key.clone())?, Some(value(key)));
+        }
+        assert_eq!(disk_reads(&store), keys.len() as u64);
+
+        let options = BitcaskOptions {
+            prefetch_size: 0,
+            ..BitcaskOptions::default()
+        };
+        let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+        for key in keys.iter() {
+            assert_eq!(store.get(key.clone())?, Some(value(key)));
+        }
+        assert_eq!(disk_reads(&store), keys.len() as u64);
+        Ok(())
+    }
+
+    // With `mmap_sealed`, values should be read from a mapping of each sealed file, including one that was still active
+    // when it was first read, while the active file is read from disk.
+    #[test]
+    fn mmap_sealed() -> StorageResult<()> {
+        let mapped = |store: &Bitcask| -> Vec<(u64, bool)> {
+            let mut mapped: Vec<(u64, bool)> = store
+                .reader
+                .readers
+                .borrow()
+                .iter()
+                .map(|(file_id, reader)| (*file_id, reader.map.is_some()))
+                .collect();
+            mapped.sort_unstable();
+            mapped
+        };
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            max_log_size: 256,
+            mmap_sealed: true,
+            ..BitcaskOptions::default()
+        };
+        let store = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
+        let mut key_id = 0;
+        let mut set_next = || -> StorageResult<String> {
+            let key = format!("key{}", key_id);
+            store.set(key.clone(), format!("value{}", key_id))?;
+            key_id += 1;
+            Ok(key)
+        };
+        for _ in 0..100 {
+            set_next()?;
+        }
+        for i in 0..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+        let active = store.writer.lock()?.active_file_id();
+        let readers = mapped(&store);
+        assert!(readers.len() > 1);
+        assert!(readers
+            .iter()
+            .all(|(file_id, is_mapped)| *is_mapped == (*file_id < active)));
+
+        // The active file is mapped on the first read after it is sealed.
+        let last = set_next()?;
+        store.get(last.clone())?;
+        assert!(mapped(&store).contains(&(active, false)));
+        while store.writer.lock()?.active_file_id() == active {
+            set_next()?;
+        }
+        assert!(store.get(last)?.is_some());
+        assert!(mapped(&store).contains(&(active, true)));
+
+        // The merge file is sealed as soon as it is written, including once the store is opened again.
+        store.compact()?;
+        drop(store);
+        let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+        for i in 0..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+        assert!(mapped(&store).contains(&(store.obsolete.below(), true)));
+        Ok(())
+    }
+
+    // Should yield every record in the order it was written, across log files and compactions.
+    #[test]
+    fn replay() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            max_log_size: 64,
+            ..BitcaskOptions::default()
+        };
+        let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+        for version in 1..=3 {
+            store.set("key1".to_owned(), format!("value{}", version))?;
+        }
+        store.set("key2".to_owned(), "value".to_owned())?;
+        store.remove("key2".to_owned())?;
+        assert!(store.segments()?.len() > 1);
+
+        let records = store.replay()?.collect::<StorageResult<Vec<_>>>()?;
+        let history: Vec<(&str, &str, bool)> = records
+            .iter()
+            .map(|record| {
+                (
+                    record.key.as_str(),
+                    record.value.as_str(),
+                    record.is_tombstone,
+                )
+            })
+            .collect();
+        assert_eq!(
+            history,
+            vec![
+                ("key1", "value1", false),
+                ("key1", "value2", false),
+                ("key1", "value3", false),
+                ("key2", "value", false),
+                ("key2", "", true),
+            ]
+        );
+        assert!(records
+            .windows(2)
+            .all(|records| records[0].timestamp <= records[1].timestamp));
+
+        // Compaction only keeps the live values
+        store.compact()?;
+        store.set("key3".to_owned(), "value".to_owned())?;
+        let keys = store
+            .replay()?
+            .map(|record| record.map(|record| record.key))
+            .collect::<StorageResult<Vec<_>>>()?;
+        assert_eq!(keys, vec!["key1".to_owned(), "key3".to_owned()]);
+        Ok(())
+    }
+
+    // Compaction should keep the latest versions of each key up to `retain_versions`.
+    #[test]
+    fn retain_versions() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            max_log_size: 64,
+            retain_versions: 2,
+            ..BitcaskOptions::default()
+        };
+        let store = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
+        let history = |store: &Bitcask| -> StorageResult<Vec<(String, String)>> {
+            store
+                .replay()?
+                .map(|record| record.map(|record| (record.key, record.value)))
+                .collect()
+        };
+        let versions = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+
+        for version in 1..=3 {
+            store.set("key1".to_owned(), format!("value{}", version))?;
+        }
+        store.set("key2".to_owned(), "value".to_owned())?;
+        store.set("key3".to_owned(), "value".to_owned())?;
+        store.remove("key3".to_owned())?;
+        store.compact()?;
+
+        // The two latest versions of key1 survive, the older one first, and a removed key leaves nothing behind.
+        let retained = versions(&[("key1", "value2"), ("key1", "value3"), ("key2", "value")]);
+        assert_eq!(history(&store)?, retained);
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+
+        // Compacting again keeps the same versions rather than copying them twice, including after a reopen.
+        store.compact()?;
+        assert_eq!(history(&store)?, retained);
+        drop(store);
+        let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(history(&store)?, retained);
+
+        // A new version pushes the oldest retained one out.
+        store.set("key1".to_owned(), "value4".to_owned())?;
+        store.compact()?;
+        assert_eq!(
+            history(&store)?,
+            versions(&[("key1", "value3"), ("key1", "value4"), ("key2", "value")])
+        );
+        assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+        Ok(())
+    }
+
+    // Records the fields of every event logged, along with its message.
+    #[derive(Clone, Default)]
+    struct EventRecorder(Arc<Mutex<Vec<HashMap<String, String>>>>);
+
+    impl<S: tracing::Subscriber> Layer<S> for EventRecorder {
+        fn on_event(&self, event: &tracing::Event<'_>, _: layer::Context<'_, S>) {
+            struct Visitor<'a>(&'a mut HashMap<String, String>);
+            impl Visit for Visitor<'_> {
+                fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                    self.0
+                        .insert(field.name().to_owned(), format!("{:?}", value));
+                }
+            }
+            let mut fields = HashMap::new();
+            event.record(&mut Visitor(&mut fields));
+            self.0.lock().unwrap().push(fields);
+        }
+    }
+
+    // Compaction should log an event as it starts and another once it has finished, with what it did.
+    #[test]
+    fn compaction_events() -> StorageResult<()> {
+        let recorder = EventRecorder::default();
+        let _guard = tracing::subscriber::set_default(Registry::default().with(recorder.clone()));
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Bitcask::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key2".to_owned())?;
+        let plan = store.compact_plan()?;
+        store.compact()?;
+
+        let events = recorder.0.lock().unwrap().clone();
+        let event = |message: &str| {
+            events
+                .iter()
+                .find(|event| event["message"] == message)
+                .cloned()
+                .unwrap_or_else(|| panic!("no {} event in {:?}", message, events))
+        };
+        let started = event("compaction started");
+        assert_eq!(started["live_keys"], "1");
+        let finished = event("compaction finished");
+        assert_eq!(finished["merged_keys"], "1");
+        assert_eq!(finished["dropped_keys"], "1");
+        assert_eq!(
+            finished["merged_files"],
+            plan.files_to_remove.len().to_string()
+        );
+        assert_eq!(
+            finished["bytes_written"],
+            plan.estimated_bytes_after.to_string()
+        );
+        assert!(finished.contains_key("elapsed_ms"));
+        Ok(())
+    }
+
+    // Compaction should keep tombstones written within `tombstone_grace` and drop older ones.
+    #[test]
+    fn tombstone_grace() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            tombstone_grace: Duration::from_millis(500),
+            ..BitcaskOptions::default()
+        };
+        let store = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("aged".to_owned(), "value".to_owned())?;
+        store.set("fresh".to_owned(), "value".to_owned())?;
+        store.set("kept".to_owned(), "value".to_owned())?;
+        store.remove("aged".to_owned())?;
+        thread::sleep(Duration::from_millis(600));
+        store.remove("fresh".to_owned())?;
+
+        let plan = store.compact_plan()?;
+        store.compact()?;
+        let retained = vec![
+            ("fresh".to_owned(), None),
+            ("kept".to_owned(), Some("value".to_owned())),
+        ];
+        assert_eq!(
+            store.changes_since(0)?.collect::<StorageResult<Vec<_>>>()?,
+            retained
+        );
+        assert_eq!(store.get("fresh".to_owned())?, None);
+        assert_eq!(store.len()?, 1);
+        assert_eq!(store.stats().total_bytes, plan.estimated_bytes_after);
+
+        // The retained tombstone is read back from the hint file, and dropped once it ages past the grace period.
+        drop(store);
+        let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(
+            store.changes_since(0)?.collect::<StorageResult<Vec<_>>>()?,
+            retained
+        );
+        assert_eq!(store.get("fresh".to_owned())?, None);
+        thread::sleep(Duration::from_millis(600));
+        store.compact()?;
+        assert_eq!(
+            store.changes_since(0)?.collect::<StorageResult<Vec<_>>>()?,
+            vec![("kept".to_owned(), Some("value".to_owned()))]
+        );
+        Ok(())
+    }
+
+    // A write should be visible to every other clone as soon as it returns, including the writes that rotate the active
+    // file, whose value is in a file the reading clone may not have open yet.
+    #[test]
+    fn read_your_writes_across_rotations() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            max_log_size: 256,
+            ..BitcaskOptions::default()
+        };
+        let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+
+        // The channel has no capacity, so each key is read the moment `set` has returned for it.
+        let (written, reads) = std::sync::mpsc::sync_channel::<usize>(0);
+        let reader = {
+            let store = store.clone();
+            std::thread::spawn(move || {
+                for key_id in reads {
+                    // Reading the keys before it as well makes the reader prefetch from files the writer is appending to.
+                    for key_id in key_id.saturating_sub(8)..=key_id {
+                        assert_eq!(
+                            store.get(format!("key{}", key_id % 100)).unwrap(),
+                            Some(format!("value{}", key_id))
+                        );
+                    }
+                }
+            })
+        };
+        // Keys are overwritten every hundred writes, long after the reader has moved past them, so a stale read fails.
+        for key_id in 0..5000 {
+            store.set(format!("key{}", key_id % 100), format!("value{}", key_id))?;
+            written.send(key_id).unwrap();
+        }
+        drop(written);
+        reader.join().unwrap();
+        assert!(log_file_count(&StdFileSystem, temp_dir.path())? > 100);
+
+        Ok(())
+    }
+
+    // Reads on other clones should carry on through compactions, and the merged files should be gone once every
+    // clone has closed its handles to them.
+    #[test]
+    fn compaction_with_open_readers() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            max_log_size: 64 * 1024,
+            ..BitcaskOptions::default()
+        };
+        let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+        for iter in 0..2 {
+            for key_id in 0..5000 {
+                store.set(
+                    format!("key{}", key_id),
+                    format!("value{}-{}", key_id, iter),
+                )?;
+            }
+        }
+
+        // Holds a handle to every log file, and does not read again until the compactions are done.
+        let idle = store.clone();
+        for key_id in 0..5000 {
+            assert!(idle.get(format!("key{}", key_id))?.is_some());
+        }
+
+        let compacting = Arc::new(AtomicBool::new(true));
+        let reader = {
+            let store = store.clone();
+            let compacting = compacting.clone();
+            std::thread::spawn(move || {
+                let mut key_id = 0;
+                while compacting.load(Ordering::SeqCst) {
+                    assert_eq!(
+                        store.get(format!("key{}", key_id)).unwrap(),
+                        Some(format!("value{}-1", key_id))
+                    );
+                    key_id = (key_id + 1) % 5000;
+                }
+            })
+        };
+        for _ in 0..3 {
+            store.compact()?;
+        }
+        compacting.store(false, Ordering::SeqCst);
+        reader.join().unwrap();
+
+        // Only the last merge file, its hint and the active file are left.
+        let mut files: Vec<String> = fs::read_dir(temp_dir.path())?
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(LOG_FILE_EXT) || name.ends_with(HINT_FILE_EXT))
+            .collect();
+        files.sort();
+        assert_eq!(files.len(), 3, "{:?}", files);
+
+        // The idle clone closes its handles to the merged files on its next read.
+        #[cfg(target_os = "linux")]
+        let deleted_handles = || {
+            fs::read_dir("/proc/self/fd")
+                .unwrap()
+                .filter_map(|fd| fs::read_link(fd.ok()?.path()).ok())
+                .filter(|target| {
+                    target.starts_with(temp_dir.path())
+                        && target.to_string_lossy().ends_with(" (deleted)")
+                })
+                .count()
+        };
+        #[cfg(target_os = "linux")]
+        assert!(deleted_handles() > 0);
+        assert_eq!(idle.get("key0".to_owned())?, Some("value0-1".to_owned()));
+        #[cfg(target_os = "linux")]
+        assert_eq!(deleted_handles(), 0);
+
+        drop((store, idle));
+        let store = Bitcask::open(temp_dir.path())?;
+        for key_id in 0..5000 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}-1", key_id))
+            );
+        }
+        Ok(())
+    }
+
+    // A merged file that cannot be removed should be kept and removed by a later attempt.
+    #[test]
+    fn deferred_removal() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let busy = temp_dir.path().join("0.log");
+        let free = temp_dir.path().join("1.log");
+        // A directory cannot be removed as a file, standing in for a file that is still open.
+        fs::create_dir(&busy)?;
+        fs::write(busy.join("contents"), "")?;
+        fs::write(&free, "")?;
+
+        let obsolete = ObsoleteFiles::new(StdFileSystem, 2);
+        obsolete.remove(vec![busy.clone(), free.clone()])?;
+        assert!(!free.exists());
+        assert_eq!(*obsolete.pending.lock()?, vec![busy.clone()]);
+
+        fs::remove_dir_all(&busy)?;
+        fs::write(&busy, "")?;
+        obsolete.remove(vec![])?;
+        assert!(!busy.exists());
+        assert!(obsolete.pending.lock()?.is_empty());
+        Ok(())
+    }
+
+    // A hint file left unfinished by a compaction that stopped before renaming it should be removed on open.
+    #[test]
+    fn unfinished_hint_file() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Bitcask::open(temp_dir.path())?;
+        store.set("key".to_owned(), "value".to_owned())?;
+        drop(store);
+        let hint_tmp = hint_tmp_path(temp_dir.path(), &2);
+        fs::write(&hint_tmp, "unfinished")?;
+
+        // Opening read only leaves the directory as it is.
+        Bitcask::open_up_to(temp_dir.path(), 1)?;
+        assert!(hint_tmp.exists());
+
+        let store = Bitcask::open(temp_dir.path())?;
+        assert!(!hint_tmp.exists());
+        store.compact()?;
+        assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+        Ok(())
+    }
+
+    // Should only see the values written up to the given file, and refuse to change anything.
+    #[test]
+    fn open_up_to() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            max_log_size: 128,
+            ..BitcaskOptions::default()
+        };
+        let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+
+        // Record the value of every key as of each segment being sealed.
+        let mut sealed = Vec::new();
+        for iter in 0..20 {
+            for key_id in 0..5 {
+                store.set(format!("key{}", key_id), format!("value{}", iter))?;
+            }
+            let segments = store.segments()?;
+            if segments.len() > sealed.len() + 1 {
+                sealed.push((segments[segments.len() - 2].file_id, iter));
+            }
+        }
+        store.remove("key0".to_owned())?;
+        drop(store);
+        assert!(sealed.len() > 2);
+
+        let (max_file_id, iter) = sealed[1];
+        let past = Bitcask::open_up_to(temp_dir.path(), max_file_id)?;
+        for key_id in 0..5 {
+            assert_eq!(
+                past.get(format!("key{}", key_id))?,
+                Some(format!("value{}", iter))
+            );
+        }
+        assert!(past.segments()?.iter().all(|s| s.file_id <= max_file_id));
+        assert!(matches!(
+            past.set("key1".to_owned(), "value".to_owned()),
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(past.compact(), Err(StorageError::ReadOnly)));
+        drop(past);
+
+        // The later segments are untouched.
+        let store = Bitcask::open(temp_dir.path())?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        assert_eq!(store.get("key1".to_owned())?, Some("value19".to_owned()));
+
+        // Once compacted, the earlier segments are gone.
+        store.compact()?;
+        drop(store);
+        assert!(Bitcask::open_up_to(temp_dir.path(), max_file_id).is_err());
+
+        Ok(())
+    }
+
+    // Should report every log file on disk along with the keys it holds.
+    #[test]
+    fn get_with_segment() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            max_log_size: 128,
+            ..BitcaskOptions::default()
+        };
+        let bitcask = Bitcask::open_with_options(temp_dir.path(), options)?;
+
+        bitcask.set("key1".to_owned(), "value1".to_owned())?;
+        let (value, metadata) = bitcask.get_with_segment("key1".to_owned())?.unwrap();
+        assert_eq!(value, "value1");
+        assert!(metadata.is_active_segment);
+
+        // Writing past the size limit rotates the active file, sealing the one holding key1.
+        while bitcask.get_with_segment("key1".to_owned())?.unwrap().1 == metadata {
+            bitcask.set("key2".to_owned(), "v".repeat(32))?;
+        }
+        let (value, sealed) = bitcask.get_with_segment("key1".to_owned())?.unwrap();
+        assert_eq!(value, "value1");
+        assert_eq!(
+            (sealed.file_id, sealed.timestamp),
+            (metadata.file_id, metadata.timestamp)
+        );
+        assert!(!sealed.is_active_segment);
+
+        bitcask.set("key3".to_owned(), "value3".to_owned())?;
+        let (_, active) = bitcask.get_with_segment("key3".to_owned())?.unwrap();
+        assert!(active.is_active_segment);
+        assert!(active.file_id > sealed.file_id);
+        assert_eq!(bitcask.get_with_segment("key4".to_owned())?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn segments() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            max_log_size: 128,
+            ..BitcaskOptions::default()
+        };
+        let bitcask = Bitcask::open_with_options(temp_dir.path(), options)?;
+
+        let check = |bitcask: &Bitcask| -> StorageResult<Vec<SegmentInfo>> {
+            bitcask.flush()?;
+            let segments = bitcask.segments()?;
+            let mut on_disk: Vec<(u64, u64)> = fs::read_dir(temp_dir.path())?
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("log"))
+                .map(|path| {
+                    let file_id = path.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+                    (file_id, fs::metadata(&path).unwrap().len())
+                })
+                .collect();
+            on_disk.sort_unstable();
+            let reported: Vec<(u64, u64)> = segments
+                .iter()
+                .map(|segment| (segment.file_id, segment.size_bytes))
+                .collect();
+            assert_eq!(reported, on_disk);
+            assert_eq!(
+                segments
+                    .iter()
+                    .map(|segment| segment.live_keys)
+                    .sum::<usize>(),
+                bitcask.list_keys().len()
+            );
+            assert!(segments.last().unwrap().is_active);
+            assert_eq!(
+                segments.iter().filter(|segment| segment.is_active).count(),
+                1
+            );
+            Ok(segments)
+        };
+
+        for key_id in 0..20 {
+            bitcask.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        bitcask.remove("key0".to_owned())?;
+        let segments = check(&bitcask)?;
+        assert!(segments.len() > 2);
+        assert!(segments.iter().all(|segment| !segment.has_hint));
+
+        bitcask.compact()?;
+        let segments = check(&bitcask)?;
+        assert_eq!(segments.len(), 2);
+        assert!(segments[0].has_hint);
+        assert_eq!(segments[0].live_keys, 19);
+        assert_eq!(segments[1].live_keys, 0);
+
+        Ok(())
+    }
+
+    // Should match and order prefixes by the normalized keys while keeping the keys as written.
+    #[test]
+    fn scan_prefix_with_normalizer() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            key_normalizer: Some(str::to_lowercase),
+            ..BitcaskOptions::default()
+        };
+        let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+        store.set("abc".to_owned(), "value1".to_owned())?;
+        store.set("ABd".to_owned(), "value2".to_owned())?;
+        store.set("Abb".to_owned(), "value3".to_owned())?;
+        store.set("b".to_owned(), "value4".to_owned())?;
+
+        assert_eq!(
+            store.scan_prefix("AB".to_owned())?,
+            vec!["Abb".to_owned(), "abc".to_owned(), "ABd".to_owned()]
+        );
+        assert_eq!(store.get("abc".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("ABC".to_owned())?, None);
+
+        assert_eq!(store.remove_prefix("aB".to_owned())?, 3);
+        assert_eq!(store.list_keys(), vec!["b".to_owned()]);
+
+        // Without a normalizer prefixes match the original bytes.
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Bitcask::open(temp_dir.path())?;
+        store.set("abc".to_owned(), "value1".to_owned())?;
+        store.set("ABd".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.scan_prefix("AB".to_owned())?, vec!["ABd".to_owned()]);
+
+        Ok(())
+    }
+
+    // Should count live keys and estimate the key directory size growing linearly with the keys.
+    #[test]
+    fn stats() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Bitcask::open(temp_dir.path())?;
+        assert_eq!(
+            store.stats(),
+            BitcaskStats {
+                keys: 0,
+                index_memory_estimate: 0,
+                total_bytes: 0,
+                live_bytes: 0,
+                dead_bytes: 0,
+                fragmentation_ratio: 0.0,
+                avg_segment_size: 0,
+            }
+        );
+
+        for i in 0..1000 {
+            store.set(format!("key{:04}", i), "value".to_owned())?;
+        }
+        let first = store.index_memory_estimate();
+        assert_eq!(first, 1000 * (7 + KEY_DIR_ENTRY_OVERHEAD));
+        for i in 1000..2000 {
+            store.set(format!("key{:04}", i), "value".to_owned())?;
+        }
+        assert_eq!(store.index_memory_estimate(), 2 * first);
+
+        // Overwrites reuse the entry, removed keys stay in the key directory until a compaction. The overwritten and
+        // removed values are dead along with the tombstone.
+        store.set("key0000".to_owned(), "value2".to_owned())?;
+        store.remove("key0001".to_owned())?;
+        let record_len = RECORD_HEADER_LEN + 7 + 5;
+        let live_bytes = 1998 * record_len + record_len + 1;
+        let dead_bytes = 2 * record_len + RECORD_HEADER_LEN + 7;
+        assert_eq!(
+            store.stats(),
+            BitcaskStats {
+                keys: 1999,
+                index_memory_estimate: 2 * first,
+                total_bytes: live_bytes + dead_bytes,
+                live_bytes,
+                dead_bytes,
+                fragmentation_ratio: dead_bytes as f64 / (live_bytes + dead_bytes) as f64,
+                avg_segment_size: live_bytes + dead_bytes,
+            }
+        );
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            bloom_filter_bits: Some(1024),
+            ..BitcaskOptions::default()
+        };
+        let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.index_memory_estimate(), 1024 / 8);
+
+        Ok(())
+    }
+
+    // Fragmentation should rise as values are overwritten and drop back to nothing once a compaction drops them.
+    #[test]
+    fn fragmentation_ratio() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            max_log_size: 1024,
+            ..BitcaskOptions::default()
+        };
+        let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+        for i in 0..100 {
+            store.set(format!("key{:02}", i), "value".to_owned())?;
+        }
+        let mut fragmentation_ratio = store.stats().fragmentation_ratio;
+        assert_eq!(fragmentation_ratio, 0.0);
+        for round in 0..5 {
+            for i in 0..100 {
+                store.set(format!("key{:02}", i), format!("value{}", round))?;
+            }
+            let stats = store.stats();
+            assert!(stats.fragmentation_ratio > fragmentation_ratio);
+            fragmentation_ratio = stats.fragmentation_ratio;
+        }
+        assert!(fragmentation_ratio > 0.75);
+
+        let stats = store.stats();
+        let log_files = store.segments()?.len() as u64;
+        assert!(log_files > 1);
+        assert_eq!(stats.avg_segment_size, stats.total_bytes / log_files);
+
+        store.compact()?;
+        let stats = store.stats();
+        assert!(stats.fragmentation_ratio < 0.01);
+        assert_eq!(
+            stats,
+            Bitcask::open_up_to(temp_dir.path(), u64::MAX)?.stats()
+        );
+
+        Ok(())
+    }
+
+    // Should plan to remove exactly the files a compaction then removes, without changing the store.
+    #[test]
+    fn compact_plan() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            max_log_size: 128,
+            ..BitcaskOptions::default()
+        };
+        let bitcask = Bitcask::open_with_options(temp_dir.path(), options)?;
+
+        let files = || -> Vec<(u64, String)> {
+            let mut files: Vec<(u64, String)> = fs::read_dir(temp_dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .filter_map(|name| Some((name.split('.').next()?.parse().ok()?, name)))
+                .collect();
+            files.sort_unstable();
+            files
+        };
+
+        for round in 0..2 {
+            for key_id in 0..20 {
+                bitcask.set(format!("key{}", key_id), format!("value{}", round))?;
+            }
+            bitcask.remove("key0".to_owned())?;
+            bitcask.set("key1".to_owned(), "a longer value".to_owned())?;
+            bitcask.flush()?;
+
+            let before = files();
+            let plan = bitcask.compact_plan()?;
+            assert_eq!(files(), before);
+            assert_eq!(plan.live_keys, 19);
+
+            bitcask.compact()?;
+            let after = files();
+            let mut removed: Vec<u64> = before
+                .iter()
+                .filter(|file| !after.contains(file))
+                .map(|(file_id, _)| *file_id)
+                .collect();
+            removed.dedup();
+            assert_eq!(plan.files_to_remove, removed);
+
+            let size_after: u64 = after
+                .iter()
+                .map(|(_, name)| fs::metadata(temp_dir.path().join(name)).unwrap().len())
+                .sum();
+            assert_eq!(plan.estimated_bytes_after, size_after);
+        }
+
+        Ok(())
+    }
+
+    // Should write only the current version of each live key into the new directory, leaving the store unchanged.
+    #[test]
+    fn compact_into() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+        let dest = dest_dir.path().join("compacted");
+        let options = BitcaskOptions {
+            max_log_size: 128,
+            ..BitcaskOptions::default()
+        };
+        let bitcask = Bitcask::open_with_options(temp_dir.path(), options)?;
+
+        for round in 0..3 {
+            for key_id in 0..20 {
+                bitcask.set(format!("key{}", key_id), format!("value{}", round))?;
+            }
+        }
+        bitcask.remove("key0".to_owned())?;
+        bitcask.remove("key1".to_owned())?;
+        bitcask.flush()?;
+
+        let files = || {
+            let mut files: Vec<_> = fs::read_dir(temp_dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            files.sort_unstable();
+            files
+        };
+        let before = files();
+        let plan = bitcask.compact_plan()?;
+        let stats = bitcask.compact_into(&dest)?;
+        assert_eq!(files(), before);
+        assert_eq!(stats.live_keys, 18);
+        assert_eq!(stats.bytes_written, plan.estimated_bytes_after);
+        assert_eq!(bitcask.len()?, 18);
+
+        let compacted = Bitcask::open(&dest)?;
+        assert_eq!(compacted.list_keys(), bitcask.list_keys());
+        for key in bitcask.list_keys() {
+            assert_eq!(compacted.get(key.clone())?, bitcask.get(key)?);
+        }
+        let records = compacted.replay()?.collect::<StorageResult<Vec<_>>>()?;
+        assert_eq!(records.len(), 18);
+        assert!(records.iter().all(|record| !record.is_tombstone));
+
+        // The copy is written into a fresh directory only.
+        assert!(matches!(
+            bitcask.compact_into(&dest),
+            Err(StorageError::Io(e)) if e.kind() == io::ErrorKind::AlreadyExists
+        ));
+
+        Ok(())
+    }
+
+    // Should name the file and what was being done with it when the data directory is not writable.
+    #[cfg(unix)]
+    #[test]
+    fn open_read_only_dir() -> StorageResult<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        fs::set_permissions(temp_dir.path(), fs::Permissions::from_mode(0o555))?;
+        // Permissions do not apply to root, which can write to the directory anyway.
+        if File::create(temp_dir.path().join("probe")).is_ok() {
+            return Ok(());
+        }
+
+        let err = match Bitcask::open(temp_dir.path()) {
+            Err(err) => err,
+            Ok(_) => panic!("opened a store in a read-only directory"),
+        };
+        let message = err.to_string();
+        assert!(
+            matches!(&err, StorageError::Access { source, .. } if source.kind() == io::ErrorKind::PermissionDenied),
+            "{:?}",
+            err
+        );
+        assert!(
+            message.contains("open the active log file for writing"),
+            "{}",
+            message
+        );
+        assert!(
+            message.contains(&temp_dir.path().display().to_string()),
+            "{}",
+            message
+        );
+
+        fs::set_permissions(temp_dir.path(), fs::Permissions::from_mode(0o755))?;
+        Ok(())
+    }
+
+    // Should report a store as new only when it was opened without any data files.
+    #[test]
+    fn is_new() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Bitcask::open(temp_dir.path())?;
+        assert!(store.is_new());
+        assert!(store.clone().is_new());
+        drop(store);
+
+        // The active log file left by the first open marks the store as existing, even without any writes.
+        assert!(!Bitcask::open(temp_dir.path())?.is_new());
+        assert!(!Bitcask::open_up_to(temp_dir.path(), 0)?.is_new());
+        Ok(())
+    }
+
+    // Should keep the record written last for a key even if its timestamp is earlier, as after the clock went back.
+    #[test]
+    fn clock_skew() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+        let write_log = |file_id: u64, records: &[(&str, &str, u64)]| -> StorageResult<()> {
+            let mut log = Cursor::new(Vec::new());
+            for (key, value, timestamp) in records {
+                write_value(
+                    &mut log,
+                    file_id,
+                    &key.to_string(),
+                    Some(&value.to_string()),
+                    *timestamp,
+                    None,
+                )?;
+            }
+            fs::write(log_path(temp_dir.path(), &file_id), log.into_inner())?;
+            Ok(())
+        };
+        // Within a file, and across files.
+        write_log(
+            LOWEST_LOG_FILE_ID,
+            &[("key1", "value1", 2000), ("key1", "value2", 1000)],
+        )?;
+        write_log(
+            LOWEST_LOG_FILE_ID + 1,
+            &[("key2", "value3", 4000), ("key1", "value4", 500)],
+        )?;
+        write_log(LOWEST_LOG_FILE_ID + 2, &[("key2", "value5", 3000)])?;
+        meta::write(&StdFileSystem, temp_dir.path(), ENGINE, FORMAT_VERSION)?;
+
+        let store = Bitcask::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value5".to_owned()));
+
+        // The same versions are kept by compaction and read back from the hint file.
+        store.compact()?;
+        drop(store);
+        let store = Bitcask::open(temp_dir.path())?;
+        assert_eq!(
+            store.get_with_metadata("key1".to_owned())?,
+            Some(("value4".to_owned(), Some(500)))
+        );
+        assert_eq!(store.get("key2".to_owned())?, Some("value5".to_owned()));
+
+        Ok(())
+    }
+
+    // Should read and migrate a store written before records carried a format version.
+    #[test]
+    fn read_format_v0() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+        // Version 0 records are the version 1 records without the leading version byte
+        let mut log = Vec::new();
+        for (key, value) in [("key1", "value1"), ("key2", "value2"), ("key1", "value3")] {
+            let mut record = Vec::new();
+            write_value(
+                &mut std::io::Cursor::new(&mut record),
+                0,
+                &key.to_owned(),
+                Some(&value.to_owned()),
+                0,
+                None,
+            )?;
+            log.extend_from_slice(&record[1..]);
+        }
+        fs::write(log_path(temp_dir.path(), &0), log)?;
+
+        let store = Bitcask::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(
+            read_format(&StdFileSystem, temp_dir.path())?,
+            Some(FORMAT_VERSION)
+        );
+        assert!(!log_path(temp_dir.path(), &0).exists());
+
+        store.set("key3".to_owned(), "value4".to_owned())?;
+        drop(store);
+        let store = Bitcask::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+
+        Ok(())
+    }
+
+    // A migration cut short by a crash should be recognised on the next open, whether or not its merge file was
+    // installed.
+    #[test]
+    fn interrupted_migration() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut log = Vec::new();
+        for (key, value) in [("key1", "value1"), ("key2", "value2")] {
+            let mut record = Vec::new();
+            write_value(
+                &mut std::io::Cursor::new(&mut record),
+                0,
+                &key.to_owned(),
+                Some(&value.to_owned()),
+                0,
+                None,
+            )?;
+            log.extend_from_slice(&record[1..]);
+        }
+        fs::write(log_path(temp_dir.path(), &0), log)?;
+        let assert_values = |store: &Bitcask| -> StorageResult<()> {
+            assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+            assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+            Ok(())
+        };
+
+        // Before its hint file is in place, the partly written merge file is dropped and the migration starts over.
+        meta::write_migration(&StdFileSystem, temp_dir.path(), ENGINE, 0, 1)?;
+        fs::write(log_path(temp_dir.path(), &1), [FORMAT_VERSION, 0, 0])?;
+        let store = Bitcask::open(temp_dir.path())?;
+        assert_values(&store)?;
+        assert!(hint_path(temp_dir.path(), &1).exists());
+        assert_eq!(
+            meta::read(&StdFileSystem, temp_dir.path())?.and_then(|meta| meta.migration),
+            None
+        );
+        drop(store);
 
-impl Writer {
-    fn write_value(&mut self, key: &String, value: &String) -> StorageResult<Entry> {
-        write_value(self.writer.get_mut(), self.active_file_id, key, value)
+        // Once its hint file is in place, the store is in the current format even though the meta file still records
+        // the old one.
+        meta::write_migration(&StdFileSystem, temp_dir.path(), ENGINE, 0, 1)?;
+        let store = Bitcask::open(temp_dir.path())?;
+        assert_values(&store)?;
+        assert_eq!(
+            meta::read(&StdFileSystem, temp_dir.path())?.map(|meta| meta.format_version),
+            Some(FORMAT_VERSION)
+        );
+        Ok(())
     }
 
-    fn set_writer(&mut self, active_file_id: u64) -> StorageResult<()> {
-        self.writer = BufWriter::new(
-            fs::OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(log_path(&self.path, &active_file_id))?,
+    // Should read and migrate a store whose records are timestamped in seconds.
+    #[test]
+    fn read_format_v1() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+        // Version 1 records are the version 2 records with their timestamp in seconds
+        let mut log = Vec::new();
+        for (key, value, timestamp) in [("key1", "value1", 1), ("key2", "value2", 2)] {
+            let mut record = Vec::new();
+            write_value(
+                &mut std::io::Cursor::new(&mut record),
+                0,
+                &key.to_owned(),
+                Some(&value.to_owned()),
+                timestamp,
+                None,
+            )?;
+            record[0] = 1;
+            log.extend_from_slice(&record);
+        }
+        fs::write(log_path(temp_dir.path(), &0), log)?;
+        fs::write(temp_dir.path().join(FORMAT_FILE), [1])?;
+
+        let store = Bitcask::open(temp_dir.path())?;
+        assert_eq!(
+            store.get_with_metadata("key1".to_owned())?,
+            Some(("value1".to_owned(), Some(1000)))
+        );
+        assert_eq!(
+            read_format(&StdFileSystem, temp_dir.path())?,
+            Some(FORMAT_VERSION)
+        );
+        assert!(!log_path(temp_dir.path(), &0).exists());
+
+        // The migrated timestamps are read back from the hint file as they are
+        drop(store);
+        let store = Bitcask::open(temp_dir.path())?;
+        assert_eq!(
+            store.get_with_metadata("key2".to_owned())?,
+            Some(("value2".to_owned(), Some(2000)))
         );
-        self.active_file_id = active_file_id;
+
         Ok(())
     }
-}
 
-#[derive(Debug)]
-struct Reader {
-    path: Arc<PathBuf>,
-    readers: RefCell<HashMap<u64, BufReader<File>>>,
-}
+    // Should read and migrate a store whose tombstones are written as empty values.
+    #[test]
+    fn read_format_v2() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-impl Reader {
-    fn read_value(&self, entry: &Entry) -> StorageResult<String> {
-        let mut readers = self.readers.borrow_mut();
-        if let Some(reader) = readers.get_mut(&entry.file_id) {
-            return read_value(reader, entry);
+        // Version 2 records are the version 3 records with an empty value for a tombstone
+        let mut log = Vec::new();
+        for (key, value) in [("key1", "value1"), ("key2", "value2"), ("key1", "")] {
+            let mut record = Vec::new();
+            write_value(
+                &mut std::io::Cursor::new(&mut record),
+                0,
+                &key.to_owned(),
+                Some(&value.to_owned()),
+                0,
+                None,
+            )?;
+            record[0] = 2;
+            log.extend_from_slice(&record);
         }
-        let mut reader = BufReader::new(
-            fs::OpenOptions::new()
-                .read(true)
-                .open(log_path(&self.path, &entry.file_id))?,
+        fs::write(log_path(temp_dir.path(), &0), log)?;
+        meta::write(&StdFileSystem, temp_dir.path(), ENGINE, 2)?;
+
+        let store = Bitcask::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(
+            read_format(&StdFileSystem, temp_dir.path())?,
+            Some(FORMAT_VERSION)
         );
-        let value = read_value(&mut reader, entry)?;
-        readers.insert(entry.file_id, reader);
-        Ok(value)
+        assert!(!log_path(temp_dir.path(), &0).exists());
+
+        // Empty values written since the migration are values like any other
+        store.set("key3".to_owned(), "".to_owned())?;
+        drop(store);
+        let store = Bitcask::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, Some("".to_owned()));
+
+        Ok(())
     }
-}
 
-impl Clone for Reader {
-    fn clone(&self) -> Self {
-        Reader {
-            path: self.path.clone(),
-            readers: RefCell::new(HashMap::new()),
+    // Should prefix records with their version and refuse to read unknown versions.
+    #[test]
+    fn read_format_v3() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Bitcask::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        drop(store);
+        assert_eq!(
+            read_format(&StdFileSystem, temp_dir.path())?,
+            Some(FORMAT_VERSION)
+        );
+
+        let store = Bitcask::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        drop(store);
+
+        let path = log_path(temp_dir.path(), &LOWEST_LOG_FILE_ID);
+        let mut log = fs::read(&path)?;
+        assert_eq!(log[0], 3);
+        log[0] = 7;
+        fs::write(&path, log)?;
+        assert!(matches!(
+            Bitcask::open(temp_dir.path()),
+            Err(StorageError::UnsupportedFormat(7))
+        ));
+
+        fs::write(temp_dir.path().join(FORMAT_FILE), [FORMAT_VERSION + 1])?;
+        fs::remove_file(temp_dir.path().join("STORE_META"))?;
+        assert!(matches!(
+            Bitcask::open(temp_dir.path()),
+            Err(StorageError::IncompatibleFormat { format_version, .. }) if format_version == FORMAT_VERSION + 1
+        ));
+
+        Ok(())
+    }
+
+    // Should only write version 4 records for values with an expiry, and keep the expiry through compaction.
+    #[test]
+    fn read_format_v4() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Bitcask::open(temp_dir.path())?;
+        store.set_with_ttl(
+            "short".to_owned(),
+            "value1".to_owned(),
+            Duration::from_millis(200),
+        )?;
+        store.set_with_ttl(
+            "long".to_owned(),
+            "value2".to_owned(),
+            Duration::from_secs(60),
+        )?;
+        store.set("plain".to_owned(), "value3".to_owned())?;
+        drop(store);
+
+        let log = fs::read(log_path(temp_dir.path(), &LOWEST_LOG_FILE_ID))?;
+        assert_eq!(log[0], EXPIRY_FORMAT_VERSION);
+        let record_len = |key: &str, value: &str| (key.len() + value.len()) as u64;
+        assert_eq!(
+            log.len() as u64,
+            2 * (RECORD_HEADER_LEN + RECORD_EXPIRY_LEN)
+                + RECORD_HEADER_LEN
+                + record_len("short", "value1")
+                + record_len("long", "value2")
+                + record_len("plain", "value3")
+        );
+
+        // The expiries are read back from the log, then from the hint file written by the compaction.
+        let store = Bitcask::open(temp_dir.path())?;
+        store.compact()?;
+        drop(store);
+        let store = Bitcask::open(temp_dir.path())?;
+        thread::sleep(Duration::from_millis(250));
+        assert_eq!(store.get("short".to_owned())?, None);
+        assert_eq!(store.get("long".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("plain".to_owned())?, Some("value3".to_owned()));
+
+        // The expired value is dropped by the next compaction.
+        store.compact()?;
+        assert_eq!(store.compact_plan()?.live_keys, 2);
+        assert!(store.key_dir.get("short").is_none());
+
+        Ok(())
+    }
+
+    // Should record the engine and format version in the store meta file and refuse stores it cannot read.
+    #[test]
+    fn store_meta() -> StorageResult<()> {
+        // Matching
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Bitcask::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        drop(store);
+        assert_eq!(
+            meta::read(&StdFileSystem, temp_dir.path())?,
+            Some(meta::StoreMeta {
+                engine: ENGINE.to_owned(),
+                format_version: FORMAT_VERSION,
+                migration: None,
+            })
+        );
+        let store = Bitcask::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        drop(store);
+
+        // Legacy, written before the meta file existed
+        fs::remove_file(temp_dir.path().join("STORE_META"))?;
+        let store = Bitcask::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        drop(store);
+        assert_eq!(
+            meta::read(&StdFileSystem, temp_dir.path())?.map(|meta| meta.format_version),
+            Some(FORMAT_VERSION)
+        );
+
+        // Newer
+        meta::write(&StdFileSystem, temp_dir.path(), ENGINE, FORMAT_VERSION + 1)?;
+        assert!(matches!(
+            Bitcask::open(temp_dir.path()),
+            Err(StorageError::IncompatibleFormat { format_version, .. }) if format_version == FORMAT_VERSION + 1
+        ));
+
+        // Another engine
+        meta::write(&StdFileSystem, temp_dir.path(), "sled", FORMAT_VERSION)?;
+        assert!(matches!(
+            Bitcask::open(temp_dir.path()),
+            Err(StorageError::IncompatibleFormat { engine, .. }) if engine == "sled"
+        ));
+
+        Ok(())
+    }
+
+    // Exceeding `max_log_files` should compact the rotated log files in the background.
+    #[test]
+    fn compaction_triggered_by_log_file_count() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            max_log_size: 128,
+            max_log_files: Some(4),
+            ..BitcaskOptions::default()
+        };
+        let bitcask = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
+
+        for key_id in 0..1000 {
+            bitcask.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+
+        let start = std::time::Instant::now();
+        while log_file_count(&StdFileSystem, temp_dir.path())? > 4 {
+            assert!(
+                start.elapsed() < std::time::Duration::from_secs(10),
+                "expected background compaction to collapse the log files"
+            );
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        for key_id in 0..1000 {
+            assert_eq!(
+                bitcask.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+
+        // Open from disk again and check persistent data
+        drop(bitcask);
+        let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+        for key_id in 0..1000 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
         }
+
+        Ok(())
     }
-}
 
-fn log_path(path: &Path, gen: &u64) -> PathBuf {
-    path.join(format!("{}.log", gen))
-}
+    // Exceeding `max_dead_bytes` should compact the dead records away in the background.
+    #[test]
+    fn compaction_triggered_by_dead_bytes() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            max_log_size: 1024,
+            max_dead_bytes: Some(4096),
+            ..BitcaskOptions::default()
+        };
+        let bitcask = Bitcask::open_with_options(temp_dir.path(), options)?;
 
-fn hint_path(path: &Path, gen: &u64) -> PathBuf {
-    path.join(format!("{}.hint", gen))
-}
+        // Only ever overwriting the same ten keys leaves almost every record dead.
+        for round in 0..100 {
+            for key_id in 0..10 {
+                bitcask.set(format!("key{}", key_id), format!("value{}", round))?;
+            }
+        }
 
-// Write a key/value pair to the given writer in the bitcask format.
-// An entry indicating the location of the value for the given key is returned.
-// Fixed-width header            Variable-length body
-//+=====+=====+=====+====== - - +============== - - +
-//| u16 | u64 | u32 | u32       | [u8] | [u8] |
-//+=====+=====+=====+====== - - +============== - - +
-// checksum (2 bytes)
-// timestamp (8 bytes)
-// key_len (4 bytes)
-// val_len (4 bytes)
-// key (key_len bytes)
-// value (val_len bytes)
-fn write_value<W: Write + Seek>(
-    writer: &mut W,
-    file_id: u64,
-    key: &String,
-    value: &String,
-) -> StorageResult<Entry> {
-    let key_len = key.len();
-    let value_len = value.len();
-    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
-    let mut entry = Vec::<u8>::with_capacity(8 + 4 + 4 + key_len + value_len);
+        let start = std::time::Instant::now();
+        while bitcask.stats().dead_bytes > 4096 + 1024 {
+            assert!(
+                start.elapsed() < std::time::Duration::from_secs(10),
+                "expected background compaction to drop the dead records"
+            );
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        for key_id in 0..10 {
+            assert_eq!(
+                bitcask.get(format!("key{}", key_id))?,
+                Some("value99".to_owned())
+            );
+        }
 
-    entry.write_u64::<BigEndian>(timestamp)?;
-    entry.write_u32::<BigEndian>(key_len as u32)?;
-    entry.write_u32::<BigEndian>(value_len as u32)?;
-    entry.write_all(key.as_bytes())?;
-    entry.write_all(value.as_bytes())?;
+        Ok(())
+    }
 
-    let checksum = X25.checksum(&entry);
+    // The byte counts kept up to date by writes and compactions should match counting them from scratch.
+    #[test]
+    fn stats_bytes_match_directory() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            max_log_size: 512,
+            retain_versions: 2,
+            ..BitcaskOptions::default()
+        };
+        let store = Bitcask::open_with_options(temp_dir.path(), options)?;
 
-    writer.write_u16::<BigEndian>(checksum)?;
-    writer.write_all(&entry)?;
-    writer.flush()?;
+        let check = || -> StorageResult<()> {
+            let stats = store.stats();
+            let total_bytes: u64 = fs::read_dir(temp_dir.path())?
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| {
+                    let ext = path.extension().and_then(|ext| ext.to_str());
+                    ext == Some(LOG_FILE_EXT) || ext == Some(HINT_FILE_EXT)
+                })
+                .map(|path| fs::metadata(path).unwrap().len())
+                .sum();
+            assert_eq!(stats.total_bytes, total_bytes);
 
-    let value_pos = writer.seek(std::io::SeekFrom::Current(0))? - value_len as u64;
+            let recounted = Bitcask::open_up_to(temp_dir.path(), u64::MAX)?.stats();
+            assert_eq!(stats, recounted);
+            Ok(())
+        };
 
-    Ok(Entry {
-        file_id,
-        value_len: value_len as u32,
-        value_pos,
-        _timestamp: timestamp,
-    })
-}
+        check()?;
+        for round in 0..5 {
+            for key_id in 0..50 {
+                store.set(format!("key{}", key_id), format!("value{}", round))?;
+            }
+            check()?;
+        }
+        for key_id in 0..10 {
+            store.remove(format!("key{}", key_id))?;
+        }
+        store.touch("key10".to_owned())?;
+        store.set_with_ttl(
+            "key11".to_owned(),
+            "expiring".to_owned(),
+            Duration::from_secs(60),
+        )?;
+        store.rpush("queue".to_owned(), "a".to_owned())?;
+        check()?;
 
-// Read the next key/value entry from the given reader in the bitcask data format.
-// Fixed-width header            Variable-length body
-//+=====+=====+=====+====== - - +============== - - +
-//| u16 | u64 | u32 | u32       | [u8] | [u8] |
-//+=====+=====+=====+====== - - +============== - - +
-// checksum (2 bytes)
-// timestamp (8 bytes)
-// key_len (4 bytes)
-// val_len (4 bytes)
-// key (key_len bytes)
-// value (val_len bytes)
-fn read_next_entry<R: Read + Seek>(
-    reader: &mut R,
-    file_id: u64,
-) -> StorageResult<Option<(String, Entry)>> {
-    // Check if we are at the end of the reader
-    // Move back to the current position after checking
-    let current_pos = reader.seek(std::io::SeekFrom::Current(0))?;
-    if current_pos == reader.seek(std::io::SeekFrom::End(0))? {
-        return Ok(None);
+        store.bulk_load((0..100).map(|i| (format!("bulk{}", i), "value".to_owned())))?;
+        check()?;
+
+        store.compact()?;
+        check()?;
+        assert!(
+            store.stats().dead_bytes > 0,
+            "the retained versions are dead"
+        );
+
+        store.set("key20".to_owned(), "after compaction".to_owned())?;
+        store.remove("key21".to_owned())?;
+        store.compact()?;
+        check()?;
+
+        Ok(())
     }
-    reader.seek(std::io::SeekFrom::Start(current_pos))?;
 
-    let checksum = reader.read_u16::<BigEndian>()?;
-    let timestamp = reader.read_u64::<BigEndian>()?;
-    let key_len = reader.read_u32::<BigEndian>()?;
-    let value_len = reader.read_u32::<BigEndian>()?;
+    // A sharded key_dir should behave exactly like a single one, including key order.
+    #[test]
+    fn sharded_key_dir() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            key_dir_shards: 8,
+            ..BitcaskOptions::default()
+        };
+        let bitcask = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
 
-    let mut key_bytes = vec![0; key_len as usize];
-    reader.read_exact(&mut key_bytes)?;
+        let mut keys: Vec<String> = (0..100).map(|key_id| format!("key{}", key_id)).collect();
+        for key in keys.iter() {
+            bitcask.set(key.clone(), format!("value-{}", key))?;
+        }
+        bitcask.remove("key50".to_owned())?;
+        keys.retain(|key| key != "key50");
+        keys.sort();
 
-    let value_pos = reader.seek(std::io::SeekFrom::Current(0))?;
+        assert_eq!(bitcask.list_keys(), keys);
+        bitcask.compact()?;
+        assert_eq!(bitcask.list_keys(), keys);
 
-    let mut value_bytes = vec![0; value_len as usize];
-    reader.read_exact(&mut value_bytes)?;
+        // Open from disk again and check persistent data
+        drop(bitcask);
+        let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.list_keys(), keys);
+        for key in keys.iter() {
+            assert_eq!(store.get(key.clone())?, Some(format!("value-{}", key)));
+        }
+        assert_eq!(store.get("key50".to_owned())?, None);
 
-    let mut entry_bytes =
-        Vec::<u8>::with_capacity(8 + 4 + 4 + key_len as usize + value_len as usize);
-    entry_bytes.write_u64::<BigEndian>(timestamp)?;
-    entry_bytes.write_u32::<BigEndian>(key_len)?;
-    entry_bytes.write_u32::<BigEndian>(value_len)?;
-    entry_bytes.write_all(&key_bytes)?;
-    entry_bytes.write_all(&value_bytes)?;
+        Ok(())
+    }
 
-    let read_checksum = X25.checksum(&entry_bytes);
+    // A store with a bloom filter should still find every key it holds.
+    #[test]
+    fn bloom_filter() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            bloom_filter_bits: Some(256),
+            ..BitcaskOptions::default()
+        };
+        let bitcask = Bitcask::open_with_options(temp_dir.path(), options.clone())?;
 
-    if checksum != read_checksum {
-        return Err(StorageError::DataCorruption(checksum, read_checksum));
+        for key_id in 0..1000 {
+            bitcask.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        bitcask.remove("key500".to_owned())?;
+        assert!(bitcask
+            .set_if_absent("key1".to_owned(), "other".to_owned())
+            .is_ok_and(|set| !set));
+        assert_eq!(bitcask.get("absent".to_owned())?, None);
+        assert!(matches!(
+            bitcask.remove("absent".to_owned()),
+            Err(StorageError::KeyNotFound)
+        ));
+
+        let check = |bitcask: &Bitcask| -> StorageResult<()> {
+            for key_id in (0..1000).filter(|key_id| *key_id != 500) {
+                assert_eq!(
+                    bitcask.get(format!("key{}", key_id))?,
+                    Some(format!("value{}", key_id))
+                );
+            }
+            assert_eq!(bitcask.get("key500".to_owned())?, None);
+            Ok(())
+        };
+        check(&bitcask)?;
+
+        // The filter is rebuilt from disk
+        drop(bitcask);
+        let bitcask = Bitcask::open_with_options(temp_dir.path(), options)?;
+        check(&bitcask)?;
+
+        Ok(())
     }
 
-    let entry = Entry {
-        file_id,
-        value_len,
-        value_pos,
-        _timestamp: timestamp,
-    };
+    // Should push and pop from both ends of a queue, persisting it across reopens.
+    #[test]
+    fn queue() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Bitcask::open(temp_dir.path())?;
 
-    let key = String::from_utf8(key_bytes)?;
+        assert_eq!(store.rpush("queue".to_owned(), "a".to_owned())?, 1);
+        assert_eq!(store.rpush("queue".to_owned(), "b:2".to_owned())?, 2);
+        assert_eq!(store.lpush("queue".to_owned(), "z".to_owned())?, 3);
 
-    Ok(Some((key, entry)))
-}
+        // FIFO from the front, LIFO from the back
+        assert_eq!(store.lpop("queue".to_owned())?, Some("z".to_owned()));
+        assert_eq!(store.rpush("queue".to_owned(), "c".to_owned())?, 3);
+        assert_eq!(store.rpop("queue".to_owned())?, Some("c".to_owned()));
 
-// Read the value for the given entry from the given reader.
-fn read_value<R: Read + Seek>(reader: &mut R, entry: &Entry) -> StorageResult<String> {
-    reader.seek(std::io::SeekFrom::Start(entry.value_pos))?;
+        drop(store);
+        let store = Bitcask::open(temp_dir.path())?;
+        assert_eq!(store.lpop("queue".to_owned())?, Some("a".to_owned()));
+        assert_eq!(store.lpop("queue".to_owned())?, Some("b:2".to_owned()));
 
-    let mut value_bytes = vec![0; entry.value_len as usize];
-    reader.read_exact(&mut value_bytes)?;
+        // The emptied queue is removed
+        assert_eq!(store.lpop("queue".to_owned())?, None);
+        assert_eq!(store.get("queue".to_owned())?, None);
+        assert!(store.list_keys().is_empty());
+
+        store.set("plain".to_owned(), "value".to_owned())?;
+        assert!(matches!(
+            store.lpop("plain".to_owned()),
+            Err(StorageError::WrongType)
+        ));
+
+        Ok(())
+    }
+
+    // Exactly one of many racing `set_if_absent` calls should write the value.
+    #[test]
+    fn concurrent_set_if_absent() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Bitcask::open(temp_dir.path())?;
+        let barrier = Arc::new(Barrier::new(100));
+        let handles: Vec<_> = (0..100)
+            .map(|i| {
+                let store = store.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    let written = store
+                        .set_if_absent("lock".to_owned(), format!("owner{}", i))
+                        .unwrap();
+                    (i, written)
+                })
+            })
+            .collect();
+        let winners: Vec<_> = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .filter(|(_, written)| *written)
+            .collect();
 
-    Ok(String::from_utf8(value_bytes)?)
-}
+        assert_eq!(winners.len(), 1);
+        let owner = format!("owner{}", winners[0].0);
+        assert_eq!(store.get("lock".to_owned())?, Some(owner.clone()));
 
-// Write a given key/value entry to the writer in the bitcask hint format.
-// Fixed-width header            Variable-length body
-//+=====+=====+=====+====== - - +======== - - +
-//| u64 | u32 | u32 | u64       | [u8] |
-//+=====+=====+=====+====== - - +======== - - +
-// timestamp (8 bytes)
-// key_len (4 bytes)
-// val_len (4 bytes)
-// val_pos (8 bytes)
-// key (key_len bytes)
-fn write_hint<W: Write + Seek>(writer: &mut W, key: &String, entry: &Entry) -> StorageResult<()> {
-    writer.write_u64::<BigEndian>(entry._timestamp)?;
-    writer.write_u32::<BigEndian>(key.len() as u32)?;
-    writer.write_u32::<BigEndian>(entry.value_len)?;
-    writer.write_u64::<BigEndian>(entry.value_pos)?;
-    writer.write_all(key.as_bytes())?;
-    Ok(())
-}
+        // A removed key is absent again
+        store.remove("lock".to_owned())?;
+        assert!(store.set_if_absent("lock".to_owned(), "owner".to_owned())?);
+        assert!(!store.set_if_absent("lock".to_owned(), owner)?);
+        assert_eq!(store.get("lock".to_owned())?, Some("owner".to_owned()));
 
-// Read the next key/value entry from the given reader in the bitcask hint format.
-// Fixed-width header            Variable-length body
-//+=====+=====+=====+====== - - +======== - - +
-//| u64 | u32 | u32 | u64       | [u8] |
-//+=====+=====+=====+====== - - +======== - - +
-// timestamp (8 bytes)
-// key_len (4 bytes)
-// val_len (4 bytes)
-// val_pos (8 bytes)
-// key (key_len bytes)
-fn read_next_hint<R: Read + Seek>(
-    reader: &mut R,
-    file_id: u64,
-) -> StorageResult<Option<(String, Entry)>> {
-    // Check if we are at the end of the reader
-    // Move back to the current position after checking
-    let current_pos = reader.seek(std::io::SeekFrom::Current(0))?;
-    if current_pos == reader.seek(std::io::SeekFrom::End(0))? {
-        return Ok(None);
+        Ok(())
     }
-    reader.seek(std::io::SeekFrom::Start(current_pos))?;
 
-    let timestamp = reader.read_u64::<BigEndian>()?;
-    let key_len = reader.read_u32::<BigEndian>()?;
-    let value_len = reader.read_u32::<BigEndian>()?;
-    let value_pos = reader.read_u64::<BigEndian>()?;
+    // Should report whether a put created or updated the key.
+    #[test]
+    fn put() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let bitcask = Bitcask::open(temp_dir.path())?;
 
-    let mut key_bytes = vec![0; key_len as usize];
-    reader.read_exact(&mut key_bytes)?;
-    let key = String::from_utf8(key_bytes)?;
+        assert_eq!(
+            bitcask.put("key1".to_owned(), "value1".to_owned())?,
+            PutOutcome::Created
+        );
+        assert_eq!(
+            bitcask.put("key1".to_owned(), "value2".to_owned())?,
+            PutOutcome::Updated
+        );
+        assert_eq!(bitcask.get("key1".to_owned())?, Some("value2".to_owned()));
 
-    let entry = Entry {
-        file_id,
-        value_len,
-        value_pos,
-        _timestamp: timestamp,
-    };
+        // A removed key is created again
+        bitcask.remove("key1".to_owned())?;
+        assert_eq!(
+            bitcask.put("key1".to_owned(), "value3".to_owned())?,
+            PutOutcome::Created
+        );
 
-    Ok(Some((key, entry)))
-}
+        drop(bitcask);
+        let bitcask = Bitcask::open(temp_dir.path())?;
+        assert_eq!(
+            bitcask.put("key1".to_owned(), "value4".to_owned())?,
+            PutOutcome::Updated
+        );
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::sync::Barrier;
-    use tempfile::TempDir;
-    use walkdir::WalkDir;
+        Ok(())
+    }
 
-    // Should get previously stored value.
+    // Should report the size of each live value in bytes.
     #[test]
-    fn get_stored_value() -> StorageResult<()> {
+    fn list_with_sizes() -> StorageResult<()> {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
         let bitcask = Bitcask::open(temp_dir.path())?;
-
         bitcask.set("key1".to_owned(), "value1".to_owned())?;
-        bitcask.set("key2".to_owned(), "value2".to_owned())?;
+        bitcask.set("key2".to_owned(), "héllo wörld".to_owned())?;
+        bitcask.set("key3".to_owned(), "x".repeat(1000))?;
+        bitcask.set("key1".to_owned(), "v".to_owned())?;
+        bitcask.set("key4".to_owned(), "gone".to_owned())?;
+        bitcask.remove("key4".to_owned())?;
 
-        assert_eq!(bitcask.get("key1".to_owned())?, Some("value1".to_owned()));
-        assert_eq!(bitcask.get("key2".to_owned())?, Some("value2".to_owned()));
+        let expected = vec![
+            ("key1".to_owned(), 1),
+            ("key2".to_owned(), "héllo wörld".len() as u32),
+            ("key3".to_owned(), 1000),
+        ];
+        assert_eq!(bitcask.list_with_sizes()?, expected);
 
-        // Open from disk again and check persistent data.
         drop(bitcask);
+        let bitcask = Bitcask::open(temp_dir.path())?;
+        assert_eq!(bitcask.list_with_sizes()?, expected);
+
+        Ok(())
+    }
+
+    // Paging from each cursor should visit every live key once, in order, across shards.
+    #[test]
+    fn list_page() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = BitcaskOptions {
+            key_dir_shards: 4,
+            ..BitcaskOptions::default()
+        };
+        let bitcask = Bitcask::open_with_options(temp_dir.path(), options)?;
+        for key_id in 0..250 {
+            bitcask.set(format!("key{:03}", key_id), "value".to_owned())?;
+        }
+        for key_id in (0..250).step_by(3) {
+            bitcask.remove(format!("key{:03}", key_id))?;
+        }
+
+        let mut paged = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = bitcask.list_page(cursor, 16)?;
+            assert!(page.keys.len() <= 16);
+            paged.extend(page.keys);
+            cursor = match page.next_cursor {
+                Some(next_cursor) => Some(next_cursor),
+                None => break,
+            };
+        }
+        assert_eq!(paged, bitcask.list_keys());
+
+        // A cursor need not be a key that exists.
+        let page = bitcask.list_page(Some("key0015".to_owned()), 2)?;
+        assert_eq!(page.keys, vec!["key002".to_owned(), "key004".to_owned()]);
+        assert_eq!(page.next_cursor, Some("key004".to_owned()));
+
+        Ok(())
+    }
+
+    // Racing `get_or_set` calls should all resolve to the single value that was written.
+    #[test]
+    fn concurrent_get_or_set() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
         let store = Bitcask::open(temp_dir.path())?;
-        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        let barrier = Arc::new(Barrier::new(100));
+        let handles: Vec<_> = (0..100)
+            .map(|i| {
+                let store = store.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    store
+                        .get_or_set("cache".to_owned(), format!("value{}", i))
+                        .unwrap()
+                })
+            })
+            .collect();
+        let values: Vec<_> = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect();
+
+        assert!(values.iter().all(|value| value == &values[0]));
+        assert_eq!(store.get("cache".to_owned())?, Some(values[0].clone()));
+        assert_eq!(
+            store.get_or_set("cache".to_owned(), "other".to_owned())?,
+            values[0]
+        );
 
         Ok(())
     }
 
-    // Should overwrite existent value.
+    // Should remove only the keys holding the given value.
     #[test]
-    fn overwrite_value() -> StorageResult<()> {
+    fn remove_by_value() -> StorageResult<()> {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
         let bitcask = Bitcask::open(temp_dir.path())?;
+        bitcask.set("key1".to_owned(), "DELETED".to_owned())?;
+        bitcask.set("key2".to_owned(), "value2".to_owned())?;
+        bitcask.set("key3".to_owned(), "DELETED".to_owned())?;
+        bitcask.set("key4".to_owned(), "DELETED!".to_owned())?;
+        bitcask.set("key5".to_owned(), "DELETED".to_owned())?;
+        bitcask.set("key5".to_owned(), "value5".to_owned())?;
 
-        bitcask.set("key1".to_owned(), "value1".to_owned())?;
-        assert_eq!(bitcask.get("key1".to_owned())?, Some("value1".to_owned()));
-        bitcask.set("key1".to_owned(), "value2".to_owned())?;
-        assert_eq!(bitcask.get("key1".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(bitcask.remove_by_value("DELETED".to_owned())?, 2);
+        assert_eq!(
+            bitcask.list_keys(),
+            vec!["key2".to_owned(), "key4".to_owned(), "key5".to_owned()]
+        );
+        assert_eq!(bitcask.remove_by_value("DELETED".to_owned())?, 0);
 
         // Open from disk again and check persistent data.
         drop(bitcask);
         let store = Bitcask::open(temp_dir.path())?;
-        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
-        store.set("key1".to_owned(), "value3".to_owned())?;
-        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(
+            store.list_keys(),
+            vec!["key2".to_owned(), "key4".to_owned(), "key5".to_owned()]
+        );
 
         Ok(())
     }
 
-    // Should get `None` when getting a non-existent key.
     #[test]
-    fn get_non_existent_value() -> StorageResult<()> {
+    fn remove_prefix() -> StorageResult<()> {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
         let bitcask = Bitcask::open(temp_dir.path())?;
+        bitcask.set("session:1".to_owned(), "a".to_owned())?;
+        bitcask.set("session:2".to_owned(), "b".to_owned())?;
+        bitcask.set("session:3".to_owned(), "c".to_owned())?;
+        bitcask.set("session".to_owned(), "d".to_owned())?;
+        bitcask.set("user:1".to_owned(), "e".to_owned())?;
+        bitcask.remove("session:3".to_owned())?;
 
-        bitcask.set("key1".to_owned(), "value1".to_owned())?;
-        assert_eq!(bitcask.get("key2".to_owned())?, None);
+        assert_eq!(bitcask.remove_prefix("session:".to_owned())?, 2);
+        assert_eq!(
+            bitcask.list_keys(),
+            vec!["session".to_owned(), "user:1".to_owned()]
+        );
+        assert_eq!(bitcask.remove_prefix("session:".to_owned())?, 0);
 
         // Open from disk again and check persistent data.
         drop(bitcask);
         let store = Bitcask::open(temp_dir.path())?;
-        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(
+            store.list_keys(),
+            vec!["session".to_owned(), "user:1".to_owned()]
+        );
 
         Ok(())
     }
 
     #[test]
-    fn remove_non_existent_key() -> StorageResult<()> {
+    fn for_each_key() -> StorageResult<()> {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-        let bitcask = Bitcask::open(temp_dir.path())?;
-        assert!(bitcask.remove("key1".to_owned()).is_err());
+        let store = Bitcask::open(temp_dir.path())?;
+        for i in 0..100 {
+            store.set(format!("key{:02}", i), format!("value{}", i))?;
+        }
+        for i in (0..100).step_by(3) {
+            store.remove(format!("key{:02}", i))?;
+        }
+        store.set_with_ttl(
+            "expired".to_owned(),
+            "value".to_owned(),
+            Duration::from_millis(1),
+        )?;
+        std::thread::sleep(Duration::from_millis(10));
+
+        let mut count = 0;
+        let mut keys = Vec::new();
+        store.for_each_key(|key| {
+            count += 1;
+            keys.push(key.to_owned());
+        });
+        assert_eq!(count, store.list_keys().len());
+        assert_eq!(keys, store.list_keys());
 
         Ok(())
     }
 
+    // A file system on the host that records the syncs, renames and removals made through it, and whose disk can be
+    // filled up, after which writes only write the bytes that still fit and then fail as they would on a full disk.
+    #[derive(Debug, Clone, Default)]
+    struct TestDisk {
+        // The bytes that can still be written, `None` for no limit.
+        free: Arc<Mutex<Option<u64>>>,
+        // The operations made, such as `sync 3.log`, naming files without their directory.
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl TestDisk {
+        fn set_free(&self, free: Option<u64>) {
+            *self.free.lock().unwrap() = free;
+        }
+
+        fn events(&self) -> Vec<String> {
+            self.events.lock().unwrap().clone()
+        }
+
+        fn record(&self, operation: &str, path: &Path) {
+            let name = path.file_name().unwrap().to_string_lossy();
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", operation, name));
+        }
+
+        fn wrap(&self, file: File, path: &Path) -> TestDiskFile {
+            TestDiskFile {
+                file,
+                path: path.to_path_buf(),
+                free: self.free.clone(),
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    struct TestDiskFile {
+        file: File,
+        path: PathBuf,
+        free: Arc<Mutex<Option<u64>>>,
+    }
+
+    impl Read for TestDiskFile {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.file.read(buf)
+        }
+    }
+
+    impl Seek for TestDiskFile {
+        fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+            self.file.seek(pos)
+        }
+    }
+
+    impl Write for TestDiskFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut free = self.free.lock().unwrap();
+            let len = match *free {
+                Some(0) if !buf.is_empty() => return Err(io::ErrorKind::StorageFull.into()),
+                Some(free) => buf.len().min(free as usize),
+                None => buf.len(),
+            };
+            let written = self.file.write(&buf[..len])?;
+            if let Some(free) = free.as_mut() {
+                *free -= written as u64;
+            }
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    impl FileSystem for TestDisk {
+        type File = TestDiskFile;
+
+        fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+            StdFileSystem.create_dir_all(path)
+        }
+
+        fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+            StdFileSystem.read_dir(path)
+        }
+
+        fn open(&self, path: &Path) -> io::Result<TestDiskFile> {
+            Ok(self.wrap(StdFileSystem.open(path)?, path))
+        }
+
+        fn append(&self, path: &Path) -> io::Result<TestDiskFile> {
+            Ok(self.wrap(StdFileSystem.append(path)?, path))
+        }
+
+        fn create(&self, path: &Path) -> io::Result<TestDiskFile> {
+            Ok(self.wrap(StdFileSystem.create(path)?, path))
+        }
+
+        fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+            StdFileSystem.read(path)
+        }
+
+        fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+            self.create(path)?.write_all(contents)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.record("rename", to);
+            StdFileSystem.rename(from, to)
+        }
+
+        fn remove_file(&self, path: &Path) -> io::Result<()> {
+            self.record("remove", path);
+            StdFileSystem.remove_file(path)
+        }
+
+        fn len(&self, path: &Path) -> io::Result<u64> {
+            StdFileSystem.len(path)
+        }
+
+        fn file_len(&self, file: &TestDiskFile) -> io::Result<u64> {
+            StdFileSystem.file_len(&file.file)
+        }
+
+        fn sync_data(&self, file: &TestDiskFile) -> io::Result<()> {
+            self.record("sync", &file.path);
+            StdFileSystem.sync_data(&file.file)
+        }
+
+        fn sync_all(&self, file: &TestDiskFile) -> io::Result<()> {
+            self.record("sync", &file.path);
+            StdFileSystem.sync_all(&file.file)
+        }
+
+        fn sync_dir(&self, path: &Path) -> io::Result<()> {
+            self.events.lock().unwrap().push("sync_dir".to_owned());
+            StdFileSystem.sync_dir(path)
+        }
+
+        fn map(&self, file: &TestDiskFile) -> io::Result<Option<Mmap>> {
+            StdFileSystem.map(&file.file)
+        }
+    }
+
+    // A compaction that runs out of disk space partway through the merge file should leave the store as it was, both
+    // while it is open and when it is opened again.
     #[test]
-    fn remove_key() -> StorageResult<()> {
+    fn compaction_out_of_space() -> StorageResult<()> {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-        let bitcask = Bitcask::open(temp_dir.path())?;
-        bitcask.set("key1".to_owned(), "value1".to_owned())?;
-        assert!(bitcask.remove("key1".to_owned()).is_ok());
-        assert_eq!(bitcask.get("key1".to_owned())?, None);
+        let disk = TestDisk::default();
+        let store = Bitcask::open_with_file_system(
+            temp_dir.path(),
+            BitcaskOptions::default(),
+            disk.clone(),
+        )?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        for i in 0..50 {
+            store.set(format!("key{}", i), format!("overwritten{}", i))?;
+        }
+        store.flush()?;
+        let value = |i| match i < 50 {
+            true => format!("overwritten{}", i),
+            false => format!("value{}", i),
+        };
+
+        disk.set_free(Some(1024));
+        match store.compact() {
+            Err(StorageError::Io(e)) if e.kind() == io::ErrorKind::StorageFull => {}
+            result => panic!("expected the disk to be full, got {:?}", result),
+        }
+        for i in 0..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+        }
+
+        disk.set_free(None);
+        drop(store);
+        let store =
+            Bitcask::open_with_file_system(temp_dir.path(), BitcaskOptions::default(), disk)?;
+        for i in 0..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+        }
+        store.compact()?;
+        for i in 0..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+        }
 
         Ok(())
     }
 
-    // Insert data and call `merge` to compact log files
-    // Test dir size grows and shrinks before and after merging
-    // Test data correctness after merging
+    // A compaction should have the merge and hint files and the rename of the hint file on disk before it removes
+    // any of the files it merged.
     #[test]
-    fn compaction() -> StorageResult<()> {
+    fn compaction_syncs_before_removing() -> StorageResult<()> {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-        let bitcask = Bitcask::open(temp_dir.path()).unwrap();
+        let disk = TestDisk::default();
+        let options = BitcaskOptions {
+            max_log_size: 256,
+            ..BitcaskOptions::default()
+        };
+        let store = Bitcask::open_with_file_system(temp_dir.path(), options, disk.clone())?;
+        for i in 0..100 {
+            store.set(format!("key{}", i % 10), format!("value{}", i))?;
+        }
 
-        let dir_size = || {
-            let entries = WalkDir::new(temp_dir.path()).into_iter();
-            let len: walkdir::Result<u64> = entries
-                .map(|res| {
-                    res.and_then(|entry| entry.metadata())
-                        .map(|metadata| metadata.len())
-                })
-                .sum();
-            len.expect("fail to get directory size")
+        let before = disk.events().len();
+        store.compact()?;
+        let events = disk.events().split_off(before);
+        let merge_file_id = store.obsolete.below();
+        let position = |event: String| {
+            events
+                .iter()
+                .position(|e| *e == event)
+                .unwrap_or_else(|| panic!("expected {:?} in {:?}", event, events))
         };
+        let merge_synced = position(format!("sync {}.log", merge_file_id));
+        let hint_synced = position(format!("sync {}.hint.tmp", merge_file_id));
+        let renamed = position(format!("rename {}.hint", merge_file_id));
+        let dir_synced = position("sync_dir".to_owned());
+        let first_removed = events
+            .iter()
+            .position(|e| e.starts_with("remove "))
+            .expect("expected the merged files to be removed");
+        assert!(merge_synced < renamed && hint_synced < renamed);
+        assert!(renamed < dir_synced && dir_synced < first_removed);
 
-        let initial_size = dir_size();
-        for iter in 0..=1000 {
-            for key_id in 0..=1000 {
-                let key = format!("key{}", key_id);
-                let value = format!("{}", iter);
-                bitcask.set(key, value).unwrap();
-            }
+        for i in 0..10 {
+            assert_eq!(
+                store.get(format!("key{}", i))?,
+                Some(format!("value{}", 90 + i))
+            );
         }
 
-        let new_size = dir_size();
-        assert!(
-            new_size > initial_size,
-            "expected dir size to grow before merge"
-        );
-
-        bitcask.compact()?;
+        Ok(())
+    }
 
-        let final_size = dir_size();
-        assert!(
-            final_size < new_size,
-            "expected dir size to shrink after merge"
-        );
+    // A key pointing past the end of its log file should report which value could not be read, whether the file is
+    // read through a buffer or a mapping.
+    #[test]
+    fn truncated_value() -> StorageResult<()> {
+        for mmap_sealed in [false, true] {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let options = BitcaskOptions {
+                mmap_sealed,
+                ..BitcaskOptions::default()
+            };
+            let store = Bitcask::open_with_options(temp_dir.path(), options)?;
+            store.set("key".to_owned(), "value".to_owned())?;
+            store.compact()?;
 
-        // test that store can read from the merged log
-        drop(bitcask);
+            let entry = store.key_dir.get("key").unwrap().value().load();
+            let file_len = fs::metadata(log_path(temp_dir.path(), &entry.file_id))?.len();
+            let past_end = Entry {
+                value_pos: file_len - 2,
+                ..entry
+            };
+            store.key_dir.upsert("key".to_owned(), past_end);
 
-        let store = Bitcask::open(temp_dir.path())?;
-        for key_id in 0..=1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", 1000)));
+            match store.get("key".to_owned()) {
+                Err(StorageError::TruncatedValue {
+                    key,
+                    file_id,
+                    value_pos,
+                    value_len,
+                }) => {
+                    assert_eq!(key, "key");
+                    assert_eq!(file_id, entry.file_id);
+                    assert_eq!(value_pos, file_len - 2);
+                    assert_eq!(value_len, 5);
+                }
+                result => panic!("expected a truncated value error, got {:?}", result),
+            }
         }
 
         Ok(())
@@ -0,0 +1,92 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+// The number of bits set for each key.
+const HASHES: u64 = 3;
+
+// A fixed size bloom filter over string keys.
+//
+// Keys can be inserted concurrently with lookups and are never removed, so a removed key still tests as maybe present.
+// `contains` never returns a false negative for a key whose insert has completed.
+pub(super) struct BloomFilter {
+    bits: Vec<AtomicU64>,
+    len: u64,
+}
+
+impl BloomFilter {
+    // Creates an empty filter of at least one bit, rounded up to a multiple of 64.
+    pub(super) fn new(bits: usize) -> Self {
+        let words = bits.max(1).div_ceil(64);
+        BloomFilter {
+            bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            len: words as u64 * 64,
+        }
+    }
+
+    pub(super) fn insert(&self, key: &str) {
+        for bit in self.bits_for(key) {
+            self.bits[(bit / 64) as usize].fetch_or(1 << (bit % 64), Ordering::SeqCst);
+        }
+    }
+
+    // Whether the key may have been inserted, `false` means it definitely was not.
+    pub(super) fn contains(&self, key: &str) -> bool {
+        self.bits_for(key).all(|bit| {
+            self.bits[(bit / 64) as usize].load(Ordering::SeqCst) & (1 << (bit % 64)) != 0
+        })
+    }
+
+    // The size of the filter in bytes.
+    pub(super) fn size_bytes(&self) -> usize {
+        self.bits.len() * std::mem::size_of::<AtomicU64>()
+    }
+
+    // The bits for a key, derived from two hashes by double hashing.
+    fn bits_for(&self, key: &str) -> impl Iterator<Item = u64> {
+        let (h1, h2) = (hash(0, key), hash(1, key));
+        let len = self.len;
+        (0..HASHES).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % len)
+    }
+}
+
+fn hash(seed: u64, key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every inserted key should test as present, even once the filter is saturated.
+    #[test]
+    fn no_false_negatives() {
+        for bits in [64, 1024, 1 << 16] {
+            let filter = BloomFilter::new(bits);
+            for i in 0..10_000 {
+                filter.insert(&format!("key{}", i));
+            }
+            for i in 0..10_000 {
+                assert!(filter.contains(&format!("key{}", i)));
+            }
+        }
+    }
+
+    // A filter with room to spare should reject most keys that were never inserted.
+    #[test]
+    fn rejects_absent_keys() {
+        let filter = BloomFilter::new(1 << 16);
+        for i in 0..1_000 {
+            filter.insert(&format!("key{}", i));
+        }
+        let false_positives = (0..1_000)
+            .filter(|i| filter.contains(&format!("absent{}", i)))
+            .count();
+        assert!(false_positives < 10);
+    }
+}
@@ -0,0 +1,103 @@
+// The id of the first log file of an empty store.
+pub(super) const LOWEST_LOG_FILE_ID: u64 = 0;
+
+// Hands out the ids of a bitcask store's files.
+//
+// Ids only ever increase so that a later file always holds later writes. There is a single active log file at a
+// time; rotating moves the writer onto the next id. A compaction reserves the id after the active file for its
+// merge file, which shares its id with its hint file, and moves the writer onto the id after that. Every file
+// below the merge file is then sealed, and no later log file can collide with the merge file or its hint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct FileIdAllocator {
+    active: u64,
+}
+
+impl FileIdAllocator {
+    // Resumes allocation for a store whose newest log file and merge file have the given ids.
+    //
+    // The newest log file becomes the active file again. A store with only a merge file writes to a fresh file
+    // after it, and an empty store starts from `LOWEST_LOG_FILE_ID`.
+    pub(super) fn resume(last_log_file_id: Option<u64>, merge_file_id: Option<u64>) -> Self {
+        let active = match (last_log_file_id, merge_file_id) {
+            (Some(last_log_file_id), _) => last_log_file_id,
+            (None, Some(merge_file_id)) => merge_file_id + 1,
+            (None, None) => LOWEST_LOG_FILE_ID,
+        };
+        FileIdAllocator { active }
+    }
+
+    // The id of the file that writes are appended to.
+    pub(super) fn active(&self) -> u64 {
+        self.active
+    }
+
+    // Seals the active file and returns the id of the new active file.
+    pub(super) fn rotate(&mut self) -> u64 {
+        self.active += 1;
+        self.active
+    }
+
+    // The id the next call to `reserve_merge` will return, if no file is rotated before it.
+    pub(super) fn next_merge(&self) -> u64 {
+        self.active + 1
+    }
+
+    // Seals the active file and returns the id reserved for a compaction's merge file.
+    //
+    // The new active file takes the id after the merge file.
+    pub(super) fn reserve_merge(&mut self) -> u64 {
+        let merge_file_id = self.next_merge();
+        self.active = merge_file_id + 1;
+        merge_file_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resume() {
+        assert_eq!(
+            FileIdAllocator::resume(None, None).active(),
+            LOWEST_LOG_FILE_ID
+        );
+        assert_eq!(FileIdAllocator::resume(None, Some(4)).active(), 5);
+        assert_eq!(FileIdAllocator::resume(Some(7), Some(4)).active(), 7);
+    }
+
+    // Every file, merge or log, should get an id of its own that is higher than any before it.
+    #[test]
+    fn rotations_and_compactions() {
+        let mut ids = FileIdAllocator::resume(None, None);
+        let mut allocated = vec![ids.active()];
+        for round in 0..100 {
+            if round % 3 == 0 {
+                let merge_file_id = ids.reserve_merge();
+                assert!(merge_file_id < ids.active());
+                allocated.push(merge_file_id);
+            } else {
+                assert_eq!(ids.rotate(), ids.active());
+            }
+            allocated.push(ids.active());
+        }
+        assert!(allocated.windows(2).all(|ids| ids[0] < ids[1]));
+    }
+
+    // After a reopen, allocation should carry on past every file already on disk.
+    #[test]
+    fn resume_after_compaction() {
+        let mut ids = FileIdAllocator::resume(None, None);
+        ids.rotate();
+        let merge_file_id = ids.reserve_merge();
+
+        // The new active file was never written to, so only the merge file is left on disk.
+        let mut resumed = FileIdAllocator::resume(None, Some(merge_file_id));
+        assert_eq!(resumed, ids);
+        assert!(resumed.reserve_merge() > merge_file_id);
+
+        let mut resumed = FileIdAllocator::resume(Some(ids.active()), Some(merge_file_id));
+        assert_eq!(resumed, ids);
+        assert!(resumed.rotate() > ids.active());
+    }
+}
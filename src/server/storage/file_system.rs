@@ -0,0 +1,140 @@
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+use memmap2::Mmap;
+
+/// The file system a `Bitcask` store keeps its files on.
+///
+/// `StdFileSystem` keeps them on disk through `std::fs`. Other implementations let tests simulate failures that are
+/// hard to cause on a real disk, such as writes that are cut short or a disk that is full, see
+/// `Bitcask::open_with_file_system`.
+pub trait FileSystem: fmt::Debug + Clone + Send + Sync + 'static {
+    /// A file opened by the file system.
+    type File: Read + Write + Seek + fmt::Debug + Send + 'static;
+
+    /// Creates a directory along with any missing parents, doing nothing if it already exists.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Lists the paths of the entries of a directory, in no particular order.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Opens a file for reading.
+    fn open(&self, path: &Path) -> io::Result<Self::File>;
+
+    /// Opens a file for appending, creating it if it does not exist.
+    fn append(&self, path: &Path) -> io::Result<Self::File>;
+
+    /// Creates a file for writing, truncating it if it already exists.
+    fn create(&self, path: &Path) -> io::Result<Self::File>;
+
+    /// Reads the whole contents of a file.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// Replaces the contents of a file, creating it if it does not exist.
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+
+    /// Renames a file, replacing any file already at the new path.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Removes a file.
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// The length in bytes of the file at a path.
+    fn len(&self, path: &Path) -> io::Result<u64>;
+
+    /// The length in bytes of an open file.
+    fn file_len(&self, file: &Self::File) -> io::Result<u64>;
+
+    /// Syncs the contents of a file to disk, along with the metadata needed to read them back.
+    fn sync_data(&self, file: &Self::File) -> io::Result<()>;
+
+    /// Syncs the contents and all the metadata of a file to disk.
+    fn sync_all(&self, file: &Self::File) -> io::Result<()>;
+
+    /// Syncs the entries of a directory to disk, so that files created, renamed or removed in it stay that way after a
+    /// crash.
+    fn sync_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Maps a file that is no longer written to into memory, see `BitcaskOptions::mmap_sealed`.
+    ///
+    /// `None` if the file system cannot map files, in which case the file is read as any other.
+    fn map(&self, file: &Self::File) -> io::Result<Option<Mmap>>;
+}
+
+/// The `FileSystem` of the host, through `std::fs`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdFileSystem;
+
+impl FileSystem for StdFileSystem {
+    type File = File;
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(path)?.map(|entry| Ok(entry?.path())).collect()
+    }
+
+    fn open(&self, path: &Path) -> io::Result<File> {
+        File::open(path)
+    }
+
+    fn append(&self, path: &Path) -> io::Result<File> {
+        fs::OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    fn create(&self, path: &Path) -> io::Result<File> {
+        File::create(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        fs::write(path, contents)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn len(&self, path: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    fn file_len(&self, file: &File) -> io::Result<u64> {
+        Ok(file.metadata()?.len())
+    }
+
+    fn sync_data(&self, file: &File) -> io::Result<()> {
+        file.sync_data()
+    }
+
+    fn sync_all(&self, file: &File) -> io::Result<()> {
+        file.sync_all()
+    }
+
+    // Only unix can open a directory to sync it, elsewhere this does nothing.
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        if cfg!(unix) {
+            File::open(path)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn map(&self, file: &File) -> io::Result<Option<Mmap>> {
+        // SAFETY: Only sealed files are mapped, which nothing writes to. They are only ever removed, which leaves the
+        // mapping readable, after compaction has moved every value out of them.
+        Ok(Some(unsafe { Mmap::map(file)? }))
+    }
+}
@@ -0,0 +1,348 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ops::Bound,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    queue, validate_key, Changes, ListPage, PutOutcome, Storage, StorageError, StorageResult,
+};
+
+/// Statistics about a `Memory` store, as reported by `Memory::stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryStats {
+    /// The number of keys in the store.
+    pub keys: usize,
+
+    /// The total length in bytes of the keys and values held, including expired ones that have not been dropped yet.
+    pub size_bytes: usize,
+}
+
+/// A storage engine that keeps every key in memory and writes nothing to disk.
+///
+/// Clones share the same keys, which are lost once the last clone is dropped. There is nothing to flush or compact, so
+/// `flush` does nothing and `compact` only drops expired values.
+#[derive(Clone, Default)]
+pub struct Memory {
+    values: Arc<Mutex<BTreeMap<String, Value>>>,
+}
+
+// A value along with when it was last written and when it expires, in milliseconds since the unix epoch.
+#[derive(Debug, Clone)]
+struct Value {
+    value: String,
+    timestamp: u64,
+    expires_at: Option<u64>,
+}
+
+impl Value {
+    fn new(value: String, expires_at: Option<u64>) -> StorageResult<Self> {
+        Ok(Value {
+            value,
+            timestamp: now()?,
+            expires_at,
+        })
+    }
+
+    // Whether the value has not expired at the given time.
+    fn is_live(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+impl Memory {
+    /// Creates an empty `Memory` storage engine.
+    pub fn new() -> Self {
+        Memory::default()
+    }
+
+    /// Reports statistics about the store.
+    pub fn stats(&self) -> StorageResult<MemoryStats> {
+        let values = self.values.lock()?;
+        Ok(MemoryStats {
+            keys: live(&values, now()?).count(),
+            size_bytes: values
+                .iter()
+                .map(|(key, value)| key.len() + value.value.len())
+                .sum(),
+        })
+    }
+
+    // Runs `f` on the values while holding the lock, after dropping the value of `key` if it has expired so that `f`
+    // treats it as missing.
+    fn update<T>(
+        &self,
+        key: &str,
+        f: impl FnOnce(&mut BTreeMap<String, Value>) -> StorageResult<T>,
+    ) -> StorageResult<T> {
+        validate_key(key)?;
+        let mut values = self.values.lock()?;
+        let now = now()?;
+        if values.get(key).is_some_and(|value| !value.is_live(now)) {
+            values.remove(key);
+        }
+        f(&mut values)
+    }
+
+    // Reads the live value of a key.
+    fn read(&self, key: &str) -> StorageResult<Option<Value>> {
+        validate_key(key)?;
+        let now = now()?;
+        Ok(self
+            .values
+            .lock()?
+            .get(key)
+            .filter(|value| value.is_live(now))
+            .cloned())
+    }
+
+    // Lists the live keys in the given range.
+    fn keys_in(&self, range: (Bound<&str>, Bound<&str>)) -> StorageResult<Vec<String>> {
+        let now = now()?;
+        Ok(self
+            .values
+            .lock()?
+            .range::<str, _>(range)
+            .filter(|(_, value)| value.is_live(now))
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    // Applies `f` to the queue stored at a key and writes the updated queue back.
+    //
+    // An emptied queue removes the key rather than leaving an empty queue behind.
+    fn update_queue<T>(
+        &self,
+        key: String,
+        f: impl FnOnce(&mut VecDeque<String>) -> T,
+    ) -> StorageResult<T> {
+        self.update(&key, |values| {
+            let mut queue = queue::decode(values.get(&key).map(|value| value.value.as_str()))?;
+            let result = f(&mut queue);
+            if queue.is_empty() {
+                values.remove(&key);
+            } else {
+                values.insert(key.clone(), Value::new(queue::encode(&queue), None)?);
+            }
+            Ok(result)
+        })
+    }
+}
+
+impl Storage for Memory {
+    /// Does nothing, as nothing is written to disk.
+    fn flush(&self) -> StorageResult<()> {
+        Ok(())
+    }
+
+    /// Drops the expired values, which are otherwise only dropped when their key is written.
+    fn compact(&self) -> StorageResult<()> {
+        let now = now()?;
+        self.values.lock()?.retain(|_, value| value.is_live(now));
+        Ok(())
+    }
+
+    fn data_dir(&self) -> Option<&Path> {
+        None
+    }
+
+    /// Always `true`, as nothing is kept from one run to the next.
+    fn is_new(&self) -> bool {
+        true
+    }
+
+    fn set(&self, key: String, value: String) -> StorageResult<()> {
+        validate_key(&key)?;
+        let value = Value::new(value, None)?;
+        self.values.lock()?.insert(key, value);
+        Ok(())
+    }
+
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> StorageResult<()> {
+        validate_key(&key)?;
+        let expires_at = now()?.saturating_add(ttl.as_millis() as u64);
+        let value = Value::new(value, Some(expires_at))?;
+        self.values.lock()?.insert(key, value);
+        Ok(())
+    }
+
+    fn put(&self, key: String, value: String) -> StorageResult<PutOutcome> {
+        self.update(&key, |values| {
+            Ok(match values.insert(key.clone(), Value::new(value, None)?) {
+                Some(_) => PutOutcome::Updated,
+                None => PutOutcome::Created,
+            })
+        })
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> StorageResult<bool> {
+        self.update(&key, |values| {
+            if values.contains_key(&key) {
+                return Ok(false);
+            }
+            values.insert(key.clone(), Value::new(value, None)?);
+            Ok(true)
+        })
+    }
+
+    fn get_or_set(&self, key: String, default: String) -> StorageResult<String> {
+        self.update(&key, |values| {
+            if let Some(value) = values.get(&key) {
+                return Ok(value.value.clone());
+            }
+            values.insert(key.clone(), Value::new(default.clone(), None)?);
+            Ok(default)
+        })
+    }
+
+    fn get(&self, key: String) -> StorageResult<Option<String>> {
+        Ok(self.read(&key)?.map(|value| value.value))
+    }
+
+    fn get_with_metadata(&self, key: String) -> StorageResult<Option<(String, Option<u64>)>> {
+        Ok(self
+            .read(&key)?
+            .map(|value| (value.value, Some(value.timestamp))))
+    }
+
+    fn exists(&self, key: String) -> StorageResult<bool> {
+        Ok(self.read(&key)?.is_some())
+    }
+
+    fn touch(&self, key: String) -> StorageResult<()> {
+        self.update(&key, |values| {
+            let value = values.get_mut(&key).ok_or(StorageError::KeyNotFound)?;
+            value.timestamp = now()?;
+            Ok(())
+        })
+    }
+
+    fn remove(&self, key: String) -> StorageResult<()> {
+        self.update(&key, |values| {
+            values.remove(&key).ok_or(StorageError::KeyNotFound)?;
+            Ok(())
+        })
+    }
+
+    fn remove_optional(&self, key: String) -> StorageResult<bool> {
+        self.update(&key, |values| Ok(values.remove(&key).is_some()))
+    }
+
+    fn remove_prefix(&self, prefix: String) -> StorageResult<usize> {
+        let mut values = self.values.lock()?;
+        let now = now()?;
+        let keys: Vec<(String, bool)> = values
+            .range::<str, _>((Bound::Included(prefix.as_str()), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(key, value)| (key.clone(), value.is_live(now)))
+            .collect();
+        let mut removed = 0;
+        for (key, is_live) in keys {
+            values.remove(&key);
+            if is_live {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn remove_by_value(&self, value: String) -> StorageResult<usize> {
+        let mut values = self.values.lock()?;
+        let now = now()?;
+        let before = live(&values, now).count();
+        values.retain(|_, current| current.is_live(now) && current.value != value);
+        Ok(before - values.len())
+    }
+
+    fn lpush(&self, key: String, value: String) -> StorageResult<usize> {
+        self.update_queue(key, |queue| {
+            queue.push_front(value);
+            queue.len()
+        })
+    }
+
+    fn rpush(&self, key: String, value: String) -> StorageResult<usize> {
+        self.update_queue(key, |queue| {
+            queue.push_back(value);
+            queue.len()
+        })
+    }
+
+    fn lpop(&self, key: String) -> StorageResult<Option<String>> {
+        self.update_queue(key, VecDeque::pop_front)
+    }
+
+    fn rpop(&self, key: String) -> StorageResult<Option<String>> {
+        self.update_queue(key, VecDeque::pop_back)
+    }
+
+    fn list_keys(&self) -> Vec<String> {
+        self.keys_in((Bound::Unbounded, Bound::Unbounded))
+            .unwrap_or_default()
+    }
+
+    fn list_with_sizes(&self) -> StorageResult<Vec<(String, u32)>> {
+        let values = self.values.lock()?;
+        Ok(live(&values, now()?)
+            .map(|(key, value)| (key.clone(), value.value.len() as u32))
+            .collect())
+    }
+
+    fn list_page(&self, cursor: Option<String>, limit: usize) -> StorageResult<ListPage> {
+        let values = self.values.lock()?;
+        let now = now()?;
+        let start = cursor.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+        let keys = values
+            .range::<str, _>((start, Bound::Unbounded))
+            .filter(|(_, value)| value.is_live(now))
+            .map(|(key, _)| Ok(key.clone()));
+        ListPage::collect(keys, limit)
+    }
+
+    /// Returns `StorageError::Unsupported`, as removed keys are forgotten.
+    fn changes_since(&self, _since: u64) -> StorageResult<Changes> {
+        Err(StorageError::Unsupported("changes".to_owned()))
+    }
+
+    fn scan_prefix(&self, prefix: String) -> StorageResult<Vec<String>> {
+        let now = now()?;
+        Ok(self
+            .values
+            .lock()?
+            .range::<str, _>((Bound::Included(prefix.as_str()), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .filter(|(_, value)| value.is_live(now))
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    fn range(&self, start: String, end: String) -> StorageResult<Vec<String>> {
+        if start >= end {
+            return Ok(Vec::new());
+        }
+        self.keys_in((
+            Bound::Included(start.as_str()),
+            Bound::Excluded(end.as_str()),
+        ))
+    }
+
+    fn len(&self) -> StorageResult<usize> {
+        Ok(live(&*self.values.lock()?, now()?).count())
+    }
+
+    fn is_empty(&self) -> StorageResult<bool> {
+        Ok(live(&*self.values.lock()?, now()?).next().is_none())
+    }
+}
+
+// Iterates over the values that have not expired at the given time, in key order.
+fn live(values: &BTreeMap<String, Value>, now: u64) -> impl Iterator<Item = (&String, &Value)> {
+    values.iter().filter(move |(_, value)| value.is_live(now))
+}
+
+// The current time in milliseconds since the unix epoch.
+fn now() -> StorageResult<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64)
+}
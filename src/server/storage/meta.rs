@@ -0,0 +1,122 @@
+use std::{io::Write, path::Path};
+
+use super::{access_error, FileSystem, StorageError, StorageResult};
+
+// Every store records the engine that wrote it and the version of its on-disk format in a `STORE_META` file,
+// so that a data directory is refused rather than misread by another engine or by an older build.
+//
+// The file is plain text with one `name=value` pair per line, e.g. `engine=bitcask` and `format_version=1`.
+// Unknown names are ignored so later builds may record more without breaking this one. A store being migrated to a
+// newer format also records the file its migration writes, e.g. `migration=7`, see `write_migration`.
+
+const STORE_META_FILE: &str = "STORE_META";
+
+// The contents of a `STORE_META` file.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct StoreMeta {
+    pub(super) engine: String,
+    pub(super) format_version: u8,
+    pub(super) migration: Option<u64>,
+}
+
+// Reads the meta of the store, `None` if the store has no meta file.
+pub(super) fn read(fs: &impl FileSystem, path: &Path) -> StorageResult<Option<StoreMeta>> {
+    let contents = match fs.read(&path.join(STORE_META_FILE)) {
+        Ok(contents) => String::from_utf8(contents)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let invalid = || {
+        StorageError::Unexpected(format!(
+            "Could not parse store meta file {}",
+            path.join(STORE_META_FILE).display()
+        ))
+    };
+    let mut engine = None;
+    let mut format_version = None;
+    let mut migration = None;
+    for line in contents.lines() {
+        match line.split_once('=') {
+            Some(("engine", value)) => engine = Some(value.to_owned()),
+            Some(("format_version", value)) => {
+                format_version = Some(value.parse().map_err(|_| invalid())?)
+            }
+            Some(("migration", value)) => migration = Some(value.parse().map_err(|_| invalid())?),
+            _ => {}
+        }
+    }
+    match (engine, format_version) {
+        (Some(engine), Some(format_version)) => Ok(Some(StoreMeta {
+            engine,
+            format_version,
+            migration,
+        })),
+        _ => Err(invalid()),
+    }
+}
+
+// Records that the store was written by the given engine in the given format version.
+pub(super) fn write(
+    fs: &impl FileSystem,
+    path: &Path,
+    engine: &str,
+    format_version: u8,
+) -> StorageResult<()> {
+    let meta_path = path.join(STORE_META_FILE);
+    fs.write(
+        &meta_path,
+        format!("engine={}\nformat_version={}\n", engine, format_version).as_bytes(),
+    )
+    .map_err(access_error("write the store meta file", &meta_path))?;
+    Ok(())
+}
+
+// Records that the store, still in the given format version, is being migrated to a newer one by rewriting it into
+// the file with the given id.
+//
+// The meta file is replaced by a rename and synced before this returns, so the record is on disk before anything is
+// written in the newer format. `write` clears it once the migration has finished.
+pub(super) fn write_migration<F: FileSystem>(
+    fs: &F,
+    path: &Path,
+    engine: &str,
+    format_version: u8,
+    file_id: u64,
+) -> StorageResult<()> {
+    let meta_path = path.join(STORE_META_FILE);
+    let tmp_path = path.join(format!("{}.tmp", STORE_META_FILE));
+    let contents = format!(
+        "engine={}\nformat_version={}\nmigration={}\n",
+        engine, format_version, file_id
+    );
+    let mut file = fs
+        .create(&tmp_path)
+        .map_err(access_error("write the store meta file", &tmp_path))?;
+    file.write_all(contents.as_bytes())?;
+    fs.sync_all(&file)?;
+    fs.rename(&tmp_path, &meta_path)
+        .map_err(access_error("write the store meta file", &meta_path))?;
+    fs.sync_dir(path)?;
+    Ok(())
+}
+
+// Checks that a store can be opened by the given engine at the given format version.
+//
+// Returns the format version the store was written in, `None` if the store has no meta file.
+pub(super) fn check(
+    fs: &impl FileSystem,
+    path: &Path,
+    engine: &str,
+    format_version: u8,
+) -> StorageResult<Option<u8>> {
+    match read(fs, path)? {
+        Some(meta) if meta.engine != engine || meta.format_version > format_version => {
+            Err(StorageError::IncompatibleFormat {
+                engine: meta.engine,
+                format_version: meta.format_version,
+            })
+        }
+        Some(meta) => Ok(Some(meta.format_version)),
+        None => Ok(None),
+    }
+}
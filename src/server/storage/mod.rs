@@ -1,39 +1,220 @@
+mod async_bitcask;
 mod bitcask;
+mod bloom;
+mod file_id;
+mod file_system;
+mod memory;
+mod meta;
+mod queue;
 mod sled;
 
 use std::{
+    path::{Path, PathBuf},
     string::FromUtf8Error,
     sync::{MutexGuard, PoisonError},
+    time::Duration,
 };
 use thiserror::Error;
 
-pub use bitcask::Bitcask;
-pub use sled::Sled;
+pub use async_bitcask::AsyncBitcask;
+pub use bitcask::{
+    Bitcask, BitcaskOptions, BitcaskStats, CompactionPlan, CompactionStats, LogRecord, SegmentInfo,
+    ValueMetadata,
+};
+pub use file_system::{FileSystem, StdFileSystem};
+pub use memory::{Memory, MemoryStats};
+pub use sled::{Sled, SledOptions, SledStats};
 
 /// The `Engine` trait for the various storage engines.
+///
+/// Every operation on a single key returns `StorageError::InvalidKey` for an empty key, whatever the engine.
 pub trait Storage: Clone + Send + 'static {
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
     fn get(&self, key: String) -> StorageResult<Option<String>>;
 
+    /// Gets the string value of a given string key along with the time it was last written,
+    /// in milliseconds since the unix epoch.
+    ///
+    /// Returns `None` if the given key does not exist. The timestamp is `None` if the engine has no record of when
+    /// the value was written.
+    fn get_with_metadata(&self, key: String) -> StorageResult<Option<(String, Option<u64>)>>;
+
+    /// Checks whether a given string key exists, without reading its value.
+    fn exists(&self, key: String) -> StorageResult<bool>;
+
     /// Sets the value of a string key to a string.
     ///
     /// If the key already exists, the previous value will be overwritten.
     fn set(&self, key: String, value: String) -> StorageResult<()>;
 
+    /// Sets the value of a string key to a string that expires once `ttl` has passed.
+    ///
+    /// An expired key reads and is written as if it did not exist. Setting the key again, whether with a TTL or
+    /// without one, replaces its expiry, while `touch` keeps it.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> StorageResult<()>;
+
+    /// Sets the value of a string key to a string, reporting whether the key was created or updated.
+    ///
+    /// The check and the write happen atomically.
+    fn put(&self, key: String, value: String) -> StorageResult<PutOutcome>;
+
+    /// Sets the value of a string key to a string only if the key does not already exist.
+    ///
+    /// Returns `true` if the value was written and `false` if the key already existed.
+    /// The check and the write happen atomically.
+    fn set_if_absent(&self, key: String, value: String) -> StorageResult<bool>;
+
+    /// Gets the value of a string key, setting it to `default` first if the key does not exist.
+    ///
+    /// Returns the value stored once the call completes. The check and the write happen atomically, so racing
+    /// callers all observe the same value.
+    fn get_or_set(&self, key: String, default: String) -> StorageResult<String>;
+
+    /// Records the current time as the last write of a key without changing its value, as reported by
+    /// `get_with_metadata`.
+    ///
+    /// Returns `StorageError::KeyNotFound` if the key does not exist.
+    fn touch(&self, key: String) -> StorageResult<()>;
+
     /// Remove a given key.
     ///
     /// Returns `StorageError::KeyNotFound` if the key does not exist.
     fn remove(&self, key: String) -> StorageResult<()>;
 
+    /// Remove a given key if it exists.
+    ///
+    /// Returns whether the key existed, rather than `StorageError::KeyNotFound` if it did not.
+    fn remove_optional(&self, key: String) -> StorageResult<bool>;
+
+    /// Remove every key starting with the given prefix.
+    ///
+    /// Returns the number of keys removed.
+    fn remove_prefix(&self, prefix: String) -> StorageResult<usize>;
+
+    /// Remove every key whose value equals the given value.
+    ///
+    /// Returns the number of keys removed. This is O(n) in the number of keys and reads every value, so it is meant
+    /// for occasional maintenance rather than regular use.
+    fn remove_by_value(&self, value: String) -> StorageResult<usize>;
+
+    /// Pushes a value onto the front of the queue stored at a key, creating the queue if the key does not exist.
+    ///
+    /// Returns the length of the queue after the push.
+    fn lpush(&self, key: String, value: String) -> StorageResult<usize>;
+
+    /// Pushes a value onto the back of the queue stored at a key, creating the queue if the key does not exist.
+    ///
+    /// Returns the length of the queue after the push.
+    fn rpush(&self, key: String, value: String) -> StorageResult<usize>;
+
+    /// Pops a value from the front of the queue stored at a key.
+    ///
+    /// Returns `None` if the key does not exist. The key is removed once its last value has been popped.
+    fn lpop(&self, key: String) -> StorageResult<Option<String>>;
+
+    /// Pops a value from the back of the queue stored at a key.
+    ///
+    /// Returns `None` if the key does not exist. The key is removed once its last value has been popped.
+    fn rpop(&self, key: String) -> StorageResult<Option<String>>;
+
     /// List all keys.
     fn list_keys(&self) -> Vec<String>;
 
+    /// List all keys along with the size of their values in bytes.
+    fn list_with_sizes(&self) -> StorageResult<Vec<(String, u32)>>;
+
+    /// Lists up to `limit` keys in order, starting after `cursor` or from the first key if it is `None`.
+    ///
+    /// Only the keys of the page are read, so every key can be paged through without holding them all at once.
+    fn list_page(&self, cursor: Option<String>, limit: usize) -> StorageResult<ListPage>;
+
+    /// Lists the writes made at or after `since`, in milliseconds since the unix epoch, in the order they were made.
+    ///
+    /// Each change is a key and the value it was set to, or `None` if it was removed, so applying the changes in order
+    /// to a copy of the store taken at `since` brings it up to date. The changes are read as they are iterated, so the
+    /// whole log is never held at once. Returns `StorageError::Unsupported` for engines that do not keep a log of their
+    /// writes.
+    fn changes_since(&self, since: u64) -> StorageResult<Changes>;
+
+    /// Lists the keys starting with the given prefix, in order.
+    fn scan_prefix(&self, prefix: String) -> StorageResult<Vec<String>>;
+
+    /// Lists the keys from `start` up to but not including `end`, in order.
+    ///
+    /// Keys are compared by their bytes. The range is empty if `start` is not below `end`.
+    fn range(&self, start: String, end: String) -> StorageResult<Vec<String>>;
+
+    /// The number of keys.
+    fn len(&self) -> StorageResult<usize>;
+
+    /// Checks whether there are no keys.
+    fn is_empty(&self) -> StorageResult<bool>;
+
+    /// The directory the data is stored in, `None` if it is not stored on disk.
+    fn data_dir(&self) -> Option<&Path>;
+
+    /// Whether the store was created empty when it was opened, rather than opened with data an earlier run left
+    /// behind, so that defaults can be seeded on the first run only.
+    fn is_new(&self) -> bool;
+
+    /// Flushes any buffered writes and syncs them to disk.
+    ///
+    /// Once `flush` returns, every write that completed before it was called is durable.
+    fn flush(&self) -> StorageResult<()>;
+
     /// Compacts storage.
     fn compact(&self) -> StorageResult<()>;
 }
 
+/// Whether a `Storage::put` created a new key or updated an existing one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PutOutcome {
+    /// The key did not exist before the put.
+    Created,
+    /// The key existed and its value was overwritten.
+    Updated,
+}
+
+/// The changes returned by `Storage::changes_since`, each a key and the value it was set to or `None` if it was removed.
+pub type Changes = Box<dyn Iterator<Item = StorageResult<(String, Option<String>)>> + Send>;
+
+/// A page of keys returned by `Storage::list_page`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListPage {
+    /// The keys of the page, in order.
+    pub keys: Vec<String>,
+    /// The cursor to list the next page from, `None` if this is the last page.
+    pub next_cursor: Option<String>,
+}
+
+impl ListPage {
+    // Collects a page of up to `limit` keys, taking at most one more key from `keys` to learn if there is a next page.
+    //
+    // A limit of 0 is treated as 1 so that paging always makes progress.
+    pub(crate) fn collect(
+        mut keys: impl Iterator<Item = StorageResult<String>>,
+        limit: usize,
+    ) -> StorageResult<ListPage> {
+        let page = keys
+            .by_ref()
+            .take(limit.max(1))
+            .collect::<StorageResult<Vec<String>>>()?;
+        let next_cursor = match keys.next() {
+            Some(next) => {
+                next?;
+                page.last().cloned()
+            }
+            None => None,
+        };
+        Ok(ListPage {
+            keys: page,
+            next_cursor,
+        })
+    }
+}
+
 /// The `StorageError` type for `Storage`.
 #[derive(Error, Debug)]
 pub enum StorageError {
@@ -41,6 +222,18 @@ pub enum StorageError {
     #[error("An IO error occurred: {0}")]
     Io(#[from] std::io::Error),
 
+    /// A file or directory of the store could not be created, read or written, such as in a data directory that is
+    /// not writable.
+    #[error("Unable to {operation} {}: {source}", .path.display())]
+    Access {
+        /// What was being done with the file or directory.
+        operation: &'static str,
+        /// The file or directory.
+        path: PathBuf,
+        /// The error it failed with.
+        source: std::io::Error,
+    },
+
     /// SystemTime error.
     #[error("A system time error occurred: {0}")]
     SystemTime(#[from] std::time::SystemTimeError),
@@ -49,6 +242,19 @@ pub enum StorageError {
     #[error("Key not found")]
     KeyNotFound,
 
+    /// The key cannot be stored, such as an empty key.
+    #[error("Invalid key: {0}")]
+    InvalidKey(String),
+
+    /// The value is larger than the engine can store.
+    #[error("The value is {len} bytes, larger than the {max} bytes that can be stored")]
+    ValueTooLarge {
+        /// The length of the value in bytes.
+        len: usize,
+        /// The largest value in bytes that can be stored.
+        max: usize,
+    },
+
     /// UTF-8 decoding error.
     #[error("A UTF-8 decoding error occured: {0}")]
     Utf8(#[from] FromUtf8Error),
@@ -57,6 +263,46 @@ pub enum StorageError {
     #[error("A data corruption error was detected. Stored checksum: {0}, Calculated checksum:{1}")]
     DataCorruption(u16, u16),
 
+    /// A value extends past the end of the log file its key points into, such as when the file was truncated after
+    /// the value was written.
+    #[error("The value of key {key:?} at offset {value_pos} of log file {file_id} extends past the end of the file")]
+    TruncatedValue {
+        /// The key whose value could not be read.
+        key: String,
+        /// The id of the log file the value is in.
+        file_id: u64,
+        /// The offset of the value in the log file.
+        value_pos: u64,
+        /// The length of the value in bytes.
+        value_len: u32,
+    },
+
+    /// A queue operation was used on a key whose value is not a queue.
+    #[error("The value stored at the key is not a queue")]
+    WrongType,
+
+    /// The data files were written in a format version this build does not understand.
+    #[error("Unsupported data format version: {0}")]
+    UnsupportedFormat(u8),
+
+    /// The data directory was written by another engine or by a newer build in a format this build does not
+    /// understand.
+    #[error("The data directory was written by the {engine} engine in format version {format_version}, which this build cannot open")]
+    IncompatibleFormat {
+        /// The engine recorded in the data directory.
+        engine: String,
+        /// The format version recorded in the data directory.
+        format_version: u8,
+    },
+
+    /// A write was made to a store that was opened read-only.
+    #[error("The store was opened read-only")]
+    ReadOnly,
+
+    /// The engine does not support the operation.
+    #[error("The {0} operation is not supported by this storage engine")]
+    Unsupported(String),
+
     /// Unexpected error.
     #[error("An unexpected error occurred: {0}")]
     Unexpected(String),
@@ -78,3 +324,62 @@ impl<T> From<PoisonError<MutexGuard<'_, T>>> for StorageError {
 
 /// The `Result` type for `Storage`.
 pub type StorageResult<T> = std::result::Result<T, StorageError>;
+
+// Wraps an IO error with what was being done and to which file or directory, for errors an operator may need to fix
+// such as missing permissions.
+pub(crate) fn access_error(
+    operation: &'static str,
+    path: &Path,
+) -> impl FnOnce(std::io::Error) -> StorageError {
+    let path = path.to_path_buf();
+    move |source| StorageError::Access {
+        operation,
+        path,
+        source,
+    }
+}
+
+// Checks a key against the policy every engine applies before reading or writing it.
+//
+// An empty key is rejected as it is ambiguous: it is also the prefix of every key and the lowest bound of every range.
+pub(crate) fn validate_key(key: &str) -> StorageResult<()> {
+    if key.is_empty() {
+        return Err(StorageError::InvalidKey("the key is empty".to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    // A page should only take the keys it returns and one more, however many keys there are.
+    #[test]
+    fn list_page_is_lazy() -> StorageResult<()> {
+        let taken = Cell::new(0);
+        let keys = || {
+            (0..1_000_000).map(|i| {
+                taken.set(taken.get() + 1);
+                Ok(format!("key{:07}", i))
+            })
+        };
+
+        let page = ListPage::collect(keys(), 100)?;
+        assert_eq!(page.keys.len(), 100);
+        assert_eq!(page.keys.last(), Some(&"key0000099".to_owned()));
+        assert_eq!(page.next_cursor, Some("key0000099".to_owned()));
+        assert_eq!(taken.get(), 101);
+
+        taken.set(0);
+        let page = ListPage::collect(keys(), 0)?;
+        assert_eq!(page.keys, vec!["key0000000".to_owned()]);
+        assert_eq!(taken.get(), 2);
+
+        let page = ListPage::collect(keys().take(10), 100)?;
+        assert_eq!(page.keys.len(), 10);
+        assert_eq!(page.next_cursor, None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,36 @@
+use std::collections::VecDeque;
+
+use super::{StorageError, StorageResult};
+
+// Queues are stored as ordinary string values so every engine can hold them without a separate value type.
+//
+// Each element is written as its length in bytes, a colon and then the element itself, e.g. `["a", "bc"]` is `1:a2:bc`.
+// The length prefix means elements may themselves contain colons or digits.
+
+// Decodes the queue stored in a value, a missing value is an empty queue.
+pub(super) fn decode(value: Option<&str>) -> StorageResult<VecDeque<String>> {
+    let mut queue = VecDeque::new();
+    let mut rest = match value {
+        Some(value) => value,
+        None => return Ok(queue),
+    };
+    while !rest.is_empty() {
+        let (len, tail) = rest.split_once(':').ok_or(StorageError::WrongType)?;
+        let len: usize = len.parse().map_err(|_| StorageError::WrongType)?;
+        if !tail.is_char_boundary(len) {
+            return Err(StorageError::WrongType);
+        }
+        let (element, tail) = tail.split_at(len);
+        queue.push_back(element.to_owned());
+        rest = tail;
+    }
+    Ok(queue)
+}
+
+// Encodes a queue into a value.
+pub(super) fn encode(queue: &VecDeque<String>) -> String {
+    queue
+        .iter()
+        .map(|element| format!("{}:{}", element.len(), element))
+        .collect()
+}
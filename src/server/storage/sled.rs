@@ -1,35 +1,348 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::{HashSet, VecDeque},
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use sled::{Db, Tree};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError},
+    Batch, Db, IVec, Transactional, Tree,
+};
+use tracing::error;
 
-use super::{Storage, StorageError, StorageResult};
+use super::{
+    meta, queue, validate_key, Changes, ListPage, PutOutcome, StdFileSystem, Storage, StorageError,
+    StorageResult,
+};
+
+// The name this engine records in the store meta file.
+const ENGINE: &str = "sled";
+
+// The version of the layout of the trees written by this build.
+//
+// Version 1 added the timestamps tree, stores without a meta file predate it and are read as version 0.
+// Version 2 records timestamps in milliseconds rather than seconds, earlier timestamps are converted on open.
+// Version 3 added the expiries tree, which earlier builds would ignore and serve expired keys.
+const FORMAT_VERSION: u8 = 3;
+
+// The tree recording when each key in the default tree was last written.
+const TIMESTAMPS_TREE: &str = "timestamps";
+
+// The tree recording when each key in the default tree that was set with a TTL expires.
+const EXPIRIES_TREE: &str = "expiries";
+
+// How often expired keys are removed in the background.
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Options for tuning a `Sled` store.
+#[derive(Debug, Clone, Default)]
+pub struct SledOptions {
+    /// The size in bytes of sled's page cache.
+    ///
+    /// `None` uses sled's default.
+    pub cache_capacity: Option<u64>,
+}
+
+/// Statistics about a `Sled` store, as reported by `Sled::stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SledStats {
+    /// The number of keys in the store.
+    pub keys: usize,
+
+    /// The size in bytes the database takes up on disk, as reported by sled.
+    pub size_on_disk: u64,
+}
 
 /// Wrapper of `sled::Db`
 #[derive(Clone)]
-pub struct Sled(Arc<Db>);
+pub struct Sled {
+    db: Arc<Db>,
+    path: Arc<PathBuf>,
+    timestamps: Tree,
+    expiries: Tree,
+}
 
 impl Sled {
     /// Creates a `Sled` storage engine using `sled::Db`.
     pub fn open(path: impl Into<PathBuf>) -> StorageResult<Self> {
-        let db = ::sled::open(path.into())?;
-        Ok(Sled(Arc::new(db)))
+        Sled::open_with_options(path, SledOptions::default())
+    }
+
+    /// Creates a `Sled` storage engine using `sled::Db` with the given options.
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: SledOptions,
+    ) -> StorageResult<Self> {
+        let path = path.into();
+        // Keys a version 0 store wrote before timestamps were recorded simply have none, but it may hold timestamps
+        // in seconds if it was written after timestamps were added and before the meta file was.
+        let format_version =
+            meta::check(&StdFileSystem, &path, ENGINE, FORMAT_VERSION)?.unwrap_or(0);
+        let mut config = ::sled::Config::new().path(&path);
+        if let Some(cache_capacity) = options.cache_capacity {
+            config = config.cache_capacity(cache_capacity);
+        }
+        let db = config.open()?;
+        let timestamps = db.open_tree(TIMESTAMPS_TREE)?;
+        if format_version < FORMAT_VERSION {
+            let mut batch = Batch::default();
+            for item in timestamps.iter() {
+                let (key, timestamp) = item?;
+                if let Ok(timestamp) = <[u8; 8]>::try_from(timestamp.as_ref()) {
+                    let millis = u64::from_be_bytes(timestamp).saturating_mul(1000);
+                    batch.insert(key, &millis.to_be_bytes());
+                }
+            }
+            timestamps.apply_batch(batch)?;
+            timestamps.flush()?;
+        }
+        let expiries = db.open_tree(EXPIRIES_TREE)?;
+        meta::write(&StdFileSystem, &path, ENGINE, FORMAT_VERSION)?;
+        let store = Sled {
+            db: Arc::new(db),
+            path: Arc::new(path),
+            timestamps,
+            expiries,
+        };
+        store.sweep_in_background();
+        Ok(store)
+    }
+
+    /// Reports statistics about the store.
+    pub fn stats(&self) -> StorageResult<SledStats> {
+        Ok(SledStats {
+            keys: self.len()?,
+            size_on_disk: self.db.size_on_disk()?,
+        })
+    }
+
+    // Records the current time as the last write of a key.
+    //
+    // Timestamps are kept in their own tree rather than alongside the values so existing databases remain readable,
+    // keys written before timestamps were recorded simply have none.
+    fn record_write(&self, key: &str) -> StorageResult<()> {
+        self.timestamps.insert(key, &now()?.to_be_bytes())?;
+        Ok(())
+    }
+
+    // Removes expired keys every `EXPIRY_SWEEP_INTERVAL` on a background thread, so that keys which are never read
+    // again do not keep taking up space.
+    //
+    // The thread only holds a weak reference to the database and stops once every clone of the store has been dropped.
+    fn sweep_in_background(&self) {
+        let db = Arc::downgrade(&self.db);
+        thread::spawn(move || loop {
+            thread::sleep(EXPIRY_SWEEP_INTERVAL);
+            let db = match db.upgrade() {
+                Some(db) => db,
+                None => return,
+            };
+            let swept = db.open_tree(TIMESTAMPS_TREE).and_then(|timestamps| {
+                db.open_tree(EXPIRIES_TREE)
+                    .map(|expiries| (timestamps, expiries))
+            });
+            let result = swept
+                .map_err(StorageError::from)
+                .and_then(|(timestamps, expiries)| sweep(&db, &timestamps, &expiries));
+            if let Err(e) = result {
+                error!("unable to remove expired keys: {}", e);
+            }
+        });
+    }
+
+    // Removes the key if its value has expired, so that a write that depends on whether the key exists treats it as
+    // missing.
+    fn expire(&self, key: &str) -> StorageResult<()> {
+        expire(
+            &self.db,
+            &self.timestamps,
+            &self.expiries,
+            key.as_bytes(),
+            now()?,
+        )?;
+        Ok(())
+    }
+
+    // Whether the key was set with a TTL that has passed. Reads check this rather than removing the key, which is left
+    // to the next write of the key or to the sweep.
+    fn is_expired(&self, key: &[u8], now: u64) -> StorageResult<bool> {
+        Ok(self
+            .expiries
+            .get(key)?
+            .is_some_and(|expires_at| decode_millis(&expires_at).is_some_and(|e| e <= now)))
+    }
+
+    // The keys whose TTL has passed, for filtering listings without a lookup per key.
+    fn expired_keys(&self) -> StorageResult<HashSet<IVec>> {
+        let now = now()?;
+        let mut expired = HashSet::new();
+        for item in self.expiries.iter() {
+            let (key, expires_at) = item?;
+            if decode_millis(&expires_at).is_some_and(|expires_at| expires_at <= now) {
+                expired.insert(key);
+            }
+        }
+        Ok(expired)
+    }
+
+    // Applies `f` to the queue stored at a key in a transaction and writes the updated queue back.
+    //
+    // The transaction may be retried on conflict, so `f` may run more than once.
+    fn update_queue<T>(
+        &self,
+        key: String,
+        f: impl Fn(&mut VecDeque<String>) -> T,
+    ) -> StorageResult<T> {
+        validate_key(&key)?;
+        self.expire(&key)?;
+        let tree: &Tree = &self.db;
+        let timestamp = now()?.to_be_bytes();
+        let result = (tree, &self.timestamps, &self.expiries)
+            .transaction(|(tx, timestamps, expiries)| {
+                let value = tx
+                    .get(key.as_bytes())?
+                    .map(|i_vec| String::from_utf8(i_vec.to_vec()))
+                    .transpose()
+                    .map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+                let mut queue =
+                    queue::decode(value.as_deref()).map_err(ConflictableTransactionError::Abort)?;
+                let result = f(&mut queue);
+                expiries.remove(key.as_bytes())?;
+                if queue.is_empty() {
+                    tx.remove(key.as_bytes())?;
+                    timestamps.remove(key.as_bytes())?;
+                } else {
+                    tx.insert(key.as_bytes(), queue::encode(&queue).into_bytes())?;
+                    timestamps.insert(key.as_bytes(), &timestamp)?;
+                }
+                Ok(result)
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => StorageError::Sled(e),
+            })?;
+        tree.flush()?;
+        Ok(result)
     }
 }
 
 impl Storage for Sled {
+    fn flush(&self) -> StorageResult<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
     fn compact(&self) -> StorageResult<()> {
         Ok(())
     }
 
+    fn data_dir(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+
+    /// Whether sled created the database rather than recovering it from disk.
+    fn is_new(&self) -> bool {
+        !self.db.was_recovered()
+    }
+
     fn set(&self, key: String, value: String) -> StorageResult<()> {
-        let tree: &Tree = &self.0;
-        tree.insert(key, value.into_bytes()).map(|_| ())?;
+        validate_key(&key)?;
+        let tree: &Tree = &self.db;
+        // The expiry is removed first so that the sweep cannot remove the new value.
+        self.expiries.remove(key.as_bytes())?;
+        tree.insert(key.as_bytes(), value.into_bytes())
+            .map(|_| ())?;
+        self.record_write(&key)?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    /// Records the expiry in the expiries tree alongside the value. Expired keys are skipped by reads and removed by
+    /// the next write of the key or by a sweep that runs every second.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> StorageResult<()> {
+        validate_key(&key)?;
+        let tree: &Tree = &self.db;
+        let now = now()?;
+        let expires_at = now.saturating_add(ttl.as_millis() as u64);
+        (tree, &self.timestamps, &self.expiries)
+            .transaction(|(tx, timestamps, expiries)| {
+                tx.insert(key.as_bytes(), value.as_bytes())?;
+                timestamps.insert(key.as_bytes(), &now.to_be_bytes())?;
+                expiries.insert(key.as_bytes(), &expires_at.to_be_bytes())?;
+                Ok::<_, ConflictableTransactionError<StorageError>>(())
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => StorageError::Sled(e),
+            })?;
         tree.flush()?;
         Ok(())
     }
 
+    fn put(&self, key: String, value: String) -> StorageResult<PutOutcome> {
+        validate_key(&key)?;
+        self.expire(&key)?;
+        let tree: &Tree = &self.db;
+        self.expiries.remove(key.as_bytes())?;
+        let previous = tree.insert(key.as_bytes(), value.into_bytes())?;
+        self.record_write(&key)?;
+        tree.flush()?;
+        Ok(match previous {
+            Some(_) => PutOutcome::Updated,
+            None => PutOutcome::Created,
+        })
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> StorageResult<bool> {
+        validate_key(&key)?;
+        self.expire(&key)?;
+        let tree: &Tree = &self.db;
+        let written = tree
+            .compare_and_swap(
+                key.as_bytes(),
+                None as Option<&[u8]>,
+                Some(value.into_bytes()),
+            )?
+            .is_ok();
+        if written {
+            self.record_write(&key)?;
+            tree.flush()?;
+        }
+        Ok(written)
+    }
+
+    fn get_or_set(&self, key: String, default: String) -> StorageResult<String> {
+        validate_key(&key)?;
+        self.expire(&key)?;
+        let tree: &Tree = &self.db;
+        match tree.compare_and_swap(
+            key.as_bytes(),
+            None as Option<&[u8]>,
+            Some(default.as_bytes()),
+        )? {
+            Ok(()) => {
+                self.record_write(&key)?;
+                tree.flush()?;
+                Ok(default)
+            }
+            Err(e) => match e.current {
+                Some(current) => Ok(String::from_utf8(current.to_vec())?),
+                None => Err(StorageError::Unexpected(
+                    "compare and swap failed on a missing key".to_string(),
+                )),
+            },
+        }
+    }
+
     fn get(&self, key: String) -> StorageResult<Option<String>> {
-        let tree: &Tree = &self.0;
+        validate_key(&key)?;
+        if self.is_expired(key.as_bytes(), now()?)? {
+            return Ok(None);
+        }
+        let tree: &Tree = &self.db;
         Ok(tree
             .get(key)?
             .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
@@ -37,20 +350,474 @@ impl Storage for Sled {
             .transpose()?)
     }
 
+    fn get_with_metadata(&self, key: String) -> StorageResult<Option<(String, Option<u64>)>> {
+        validate_key(&key)?;
+        let timestamp = self
+            .timestamps
+            .get(key.as_bytes())?
+            .and_then(|i_vec| <[u8; 8]>::try_from(i_vec.as_ref()).ok())
+            .map(u64::from_be_bytes);
+        Ok(self.get(key)?.map(|value| (value, timestamp)))
+    }
+
+    fn exists(&self, key: String) -> StorageResult<bool> {
+        validate_key(&key)?;
+        if self.is_expired(key.as_bytes(), now()?)? {
+            return Ok(false);
+        }
+        Ok(self.db.contains_key(key.as_bytes())?)
+    }
+
+    fn touch(&self, key: String) -> StorageResult<()> {
+        validate_key(&key)?;
+        self.expire(&key)?;
+        if !self.db.contains_key(key.as_bytes())? {
+            return Err(StorageError::KeyNotFound);
+        }
+        self.record_write(&key)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
     fn remove(&self, key: String) -> StorageResult<()> {
-        let tree: &Tree = &self.0;
-        tree.remove(key)?.ok_or(StorageError::KeyNotFound)?;
-        tree.flush()?;
+        if !self.remove_optional(key)? {
+            return Err(StorageError::KeyNotFound);
+        }
         Ok(())
     }
 
+    fn remove_optional(&self, key: String) -> StorageResult<bool> {
+        validate_key(&key)?;
+        self.expire(&key)?;
+        let tree: &Tree = &self.db;
+        if tree.remove(key.as_bytes())?.is_none() {
+            return Ok(false);
+        }
+        self.timestamps.remove(key.as_bytes())?;
+        self.expiries.remove(key.as_bytes())?;
+        tree.flush()?;
+        Ok(true)
+    }
+
+    fn remove_prefix(&self, prefix: String) -> StorageResult<usize> {
+        let tree: &Tree = &self.db;
+        // Expired keys are swept first so that they are not counted as removed.
+        sweep(tree, &self.timestamps, &self.expiries)?;
+        let mut batch = Batch::default();
+        let mut removed = 0;
+        let mut timestamps = Batch::default();
+        for key in tree.scan_prefix(prefix).keys() {
+            let key = key?;
+            timestamps.remove(key.clone());
+            batch.remove(key);
+            removed += 1;
+        }
+        tree.apply_batch(batch)?;
+        // The keys have no timestamps or expiries left to remove once they are gone.
+        self.expiries.apply_batch(timestamps.clone())?;
+        self.timestamps.apply_batch(timestamps)?;
+        tree.flush()?;
+        Ok(removed)
+    }
+
+    fn remove_by_value(&self, value: String) -> StorageResult<usize> {
+        let tree: &Tree = &self.db;
+        sweep(tree, &self.timestamps, &self.expiries)?;
+        let mut removed = 0;
+        for item in tree.iter() {
+            let (key, current) = item?;
+            if current != value.as_bytes() {
+                continue;
+            }
+            // Only remove the key if it still holds the value, in case it was written since it was read.
+            let swapped = tree.compare_and_swap(&key, Some(current), None as Option<&[u8]>)?;
+            if swapped.is_ok() {
+                self.timestamps.remove(&key)?;
+                self.expiries.remove(&key)?;
+                removed += 1;
+            }
+        }
+        tree.flush()?;
+        Ok(removed)
+    }
+
+    fn lpush(&self, key: String, value: String) -> StorageResult<usize> {
+        self.update_queue(key, |queue| {
+            queue.push_front(value.clone());
+            queue.len()
+        })
+    }
+
+    fn rpush(&self, key: String, value: String) -> StorageResult<usize> {
+        self.update_queue(key, |queue| {
+            queue.push_back(value.clone());
+            queue.len()
+        })
+    }
+
+    fn lpop(&self, key: String) -> StorageResult<Option<String>> {
+        self.update_queue(key, VecDeque::pop_front)
+    }
+
+    fn rpop(&self, key: String) -> StorageResult<Option<String>> {
+        self.update_queue(key, VecDeque::pop_back)
+    }
+
     fn list_keys(&self) -> Vec<String> {
-        let tree: &Tree = &self.0;
+        let tree: &Tree = &self.db;
+        let expired = self.expired_keys().unwrap_or_default();
         tree.iter()
             .keys()
             .filter_map(Result::ok)
+            .filter(|key| !expired.contains(key))
             .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
             .filter_map(|i_vec| String::from_utf8(i_vec).ok())
             .collect()
     }
+
+    fn list_with_sizes(&self) -> StorageResult<Vec<(String, u32)>> {
+        let tree: &Tree = &self.db;
+        let expired = self.expired_keys()?;
+        tree.iter()
+            .filter(|item| !item.as_ref().is_ok_and(|(key, _)| expired.contains(key)))
+            .map(|item| {
+                let (key, value) = item?;
+                Ok((String::from_utf8(key.to_vec())?, value.len() as u32))
+            })
+            .collect()
+    }
+
+    fn list_page(&self, cursor: Option<String>, limit: usize) -> StorageResult<ListPage> {
+        let tree: &Tree = &self.db;
+        let start = cursor.as_deref().map_or(Bound::Unbounded, |cursor| {
+            Bound::Excluded(cursor.as_bytes())
+        });
+        // Keys are checked one at a time rather than listing every expired key, as only a page of them is visited.
+        let now = now()?;
+        let keys = tree
+            .range::<&[u8], _>((start, Bound::Unbounded))
+            .keys()
+            .filter(|key| {
+                !key.as_ref()
+                    .is_ok_and(|key| self.is_expired(key, now).unwrap_or(false))
+            })
+            .map(|key| Ok(String::from_utf8(key?.to_vec())?));
+        ListPage::collect(keys, limit)
+    }
+
+    /// Returns `StorageError::Unsupported`, as only the time each key was last written is kept.
+    fn changes_since(&self, _since: u64) -> StorageResult<Changes> {
+        Err(StorageError::Unsupported("changes".to_owned()))
+    }
+
+    fn scan_prefix(&self, prefix: String) -> StorageResult<Vec<String>> {
+        let tree: &Tree = &self.db;
+        let expired = self.expired_keys()?;
+        tree.scan_prefix(prefix.as_bytes())
+            .keys()
+            .filter(|key| !key.as_ref().is_ok_and(|key| expired.contains(key)))
+            .map(|key| Ok(String::from_utf8(key?.to_vec())?))
+            .collect()
+    }
+
+    fn range(&self, start: String, end: String) -> StorageResult<Vec<String>> {
+        if start >= end {
+            return Ok(Vec::new());
+        }
+        let tree: &Tree = &self.db;
+        let expired = self.expired_keys()?;
+        tree.range(start.as_bytes()..end.as_bytes())
+            .keys()
+            .filter(|key| !key.as_ref().is_ok_and(|key| expired.contains(key)))
+            .map(|key| Ok(String::from_utf8(key?.to_vec())?))
+            .collect()
+    }
+
+    fn len(&self) -> StorageResult<usize> {
+        let tree: &Tree = &self.db;
+        let mut expired = 0;
+        for key in self.expired_keys()? {
+            if tree.contains_key(key)? {
+                expired += 1;
+            }
+        }
+        Ok(tree.len().saturating_sub(expired))
+    }
+
+    fn is_empty(&self) -> StorageResult<bool> {
+        let tree: &Tree = &self.db;
+        Ok(tree.is_empty() || self.len()? == 0)
+    }
+}
+
+// Removes the key along with its timestamp and expiry if its TTL has passed at `now`, returning whether it did.
+//
+// The expiry is checked again in the same transaction as the removal, so a key that was set again in the meantime is
+// left alone.
+fn expire(
+    db: &Tree,
+    timestamps: &Tree,
+    expiries: &Tree,
+    key: &[u8],
+    now: u64,
+) -> StorageResult<bool> {
+    (db, timestamps, expiries)
+        .transaction(|(tx, timestamps, expiries)| {
+            let expired = expiries
+                .get(key)?
+                .is_some_and(|expires_at| decode_millis(&expires_at).is_some_and(|e| e <= now));
+            if expired {
+                tx.remove(key)?;
+                timestamps.remove(key)?;
+                expiries.remove(key)?;
+            }
+            Ok::<_, ConflictableTransactionError<StorageError>>(expired)
+        })
+        .map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => StorageError::Sled(e),
+        })
+}
+
+// Removes every key whose TTL has passed, returning how many were removed.
+fn sweep(db: &Tree, timestamps: &Tree, expiries: &Tree) -> StorageResult<usize> {
+    let now = now()?;
+    let mut removed = 0;
+    for item in expiries.iter() {
+        let (key, expires_at) = item?;
+        if decode_millis(&expires_at).is_some_and(|expires_at| expires_at <= now)
+            && expire(db, timestamps, expiries, &key, now)?
+        {
+            removed += 1;
+        }
+    }
+    if removed > 0 {
+        db.flush()?;
+    }
+    Ok(removed)
+}
+
+// Decodes a time in milliseconds as stored in the timestamps and expiries trees.
+fn decode_millis(bytes: &[u8]) -> Option<u64> {
+    <[u8; 8]>::try_from(bytes).ok().map(u64::from_be_bytes)
+}
+
+// The current time in milliseconds since the unix epoch.
+fn now() -> StorageResult<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // Reopens a store that was just dropped.
+    //
+    // Sled's background flusher can briefly hold the lock on the database after it is dropped, so reopening is retried
+    // until it is released.
+    fn reopen(path: &Path) -> StorageResult<Sled> {
+        let start = std::time::Instant::now();
+        loop {
+            match Sled::open(path) {
+                Err(StorageError::Sled(_)) if start.elapsed() < Duration::from_secs(5) => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Should report a store as new only the first time it is opened.
+    #[test]
+    fn is_new() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        assert!(Sled::open(temp_dir.path())?.is_new());
+        let store = reopen(temp_dir.path())?;
+        assert!(!store.is_new());
+        Ok(())
+    }
+
+    // Should report whether a put created or updated the key.
+    #[test]
+    fn put() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Sled::open(temp_dir.path())?;
+
+        assert_eq!(
+            store.put("key1".to_owned(), "value1".to_owned())?,
+            PutOutcome::Created
+        );
+        assert_eq!(
+            store.put("key1".to_owned(), "value2".to_owned())?,
+            PutOutcome::Updated
+        );
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+        Ok(())
+    }
+
+    // Should record when each key was last written, forgetting removed keys.
+    #[test]
+    fn get_with_metadata() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Sled::open(temp_dir.path())?;
+
+        let before = now()?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.rpush("queue".to_owned(), "a".to_owned())?;
+        let (value, timestamp) = store.get_with_metadata("key1".to_owned())?.unwrap();
+        assert_eq!(value, "value1");
+        assert!(timestamp.unwrap() >= before && timestamp.unwrap() <= now()?);
+        assert!(store
+            .get_with_metadata("queue".to_owned())?
+            .unwrap()
+            .1
+            .is_some());
+        assert_eq!(store.get_with_metadata("key2".to_owned())?, None);
+
+        store.remove("key1".to_owned())?;
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        store.remove_prefix("key".to_owned())?;
+        assert!(!store.timestamps.contains_key("key1")?);
+        assert_eq!(store.timestamps.len(), 1);
+        assert_eq!(store.list_keys(), vec!["queue".to_owned()]);
+
+        Ok(())
+    }
+
+    // Should remove only the keys holding the given value.
+    #[test]
+    fn remove_by_value() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Sled::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "DELETED".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.set("key3".to_owned(), "DELETED".to_owned())?;
+        store.set("key4".to_owned(), "DELETED!".to_owned())?;
+
+        assert_eq!(store.remove_by_value("DELETED".to_owned())?, 2);
+        assert_eq!(
+            store.list_keys(),
+            vec!["key2".to_owned(), "key4".to_owned()]
+        );
+        assert_eq!(store.timestamps.len(), 2);
+        assert_eq!(store.remove_by_value("DELETED".to_owned())?, 0);
+
+        Ok(())
+    }
+
+    // Should refuse a data directory written by another engine or a newer build.
+    #[test]
+    fn store_meta() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        meta::write(&StdFileSystem, temp_dir.path(), "bitcask", 1)?;
+        assert!(matches!(
+            Sled::open(temp_dir.path()),
+            Err(StorageError::IncompatibleFormat { engine, .. }) if engine == "bitcask"
+        ));
+
+        meta::write(&StdFileSystem, temp_dir.path(), ENGINE, FORMAT_VERSION + 1)?;
+        assert!(matches!(
+            Sled::open(temp_dir.path()),
+            Err(StorageError::IncompatibleFormat { format_version, .. }) if format_version == FORMAT_VERSION + 1
+        ));
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let _store = Sled::open(temp_dir.path())?;
+        assert_eq!(
+            meta::read(&StdFileSystem, temp_dir.path())?,
+            Some(meta::StoreMeta {
+                engine: ENGINE.to_owned(),
+                format_version: FORMAT_VERSION,
+                migration: None,
+            })
+        );
+
+        Ok(())
+    }
+
+    // Should count keys and report the size of the database on disk.
+    #[test]
+    fn stats() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Sled::open(temp_dir.path())?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), "value".to_owned())?;
+        }
+        store.remove("key0".to_owned())?;
+
+        let stats = store.stats()?;
+        assert_eq!(stats.keys, 99);
+        assert!(stats.size_on_disk > 0);
+
+        Ok(())
+    }
+
+    // Paging from each cursor should visit every key once, in order.
+    #[test]
+    fn list_page() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Sled::open(temp_dir.path())?;
+        for key_id in 0..50 {
+            store.set(format!("key{:02}", key_id), "value".to_owned())?;
+        }
+
+        let mut paged = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = store.list_page(cursor, 7)?;
+            assert!(page.keys.len() <= 7);
+            paged.extend(page.keys);
+            cursor = match page.next_cursor {
+                Some(next_cursor) => Some(next_cursor),
+                None => break,
+            };
+        }
+        assert_eq!(paged, store.list_keys());
+
+        Ok(())
+    }
+
+    // Should push and pop from both ends of a queue, persisting it across reopens.
+    #[test]
+    fn queue() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Sled::open(temp_dir.path())?;
+
+        assert_eq!(store.rpush("queue".to_owned(), "a".to_owned())?, 1);
+        assert_eq!(store.rpush("queue".to_owned(), "b".to_owned())?, 2);
+        assert_eq!(store.lpush("queue".to_owned(), "z".to_owned())?, 3);
+        assert_eq!(store.rpop("queue".to_owned())?, Some("b".to_owned()));
+
+        drop(store);
+        let store = reopen(temp_dir.path())?;
+        assert_eq!(store.lpop("queue".to_owned())?, Some("z".to_owned()));
+        assert_eq!(store.lpop("queue".to_owned())?, Some("a".to_owned()));
+        assert_eq!(store.lpop("queue".to_owned())?, None);
+        assert!(store.list_keys().is_empty());
+
+        Ok(())
+    }
+
+    // Should remove expired keys in the background, even if they are never read again.
+    #[test]
+    fn sweep_expired() -> StorageResult<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = Sled::open(temp_dir.path())?;
+        store.set_with_ttl(
+            "key1".to_owned(),
+            "value1".to_owned(),
+            Duration::from_millis(10),
+        )?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+
+        thread::sleep(EXPIRY_SWEEP_INTERVAL + Duration::from_millis(500));
+        assert!(!store.db.contains_key("key1")?);
+        assert!(!store.timestamps.contains_key("key1")?);
+        assert!(store.expiries.is_empty());
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,107 @@
+mod naive;
+mod rayon;
+mod shared_queue;
+
+use std::{
+    io,
+    sync::{Arc, Condvar, Mutex, PoisonError},
+};
+use thiserror::Error;
+
+pub use self::rayon::RayonThreadPool;
+pub use naive::NaiveThreadPool;
+pub use shared_queue::SharedQueueThreadPool;
+
+/// The `ThreadPool` trait for the thread pools blocking work can be run on.
+pub trait ThreadPool: Send + Sync + 'static {
+    /// Creates a new thread pool with the given number of threads.
+    ///
+    /// Returns an error if the pool's threads could not be spawned, or if the pool needs threads and none were asked
+    /// for.
+    fn new(threads: u32) -> ThreadPoolResult<Self>
+    where
+        Self: Sized;
+
+    /// Runs a job on the thread pool.
+    ///
+    /// Spawning always succeeds, and a job that panics does not reduce the number of threads available to later jobs.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+
+    /// Blocks until every job spawned before the call has finished, whether it completed or panicked.
+    ///
+    /// Jobs spawned while waiting may or may not be waited for. Calling `join` from a job on the same pool deadlocks.
+    fn join(&self);
+}
+
+/// The thread pool implementations.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ThreadPoolType {
+    /// `NaiveThreadPool`, a new thread for every job.
+    Naive,
+    /// `SharedQueueThreadPool`, a fixed number of threads taking jobs from a shared queue.
+    SharedQueue,
+    /// `RayonThreadPool`, a rayon work-stealing pool.
+    Rayon,
+}
+
+/// The `ThreadPoolError` type for `ThreadPool`.
+#[derive(Error, Debug)]
+pub enum ThreadPoolError {
+    /// A thread could not be spawned.
+    #[error("Failed to spawn a thread: {0}")]
+    Io(#[from] io::Error),
+
+    /// The rayon pool could not be built.
+    #[error("Failed to build the rayon thread pool: {0}")]
+    Rayon(#[from] ::rayon::ThreadPoolBuildError),
+
+    /// A pool was asked for with no threads.
+    #[error("A thread pool needs at least one thread")]
+    NoThreads,
+
+    /// A job ended without completing, which happens if it panicked.
+    #[error("A job on the thread pool did not complete")]
+    JobAborted,
+}
+
+// Counts the jobs of a pool that have been spawned but have not finished, so that `join` can wait for them.
+#[derive(Clone, Default)]
+struct JobCounter(Arc<(Mutex<usize>, Condvar)>);
+
+impl JobCounter {
+    // Counts a job as pending until the returned guard is dropped, which happens even if the job panics.
+    fn start(&self) -> JobGuard {
+        let (pending, _) = &*self.0;
+        *pending.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+        JobGuard(self.clone())
+    }
+
+    // Blocks until there are no pending jobs.
+    fn wait(&self) {
+        let (pending, finished) = &*self.0;
+        let pending = pending.lock().unwrap_or_else(PoisonError::into_inner);
+        drop(
+            finished
+                .wait_while(pending, |pending| *pending > 0)
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+    }
+}
+
+struct JobGuard(JobCounter);
+
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        let (pending, finished) = &*(self.0).0;
+        let mut pending = pending.lock().unwrap_or_else(PoisonError::into_inner);
+        *pending -= 1;
+        if *pending == 0 {
+            finished.notify_all();
+        }
+    }
+}
+
+/// The `Result` type for `ThreadPool`.
+pub type ThreadPoolResult<T> = std::result::Result<T, ThreadPoolError>;
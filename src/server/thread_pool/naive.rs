@@ -0,0 +1,45 @@
+use std::thread;
+
+use tracing::error;
+
+use super::{JobCounter, ThreadPool, ThreadPoolError, ThreadPoolResult};
+
+/// A thread pool that spawns a new thread for every job.
+///
+/// It is not really a pool at all, it is the baseline the others are measured against. The number of threads it is
+/// created with only has to be above 0, as every job runs on a thread of its own and a panicking job takes nothing
+/// down with it but that thread.
+///
+/// If a thread cannot be spawned, the error is logged and the job is dropped without running.
+pub struct NaiveThreadPool {
+    jobs: JobCounter,
+}
+
+impl ThreadPool for NaiveThreadPool {
+    fn new(threads: u32) -> ThreadPoolResult<Self> {
+        if threads == 0 {
+            return Err(ThreadPoolError::NoThreads);
+        }
+        Ok(NaiveThreadPool {
+            jobs: JobCounter::default(),
+        })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let guard = self.jobs.start();
+        let result = thread::Builder::new().spawn(move || {
+            let _guard = guard;
+            job();
+        });
+        if let Err(e) = result {
+            error!("unable to spawn a thread for a job: {}", e);
+        }
+    }
+
+    fn join(&self) {
+        self.jobs.wait();
+    }
+}
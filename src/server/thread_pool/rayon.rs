@@ -0,0 +1,40 @@
+use tracing::error;
+
+use super::{JobCounter, ThreadPool, ThreadPoolResult};
+
+/// A thread pool backed by a `rayon::ThreadPool`.
+///
+/// Rayon aborts the process when a spawned job panics unless the pool has a panic handler, so the pool is built with
+/// one that logs the panic instead. The worker that ran the job carries on taking jobs.
+pub struct RayonThreadPool {
+    pool: ::rayon::ThreadPool,
+    jobs: JobCounter,
+}
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> ThreadPoolResult<Self> {
+        let pool = ::rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            .panic_handler(|_| error!("a job on the thread pool panicked"))
+            .build()?;
+        Ok(RayonThreadPool {
+            pool,
+            jobs: JobCounter::default(),
+        })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let guard = self.jobs.start();
+        self.pool.spawn(move || {
+            let _guard = guard;
+            job();
+        });
+    }
+
+    fn join(&self) {
+        self.jobs.wait();
+    }
+}
@@ -0,0 +1,80 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use tracing::error;
+
+use super::{JobCounter, ThreadPool, ThreadPoolError, ThreadPoolResult};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A thread pool of a fixed number of threads taking jobs from a shared queue.
+///
+/// The threads exit once the pool is dropped and the jobs already queued have run.
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+    jobs: JobCounter,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> ThreadPoolResult<Self> {
+        if threads == 0 {
+            return Err(ThreadPoolError::NoThreads);
+        }
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            let receiver = receiver.clone();
+            thread::Builder::new().spawn(move || run_jobs(receiver))?;
+        }
+        Ok(SharedQueueThreadPool {
+            sender,
+            jobs: JobCounter::default(),
+        })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let guard = self.jobs.start();
+        // The pool has at least one thread and the threads only stop receiving once it is dropped, so the send cannot
+        // fail.
+        self.sender
+            .send(Box::new(move || {
+                let _guard = guard;
+                job();
+            }))
+            .expect("the thread pool's threads have stopped");
+    }
+
+    fn join(&self) {
+        self.jobs.wait();
+    }
+}
+
+// Runs jobs from the queue until it is closed.
+//
+// A panicking job is caught rather than unwinding the thread, so the pool keeps all of its threads.
+fn run_jobs(receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        // The lock is only held while waiting for a job, not while running it.
+        let job = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        match job {
+            Ok(job) => {
+                if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    error!("a job on the thread pool panicked");
+                }
+            }
+            Err(_) => return,
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use assert_cmd::prelude::*;
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
+use smoldb::{Bitcask, Storage};
 use std::fs::{self, File};
 use std::process::Command;
 use std::sync::mpsc;
@@ -20,28 +22,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["--addr", "invalid-addr", "get", "key"])
+        .args(["--addr", "invalid-addr", "get", "key"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["--unknown-flag", "get", "key"])
+        .args(["--unknown-flag", "get", "key"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +54,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["--addr", "invalid-addr", "set", "key", "value"])
+        .args(["--addr", "invalid-addr", "set", "key", "value"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["--unknown-flag", "get", "key"])
+        .args(["--unknown-flag", "get", "key"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +93,39 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn client_cli_invalid_mget() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["mget"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +136,77 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `smoldb --rate-limit` should only accept a positive number of requests
+#[test]
+fn server_cli_invalid_rate_limit() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("smoldb")
+        .unwrap()
+        .args(["--rate-limit", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("smoldb")
+        .unwrap()
+        .args(["--rate-limit", "fast"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `smoldb --threads` should only accept a positive number of threads
+#[test]
+fn server_cli_invalid_threads() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("smoldb")
+        .unwrap()
+        .args(["--threads", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("smoldb")
+        .unwrap()
+        .args(["--threads", "many"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `smoldb` should refuse an option tuning one storage engine along with another
+#[test]
+fn server_cli_storage_option_mismatch() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("smoldb")
+        .unwrap()
+        .args(["--storage", "sled", "--max-log-size", "256"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(
+            "--max-log-size only applies to the Bitcask storage engine",
+        ));
+
+    Command::cargo_bin("smoldb")
+        .unwrap()
+        .args(["--sled-cache-capacity", "1024"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(
+            "--sled-cache-capacity only applies to the Sled storage engine",
+        ));
+
+    Command::cargo_bin("smoldb")
+        .unwrap()
+        .args(["--max-log-size", "0"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +217,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("smolcli").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,25 +228,87 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("smoldb").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// `smoldb segments` should print the log files of the store in the working directory
+#[test]
+fn server_cli_segments() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("smoldb")
+        .unwrap()
+        .args(["segments"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("file_id").and(contains("0\t0\t0\ttrue\tfalse")));
+}
+
+// `smoldb compact --dry-run` should print the compaction plan without compacting
+#[test]
+fn server_cli_compact_dry_run() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("smoldb")
+        .unwrap()
+        .args(["compact", "--dry-run"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("files_to_remove\t0\nlive_keys\t0\nestimated_bytes_after\t0\n");
+    assert!(temp_dir.path().join("0.log").exists());
+
+    Command::cargo_bin("smoldb")
+        .unwrap()
+        .args(["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert!(!temp_dir.path().join("0.log").exists());
+    assert!(temp_dir.path().join("1.hint").exists());
+}
+
+// `smoldb merge` is the name the local storage CLI used for compaction
+#[test]
+fn server_cli_merge() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = Bitcask::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    drop(store);
+
+    Command::cargo_bin("smoldb")
+        .unwrap()
+        .args(["merge"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert!(!temp_dir.path().join("0.log").exists());
+    assert!(temp_dir.path().join("1.hint").exists());
+
+    let store = Bitcask::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("smoldb").unwrap();
     let mut child = cmd
-        .args(&["--storage", "bitcask", "--addr", "127.0.0.1:4001"])
+        .args(["--storage", "bitcask", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("server was not running");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -176,19 +321,20 @@ fn cli_access_server(storage: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("smoldb").unwrap();
     let mut child = server
-        .args(&["--storage", storage, "--addr", addr])
+        .args(["--storage", storage, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["--addr", addr, "set", "key1", "value1"])
+        .args(["--addr", addr, "set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -196,7 +342,7 @@ fn cli_access_server(storage: &str, addr: &str) {
 
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["--addr", addr, "get", "key1"])
+        .args(["--addr", addr, "get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -204,7 +350,7 @@ fn cli_access_server(storage: &str, addr: &str) {
 
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["--addr", addr, "set", "key1", "value2"])
+        .args(["--addr", addr, "set", "key1", "value2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -212,7 +358,7 @@ fn cli_access_server(storage: &str, addr: &str) {
 
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["--addr", addr, "get", "key1"])
+        .args(["--addr", addr, "get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -220,7 +366,7 @@ fn cli_access_server(storage: &str, addr: &str) {
 
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["--addr", addr, "get", "key2"])
+        .args(["--addr", addr, "get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -228,7 +374,7 @@ fn cli_access_server(storage: &str, addr: &str) {
 
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["--addr", addr, "rm", "key2"])
+        .args(["--addr", addr, "rm", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -236,7 +382,31 @@ fn cli_access_server(storage: &str, addr: &str) {
 
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["--addr", addr, "set", "key2", "value3"])
+        .args(["--addr", addr, "rm", "--idempotent", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["--addr", addr, "set", "key2", "value3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["--addr", addr, "ls", "--sizes"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1\t6\nkey2\t6\n");
+
+    Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["--addr", addr, "rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -244,12 +414,27 @@ fn cli_access_server(storage: &str, addr: &str) {
 
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["--addr", addr, "rm", "key1"])
+        .args(["--addr", addr, "set", "key3", "value4"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["--addr", addr, "rm", "--idempotent", "key3"])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(is_empty());
 
+    Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["--addr", addr, "get", "key3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
     sender.send(()).unwrap();
     handle.join().unwrap();
 
@@ -260,26 +445,27 @@ fn cli_access_server(storage: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("smoldb").unwrap();
     let mut child = server
-        .args(&["--storage", storage, "--addr", addr])
+        .args(["--storage", storage, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("server was not running");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["--addr", addr, "get", "key2"])
+        .args(["--addr", addr, "get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("smolcli")
         .unwrap()
-        .args(&["--addr", addr, "get", "key1"])
+        .args(["--addr", addr, "get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -288,6 +474,167 @@ fn cli_access_server(storage: &str, addr: &str) {
     handle.join().unwrap();
 }
 
+// `smolcli set KEY -` should store everything read from stdin, which `get --raw` should reproduce exactly
+#[test]
+fn cli_set_from_stdin() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4004";
+    let mut child = Command::cargo_bin("smoldb")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let value = "first line\nsecond line\n\n- not a flag\n";
+    assert_cmd::Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["--addr", addr, "set", "key1", "-"])
+        .write_stdin(value)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["--addr", addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", value));
+
+    Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["--addr", addr, "get", "--raw", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(value);
+
+    // A value without a trailing newline comes back exactly as it was set
+    Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["--addr", addr, "set", "key2", "no newline"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["--addr", addr, "get", "-r", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("no newline");
+
+    Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["--addr", addr, "get", "--raw", "key3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(is_empty())
+        .stderr(contains("Key not found"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was not running");
+}
+
+// `SMOLDB_*` environment variables should configure both binaries when no flags are given
+#[test]
+fn cli_env_configuration() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir).unwrap();
+    let work_dir = temp_dir.path().join("work");
+    fs::create_dir(&work_dir).unwrap();
+    let addr = "127.0.0.1:4005";
+
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut child = Command::cargo_bin("smoldb")
+        .unwrap()
+        .env("SMOLDB_ADDR", addr)
+        .env("SMOLDB_STORAGE", "sled")
+        .env("SMOLDB_DATA_DIR", &data_dir)
+        .current_dir(&work_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .env("SMOLDB_ADDR", addr)
+        .current_dir(&work_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["get", "key1"])
+        .env("SMOLDB_ADDR", addr)
+        .current_dir(&work_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    // A flag takes precedence over the environment
+    Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["--addr", addr, "get", "key1"])
+        .env("SMOLDB_ADDR", "127.0.0.1:1")
+        .current_dir(&work_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was not running");
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(content.contains("Sled"));
+    assert!(content.contains(addr));
+    assert!(fs::read_dir(&data_dir).unwrap().next().is_some());
+    assert!(fs::read_dir(&work_dir).unwrap().next().is_none());
+}
+
+// `smolcli mget` should fetch several keys in one invocation, marking the missing ones
+#[test]
+fn cli_mget() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4007";
+    let mut child = Command::cargo_bin("smoldb")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for (key, value) in [("key1", "value1"), ("key2", "value2"), ("key3", "value3")] {
+        Command::cargo_bin("smolcli")
+            .unwrap()
+            .args(["--addr", addr, "set", key, value])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["--addr", addr, "mget", "key3", "key1", "key4", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key3\tvalue3\nkey1\tvalue1\nkey4\t(not found)\nkey2\tvalue2\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was not running");
+}
+
 #[test]
 fn cli_access_server_bitcask() {
     cli_access_server("bitcask", "127.0.0.1:4002");
@@ -297,3 +644,214 @@ fn cli_access_server_bitcask() {
 fn cli_access_server_sled() {
     cli_access_server("sled", "127.0.0.1:4003");
 }
+
+// `smoldb --threads 1` should serve concurrent clients on a single worker thread
+#[test]
+fn cli_single_worker_thread() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4009";
+    let mut child = Command::cargo_bin("smoldb")
+        .unwrap()
+        .args(["--addr", addr, "--threads", "1"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let clients: Vec<_> = (0..8)
+        .map(|i| {
+            thread::spawn(move || {
+                for j in 0..5 {
+                    let key = format!("key{}-{}", i, j);
+                    let value = format!("value{}-{}", i, j);
+                    Command::cargo_bin("smolcli")
+                        .unwrap()
+                        .args(["--addr", addr, "set", &key, &value])
+                        .assert()
+                        .success()
+                        .stdout(is_empty());
+                    Command::cargo_bin("smolcli")
+                        .unwrap()
+                        .args(["--addr", addr, "get", &key])
+                        .assert()
+                        .success()
+                        .stdout(format!("{}\n", value));
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was not running");
+}
+
+// `smoldb --max-log-size` should seal log files once they reach the given size
+#[test]
+fn cli_max_log_size() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4010";
+    let mut child = Command::cargo_bin("smoldb")
+        .unwrap()
+        .args(["--addr", addr, "--max-log-size", "256"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let lines: String = (0..100)
+        .map(|i| format!("{{\"key\":\"key{}\",\"value\":\"value{}\"}}\n", i, i))
+        .collect();
+    assert_cmd::Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["--addr", addr, "load"])
+        .write_stdin(lines)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was not running");
+
+    // Each record is about 30 bytes, so 100 of them fill over ten 256 byte files rather than a single 1 MiB one.
+    let output = Command::cargo_bin("smoldb")
+        .unwrap()
+        .args(["segments"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let segments = String::from_utf8(output.stdout).unwrap().lines().count() - 1;
+    assert!(segments > 10, "{} segments", segments);
+}
+
+// `smolcli info` should describe the server it connects to
+#[test]
+fn cli_info() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4011";
+    let mut child = Command::cargo_bin("smoldb")
+        .unwrap()
+        .args(["--addr", addr, "--storage", "sled"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["--addr", addr, "set", "key1", "value1"])
+        .assert()
+        .success();
+    Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["--addr", addr, "info"])
+        .assert()
+        .success()
+        .stdout(
+            contains(format!("version: {}", env!("CARGO_PKG_VERSION")))
+                .and(contains("engine:  Sled"))
+                .and(contains("uptime:  "))
+                .and(contains("keys:    1")),
+        );
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was not running");
+}
+
+// `smolcli dump` should print every pair, which `smolcli load` should restore into an empty store
+#[test]
+fn cli_dump_load() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4008";
+    let spawn_server = |data_dir: &str| {
+        let data_dir = temp_dir.path().join(data_dir);
+        fs::create_dir(&data_dir).unwrap();
+        let child = Command::cargo_bin("smoldb")
+            .unwrap()
+            .args(["--addr", addr])
+            .current_dir(&data_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child
+    };
+    let dump = || {
+        let output = Command::cargo_bin("smolcli")
+            .unwrap()
+            .args(["--addr", addr, "dump"])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let mut child = spawn_server("before");
+    for (key, value) in [
+        ("quoted", "a \"quoted\" value"),
+        ("multiline", "first line\nsecond line\n"),
+        ("empty", ""),
+    ] {
+        Command::cargo_bin("smolcli")
+            .unwrap()
+            .args(["--addr", addr, "set", key, value])
+            .assert()
+            .success();
+    }
+    // Enough keys to span several pages of the dump and batches of the load
+    let lines: String = (0..2500)
+        .map(|i| format!("{{\"key\":\"key{:04}\",\"value\":\"value{}\"}}\n", i, i))
+        .collect();
+    assert_cmd::Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["--addr", addr, "load"])
+        .write_stdin(lines)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    let dumped = dump();
+    assert_eq!(dumped.lines().count(), 2503);
+    assert!(dumped.starts_with("{\"key\":\"empty\",\"value\":\"\"}\n"));
+    assert!(dumped.contains("{\"key\":\"key0042\",\"value\":\"value42\"}\n"));
+    assert!(dumped.contains("{\"key\":\"multiline\",\"value\":\"first line\\nsecond line\\n\"}\n"));
+    assert!(dumped.contains("{\"key\":\"quoted\",\"value\":\"a \\\"quoted\\\" value\"}\n"));
+    let dump_path = temp_dir.path().join("dump.ndjson");
+    fs::write(&dump_path, &dumped).unwrap();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was not running");
+
+    // Loading the dump into an empty store reproduces it exactly
+    let mut child = spawn_server("after");
+    assert_eq!(dump(), "");
+    Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["--addr", addr, "load"])
+        .stdin(File::open(&dump_path).unwrap())
+        .assert()
+        .success();
+    assert_eq!(dump(), dumped);
+    Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["--addr", addr, "get", "multiline"])
+        .assert()
+        .success()
+        .stdout("first line\nsecond line\n\n");
+
+    // A line that is not an entry fails the load, after the lines before it
+    assert_cmd::Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["--addr", addr, "load"])
+        .write_stdin("{\"key\":\"new\",\"value\":\"value\"}\n\nnot json\n")
+        .assert()
+        .failure()
+        .stderr(contains("line 3"));
+    Command::cargo_bin("smolcli")
+        .unwrap()
+        .args(["--addr", addr, "get", "new"])
+        .assert()
+        .success()
+        .stdout("value\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was not running");
+}
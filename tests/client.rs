@@ -0,0 +1,99 @@
+use assert_cmd::prelude::*;
+use smoldb::Client;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::time::Duration;
+use tempfile::TempDir;
+
+// A `smoldb` server process, killed when dropped so that a failing test does not leave it running.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        // Errors are ignored as panicking while a failed test unwinds would abort the test run.
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn spawn_server(dir: &Path, addr: &str) -> Server {
+    Server(
+        Command::cargo_bin("smoldb")
+            .unwrap()
+            .args(["--addr", addr])
+            .current_dir(dir)
+            .spawn()
+            .unwrap(),
+    )
+}
+
+// A long-lived `Client` should keep working across a server restart without being reconnected.
+#[tokio::test]
+async fn client_survives_server_restart() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    let server = spawn_server(temp_dir.path(), addr);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let client = Client::connect(addr.parse().unwrap(), 4);
+    for i in 0..4 {
+        client
+            .set(format!("key{}", i), format!("value{}", i))
+            .await
+            .unwrap();
+    }
+    // Fill the pool with idle connections that the restart will close.
+    let (a, b, c, d) = tokio::join!(
+        client.get("key0".to_owned()),
+        client.get("key1".to_owned()),
+        client.get("key2".to_owned()),
+        client.get("key3".to_owned()),
+    );
+    for value in [a, b, c, d] {
+        assert!(value.unwrap().is_some());
+    }
+    assert_eq!(client.pool_stats().idle, 4);
+
+    drop(server);
+    let _server = spawn_server(temp_dir.path(), addr);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    for i in 0..4 {
+        assert_eq!(
+            client.get(format!("key{}", i)).await.unwrap(),
+            Some(format!("value{}", i))
+        );
+    }
+    client
+        .set("key4".to_owned(), "value4".to_owned())
+        .await
+        .unwrap();
+    assert_eq!(client.list().await.unwrap().len(), 5);
+}
+
+// With retrying turned off, a request on a connection closed by a server restart should fail rather than be retried.
+#[tokio::test]
+async fn client_without_retry() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4050";
+    let server = spawn_server(temp_dir.path(), addr);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let client = Client::builder(addr.parse().unwrap()).retry(false).build();
+    client
+        .set("key".to_owned(), "value".to_owned())
+        .await
+        .unwrap();
+    assert_eq!(client.pool_stats().idle, 1);
+
+    drop(server);
+    let _server = spawn_server(temp_dir.path(), addr);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    assert!(client.get("key".to_owned()).await.is_err());
+    // The failed connection is discarded, so the next request opens a new one.
+    assert_eq!(
+        client.get("key".to_owned()).await.unwrap(),
+        Some("value".to_owned())
+    );
+}
@@ -0,0 +1,394 @@
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use smoldb::{Bitcask, Memory, PutOutcome, Sled, Storage, StorageError, StorageResult};
+use tempfile::TempDir;
+
+// Runs the same assertions against a fresh store of an engine, so that every engine behaves the same.
+//
+// Each check opens its own store in a new temporary directory.
+fn run_conformance<S: Storage>(open: impl Fn(&Path) -> StorageResult<S>) -> StorageResult<()> {
+    set_get_overwrite(&open)?;
+    remove(&open)?;
+    remove_optional(&open)?;
+    touch(&open)?;
+    ttl(&open)?;
+    empty_value(&open)?;
+    empty_key(&open)?;
+    conditional_writes(&open)?;
+    list(&open)?;
+    scan(&open)?;
+    compact(&open)?;
+    Ok(())
+}
+
+// Runs `f` against a store opened in a new temporary directory.
+fn with_store<S: Storage>(
+    open: &dyn Fn(&Path) -> StorageResult<S>,
+    f: impl FnOnce(S) -> StorageResult<()>,
+) -> StorageResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    f(open(temp_dir.path())?)
+}
+
+fn set_get_overwrite<S: Storage>(open: &dyn Fn(&Path) -> StorageResult<S>) -> StorageResult<()> {
+    with_store(open, |store| {
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert!(!store.exists("key1".to_owned())?);
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert!(store.exists("key1".to_owned())?);
+
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+        let (value, _) = store.get_with_metadata("key1".to_owned())?.unwrap();
+        assert_eq!(value, "value2");
+        assert_eq!(store.len()?, 1);
+        Ok(())
+    })
+}
+
+fn remove<S: Storage>(open: &dyn Fn(&Path) -> StorageResult<S>) -> StorageResult<()> {
+    with_store(open, |store| {
+        assert!(matches!(
+            store.remove("key1".to_owned()),
+            Err(StorageError::KeyNotFound)
+        ));
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.remove("key1".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get_with_metadata("key1".to_owned())?, None);
+        assert!(!store.exists("key1".to_owned())?);
+        assert!(store.is_empty()?);
+
+        // A removed key is as missing as one that was never written.
+        assert!(matches!(
+            store.remove("key1".to_owned()),
+            Err(StorageError::KeyNotFound)
+        ));
+
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+        Ok(())
+    })
+}
+
+fn remove_optional<S: Storage>(open: &dyn Fn(&Path) -> StorageResult<S>) -> StorageResult<()> {
+    with_store(open, |store| {
+        assert!(!store.remove_optional("key1".to_owned())?);
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert!(store.remove_optional("key1".to_owned())?);
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert!(!store.remove_optional("key1".to_owned())?);
+
+        // An expired key no longer exists to be removed.
+        store.set_with_ttl(
+            "key2".to_owned(),
+            "value2".to_owned(),
+            Duration::from_millis(1),
+        )?;
+        thread::sleep(Duration::from_millis(10));
+        assert!(!store.remove_optional("key2".to_owned())?);
+        assert!(store.is_empty()?);
+
+        // The strict removal still fails once the key is gone.
+        assert!(matches!(
+            store.remove("key1".to_owned()),
+            Err(StorageError::KeyNotFound)
+        ));
+        Ok(())
+    })
+}
+
+fn touch<S: Storage>(open: &dyn Fn(&Path) -> StorageResult<S>) -> StorageResult<()> {
+    with_store(open, |store| {
+        assert!(matches!(
+            store.touch("key1".to_owned()),
+            Err(StorageError::KeyNotFound)
+        ));
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let (_, written) = store.get_with_metadata("key1".to_owned())?.unwrap();
+        thread::sleep(Duration::from_millis(5));
+        store.touch("key1".to_owned())?;
+        let (value, touched) = store.get_with_metadata("key1".to_owned())?.unwrap();
+        assert_eq!(value, "value1");
+        assert!(touched.unwrap() > written.unwrap());
+
+        // A removed key cannot be touched back to life.
+        store.remove("key1".to_owned())?;
+        assert!(matches!(
+            store.touch("key1".to_owned()),
+            Err(StorageError::KeyNotFound)
+        ));
+        assert_eq!(store.get("key1".to_owned())?, None);
+        Ok(())
+    })
+}
+
+fn ttl<S: Storage>(open: &dyn Fn(&Path) -> StorageResult<S>) -> StorageResult<()> {
+    with_store(open, |store| {
+        let short = Duration::from_millis(50);
+        store.set_with_ttl("key1".to_owned(), "value1".to_owned(), short)?;
+        store.set_with_ttl(
+            "key2".to_owned(),
+            "value2".to_owned(),
+            Duration::from_secs(60),
+        )?;
+        store.set_with_ttl("key3".to_owned(), "value3".to_owned(), short)?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        store.set_with_ttl("key4".to_owned(), "value4".to_owned(), short)?;
+        store.touch("key4".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.len()?, 4);
+
+        thread::sleep(Duration::from_millis(100));
+
+        // An expired key is as missing as one that was never written, and touching it does not keep it.
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get_with_metadata("key1".to_owned())?, None);
+        assert!(!store.exists("key1".to_owned())?);
+        assert_eq!(store.get("key4".to_owned())?, None);
+        let keys = vec!["key2".to_owned(), "key3".to_owned()];
+        assert_eq!(store.list_keys(), keys);
+        assert_eq!(store.list_page(None, 10)?.keys, keys);
+        assert_eq!(store.scan_prefix("key".to_owned())?, keys);
+        assert_eq!(store.range("key1".to_owned(), "key9".to_owned())?, keys);
+        assert_eq!(store.list_with_sizes()?.len(), 2);
+        assert_eq!(store.len()?, 2);
+        assert!(matches!(
+            store.touch("key1".to_owned()),
+            Err(StorageError::KeyNotFound)
+        ));
+        assert!(matches!(
+            store.remove("key4".to_owned()),
+            Err(StorageError::KeyNotFound)
+        ));
+
+        // Writing an expired key starts afresh, without the expiry.
+        assert!(store.set_if_absent("key1".to_owned(), "value5".to_owned())?);
+        store.compact()?;
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(store.get("key1".to_owned())?, Some("value5".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.len()?, 3);
+        Ok(())
+    })
+}
+
+fn empty_value<S: Storage>(open: &dyn Fn(&Path) -> StorageResult<S>) -> StorageResult<()> {
+    with_store(open, |store| {
+        store.set("key1".to_owned(), "".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+        assert!(store.exists("key1".to_owned())?);
+        assert_eq!(store.list_keys(), vec!["key1".to_owned()]);
+        assert_eq!(store.list_with_sizes()?, vec![("key1".to_owned(), 0)]);
+        assert_eq!(store.remove_by_value("".to_owned())?, 1);
+        assert_eq!(store.get("key1".to_owned())?, None);
+
+        store.set("key1".to_owned(), "".to_owned())?;
+        store.remove("key1".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        Ok(())
+    })
+}
+
+fn empty_key<S: Storage>(open: &dyn Fn(&Path) -> StorageResult<S>) -> StorageResult<()> {
+    with_store(open, |store| {
+        let invalid = |result: StorageResult<_>| matches!(result, Err(StorageError::InvalidKey(_)));
+        assert!(invalid(
+            store.set("".to_owned(), "value".to_owned()).map(drop)
+        ));
+        assert!(invalid(
+            store.put("".to_owned(), "value".to_owned()).map(drop)
+        ));
+        assert!(invalid(
+            store
+                .set_if_absent("".to_owned(), "value".to_owned())
+                .map(drop)
+        ));
+        assert!(invalid(
+            store
+                .get_or_set("".to_owned(), "value".to_owned())
+                .map(drop)
+        ));
+        assert!(invalid(
+            store.rpush("".to_owned(), "value".to_owned()).map(drop)
+        ));
+        assert!(invalid(store.set_with_ttl(
+            "".to_owned(),
+            "value".to_owned(),
+            Duration::from_secs(1)
+        )));
+        assert!(invalid(store.get("".to_owned()).map(drop)));
+        assert!(invalid(store.get_with_metadata("".to_owned()).map(drop)));
+        assert!(invalid(store.exists("".to_owned()).map(drop)));
+        assert!(invalid(store.lpop("".to_owned()).map(drop)));
+        assert!(invalid(store.remove("".to_owned())));
+        assert!(invalid(store.remove_optional("".to_owned()).map(drop)));
+        assert!(invalid(store.touch("".to_owned())));
+
+        // Nothing was written, and an empty prefix still matches every key.
+        assert!(store.is_empty()?);
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(store.scan_prefix("".to_owned())?, vec!["key1".to_owned()]);
+        Ok(())
+    })
+}
+
+fn conditional_writes<S: Storage>(open: &dyn Fn(&Path) -> StorageResult<S>) -> StorageResult<()> {
+    with_store(open, |store| {
+        assert_eq!(
+            store.put("key1".to_owned(), "value1".to_owned())?,
+            PutOutcome::Created
+        );
+        assert_eq!(
+            store.put("key1".to_owned(), "value2".to_owned())?,
+            PutOutcome::Updated
+        );
+        assert!(!store.set_if_absent("key1".to_owned(), "value3".to_owned())?);
+        assert_eq!(
+            store.get_or_set("key1".to_owned(), "value4".to_owned())?,
+            "value2"
+        );
+
+        store.remove("key1".to_owned())?;
+        assert_eq!(
+            store.put("key1".to_owned(), "value5".to_owned())?,
+            PutOutcome::Created
+        );
+        store.remove("key1".to_owned())?;
+        assert!(store.set_if_absent("key1".to_owned(), "value6".to_owned())?);
+        store.remove("key1".to_owned())?;
+        assert_eq!(
+            store.get_or_set("key1".to_owned(), "value7".to_owned())?,
+            "value7"
+        );
+        Ok(())
+    })
+}
+
+fn list<S: Storage>(open: &dyn Fn(&Path) -> StorageResult<S>) -> StorageResult<()> {
+    with_store(open, |store| {
+        assert!(store.list_keys().is_empty());
+        for key in ["b", "c", "a", "d"] {
+            store.set(key.to_owned(), format!("value-{}", key))?;
+        }
+        store.remove("c".to_owned())?;
+
+        let keys = vec!["a".to_owned(), "b".to_owned(), "d".to_owned()];
+        assert_eq!(store.list_keys(), keys);
+        assert_eq!(
+            store
+                .list_with_sizes()?
+                .into_iter()
+                .map(|(key, size)| (key, size as usize))
+                .collect::<Vec<_>>(),
+            vec![
+                ("a".to_owned(), 7),
+                ("b".to_owned(), 7),
+                ("d".to_owned(), 7)
+            ]
+        );
+        assert_eq!(store.len()?, 3);
+
+        let page = store.list_page(None, 2)?;
+        assert_eq!(page.keys, keys[..2]);
+        let page = store.list_page(page.next_cursor, 2)?;
+        assert_eq!(page.keys, keys[2..]);
+        assert_eq!(page.next_cursor, None);
+        Ok(())
+    })
+}
+
+fn scan<S: Storage>(open: &dyn Fn(&Path) -> StorageResult<S>) -> StorageResult<()> {
+    with_store(open, |store| {
+        for key in ["a", "ab", "abc", "b", "ba", "c"] {
+            store.set(key.to_owned(), "value".to_owned())?;
+        }
+        store.remove("ba".to_owned())?;
+
+        assert_eq!(
+            store.scan_prefix("a".to_owned())?,
+            vec!["a".to_owned(), "ab".to_owned(), "abc".to_owned()]
+        );
+        assert_eq!(store.scan_prefix("b".to_owned())?, vec!["b".to_owned()]);
+        assert!(store.scan_prefix("d".to_owned())?.is_empty());
+
+        assert_eq!(
+            store.range("ab".to_owned(), "c".to_owned())?,
+            vec!["ab".to_owned(), "abc".to_owned(), "b".to_owned()]
+        );
+        assert!(store.range("c".to_owned(), "a".to_owned())?.is_empty());
+        assert!(store.range("b".to_owned(), "b".to_owned())?.is_empty());
+
+        assert_eq!(store.remove_prefix("ab".to_owned())?, 2);
+        assert_eq!(
+            store.list_keys(),
+            vec!["a".to_owned(), "b".to_owned(), "c".to_owned()]
+        );
+        Ok(())
+    })
+}
+
+fn compact<S: Storage>(open: &dyn Fn(&Path) -> StorageResult<S>) -> StorageResult<()> {
+    with_store(open, |store| {
+        for iter in 0..10 {
+            for key_id in 0..10 {
+                store.set(format!("key{}", key_id), format!("value{}", iter))?;
+            }
+        }
+        store.set("empty".to_owned(), "".to_owned())?;
+        store.remove("key0".to_owned())?;
+
+        store.compact()?;
+        store.flush()?;
+
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for key_id in 1..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some("value9".to_owned())
+            );
+        }
+        assert_eq!(store.get("empty".to_owned())?, Some("".to_owned()));
+        assert_eq!(store.len()?, 10);
+
+        // The store keeps working after a compaction.
+        store.set("key0".to_owned(), "value10".to_owned())?;
+        assert_eq!(store.get("key0".to_owned())?, Some("value10".to_owned()));
+        Ok(())
+    })
+}
+
+// Only the engines that store their data on disk have a data directory.
+fn data_dir<S: Storage>(open: &dyn Fn(&Path) -> StorageResult<S>) -> StorageResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    assert_eq!(store.data_dir(), Some(temp_dir.path()));
+    assert_eq!(store.clone().data_dir(), Some(temp_dir.path()));
+    Ok(())
+}
+
+#[test]
+fn bitcask_conformance() -> StorageResult<()> {
+    run_conformance(|path| Bitcask::open(path))?;
+    data_dir(&|path| Bitcask::open(path))
+}
+
+#[test]
+fn sled_conformance() -> StorageResult<()> {
+    run_conformance(|path| Sled::open(path))?;
+    data_dir(&|path| Sled::open(path))
+}
+
+#[test]
+fn memory_conformance() -> StorageResult<()> {
+    run_conformance(|_| Ok(Memory::new()))?;
+    assert_eq!(Memory::new().data_dir(), None);
+    Ok(())
+}
@@ -0,0 +1,148 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_utils::sync::WaitGroup;
+use smoldb::{
+    NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool, ThreadPoolError,
+    ThreadPoolResult,
+};
+
+// Every spawned job should run.
+fn spawn_counter<P: ThreadPool>(pool: P) -> ThreadPoolResult<()> {
+    const TASK_NUM: usize = 20;
+    const ADD_COUNT: usize = 1000;
+
+    let wg = WaitGroup::new();
+    let counter = Arc::new(AtomicUsize::new(0));
+
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        let wg = wg.clone();
+        pool.spawn(move || {
+            for _ in 0..ADD_COUNT {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+            drop(wg);
+        })
+    }
+
+    wg.wait();
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM * ADD_COUNT);
+    Ok(())
+}
+
+// `join` should only return once every spawned job has finished, panicking ones included.
+fn spawn_join<P: ThreadPool>() -> ThreadPoolResult<()> {
+    const TASK_NUM: usize = 16;
+
+    let pool = P::new(4)?;
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+    pool.spawn(|| {
+        panic_control::disable_hook_in_current_thread();
+        thread::sleep(Duration::from_millis(20));
+        panic!();
+    });
+
+    pool.join();
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+
+    // A pool without outstanding jobs returns straight away, and can be joined again after more jobs.
+    pool.join();
+    let counter_clone = Arc::clone(&counter);
+    pool.spawn(move || {
+        counter_clone.fetch_add(1, Ordering::SeqCst);
+    });
+    pool.join();
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM + 1);
+    Ok(())
+}
+
+// A panicking job should not stop the pool from running later jobs.
+fn spawn_panic_task<P: ThreadPool>() -> ThreadPoolResult<()> {
+    const TASK_NUM: usize = 1000;
+
+    let pool = P::new(4)?;
+    for _ in 0..TASK_NUM {
+        pool.spawn(move || {
+            // Suppresses the flood of panic messages to the console.
+            panic_control::disable_hook_in_current_thread();
+
+            panic!();
+        })
+    }
+
+    spawn_counter(pool)
+}
+
+#[test]
+fn naive_thread_pool_spawn_counter() -> ThreadPoolResult<()> {
+    let pool = NaiveThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_counter() -> ThreadPoolResult<()> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn rayon_thread_pool_spawn_counter() -> ThreadPoolResult<()> {
+    let pool = RayonThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn naive_thread_pool_panic_task() -> ThreadPoolResult<()> {
+    spawn_panic_task::<NaiveThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_no_threads() {
+    assert!(matches!(
+        NaiveThreadPool::new(0),
+        Err(ThreadPoolError::NoThreads)
+    ));
+}
+
+#[test]
+fn shared_queue_thread_pool_panic_task() -> ThreadPoolResult<()> {
+    spawn_panic_task::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_no_threads() {
+    assert!(matches!(
+        SharedQueueThreadPool::new(0),
+        Err(ThreadPoolError::NoThreads)
+    ));
+}
+
+#[test]
+fn rayon_thread_pool_panic_task() -> ThreadPoolResult<()> {
+    spawn_panic_task::<RayonThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_join() -> ThreadPoolResult<()> {
+    spawn_join::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_join() -> ThreadPoolResult<()> {
+    spawn_join::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_join() -> ThreadPoolResult<()> {
+    spawn_join::<RayonThreadPool>()
+}